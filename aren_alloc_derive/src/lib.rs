@@ -0,0 +1,58 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! `#[derive(Poolable)]` for `aren_alloc`, re-exported by it under the
+//! `derive` feature.
+//!
+//! Deriving `Poolable` for a type larger than the largest size class
+//! fails to compile:
+//!
+//! ```compile_fail
+//! extern crate aren_alloc;
+//! use aren_alloc::Poolable;
+//!
+//! #[derive(Poolable)]
+//! struct TooBig([u8; 257]);
+//!
+//! fn main() { }
+//! ```
+
+extern crate proc_macro;
+extern crate proc_macro2;
+#[macro_use]
+extern crate quote;
+extern crate syn;
+
+use proc_macro::TokenStream;
+use syn::DeriveInput;
+
+/// Implement `aren_alloc::Poolable`, asserting at compile time that
+/// the type fits in 256 bytes unless it is generic.
+#[proc_macro_derive(Poolable)]
+pub fn derive_poolable(input: TokenStream) -> TokenStream {
+    let input = syn::parse_macro_input!(input as DeriveInput);
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    // the size of a generic type is only known once instantiated,
+    // which `Poolable::alloc_into` checks
+    let assertion = if input.generics.params.is_empty() {
+        let message = format!("`{}` is larger than 256 bytes, the largest size class", name);
+        quote! {
+            const _: () = ::std::assert!(::std::mem::size_of::<#name>() <= 256, #message);
+        }
+    } else {
+        quote! { }
+    };
+
+    let expanded = quote! {
+        impl #impl_generics ::aren_alloc::Poolable for #name #ty_generics #where_clause { }
+        #assertion
+    };
+    expanded.into()
+}
@@ -0,0 +1,45 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+extern crate aren_alloc;
+
+use aren_alloc::{Allocator, Poolable};
+
+#[derive(Poolable)]
+struct Exact {
+    head: u64,
+    rest: [u8; 248],
+}
+
+#[derive(Poolable)]
+#[allow(dead_code)]
+struct Small(u8, u16);
+
+#[derive(Poolable)]
+struct Wrapper<T: Clone> where T: Default {
+    inner: T,
+}
+
+#[test]
+fn test_exactly_256_bytes() {
+    assert_eq!(std::mem::size_of::<Exact>(), 256);
+    assert_eq!(Exact::SIZE_CLASS, 256);
+    let allocator = Allocator::new();
+    let ptr = Exact{ head: 1, rest: [2; 248] }.alloc_into(&allocator);
+    assert_eq!((ptr.head, ptr.rest[247]), (1, 2));
+    assert_eq!(allocator.stats().live, 1);
+}
+
+#[test]
+fn test_classes() {
+    assert_eq!(Small::SIZE_CLASS, 8);
+    assert_eq!(Wrapper::<[u32; 5]>::SIZE_CLASS, 32);
+    let allocator = Allocator::new();
+    let ptr = Wrapper{ inner: String::from("pooled") }.alloc_into(&allocator);
+    assert_eq!(ptr.inner, "pooled");
+}
@@ -0,0 +1,14 @@
+// Deriving `Poolable` for a type larger than 256 bytes fails with the
+// name of the type in the message.
+
+extern crate aren_alloc;
+
+use aren_alloc::Poolable;
+
+#[derive(Poolable)]
+struct TooBig {
+    head: u64,
+    rest: [u8; 256],
+}
+
+fn main() { }
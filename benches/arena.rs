@@ -0,0 +1,54 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Measure filling a frame with values then dropping them all, through
+//! `Pointer`s recycling one slot each, against references of
+//! `alloc_ref` reclaimed by a single `reset`.
+
+#![feature(test)]
+
+extern crate aren_alloc;
+extern crate test;
+
+use aren_alloc::Allocator;
+use test::{black_box, Bencher};
+
+/// Values per frame.
+const VALUES: usize = 4096;
+
+#[derive(Copy, Clone)]
+#[allow(dead_code)]
+struct Particle {
+    pos: [f32; 3],
+    ttl: u32,
+}
+
+#[bench]
+fn bench_pointers(b: &mut Bencher) {
+    let allocator = Allocator::with_capacity(VALUES);
+    let mut held = Vec::with_capacity(VALUES);
+    b.iter(|| {
+        for i in 0..VALUES {
+            held.push(allocator.alloc(Particle{ pos: [i as f32; 3], ttl: i as u32 }));
+        }
+        black_box(&held);
+        held.clear();
+    });
+}
+
+#[bench]
+fn bench_alloc_ref(b: &mut Bencher) {
+    let mut allocator = Allocator::with_capacity(VALUES);
+    b.iter(|| {
+        // the values stay allocated without holding on to them
+        for i in 0..VALUES {
+            black_box(allocator.alloc_ref(Particle{ pos: [i as f32; 3], ttl: i as u32 }));
+        }
+        allocator.reset();
+    });
+}
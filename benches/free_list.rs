@@ -0,0 +1,70 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Measure allocating from a free list scrambled by frees in random
+//! order, where every pop chases a pointer to a cold cache line,
+//! against one in address order.
+
+#![feature(test)]
+
+extern crate aren_alloc;
+extern crate test;
+
+use aren_alloc::Allocator;
+use test::{black_box, Bencher};
+
+/// A 64 bytes slot, each on its own cache line.
+#[derive(Copy, Clone)]
+#[allow(dead_code)]
+struct Body {
+    pos: [f64; 3],
+    vel: [f64; 3],
+}
+
+/// Slots per round, 2 MiB of them, beyond most L2 caches.
+const SLOTS: usize = 1 << 15;
+
+/// A permutation of `0..n`, shuffled by a fixed xorshift.
+fn shuffled(n: usize) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..n).collect();
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    for i in (1..n).rev() {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        order.swap(i, state as usize % (i + 1));
+    }
+    order
+}
+
+/// Allocate `SLOTS` bodies, then free them in `order`, leaving the free
+/// list in the reverse of it for the next round.
+fn round(allocator: &Allocator, order: &[usize]) {
+    let mut held: Vec<_> = (0..SLOTS)
+        .map(|i| Some(allocator.alloc(Body{ pos: [i as f64; 3], vel: [0.0; 3] })))
+        .collect();
+    for &i in order {
+        black_box(held[i].take());
+    }
+}
+
+#[bench]
+fn bench_scrambled(b: &mut Bencher) {
+    let allocator = Allocator::with_capacity(SLOTS);
+    let order = shuffled(SLOTS);
+    round(&allocator, &order);
+    b.iter(|| round(&allocator, &order));
+}
+
+#[bench]
+fn bench_in_order(b: &mut Bencher) {
+    let allocator = Allocator::with_capacity(SLOTS);
+    let order: Vec<usize> = (0..SLOTS).rev().collect();
+    round(&allocator, &order);
+    b.iter(|| round(&allocator, &order));
+}
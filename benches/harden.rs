@@ -0,0 +1,45 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Measure the cost of a hardened allocator, whose free lists are
+//! shuffled, against a plain one, on the same churn of allocations and
+//! frees. Under the `harden` feature.
+
+#![feature(test)]
+
+extern crate aren_alloc;
+extern crate test;
+
+use aren_alloc::{Allocator, AllocatorBuilder};
+use test::{black_box, Bencher};
+
+/// Slots held at once.
+const HELD: usize = 1024;
+
+/// Fill `HELD` slots, then replace every other one, twice over.
+fn churn(allocator: &Allocator) {
+    let mut held: Vec<_> = (0..HELD as u64).map(|i| allocator.alloc([i; 4])).collect();
+    for round in 0..2 {
+        for i in (round..HELD).step_by(2) {
+            held[i] = allocator.alloc([i as u64; 4]);
+        }
+    }
+    black_box(&held);
+}
+
+#[bench]
+fn bench_plain(b: &mut Bencher) {
+    let allocator = AllocatorBuilder::new().build();
+    b.iter(|| churn(&allocator));
+}
+
+#[bench]
+fn bench_hardened(b: &mut Bencher) {
+    let allocator = AllocatorBuilder::new().harden(true).build();
+    b.iter(|| churn(&allocator));
+}
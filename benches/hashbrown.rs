@@ -0,0 +1,69 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Compare building and dropping small `hashbrown` maps, of at most 16
+//! entries, with pooled tables against tables from the global allocator.
+
+#![feature(allocator_api, test)]
+
+extern crate aren_alloc;
+extern crate hashbrown;
+extern crate test;
+
+use aren_alloc::{Allocator, PoolAlloc};
+use hashbrown::{DefaultHashBuilder, HashMap};
+use test::{black_box, Bencher};
+
+const MAPS: u32 = 64;
+
+fn fill<S: std::hash::BuildHasher, A: std::alloc::Allocator>(map: &mut HashMap<u32, u32, S, A>, len: u32) {
+    for i in 0..len {
+        map.insert(i, i);
+    }
+}
+
+fn bench_global(b: &mut Bencher, len: u32) {
+    b.iter(|| {
+        for _ in 0..MAPS {
+            let mut map = HashMap::with_hasher(DefaultHashBuilder::default());
+            fill(&mut map, len);
+            black_box(&map);
+        }
+    });
+}
+
+fn bench_pooled(b: &mut Bencher, len: u32) {
+    let allocator = Allocator::new();
+    b.iter(|| {
+        for _ in 0..MAPS {
+            let mut map = HashMap::with_hasher_in(DefaultHashBuilder::default(), PoolAlloc::new(&allocator));
+            fill(&mut map, len);
+            black_box(&map);
+        }
+    });
+}
+
+#[bench]
+fn bench_global_4(b: &mut Bencher) {
+    bench_global(b, 4)
+}
+
+#[bench]
+fn bench_pooled_4(b: &mut Bencher) {
+    bench_pooled(b, 4)
+}
+
+#[bench]
+fn bench_global_16(b: &mut Bencher) {
+    bench_global(b, 16)
+}
+
+#[bench]
+fn bench_pooled_16(b: &mut Bencher) {
+    bench_pooled(b, 16)
+}
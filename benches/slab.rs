@@ -0,0 +1,68 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Compare freeing the expired particles of a `Slab` with `retain`
+//! against collecting their handles, then removing them one by one.
+
+#![feature(test)]
+
+extern crate aren_alloc;
+extern crate test;
+
+use aren_alloc::Slab;
+use test::{black_box, Bencher};
+
+const LEN: u32 = 4096;
+
+/// A slab of particles, a quarter of which expire every frame.
+fn slab() -> Slab<[f32; 4]> {
+    let mut slab = Slab::new();
+    for i in 0..LEN {
+        slab.insert([i as f32, 0.0, 0.0, (i % 4 + 1) as f32]);
+    }
+    slab
+}
+
+/// Revive the expired particles.
+fn refill(slab: &mut Slab<[f32; 4]>) {
+    while slab.len() < LEN as usize {
+        slab.insert([0.0, 0.0, 0.0, 4.0]);
+    }
+}
+
+#[bench]
+fn bench_retain(b: &mut Bencher) {
+    let mut slab = slab();
+    b.iter(|| {
+        slab.retain(|_, particle| {
+            particle[3] -= 1.0;
+            particle[3] > 0.0
+        });
+        black_box(slab.len());
+        refill(&mut slab);
+    });
+}
+
+#[bench]
+fn bench_collect_remove(b: &mut Bencher) {
+    let mut slab = slab();
+    b.iter(|| {
+        let mut expired = Vec::new();
+        for (handle, particle) in slab.iter_mut() {
+            particle[3] -= 1.0;
+            if particle[3] <= 0.0 {
+                expired.push(handle);
+            }
+        }
+        for handle in expired {
+            slab.remove(handle);
+        }
+        black_box(slab.len());
+        refill(&mut slab);
+    });
+}
@@ -0,0 +1,238 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Compare the thread-local `Allocator` against `SyncAllocator`,
+//! both with mutex-guarded and lock-free pools, and `SharedAllocator`.
+
+#![feature(test)]
+
+extern crate aren_alloc;
+extern crate test;
+
+use aren_alloc::{Allocator, SharedAllocator, SyncAllocator, ThreadCache};
+use test::{black_box, Bencher};
+
+#[derive(Copy, Clone)]
+#[allow(dead_code)]
+struct Particle {
+    pos: [f32; 3],
+    vel: [f32; 3],
+}
+
+const BATCH: usize = 256;
+
+#[bench]
+fn bench_local_alloc_free(b: &mut Bencher) {
+    let allocator = Allocator::new();
+    b.iter(|| {
+        for i in 0..BATCH {
+            black_box(allocator.alloc(Particle{ pos: [i as f32; 3], vel: [0.0; 3] }));
+        }
+    });
+}
+
+#[bench]
+fn bench_sync_alloc_free(b: &mut Bencher) {
+    let allocator = SyncAllocator::new();
+    b.iter(|| {
+        for i in 0..BATCH {
+            black_box(allocator.alloc(Particle{ pos: [i as f32; 3], vel: [0.0; 3] }));
+        }
+    });
+}
+
+#[bench]
+fn bench_local_batch(b: &mut Bencher) {
+    let allocator = Allocator::new();
+    let mut held = Vec::with_capacity(BATCH);
+    b.iter(|| {
+        for i in 0..BATCH {
+            held.push(allocator.alloc(Particle{ pos: [i as f32; 3], vel: [0.0; 3] }));
+        }
+        held.clear();
+    });
+}
+
+#[bench]
+fn bench_sync_batch(b: &mut Bencher) {
+    let allocator = SyncAllocator::new();
+    let mut held = Vec::with_capacity(BATCH);
+    b.iter(|| {
+        for i in 0..BATCH {
+            held.push(allocator.alloc(Particle{ pos: [i as f32; 3], vel: [0.0; 3] }));
+        }
+        held.clear();
+    });
+}
+
+#[bench]
+fn bench_sync_contended(b: &mut Bencher) {
+    let allocator = SyncAllocator::new();
+    b.iter(|| {
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                let allocator = &allocator;
+                s.spawn(move || {
+                    for i in 0..BATCH {
+                        black_box(allocator.alloc(Particle{ pos: [i as f32; 3], vel: [0.0; 3] }));
+                    }
+                });
+            }
+        });
+    });
+}
+
+#[bench]
+fn bench_lock_free_alloc_free(b: &mut Bencher) {
+    let allocator = SyncAllocator::lock_free();
+    b.iter(|| {
+        for i in 0..BATCH {
+            black_box(allocator.alloc(Particle{ pos: [i as f32; 3], vel: [0.0; 3] }));
+        }
+    });
+}
+
+#[bench]
+fn bench_lock_free_batch(b: &mut Bencher) {
+    let allocator = SyncAllocator::lock_free();
+    let mut held = Vec::with_capacity(BATCH);
+    b.iter(|| {
+        for i in 0..BATCH {
+            held.push(allocator.alloc(Particle{ pos: [i as f32; 3], vel: [0.0; 3] }));
+        }
+        held.clear();
+    });
+}
+
+#[bench]
+fn bench_lock_free_contended(b: &mut Bencher) {
+    let allocator = SyncAllocator::lock_free();
+    b.iter(|| {
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                let allocator = &allocator;
+                s.spawn(move || {
+                    for i in 0..BATCH {
+                        black_box(allocator.alloc(Particle{ pos: [i as f32; 3], vel: [0.0; 3] }));
+                    }
+                });
+            }
+        });
+    });
+}
+
+#[bench]
+fn bench_shared_alloc_free(b: &mut Bencher) {
+    let allocator = SharedAllocator::new();
+    let cache = ThreadCache::for_current(&allocator);
+    b.iter(|| {
+        for i in 0..BATCH {
+            cache.free(black_box(cache.alloc(Particle{ pos: [i as f32; 3], vel: [0.0; 3] })));
+        }
+    });
+}
+
+#[bench]
+fn bench_shared_batch(b: &mut Bencher) {
+    let allocator = SharedAllocator::new();
+    let cache = ThreadCache::for_current(&allocator);
+    let mut held = Vec::with_capacity(BATCH);
+    b.iter(|| {
+        for i in 0..BATCH {
+            held.push(cache.alloc(Particle{ pos: [i as f32; 3], vel: [0.0; 3] }));
+        }
+        for p in held.drain(..) {
+            cache.free(p);
+        }
+    });
+}
+
+#[bench]
+fn bench_shared_contended(b: &mut Bencher) {
+    let allocator = SharedAllocator::new();
+    b.iter(|| {
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                let allocator = &allocator;
+                s.spawn(move || {
+                    let cache = ThreadCache::for_current(allocator);
+                    for i in 0..BATCH {
+                        cache.free(black_box(cache.alloc(Particle{ pos: [i as f32; 3], vel: [0.0; 3] })));
+                    }
+                });
+            }
+        });
+    });
+}
+
+#[bench]
+fn bench_sync_cross_thread_free(b: &mut Bencher) {
+    let allocator = SyncAllocator::new();
+    b.iter(|| {
+        let (tx, rx) = std::sync::mpsc::sync_channel(BATCH);
+        std::thread::scope(|s| {
+            let allocator = &allocator;
+            s.spawn(move || {
+                for i in 0..BATCH {
+                    tx.send(allocator.alloc(Particle{ pos: [i as f32; 3], vel: [0.0; 3] })).unwrap();
+                }
+            });
+            s.spawn(move || rx.iter().for_each(drop));
+        });
+    });
+}
+
+#[bench]
+fn bench_shared_cross_thread_free(b: &mut Bencher) {
+    let allocator = SharedAllocator::new();
+    b.iter(|| {
+        let (tx, rx) = std::sync::mpsc::sync_channel(BATCH);
+        std::thread::scope(|s| {
+            let allocator = &allocator;
+            s.spawn(move || {
+                let cache = ThreadCache::for_current(allocator);
+                for i in 0..BATCH {
+                    tx.send(cache.alloc(Particle{ pos: [i as f32; 3], vel: [0.0; 3] })).unwrap();
+                }
+            });
+            s.spawn(move || {
+                let cache = ThreadCache::for_current(allocator);
+                rx.iter().for_each(|p| cache.free(p));
+            });
+        });
+    });
+}
+
+/// Two threads allocating from adjacent classes, contending only if
+/// the state of the classes shares a cache line.
+fn disjoint_classes(allocator: &SyncAllocator) {
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            for i in 0..16 * BATCH {
+                black_box(allocator.alloc(i as u64));
+            }
+        });
+        s.spawn(|| {
+            for i in 0..16 * BATCH {
+                black_box(allocator.alloc([i as u64; 2]));
+            }
+        });
+    });
+}
+
+#[bench]
+fn bench_sync_disjoint_classes(b: &mut Bencher) {
+    let allocator = SyncAllocator::new();
+    b.iter(|| disjoint_classes(&allocator));
+}
+
+#[bench]
+fn bench_lock_free_disjoint_classes(b: &mut Bencher) {
+    let allocator = SyncAllocator::lock_free();
+    b.iter(|| disjoint_classes(&allocator));
+}
@@ -0,0 +1,74 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Compare reviving 256-byte particles in a `TypedPool` by writing a
+//! fresh value against reusing the previous contents of the slot.
+
+#![feature(test)]
+
+extern crate aren_alloc;
+extern crate test;
+
+use aren_alloc::TypedPool;
+use test::{black_box, Bencher};
+
+#[derive(Copy, Clone)]
+#[allow(dead_code)]
+struct Particle {
+    pos: [f32; 3],
+    life: f32,
+    history: [[f32; 4]; 15],
+}
+
+const BATCH: u32 = 256;
+
+fn pool() -> TypedPool<Particle> {
+    let mut pool = TypedPool::new();
+    let dead = Particle{ pos: [0.0; 3], life: 0.0, history: [[0.0; 4]; 15] };
+    for _ in 0..BATCH {
+        pool.alloc(dead);
+    }
+    for i in 0..BATCH {
+        pool.recycle(i);
+    }
+    pool
+}
+
+#[bench]
+fn bench_revive_alloc(b: &mut Bencher) {
+    let mut pool = pool();
+    b.iter(|| {
+        for i in 0..BATCH {
+            let index = pool.alloc(Particle{ pos: [i as f32; 3], life: 1.0, history: [[0.0; 4]; 15] });
+            black_box(pool.get(index));
+        }
+        for i in 0..BATCH {
+            pool.recycle(i);
+        }
+    });
+}
+
+#[bench]
+fn bench_revive_alloc_recycled(b: &mut Bencher) {
+    let mut pool = pool();
+    b.iter(|| {
+        for i in 0..BATCH {
+            // the history is a ring, overwritten as the particle lives
+            let (index, particle) = unsafe { pool.alloc_recycled() };
+            let particle = particle.as_mut_ptr();
+            unsafe {
+                (*particle).pos = [i as f32; 3];
+                (*particle).life = 1.0;
+            }
+            black_box(pool.get(index));
+        }
+        for i in 0..BATCH {
+            pool.recycle(i);
+        }
+    });
+}
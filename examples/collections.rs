@@ -0,0 +1,62 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Standard collections allocating from the pools through `PoolAlloc`.
+
+#![feature(allocator_api)]
+
+extern crate aren_alloc;
+
+use std::collections::VecDeque;
+use std::fmt::Debug;
+
+use aren_alloc::{Allocator, PoolAlloc, PoolBox};
+
+#[derive(Debug)]
+#[allow(dead_code)]
+enum Command {
+    Draw{ mesh: u32, instances: u32 },
+    Clear([f32; 4]),
+}
+
+fn main() {
+    let allocator = Allocator::new();
+    let alloc = PoolAlloc::new(&allocator);
+
+    // a small vector stays in one slot, growing within its class in place
+    let mut ids = Vec::new_in(alloc);
+    ids.extend_from_slice(&[3u32, 1, 4]);
+    println!("{} ids in a slot: {:?}", ids.len(), allocator.stats());
+
+    // past 256 bytes it moves onto the heap, and back when shrunk
+    ids.extend(0..100);
+    println!("{} ids on the heap: {:?}", ids.len(), allocator.stats());
+    ids.truncate(8);
+    ids.shrink_to_fit();
+    println!("{} ids in a slot again: {:?}", ids.len(), allocator.stats());
+
+    let boxed = Box::new_in(Command::Clear([0.0; 4]), alloc);
+    println!("{:?} in a slot", boxed);
+
+    // boxes unsize as usual, and are freed to the pools wherever they go
+    let shown: Vec<PoolBox<dyn Debug>> = vec![
+        Box::new_in(Command::Draw{ mesh: 7, instances: 1 }, &allocator),
+        Box::new_in([1.0f32; 100], &allocator),
+    ];
+    println!("{:?}, the array on the heap: {:?}", shown[0], allocator.stats());
+
+    let mut queue = VecDeque::new_in(alloc);
+    for frame in 0..4 {
+        queue.push_back(Command::Draw{ mesh: frame, instances: 16 });
+    }
+    while let Some(command) = queue.pop_front() {
+        println!("{:?}", command);
+    }
+    drop((ids, boxed, shown, queue));
+    println!("all freed: {:?}", allocator.stats());
+}
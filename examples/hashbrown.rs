@@ -0,0 +1,34 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Small per-frame `hashbrown` maps whose tables are pooled.
+
+extern crate aren_alloc;
+extern crate hashbrown;
+
+use aren_alloc::{Allocator, PoolAlloc};
+use hashbrown::{DefaultHashBuilder, HashMap};
+
+fn main() {
+    let allocator = Allocator::new();
+    let alloc = PoolAlloc::new(&allocator);
+
+    for frame in 0..3u32 {
+        // visible entities per material, rebuilt every frame
+        let mut batches = HashMap::with_hasher_in(DefaultHashBuilder::default(), alloc);
+        for entity in 0..12 + frame * 4 {
+            *batches.entry(entity % 7).or_insert(0u32) += 1;
+        }
+        println!("frame {}: {} batches, {:?}", frame, batches.len(), allocator.stats());
+    }
+
+    // a map outgrowing 256 bytes moves its table onto the heap
+    let mut big = HashMap::with_hasher_in(DefaultHashBuilder::default(), alloc);
+    big.extend((0..100u64).map(|i| (i, i * i)));
+    println!("{} entries on the heap: {:?}", big.len(), allocator.stats());
+}
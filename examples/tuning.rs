@@ -0,0 +1,44 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Record the peak usage of a workload, then configure the page
+//! capacities of the allocators of later runs from it.
+
+extern crate aren_alloc;
+
+use aren_alloc::{Allocator, AllocatorBuilder};
+
+/// A frame of a simulation: particles, a few contacts between them,
+/// and a broad phase cell per particle pair.
+fn frame(allocator: &Allocator, frame: u32) {
+    let particles: Vec<_> = (0..200 + frame % 50).map(|i| allocator.alloc([i as f32; 6])).collect();
+    let contacts: Vec<_> = particles.windows(2).step_by(7).map(|w| allocator.alloc((w[0][0], w[1][0]))).collect();
+    let cells: Vec<_> = (0..particles.len() / 2).map(|i| allocator.alloc([i as u64; 6])).collect();
+    assert!(contacts.len() < particles.len() && cells.len() < particles.len());
+}
+
+fn main() {
+    // record a representative run with small pages
+    let recorder = AllocatorBuilder::new().name("recorder").capacity_for(8, 16).capacity_for(32, 16).build();
+    for i in 0..100 {
+        frame(&recorder, i);
+    }
+    let suggested = recorder.suggest_capacities(1.25);
+    println!("recorded {} pages, suggesting {:?}", recorder.pages().len(), suggested);
+
+    // configure the allocator of the next run
+    let builder = suggested.iter().fold(AllocatorBuilder::new(), |builder, &(class, cap)| builder.capacity_for(class, cap));
+    let tuned = builder.name("tuned").build();
+    let pages = tuned.pages().len();
+    for i in 0..100 {
+        frame(&tuned, i);
+    }
+    // the first page of every class held the peak
+    assert_eq!(tuned.pages().len(), pages);
+    println!("tuned run: {} pages, {:?}", pages, tuned.stats());
+}
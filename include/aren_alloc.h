@@ -0,0 +1,44 @@
+/*
+ * Copyright 2017 Dasein Phaos aka. Luxko
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+/*
+ * C bindings of aren_alloc, built with the `ffi` feature.
+ *
+ * An allocator must only be used from the thread that created it.
+ */
+
+#ifndef AREN_ALLOC_H
+#define AREN_ALLOC_H
+
+#include <stddef.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct Allocator Allocator;
+
+/* Create an allocator with pages of `page_cap` slots, or of 4096 bytes if 0. */
+Allocator *aren_alloc_new(size_t page_cap);
+
+/* Destroy `alloc`, freeing every slot. No-op on null. */
+void aren_alloc_destroy(Allocator *alloc);
+
+/* Allocate `size` bytes, at most 256. Returns null on a null allocator or oversize request. */
+void *aren_alloc_alloc(Allocator *alloc, size_t size);
+
+/* Free `ptr`, allocated with the same `size`. Ignores null and foreign pointers. */
+void aren_alloc_free(Allocator *alloc, void *ptr, size_t size);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* AREN_ALLOC_H */
@@ -0,0 +1,803 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! `std::alloc::Allocator` for `&Allocator`, so that the standard
+//! collections and `hashbrown` could allocate from the pools, and
+//! `allocator_api2::alloc::Allocator` with the `allocator-api2` feature,
+//! for the crates using it on stable.
+
+use std::alloc::{AllocError, GlobalAlloc, Layout, System};
+use std::fmt;
+use std::ptr::NonNull;
+
+use {Allocator, Node, Pool, PAGE_ALIGN};
+
+/// Largest alignment served by the pools, beyond which `System` is used.
+/// A slot is aligned to the smaller of its class and the page alignment.
+const MAX_ALIGN: usize = PAGE_ALIGN;
+
+impl Allocator {
+    /// Get the pool serving `layout`, or `None` if `layout` is served by
+    /// `System`. Allocation and deallocation both route through here,
+    /// so a block is always freed to where it came from.
+    #[inline]
+    pub(crate) fn pool_for_layout(&self, layout: Layout) -> Option<&Pool> {
+        if layout.align() <= MAX_ALIGN {
+            // the class of a block is at least its alignment
+            self.pool_for_size(std::cmp::max(layout.size(), layout.align()))
+        } else {
+            None
+        }
+    }
+
+    /// Allocate a slot fitting `layout`, of the class of the larger of
+    /// its size and alignment. The slot is to be freed by
+    /// `dealloc_layout`.
+    ///
+    /// ```rust
+    /// use std::alloc::Layout;
+    /// use aren_alloc::Allocator;
+    ///
+    /// let allocator = Allocator::new();
+    /// let layout = Layout::new::<[u16; 12]>();
+    /// let block = allocator.alloc_layout(layout).unwrap();
+    /// unsafe {
+    ///     block.as_ptr().write_bytes(0, layout.size());
+    ///     allocator.dealloc_layout(block, layout);
+    /// }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `layout` is larger than 256 bytes, aligned
+    /// beyond 16 bytes, or its class is missing. Returns an error too if
+    /// the budget of the allocator is exhausted, and its handler couldn't
+    /// recover.
+    #[inline]
+    pub fn alloc_layout(&self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
+        let pool = self.pool_for_layout(layout).ok_or(AllocError)?;
+        let node = self.pop_or_recover(pool, layout.size()).ok_or(AllocError)?;
+        Ok(unsafe { NonNull::new_unchecked(node as *mut u8) })
+    }
+
+    /// Recycle a slot allocated by `alloc_layout`.
+    ///
+    /// A layout of another class than that of the slot is caught in
+    /// debug builds.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be allocated by `alloc_layout` of `self`, with a layout
+    /// of the same class, and must not be used afterwards.
+    #[inline]
+    #[track_caller]
+    pub unsafe fn dealloc_layout(&self, ptr: NonNull<u8>, layout: Layout) {
+        let pool = self.pool_for_layout(layout);
+        debug_assert!(pool.is_some_and(|pool| pool.owns(ptr.as_ptr())),
+                      "{:?} isn't of the class of the slot at {:p}", layout, ptr);
+        if let Some(pool) = pool {
+            pool.recycle(ptr.as_ptr() as *mut Node);
+        }
+    }
+
+    /// Move a slot allocated by `alloc_layout` into a slot fitting `new`.
+    ///
+    /// If both layouts map to the same class, the slot is returned as is,
+    /// without copying. Otherwise the first `min(old.size(), new.size())`
+    /// bytes are copied into a slot of the class of `new`, and the old
+    /// slot is recycled.
+    ///
+    /// ```rust
+    /// use std::alloc::Layout;
+    /// use aren_alloc::Allocator;
+    ///
+    /// let allocator = Allocator::new();
+    /// let (small, large) = (Layout::array::<u32>(5).unwrap(), Layout::array::<u32>(7).unwrap());
+    /// let block = allocator.alloc_layout(small).unwrap();
+    /// unsafe {
+    ///     // both in the class of 32 bytes
+    ///     assert_eq!(allocator.realloc_layout(block, small, large), Ok(block));
+    ///     allocator.dealloc_layout(block, large);
+    /// }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns an error, leaving the old slot untouched, if `new` can't
+    /// be allocated by `alloc_layout`. Nothing falls back to `System`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` and `old` must be as for `dealloc_layout`.
+    #[track_caller]
+    pub unsafe fn realloc_layout(&self, ptr: NonNull<u8>, old: Layout, new: Layout) -> Result<NonNull<u8>, AllocError> {
+        let to = self.pool_for_layout(new).ok_or(AllocError)?;
+        if self.pool_for_layout(old).is_some_and(|from| std::ptr::eq(from, to)) {
+            return Ok(ptr);
+        }
+        let block = self.alloc_layout(new)?;
+        std::ptr::copy_nonoverlapping(ptr.as_ptr(), block.as_ptr(), std::cmp::min(old.size(), new.size()));
+        self.dealloc_layout(ptr, old);
+        Ok(block)
+    }
+
+    /// Allocate a block fitting `layout`, from a pool if it fits in 256
+    /// bytes, from `System` otherwise. A pooled block spans the whole slot.
+    pub(crate) fn allocate_layout(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let (ptr, len) = match self.pool_for_layout(layout) {
+            Some(pool) => (self.alloc_layout(layout)?.as_ptr(), pool.ele_size),
+            None if layout.size() == 0 => (std::ptr::without_provenance_mut(layout.align()), 0),
+            None => (unsafe { System.alloc(layout) }, layout.size()),
+        };
+        debug_assert!(ptr.addr().is_multiple_of(layout.align()));
+        NonNull::new(std::ptr::slice_from_raw_parts_mut(ptr, len)).ok_or(AllocError)
+    }
+
+    /// Free a block allocated by `allocate_layout`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be allocated by `allocate_layout` of `self`, with
+    /// a layout of the same alignment, and a size between the requested
+    /// size and the length of the returned block.
+    pub(crate) unsafe fn deallocate_layout(&self, ptr: NonNull<u8>, layout: Layout) {
+        match self.pool_for_layout(layout) {
+            Some(_) => self.dealloc_layout(ptr, layout),
+            None if layout.size() == 0 => (),
+            None => System.dealloc(ptr.as_ptr(), layout),
+        }
+    }
+
+    /// Move a block allocated by `allocate_layout` into a block fitting
+    /// `new`. The block stays in place if both layouts are served by the
+    /// same pool, and is reallocated by `System` if both are served by it.
+    /// Otherwise, across the 256 bytes boundary or between classes, the
+    /// contents are copied into a new block.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` and `old` must be as for `deallocate_layout`.
+    pub(crate) unsafe fn reallocate_layout(&self, ptr: NonNull<u8>, old: Layout, new: Layout)
+        -> Result<NonNull<[u8]>, AllocError>
+    {
+        let from = self.pool_for_layout(old);
+        let to = self.pool_for_layout(new);
+        match (from, to) {
+            (Some(_), Some(to)) => {
+                let block = self.realloc_layout(ptr, old, new)?;
+                return Ok(NonNull::slice_from_raw_parts(block, to.ele_size));
+            }
+            (None, None) if old.align() == new.align() && old.size() != 0 && new.size() != 0 => {
+                let ptr = System.realloc(ptr.as_ptr(), old, new.size());
+                return NonNull::new(std::ptr::slice_from_raw_parts_mut(ptr, new.size())).ok_or(AllocError);
+            }
+            _ => (),
+        }
+        let block = self.allocate_layout(new)?;
+        let len = std::cmp::min(old.size(), new.size());
+        std::ptr::copy_nonoverlapping(ptr.as_ptr(), block.as_ptr() as *mut u8, len);
+        self.deallocate_layout(ptr, old);
+        Ok(block)
+    }
+
+    /// As `reallocate_layout`, zeroing the block past the old size.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` and `old` must be as for `deallocate_layout`, and `new` must
+    /// be no smaller than `old`.
+    pub(crate) unsafe fn reallocate_layout_zeroed(&self, ptr: NonNull<u8>, old: Layout, new: Layout)
+        -> Result<NonNull<[u8]>, AllocError>
+    {
+        let block = self.reallocate_layout(ptr, old, new)?;
+        let start = (block.as_ptr() as *mut u8).add(old.size());
+        std::ptr::write_bytes(start, 0, block.len() - old.size());
+        Ok(block)
+    }
+}
+
+/// An `std::alloc::Allocator` allocating from the pools of an `Allocator`,
+/// for `Vec::new_in`, `Box::new_in` and the like.
+///
+/// - Blocks of up to 256 bytes, aligned to at most 16 bytes, take a whole
+///   slot of the smallest class holding them. Other blocks, including
+///   blocks too big for any class, or whose class is missing and not
+///   rounded up, see `AllocatorBuilder::only_classes`, come from `System`.
+/// - Growing or shrinking a block within its class, such as a `Vec`
+///   growing from 20 to 32 bytes, keeps the block in place.
+/// - Growing or shrinking a block to another class, or across the 256
+///   bytes boundary in either direction, copies it into a new block.
+/// - Reallocating a `System` block that stays beyond the classes goes
+///   through `System`'s `realloc`.
+///
+/// `&Allocator` implements `std::alloc::Allocator` the same way.
+///
+/// ```rust
+/// #![feature(allocator_api)]
+/// use aren_alloc::{Allocator, PoolAlloc};
+///
+/// let allocator = Allocator::new();
+/// let mut v = Vec::new_in(PoolAlloc::new(&allocator));
+/// v.extend_from_slice(&[1u32, 2, 3]);
+/// let b = Box::new_in([0u8; 100], PoolAlloc::new(&allocator));
+/// # #[cfg(feature = "stats")]
+/// assert_eq!(allocator.stats().live, 2);
+/// ```
+#[derive(Copy, Clone)]
+pub struct PoolAlloc<'a> {
+    allocator: &'a Allocator,
+}
+
+impl<'a> PoolAlloc<'a> {
+    /// Allocate from the pools of `allocator`.
+    #[inline]
+    pub fn new(allocator: &'a Allocator) -> PoolAlloc<'a> {
+        PoolAlloc{ allocator }
+    }
+
+    /// Get the underlying allocator.
+    #[inline]
+    pub fn allocator(&self) -> &'a Allocator {
+        self.allocator
+    }
+}
+
+impl<'a> fmt::Debug for PoolAlloc<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PoolAlloc").field("allocator", &(self.allocator as *const Allocator)).finish()
+    }
+}
+
+unsafe impl<'a> std::alloc::Allocator for PoolAlloc<'a> {
+    #[inline]
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.allocator.allocate_layout(layout)
+    }
+
+    #[inline]
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.allocator.deallocate_layout(ptr, layout)
+    }
+
+    #[inline]
+    unsafe fn grow(&self, ptr: NonNull<u8>, old: Layout, new: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.allocator.reallocate_layout(ptr, old, new)
+    }
+
+    #[inline]
+    unsafe fn grow_zeroed(&self, ptr: NonNull<u8>, old: Layout, new: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.allocator.reallocate_layout_zeroed(ptr, old, new)
+    }
+
+    #[inline]
+    unsafe fn shrink(&self, ptr: NonNull<u8>, old: Layout, new: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.allocator.reallocate_layout(ptr, old, new)
+    }
+}
+
+/// A `Box` allocating from the pools of an `Allocator`, see `PoolAlloc`
+/// for the blocks served by `System` instead.
+///
+/// As the `Box` keeps its allocator, it's dropped to the pool of its
+/// class, or to `System`, wherever it's moved or swapped to. It unsizes
+/// as a plain `Box` does.
+///
+/// ```rust
+/// #![feature(allocator_api)]
+/// use std::fmt::Debug;
+/// use aren_alloc::{Allocator, PoolBox};
+///
+/// let allocator = Allocator::new();
+/// let boxes: Vec<PoolBox<dyn Debug>> = vec![
+///     Box::new_in(1u8, &allocator),
+///     Box::new_in([0u64; 4], &allocator),
+///     // served by `System`
+///     Box::new_in([0u8; 1000], &allocator),
+/// ];
+/// # #[cfg(feature = "stats")]
+/// assert_eq!(allocator.stats().live, 2);
+/// drop(boxes);
+/// # #[cfg(feature = "stats")]
+/// assert_eq!(allocator.stats().live, 0);
+/// ```
+pub type PoolBox<'a, T> = Box<T, &'a Allocator>;
+
+/// Allocates as a `PoolAlloc` of the allocator.
+///
+/// ```rust
+/// #![feature(allocator_api)]
+/// use aren_alloc::Allocator;
+///
+/// let allocator = Allocator::new();
+/// let mut v = Vec::new_in(&allocator);
+/// v.extend_from_slice(&[1u32, 2, 3]);
+/// let b = Box::new_in([0u8; 100], &allocator);
+/// # #[cfg(feature = "stats")]
+/// assert_eq!(allocator.stats().live, 2);
+/// ```
+unsafe impl std::alloc::Allocator for &Allocator {
+    #[inline]
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        PoolAlloc::new(self).allocate(layout)
+    }
+
+    #[inline]
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        PoolAlloc::new(self).deallocate(ptr, layout)
+    }
+
+    #[inline]
+    unsafe fn grow(&self, ptr: NonNull<u8>, old: Layout, new: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        PoolAlloc::new(self).grow(ptr, old, new)
+    }
+
+    #[inline]
+    unsafe fn grow_zeroed(&self, ptr: NonNull<u8>, old: Layout, new: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        PoolAlloc::new(self).grow_zeroed(ptr, old, new)
+    }
+
+    #[inline]
+    unsafe fn shrink(&self, ptr: NonNull<u8>, old: Layout, new: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        PoolAlloc::new(self).shrink(ptr, old, new)
+    }
+}
+
+/// Allocates as `std::alloc::Allocator` does, for `allocator_api2`'s
+/// `Box` and `Vec`, or `hashbrown` on stable.
+///
+/// ```rust
+/// # extern crate allocator_api2;
+/// # extern crate aren_alloc;
+/// use allocator_api2::boxed::Box;
+/// use aren_alloc::Allocator;
+///
+/// # fn main() {
+/// let allocator = Allocator::new();
+/// let b = Box::new_in([0u8; 100], &allocator);
+/// # #[cfg(feature = "stats")]
+/// assert_eq!(allocator.stats().live, 1);
+/// # }
+/// ```
+#[cfg(feature = "allocator-api2")]
+unsafe impl allocator_api2::alloc::Allocator for &Allocator {
+    #[inline]
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, allocator_api2::alloc::AllocError> {
+        self.allocate_layout(layout).map_err(|AllocError| allocator_api2::alloc::AllocError)
+    }
+
+    #[inline]
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.deallocate_layout(ptr, layout)
+    }
+
+    #[inline]
+    unsafe fn grow(&self, ptr: NonNull<u8>, old: Layout, new: Layout)
+        -> Result<NonNull<[u8]>, allocator_api2::alloc::AllocError>
+    {
+        self.reallocate_layout(ptr, old, new).map_err(|AllocError| allocator_api2::alloc::AllocError)
+    }
+
+    #[inline]
+    unsafe fn grow_zeroed(&self, ptr: NonNull<u8>, old: Layout, new: Layout)
+        -> Result<NonNull<[u8]>, allocator_api2::alloc::AllocError>
+    {
+        self.reallocate_layout_zeroed(ptr, old, new).map_err(|AllocError| allocator_api2::alloc::AllocError)
+    }
+
+    #[inline]
+    unsafe fn shrink(&self, ptr: NonNull<u8>, old: Layout, new: Layout)
+        -> Result<NonNull<[u8]>, allocator_api2::alloc::AllocError>
+    {
+        self.reallocate_layout(ptr, old, new).map_err(|AllocError| allocator_api2::alloc::AllocError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hashbrown::{DefaultHashBuilder, HashMap, HashSet};
+    use std::alloc::Allocator as _;
+
+    #[test]
+    fn test_layout_round_trip() {
+        let allocator = Allocator::with_capacity(2);
+        let layouts = [(1, 1), (8, 8), (9, 1), (16, 16), (4, 16), (17, 4), (64, 8), (65, 1), (128, 16), (200, 2), (256, 16)];
+        for &(size, align) in layouts.iter() {
+            let layout = Layout::from_size_align(size, align).unwrap();
+            let pool = allocator.pool_for_layout(layout).unwrap();
+            let free = pool.free_count();
+            let block = allocator.alloc_layout(layout).unwrap();
+            assert!((block.as_ptr() as usize).is_multiple_of(align));
+            assert!(pool.ele_size >= std::cmp::max(size, align));
+            unsafe {
+                block.as_ptr().write_bytes(0xab, size);
+                allocator.dealloc_layout(block, layout);
+            }
+            assert_eq!(pool.free_count(), free);
+            // the slot is handed out again
+            assert_eq!(allocator.alloc_layout(layout).unwrap(), block);
+            unsafe { allocator.dealloc_layout(block, layout) };
+        }
+        assert_eq!(allocator.alloc_layout(Layout::from_size_align(257, 1).unwrap()), Err(AllocError));
+        assert_eq!(allocator.alloc_layout(Layout::from_size_align(32, 32).unwrap()), Err(AllocError));
+    }
+
+    #[test]
+    fn test_realloc_layout() {
+        let allocator = Allocator::new();
+        let layout = |size| Layout::from_size_align(size, 4).unwrap();
+        unsafe {
+            let block = allocator.alloc_layout(layout(20)).unwrap();
+            block.as_ptr().write_bytes(7, 20);
+            assert_eq!(allocator.realloc_layout(block, layout(20), layout(28)), Ok(block));
+            block.as_ptr().add(20).write_bytes(8, 8);
+
+            // grow into the class of 64 bytes
+            let free = allocator.pool32.free_count();
+            let grown = allocator.realloc_layout(block, layout(28), layout(60)).unwrap();
+            assert_eq!(allocator.pool32.free_count(), free + 1);
+            let bytes = std::slice::from_raw_parts(grown.as_ptr(), 28);
+            assert!(bytes[..20].iter().all(|&b| b == 7) && bytes[20..].iter().all(|&b| b == 8));
+
+            // and shrink into the class of 8 bytes
+            let free = allocator.pool64.free_count();
+            let shrunk = allocator.realloc_layout(grown, layout(60), layout(4)).unwrap();
+            assert_eq!(allocator.pool64.free_count(), free + 1);
+            assert_eq!(std::slice::from_raw_parts(shrunk.as_ptr(), 4), &[7; 4]);
+            assert_eq!(allocator.realloc_layout(shrunk, layout(4), layout(257)), Err(AllocError));
+            allocator.dealloc_layout(shrunk, layout(4));
+        }
+    }
+
+    #[test]
+    fn test_realloc_layout_capped() {
+        // room for the initial pages only, with a single slot of 64 bytes
+        let allocator = ::AllocatorBuilder::new()
+            .only_classes(&[32, 64])
+            .capacity_for(32, 2)
+            .capacity_for(64, 1)
+            .budget(2 * 32 + 64)
+            .build();
+        let (small, large) = (Layout::new::<[u8; 32]>(), Layout::new::<[u8; 64]>());
+        unsafe {
+            let taken = allocator.alloc_layout(large).unwrap();
+            let block = allocator.alloc_layout(small).unwrap();
+            block.as_ptr().write_bytes(3, 32);
+            assert_eq!(allocator.realloc_layout(block, small, large), Err(AllocError));
+            // the old slot is still allocated, its contents intact
+            assert_eq!(allocator.pool32.free_count(), 1);
+            assert_eq!(std::slice::from_raw_parts(block.as_ptr(), 32), &[3; 32]);
+            allocator.dealloc_layout(taken, large);
+            let grown = allocator.realloc_layout(block, small, large).unwrap();
+            assert_eq!((grown, allocator.pool32.free_count()), (taken, 2));
+            allocator.dealloc_layout(grown, large);
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "isn't of the class of the slot")]
+    fn test_layout_mismatch() {
+        let allocator = Allocator::new();
+        let block = allocator.alloc_layout(Layout::new::<[u8; 24]>()).unwrap();
+        unsafe { allocator.dealloc_layout(block, Layout::new::<[u8; 40]>()) };
+    }
+
+    #[test]
+    fn test_collections() {
+        let allocator = Allocator::new();
+        let mut v = Vec::new_in(&allocator);
+        for i in 0..64u32 {
+            v.push(i);
+        }
+        // 256 bytes, still pooled
+        #[cfg(feature = "stats")]
+        assert_eq!((v.capacity(), allocator.stats().live), (64, 1));
+        v.push(64);
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 0);
+        v.truncate(3);
+        v.shrink_to_fit();
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 1);
+        assert_eq!(v, [0, 1, 2]);
+
+        let aligned = Box::new_in(0u128, &allocator);
+        assert_eq!(&*aligned as *const u128 as usize % 16, 0);
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 2);
+        drop((v, aligned));
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 0);
+    }
+
+    trait Shape {
+        fn area(&self) -> f32;
+    }
+
+    impl Shape for [f32; 2] {
+        fn area(&self) -> f32 {
+            self[0] * self[1]
+        }
+    }
+
+    impl Shape for [f32; 100] {
+        fn area(&self) -> f32 {
+            self.iter().sum()
+        }
+    }
+
+    #[test]
+    fn test_pool_box() {
+        let allocator = Allocator::with_capacity(4);
+        let free = allocator.pool8.free_count();
+        let sized: PoolBox<[f32; 2]> = Box::new_in([2.0, 3.0], &allocator);
+        assert_eq!(allocator.pool8.free_count(), free - 1);
+        assert!(allocator.pool8.owns(&*sized as *const [f32; 2] as *mut u8));
+
+        // unsized, the layout dropped is the one of the value, of the
+        // same class, the 400 bytes one going to `System`
+        let shapes: Vec<PoolBox<dyn Shape>> = vec![sized, Box::new_in([1.0; 100], &allocator)];
+        assert_eq!(shapes.iter().map(|s| s.area()).collect::<Vec<f32>>(), [6.0, 100.0]);
+        let free128 = allocator.pool128.free_count();
+        let slice: PoolBox<[u16]> = Box::new_in([1u16; 40], &allocator);
+        assert_eq!((slice.len(), allocator.pool128.free_count()), (40, free128 - 1));
+        // a `Box` allocates nothing for a zero sized value
+        let zst: PoolBox<()> = Box::new_in((), &allocator);
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 2);
+        drop((shapes, slice, zst));
+        assert_eq!((allocator.pool8.free_count(), allocator.pool128.free_count()), (free, free128));
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 0);
+
+        // beyond the classes, or aligned beyond 16 bytes
+        #[repr(align(64))]
+        struct Aligned(#[allow(dead_code)] u8);
+        let big: PoolBox<[u64; 100]> = Box::new_in([7; 100], &allocator);
+        let aligned = Box::new_in(Aligned(1), &allocator);
+        assert_eq!(&*aligned as *const Aligned as usize % 64, 0);
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 0);
+        assert!(big.iter().all(|&x| x == 7));
+        drop((big, aligned));
+    }
+
+    #[test]
+    #[cfg(feature = "stats")]
+    fn test_pool_box_swap() {
+        let (first, second) = (Allocator::new(), Allocator::new());
+        let mut a: PoolBox<dyn Shape> = Box::new_in([1.0, 2.0], &first);
+        let mut b: PoolBox<dyn Shape> = Box::new_in([0.5; 100], &second);
+        std::mem::swap(&mut a, &mut b);
+        assert_eq!((a.area(), b.area()), (50.0, 2.0));
+        // each box is freed to its own allocator
+        drop(b);
+        assert_eq!((first.stats().live, second.stats().live), (0, 0));
+        let mut c: PoolBox<dyn Shape> = Box::new_in([3.0, 3.0], &second);
+        std::mem::swap(&mut a, &mut c);
+        drop(c);
+        assert_eq!(second.stats().live, 1);
+        drop(a);
+        assert_eq!(second.stats().live, 0);
+    }
+
+    #[test]
+    fn test_hash_map() {
+        let allocator = Allocator::new();
+        let mut map = HashMap::with_hasher_in(DefaultHashBuilder::default(), &allocator);
+        for i in 0..1000u32 {
+            map.insert(i, i * 2);
+        }
+        assert!((0..1000).all(|i| map[&i] == i * 2));
+        // the small tables along the way were pooled, the final one isn't
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 0);
+        #[cfg(feature = "stats")]
+        assert!(allocator.stats().allocs > 0);
+
+        let mut keys = Vec::new_in(&allocator);
+        keys.extend(map.keys().take(10).copied());
+        map.retain(|k, _| !keys.contains(k));
+        #[cfg(feature = "stats")]
+        assert_eq!((map.len(), allocator.stats().live), (990, 1));
+    }
+
+    /// Live slots of the 64, 128 and 256 bytes classes.
+    #[cfg(feature = "stats")]
+    fn live_tables(allocator: &Allocator) -> [usize; 3] {
+        [allocator.pool64.live.get(), allocator.pool128.live.get(), allocator.pool256.live.get()]
+    }
+
+    #[test]
+    #[cfg(feature = "stats")]
+    fn test_hash_map_growth() {
+        let allocator = Allocator::new();
+        let mut map = HashMap::with_hasher_in(DefaultHashBuilder::default(), PoolAlloc::new(&allocator));
+        assert_eq!(allocator.stats().allocs, 0);
+        // each table takes one slot, the old one freed on growth, until
+        // the table outgrows the classes
+        let mut seen = Vec::new();
+        for i in 0..64u32 {
+            map.insert(i, i);
+            let live = live_tables(&allocator);
+            if seen.last() != Some(&live) {
+                seen.push(live);
+            }
+            assert_eq!(allocator.stats().live, live.iter().sum::<usize>());
+        }
+        assert_eq!(seen, [[1, 0, 0], [0, 1, 0], [0, 0, 1], [0, 0, 0]]);
+        assert!((0..64).all(|i| map[&i] == i));
+
+        // shrinking moves it back into a slot
+        map.retain(|&k, _| k < 2);
+        map.shrink_to_fit();
+        assert_eq!(live_tables(&allocator), [1, 0, 0]);
+        assert_eq!((map[&0], map[&1]), (0, 1));
+    }
+
+    #[test]
+    fn test_hash_map_clear_drop() {
+        let allocator = Allocator::new();
+        let marker = std::rc::Rc::new(());
+        {
+            let mut map = HashMap::with_hasher_in(DefaultHashBuilder::default(), PoolAlloc::new(&allocator));
+            for i in 0..6u8 {
+                map.insert(i, marker.clone());
+            }
+            #[cfg(feature = "stats")]
+            let stats = allocator.stats();
+            #[cfg(feature = "stats")]
+            assert_eq!(stats.live, 1);
+            // `clear` drops the entries, yet keeps the table
+            map.clear();
+            assert_eq!(std::rc::Rc::strong_count(&marker), 1);
+            #[cfg(feature = "stats")]
+            assert_eq!(allocator.stats(), stats);
+            for i in 0..6u8 {
+                map.insert(i, marker.clone());
+            }
+            #[cfg(feature = "stats")]
+            assert_eq!(allocator.stats(), stats);
+        }
+        assert_eq!(std::rc::Rc::strong_count(&marker), 1);
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 0);
+
+        // a map grown onto the heap frees nothing to the pools
+        {
+            let mut map = HashMap::with_hasher_in(DefaultHashBuilder::default(), &allocator);
+            map.extend((0..100u64).map(|i| (i, i)));
+            #[cfg(feature = "stats")]
+            assert_eq!(allocator.stats().live, 0);
+        }
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 0);
+    }
+
+    #[test]
+    fn test_hash_set() {
+        let allocator = Allocator::new();
+        let mut set = HashSet::with_hasher_in(DefaultHashBuilder::default(), PoolAlloc::new(&allocator));
+        for s in ["a", "b", "c", "a"] {
+            set.insert(s);
+        }
+        #[cfg(feature = "stats")]
+        assert_eq!((set.len(), allocator.stats().live), (3, 1));
+        let mut other = HashSet::with_hasher_in(DefaultHashBuilder::default(), PoolAlloc::new(&allocator));
+        other.extend(["b", "d"]);
+        assert_eq!(set.intersection(&other).collect::<Vec<_>>(), [&"b"]);
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 2);
+        drop((set, other));
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 0);
+    }
+
+    unsafe fn alloc_in(alloc: PoolAlloc, size: usize) -> (NonNull<[u8]>, Layout) {
+        let layout = Layout::from_size_align(size, 4).unwrap();
+        (alloc.allocate(layout).unwrap(), layout)
+    }
+
+    #[test]
+    fn test_grow_shrink() {
+        let allocator = Allocator::new();
+        let alloc = PoolAlloc::new(&allocator);
+        unsafe {
+            // within a class, in place
+            let (block, old) = alloc_in(alloc, 20);
+            assert_eq!(block.len(), 32);
+            let ptr = block.as_ptr() as *mut u8;
+            std::ptr::write_bytes(ptr, 7, 20);
+            let new = Layout::from_size_align(32, 4).unwrap();
+            let grown = alloc.grow_zeroed(NonNull::new_unchecked(ptr), old, new).unwrap();
+            assert_eq!(grown.as_ptr() as *mut u8, ptr);
+            assert_eq!(grown.as_ref()[19..21], [7, 0]);
+
+            // up a class, then across the boundary, then back
+            let mut layout = new;
+            let mut ptr = NonNull::new_unchecked(ptr);
+            for &size in &[100, 256, 257, 4000, 300, 256, 24] {
+                let new = Layout::from_size_align(size, 4).unwrap();
+                let block = if size > layout.size() {
+                    alloc.grow(ptr, layout, new).unwrap()
+                } else {
+                    alloc.shrink(ptr, layout, new).unwrap()
+                };
+                assert!(block.len() >= size);
+                assert_eq!(block.as_ref()[..20], [7; 20]);
+                #[cfg(feature = "stats")]
+                assert_eq!(allocator.stats().live, if size <= 256 { 1 } else { 0 });
+                ptr = NonNull::new_unchecked(block.as_ptr() as *mut u8);
+                layout = new;
+            }
+            alloc.deallocate(ptr, layout);
+            #[cfg(feature = "stats")]
+            assert_eq!(allocator.stats().live, 0);
+
+            // over-aligned blocks are left to `System`
+            let aligned = Layout::from_size_align(64, 64).unwrap();
+            let block = alloc.allocate(aligned).unwrap();
+            assert_eq!(block.as_ptr() as *mut u8 as usize % 64, 0);
+            #[cfg(feature = "stats")]
+            assert_eq!(allocator.stats().allocs, 5);
+            alloc.deallocate(NonNull::new_unchecked(block.as_ptr() as *mut u8), aligned);
+        }
+    }
+
+    #[test]
+    fn test_vec_across_boundary() {
+        let allocator = Allocator::new();
+        let mut v: Vec<u64, PoolAlloc> = Vec::with_capacity_in(3, PoolAlloc::new(&allocator));
+        v.extend(0..3);
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 1);
+        v.extend(3..40);
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 0);
+        v.truncate(5);
+        v.shrink_to_fit();
+        #[cfg(feature = "stats")]
+        assert_eq!((v.capacity(), allocator.stats().live), (5, 1));
+        assert_eq!(v, [0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    #[cfg(feature = "allocator-api2")]
+    fn test_allocator_api2() {
+        use stable_hashbrown::HashMap;
+
+        let allocator = Allocator::new();
+        let mut map = HashMap::new_in(&allocator);
+        for i in 0..14u32 {
+            map.insert(i, i * 2);
+        }
+        // the table reallocated twice within the pools, up to 14 entries
+        #[cfg(feature = "stats")]
+        assert_eq!((allocator.stats().live, allocator.stats().allocs), (1, 3));
+        // then beyond 256 bytes, to `System`
+        for i in 14..200u32 {
+            map.insert(i, i * 2);
+        }
+        assert!((0..200u32).all(|i| map[&i] == i * 2));
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 0);
+        map.retain(|&k, _| k < 10);
+        map.shrink_to_fit();
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 1);
+        assert_eq!(map.len(), 10);
+
+        let bytes = allocator_api2::vec::Vec::<u8, _>::with_capacity_in(24, &allocator);
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 2);
+        drop((map, bytes));
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 0);
+    }
+}
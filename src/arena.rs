@@ -0,0 +1,230 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Slots handed out as plain references, reclaimed all at once.
+
+use std::cell::Cell;
+
+use {check_slot, Allocator, Pool};
+
+impl Pool {
+    /// Put every slot of the chain back on the free list, the first page
+    /// first, as if every page was just allocated.
+    fn reset(&self) {
+        #[cfg(debug_assertions)]
+        self.epoch.set(self.epoch.get() + 1);
+        // slots freed on other threads are about to be free anyway
+        let remote = self.remote.take_all();
+        if remote.is_null() && self.clock.is_some() && self.tracked_live() == 0 {
+            // every slot is on the free list already
+            return;
+        }
+        let pages: Vec<&Pool> = self.chain().filter(|p| p.pool.borrow().len() != 0).collect();
+        let mut head = std::ptr::null_mut();
+        let mut free = 0;
+        let pinned = !self.pinned.borrow().is_empty();
+        for p in pages.into_iter().rev() {
+            let page = p.pool.borrow();
+            if pinned {
+                // pinned values never dropped keep their slots
+                let (first, slots) = unsafe { self.link_unpinned(&page, head) };
+                head = first;
+                free += slots;
+            } else {
+                head = unsafe { self.link(&page, head).as_ptr() };
+                free += page.len() / self.ele_size;
+            }
+        }
+        self.head.set(std::ptr::NonNull::new(head));
+        self.free_slots.set(free);
+        self.track_reset();
+        #[cfg(feature = "stats")]
+        self.live.set(0);
+        self.recount_pinned();
+    }
+}
+
+impl Allocator {
+    /// Move `value` into a slot, returning a plain reference to it, with
+    /// no pointer to recycle the slot when dropped: the slot stays in use
+    /// until `reset` reclaims it, along with every other slot.
+    ///
+    /// This saves the bookkeeping of dropping a `Pointer` for values
+    /// that all die together, as in an arena. The slots of `alloc_ref`
+    /// are never recycled one by one, so a pool mixing them with
+    /// `Pointer`s only reuses the slots of the latter, and `trim` never
+    /// releases a page holding the former, until the next `reset`. They
+    /// aren't reported as leaks when the allocator is dropped.
+    ///
+    /// ```rust
+    /// use aren_alloc::Allocator;
+    /// let mut allocator = Allocator::new();
+    /// for frame in 0..3u32 {
+    ///     let points: Vec<&mut (u32, u32)> = (0..100).map(|i| allocator.alloc_ref((frame, i))).collect();
+    ///     assert_eq!(*points[99], (frame, 99));
+    ///     allocator.reset();
+    /// }
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics as `alloc` does.
+    #[inline]
+    #[track_caller]
+    #[allow(clippy::mut_from_ref)]
+    pub fn alloc_ref<T: Copy>(&self, value: T) -> &mut T {
+        let pool = self.pool_for::<T>();
+        check_slot::<T>(pool.ele_size);
+        let node = match self.pop_or_recover(pool, std::mem::size_of::<T>()) {
+            Some(node) => node as *mut T,
+            None => panic!("allocation budget exhausted"),
+        };
+        let refs = self.ref_slots(pool);
+        refs.set(refs.get() + 1);
+        unsafe {
+            std::ptr::write(node, value);
+            &mut *node
+        }
+    }
+
+    /// Reclaim every slot of the allocator, keeping its pages.
+    ///
+    /// Borrowing the allocator mutably guarantees that no reference from
+    /// `alloc_ref`, nor any `Pointer`, outlives the slots reclaimed.
+    /// Slots of pointers leaked with `mem::forget` are reclaimed too,
+    /// without dropping their values, unless the pointers were pinned:
+    /// the slot of a pinned value stays in use until the value is
+    /// dropped, see `Pointer::into_pin`. In debug builds, unsafe code
+    /// dereferencing a `Pointer` across the reset panics.
+    pub fn reset(&mut self) {
+        unsafe { self.reset_unchecked() }
+    }
+
+    /// Reclaim every slot of the allocator, as `reset`.
+    ///
+    /// # Safety
+    ///
+    /// No slot of the allocator may be accessed afterwards, until it is
+    /// handed out again.
+    pub(crate) unsafe fn reset_unchecked(&self) {
+        for pool in self.all_pools() {
+            pool.reset();
+        }
+        for refs in self.ref_slots.iter() {
+            refs.set(0);
+        }
+    }
+
+    /// The count of slots of `alloc_ref` in `pool`.
+    #[inline]
+    pub(crate) fn ref_slots(&self, pool: &Pool) -> &Cell<usize> {
+        &self.ref_slots[pool.ele_size.trailing_zeros() as usize - 3]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use AllocatorBuilder;
+
+    #[test]
+    fn test_alloc_ref_reset() {
+        let mut allocator = Allocator::with_capacity(4);
+        {
+            let mut refs: Vec<&mut u64> = (0..10).map(|i| allocator.alloc_ref(i)).collect();
+            *refs[9] += 1;
+            assert_eq!(refs.iter().map(|r| **r).sum::<u64>(), 46);
+            let ptr = allocator.alloc([1u8; 100]);
+            drop(ptr);
+            std::mem::forget(allocator.alloc([2u8; 100]));
+            assert_eq!(allocator.pool8.free_count(), 2);
+            assert_eq!(allocator.pool8.tracked_live(), 10);
+        }
+        allocator.reset();
+        assert_eq!(allocator.pool8.free_count(), 12);
+        assert_eq!(allocator.pool128.free_count(), 4);
+        assert_eq!(allocator.pool8.tracked_live() + allocator.pool128.tracked_live(), 0);
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 0);
+        // the first page is handed out first again
+        let first = &*allocator.alloc_ref(0u64) as *const u64 as usize;
+        assert_eq!(first, allocator.pages()[0].base_addr);
+    }
+
+    #[test]
+    #[cfg_attr(target_family = "wasm", ignore = "no threads on wasm")]
+    fn test_reset_remote_frees() {
+        let mut allocator = Allocator::with_capacity(4);
+        let items: Vec<_> = (0..6).map(|i| allocator.alloc_send(i as u64)).collect();
+        thread::scope(|s| {
+            s.spawn(move || drop(items));
+        });
+        allocator.reset();
+        // the slots freed remotely aren't linked twice
+        allocator.drain_remote_frees();
+        assert_eq!(allocator.pool8.free_count(), 8);
+        let held: Vec<_> = (0..8).map(|i| allocator.alloc(i as u64)).collect();
+        let mut addrs: Vec<_> = held.iter().map(|p| &**p as *const u64 as usize).collect();
+        addrs.dedup();
+        assert_eq!(addrs.len(), 8);
+    }
+
+    #[test]
+    fn test_reset_lets_trim() {
+        let mut allocator = AllocatorBuilder::new().capacity_for(8, 2).build();
+        for i in 0..6u64 {
+            allocator.alloc_ref(i);
+        }
+        let eights = |allocator: &Allocator| allocator.pages().iter().filter(|p| p.class_size == 8).count();
+        // pages holding slots of `alloc_ref` are in use until the reset
+        allocator.trim(0);
+        assert_eq!(eights(&allocator), 3);
+        allocator.reset();
+        allocator.trim(0);
+        assert_eq!((eights(&allocator), allocator.pool8.free_count()), (0, 0));
+    }
+
+    #[test]
+    fn test_alloc_ref_no_leak() {
+        let allocator = Allocator::new();
+        allocator.alloc_ref([1u32; 10]);
+        allocator.alloc_ref(1u8);
+        // dropping the allocator reclaims them, without a leak report
+        drop(allocator);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[cfg_attr(target_family = "wasm", ignore = "panics abort on wasm")]
+    fn test_stale_pointer_panics() {
+        let allocator = Allocator::new();
+        let mut ptr = allocator.alloc(1u64);
+        *ptr += 1;
+        unsafe { allocator.reset_unchecked() };
+        let err = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| *ptr)).unwrap_err();
+        let msg = err.downcast_ref::<String>().unwrap();
+        assert_eq!(msg, "pointer #0 of epoch 0 dereferenced at epoch 1, after its allocator was reset");
+        let err = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| *ptr = 3)).unwrap_err();
+        assert!(err.downcast_ref::<String>().unwrap().contains("epoch 0 dereferenced at epoch 1"));
+        // its slot is already on the free list
+        std::mem::forget(ptr);
+    }
+
+    #[test]
+    fn test_pointers_after_reset() {
+        let mut allocator = Allocator::new();
+        std::mem::forget(allocator.alloc(1u64));
+        allocator.reset();
+        allocator.reset();
+        let mut ptr = ::Pointer::map(allocator.alloc(2u32), |v| v as u64);
+        *ptr += 1;
+        let slice = ::Pointer::into_slice(allocator.alloc([*ptr; 2]));
+        assert_eq!((*ptr, &*slice), (3, &[3, 3][..]));
+    }
+}
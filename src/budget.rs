@@ -0,0 +1,250 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Capping the memory of the pages of an allocator, and recovering
+//! when an allocation would exceed the cap.
+
+use std::alloc::AllocError;
+use std::cell::Cell;
+
+use {check_slot, too_big, Allocator, Node, Pointer, Pool};
+
+/// Bytes of pages an allocator may hold, shared by its pools.
+pub(crate) struct Budget {
+    limit: usize,
+    used: Cell<usize>,
+}
+
+impl Budget {
+    #[inline]
+    pub(crate) fn new(limit: usize, used: usize) -> Budget {
+        Budget{ limit, used: Cell::new(used) }
+    }
+
+    /// Account for a page of `bytes`, or return `false` if it doesn't fit.
+    #[inline]
+    pub(crate) fn charge(&self, bytes: usize) -> bool {
+        let used = self.used.get() + bytes;
+        if used > self.limit {
+            return false;
+        }
+        self.used.set(used);
+        true
+    }
+
+    /// Bytes of pages the allocator may hold.
+    #[inline]
+    pub(crate) fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Bytes of the pages currently held.
+    #[cfg(test)]
+    pub(crate) fn used(&self) -> usize {
+        self.used.get()
+    }
+
+    /// Give back a page of `bytes` that was released.
+    #[inline]
+    pub(crate) fn refund(&self, bytes: usize) {
+        self.used.set(self.used.get() - bytes);
+    }
+}
+
+/// A handler called when an allocation would exceed the budget of an
+/// allocator. See `AllocatorBuilder::on_exhausted`.
+pub type ExhaustedHandler = fn(&Allocator, &AllocRequest) -> Recovery;
+
+/// An allocation refused by a capped allocator.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct AllocRequest {
+    /// Size of the value to allocate, in bytes.
+    pub size: usize,
+    /// Size of the slots of the class it would be allocated from.
+    pub class: usize,
+    /// Number of times the handler has already been called for this
+    /// allocation, then asked for a retry.
+    pub attempt: usize,
+}
+
+/// What to do after the handler of a refused allocation returns.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Recovery {
+    /// Give up: `try_alloc` returns an error, `alloc` panics.
+    Fail,
+    /// Try the allocation again, as the handler may have freed slots.
+    Retry,
+}
+
+impl Allocator {
+    /// Take a slot off `pool` for a value of `size` bytes, calling the
+    /// exhaustion handler for as long as it asks for a retry.
+    ///
+    /// The handler is never called while it is already running: an
+    /// allocation refused from within the handler fails right away.
+    pub(crate) fn pop_or_recover(&self, pool: &Pool, size: usize) -> Option<*mut Node> {
+        let mut attempt = 0;
+        loop {
+            if let Some(node) = pool.try_pop() {
+                self.count_request(pool, size);
+                return Some(node);
+            }
+            let handler = match self.on_exhausted {
+                Some(handler) if !self.in_handler.get() => handler,
+                _ => return None,
+            };
+            let request = AllocRequest{ size, class: pool.ele_size, attempt };
+            self.in_handler.set(true);
+            let guard = HandlerGuard(&self.in_handler);
+            let recovery = handler(self, &request);
+            drop(guard);
+            if recovery == Recovery::Fail {
+                return None;
+            }
+            attempt += 1;
+        }
+    }
+
+    /// Allocate an instance of `T` with value `elem`, or return an error
+    /// if the budget of the allocator is exhausted, and its handler
+    /// couldn't recover.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size_of::<T>()` is larger than 256 bytes. A `T` whose
+    /// class is missing, see `AllocatorBuilder::only_classes`, is an error.
+    #[inline]
+    #[track_caller]
+    pub fn try_alloc<T: Copy>(&self, elem: T) -> Result<Pointer<'_, T>, AllocError> {
+        let pool = match self.pool_for_size(std::mem::size_of::<T>()) {
+            Some(pool) => pool,
+            None if std::mem::size_of::<T>() <= 256 => return Err(AllocError),
+            None => too_big::<T>(),
+        };
+        check_slot::<T>(pool.ele_size);
+        match self.pop_or_recover(pool, std::mem::size_of::<T>()) {
+            Some(node) => {
+                let node = node as *mut T;
+                unsafe { std::ptr::write(node, elem) };
+                Ok(unsafe { Pointer::from_node(pool, node) })
+            }
+            None => Err(AllocError),
+        }
+    }
+}
+
+/// Clears the reentrancy flag even if the handler panics.
+struct HandlerGuard<'a>(&'a Cell<bool>);
+
+impl<'a> Drop for HandlerGuard<'a> {
+    #[inline]
+    fn drop(&mut self) {
+        self.0.set(false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::panic;
+    use {AllocatorBuilder, DEFAULT_POOL_SIZE};
+
+    thread_local! {
+        static HOARD: RefCell<Vec<Pointer<'static, [u8; 200]>>> = const { RefCell::new(Vec::new()) };
+        static CALLS: RefCell<Vec<AllocRequest>> = const { RefCell::new(Vec::new()) };
+    }
+
+    fn capped() -> AllocatorBuilder {
+        // room for the initial pages only
+        AllocatorBuilder::new()
+            .capacity_for(256, 4)
+            .budget(5 * DEFAULT_POOL_SIZE + 4 * 256)
+    }
+
+    #[cfg_attr(not(feature = "stats"), allow(unused_variables))]
+    fn release_hoard(allocator: &Allocator, request: &AllocRequest) -> Recovery {
+        CALLS.with(|calls| calls.borrow_mut().push(*request));
+        // reading the stats from the handler is fine
+        #[cfg(feature = "stats")]
+        assert!(allocator.stats().live >= 4);
+        match HOARD.with(|h| h.borrow_mut().pop()) {
+            Some(ptr) => { drop(ptr); Recovery::Retry }
+            None => Recovery::Fail,
+        }
+    }
+
+    #[test]
+    fn test_budget() {
+        let allocator = capped().build();
+        let ptrs: Vec<_> = (0..4).map(|i| allocator.alloc([i as u8; 200])).collect();
+        assert!(allocator.try_alloc([0u8; 200]).is_err());
+        // other classes are still within their pages
+        assert_eq!(*allocator.try_alloc(7u64).unwrap(), 7);
+        drop(ptrs);
+        assert_eq!(allocator.try_alloc([9u8; 200]).map(|p| p[0]), Ok(9));
+        assert_eq!(allocator.pool256.slot_count(), 4);
+    }
+
+    #[test]
+    #[should_panic(expected = "allocation budget exhausted")]
+    fn test_budget_alloc_panics() {
+        let allocator = capped().build();
+        let _ptrs: Vec<_> = (0..5).map(|i| allocator.alloc([i as u8; 200])).collect();
+    }
+
+    #[test]
+    fn test_on_exhausted_retry() {
+        let allocator: &'static Allocator = Box::leak(Box::new(capped().on_exhausted(release_hoard).build()));
+        HOARD.with(|h| h.borrow_mut().extend((0..4).map(|i| allocator.alloc([i as u8; 200]))));
+        CALLS.with(|c| c.borrow_mut().clear());
+
+        let ptr = allocator.try_alloc([4u8; 200]).unwrap();
+        assert_eq!(ptr[0], 4);
+        let ptr2 = allocator.alloc([5u8; 200]);
+        assert_eq!(ptr2[0], 5);
+        let calls = CALLS.with(|c| c.borrow().clone());
+        assert_eq!(calls, [AllocRequest{ size: 200, class: 256, attempt: 0 }; 2]);
+        assert_eq!(HOARD.with(|h| h.borrow().len()), 2);
+
+        // nothing more to free
+        HOARD.with(|h| h.borrow_mut().clear());
+        let _more = (allocator.alloc([6u8; 200]), allocator.alloc([7u8; 200]));
+        CALLS.with(|c| c.borrow_mut().clear());
+        assert!(allocator.try_alloc([8u8; 200]).is_err());
+        assert_eq!(CALLS.with(|c| c.borrow().len()), 1);
+    }
+
+    fn alloc_within(allocator: &Allocator, request: &AllocRequest) -> Recovery {
+        CALLS.with(|calls| calls.borrow_mut().push(*request));
+        // a nested refusal fails without calling the handler again
+        assert!(allocator.try_alloc([0u8; 200]).is_err());
+        if request.attempt < 2 { Recovery::Retry } else { Recovery::Fail }
+    }
+
+    #[test]
+    #[cfg_attr(target_family = "wasm", ignore = "panics abort on wasm")]
+    fn test_on_exhausted_reentrancy() {
+        let allocator = capped().on_exhausted(alloc_within).build();
+        let _ptrs: Vec<_> = (0..4).map(|i| allocator.alloc([i as u8; 200])).collect();
+        CALLS.with(|c| c.borrow_mut().clear());
+        assert!(allocator.try_alloc([4u8; 200]).is_err());
+        let attempts: Vec<_> = CALLS.with(|c| c.borrow().iter().map(|r| r.attempt).collect());
+        assert_eq!(attempts, [0, 1, 2]);
+
+        // a panicking handler leaves the allocator usable
+        fn panicking(_: &Allocator, _: &AllocRequest) -> Recovery {
+            panic!("handler")
+        }
+        let allocator = capped().on_exhausted(panicking).build();
+        let _ptrs: Vec<_> = (0..4).map(|i| allocator.alloc([i as u8; 200])).collect();
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| allocator.try_alloc([0u8; 200])));
+        assert!(result.is_err());
+        assert!(!allocator.in_handler.get());
+    }
+}
@@ -0,0 +1,487 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Building allocators with a page capacity per size class.
+
+use std::rc::Rc;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use budget::Budget;
+use {Allocator, ExhaustedHandler, LeakPolicy, Pool, DEFAULT_POOL_SIZE};
+
+const CLASSES: [usize; 6] = [8, 16, 32, 64, 128, 256];
+
+/// What an allocator built with `AllocatorBuilder::only_classes` does
+/// with a value whose class it was built without.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum MissingClass {
+    /// Allocate it from the next larger class present, the default.
+    /// Values larger than every class present are refused.
+    RoundUp,
+    /// Refuse it: `try_alloc` returns an error, `alloc` panics, and
+    /// `PoolAlloc` falls back to `System` as for blocks beyond 256 bytes.
+    Fail,
+}
+
+/// The settings of an allocator as plain data, to be stored along with
+/// the assets of a level, say. Serializable under the `serde` feature.
+///
+/// Only the settings that are data are kept: the handler of
+/// `on_exhausted` and the name of the allocator are not.
+///
+/// ```rust
+/// use aren_alloc::{AllocatorBuilder, AllocatorConfig};
+/// let mut config = AllocatorBuilder::new().only_classes(&[16, 64]).to_config();
+/// config.capacities[1] = 512;
+/// let allocator = AllocatorBuilder::from_config(&config).build();
+/// assert_eq!(allocator.config(), config);
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct AllocatorConfig {
+    /// Sizes of the classes present, from the smallest.
+    pub classes: Vec<usize>,
+    /// Slots per page of each class of `classes`.
+    pub capacities: Vec<usize>,
+    /// What to do with values of classes missing from `classes`.
+    pub missing_class: MissingClass,
+    /// Cap of the bytes of all pages, if any.
+    pub budget: Option<usize>,
+    /// What debug builds do with slots live when the allocator is dropped.
+    pub leak_policy: LeakPolicy,
+}
+
+/// Builds an `Allocator` whose size classes have pages of different
+/// capacities. Classes left alone have pages of 4096 bytes, or 1024
+/// bytes on wasm32.
+///
+/// ```rust
+/// use aren_alloc::AllocatorBuilder;
+/// let allocator = AllocatorBuilder::new()
+///     .capacity_for(32, 8192)
+///     .build();
+/// assert!(allocator.pages().iter().any(|p| p.class_size == 32 && p.slots == 8192));
+/// ```
+#[derive(Copy, Clone, Debug)]
+pub struct AllocatorBuilder {
+    caps: [usize; 6],
+    present: [bool; 6],
+    missing_class: MissingClass,
+    budget: Option<usize>,
+    on_exhausted: Option<ExhaustedHandler>,
+    leak_policy: LeakPolicy,
+    name: Option<&'static str>,
+    #[cfg(feature = "harden")]
+    harden: bool,
+}
+
+impl AllocatorBuilder {
+    /// Construct a builder with the default page capacities.
+    #[inline]
+    pub fn new() -> AllocatorBuilder {
+        let mut caps = [0; 6];
+        for (cap, class) in caps.iter_mut().zip(CLASSES.iter()) {
+            *cap = DEFAULT_POOL_SIZE / class;
+        }
+        AllocatorBuilder{
+            caps,
+            present: [true; 6],
+            missing_class: MissingClass::RoundUp,
+            budget: None,
+            on_exhausted: None,
+            leak_policy: LeakPolicy::Panic,
+            name: None,
+            #[cfg(feature = "harden")]
+            harden: false,
+        }
+    }
+
+    /// Give pages of `cap` slots to the size class holding elements of
+    /// `size` bytes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is larger than 256 bytes, or `cap` is 0.
+    #[inline]
+    #[track_caller]
+    pub fn capacity_for(mut self, size: usize, cap: usize) -> AllocatorBuilder {
+        assert!(cap > 0, "page capacity must be positive, got 0 for elements of {} bytes", size);
+        match CLASSES.iter().position(|&class| size <= class) {
+            Some(i) => self.caps[i] = cap,
+            None => panic!("element size too big! {} bytes, beyond the largest size class of 256 bytes", size),
+        }
+        self
+    }
+
+    /// Build the allocator with the size classes of `sizes` only, so that
+    /// the others take no page. Values of a missing class are rounded
+    /// up to the next class present, or refused, see `missing_class`.
+    /// Stats and introspection only cover the classes present.
+    ///
+    /// ```rust
+    /// use aren_alloc::AllocatorBuilder;
+    /// let allocator = AllocatorBuilder::new()
+    ///     .only_classes(&[32, 64])
+    ///     .build();
+    /// let ptr = allocator.alloc(1u64);
+    /// assert_eq!(allocator.pages().len(), 2);
+    /// assert_eq!(allocator.pages()[0].class_size, 32);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if `sizes` is empty, or one of them isn't the size of a
+    /// class: 8, 16, 32, 64, 128 or 256.
+    #[track_caller]
+    pub fn only_classes(mut self, sizes: &[usize]) -> AllocatorBuilder {
+        assert!(!sizes.is_empty(), "an allocator needs at least one size class");
+        self.present = [false; 6];
+        for &size in sizes {
+            match CLASSES.iter().position(|&class| size == class) {
+                Some(i) => self.present[i] = true,
+                None => panic!("no size class of {} bytes, expected one of {:?}", size, CLASSES),
+            }
+        }
+        self
+    }
+
+    /// Set what the allocator does with values whose class is left out
+    /// by `only_classes`, `MissingClass::RoundUp` by default.
+    #[inline]
+    pub fn missing_class(mut self, policy: MissingClass) -> AllocatorBuilder {
+        self.missing_class = policy;
+        self
+    }
+
+    /// Cap the pages of all size classes to `bytes` in total, the first
+    /// pages included. An allocation needing a page beyond the cap is
+    /// refused: `try_alloc` returns an error, and `alloc` panics.
+    #[inline]
+    pub fn budget(mut self, bytes: usize) -> AllocatorBuilder {
+        self.budget = Some(bytes);
+        self
+    }
+
+    /// Call `handler` when an allocation is refused by the budget, before
+    /// failing it. The handler may free slots, e.g. by clearing a cache it
+    /// owns, then return `Recovery::Retry` to try the allocation again.
+    ///
+    /// The handler runs with no pool borrowed, so it may read `stats()`,
+    /// drop pointers, or allocate. An allocation refused while the handler
+    /// is running fails without calling the handler again.
+    #[inline]
+    pub fn on_exhausted(mut self, handler: ExhaustedHandler) -> AllocatorBuilder {
+        self.on_exhausted = Some(handler);
+        self
+    }
+
+    /// Set what a debug build does when the allocator is dropped while
+    /// some of its slots are still handed out, `LeakPolicy::Panic` by
+    /// default.
+    #[inline]
+    pub fn leak_policy(mut self, policy: LeakPolicy) -> AllocatorBuilder {
+        self.leak_policy = policy;
+        self
+    }
+
+    /// Construct a builder with the settings of `config`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `config` has no class, or a class that isn't one of 8,
+    /// 16, 32, 64, 128 or 256, or a capacity of 0, or if it doesn't have
+    /// a capacity for every class.
+    #[track_caller]
+    pub fn from_config(config: &AllocatorConfig) -> AllocatorBuilder {
+        assert_eq!(config.classes.len(), config.capacities.len(),
+                   "the config has {} classes but {} capacities", config.classes.len(), config.capacities.len());
+        let mut builder = AllocatorBuilder::new()
+            .only_classes(&config.classes)
+            .missing_class(config.missing_class)
+            .leak_policy(config.leak_policy);
+        for (&class, &cap) in config.classes.iter().zip(config.capacities.iter()) {
+            builder = builder.capacity_for(class, cap);
+        }
+        builder.budget = config.budget;
+        builder
+    }
+
+    /// Export the settings of the builder, but the handler of
+    /// `on_exhausted` and the name.
+    pub fn to_config(&self) -> AllocatorConfig {
+        let present = (0..6).filter(|&i| self.present[i]);
+        AllocatorConfig{
+            classes: present.clone().map(|i| CLASSES[i]).collect(),
+            capacities: present.map(|i| self.caps[i]).collect(),
+            missing_class: self.missing_class,
+            budget: self.budget,
+            leak_policy: self.leak_policy,
+        }
+    }
+
+    /// Name the allocator, for diagnostics such as `registry::snapshot`
+    /// under the `registry` feature, and leak reports.
+    #[inline]
+    pub fn name(mut self, name: &'static str) -> AllocatorBuilder {
+        self.name = Some(name);
+        self
+    }
+
+    /// Hand out the slots of the allocator in an order harder to predict,
+    /// for allocators holding the data of untrusted code. Under the
+    /// `harden` feature, and not kept by `to_config`.
+    ///
+    /// The slots of every page are linked in a shuffled order, with
+    /// randomness seeded from the OS, and half of the slots recycled are
+    /// pushed up to 16 slots deep into the free list rather than on top.
+    /// Pages are added as usual, slots are only handed out in another
+    /// order.
+    ///
+    /// In `benches/harden.rs`, a churn of allocations and frees takes a
+    /// quarter to a half longer hardened, from the walks into the free
+    /// list and the cache misses of scattered slots.
+    ///
+    /// ```rust
+    /// use aren_alloc::AllocatorBuilder;
+    /// let allocator = AllocatorBuilder::new().harden(true).build();
+    /// let ptr = allocator.alloc(1u64);
+    /// ```
+    #[cfg(feature = "harden")]
+    #[inline]
+    pub fn harden(mut self, on: bool) -> AllocatorBuilder {
+        self.harden = on;
+        self
+    }
+
+    /// Construct the allocator.
+    #[inline]
+    pub fn build(&self) -> Allocator {
+        // missing classes never get a page
+        let pool = |i: usize| if self.present[i] {
+            Pool::with_capacity(self.caps[i], CLASSES[i])
+        } else {
+            Pool::lazy(self.caps[i], CLASSES[i])
+        };
+        let mut allocator = Allocator::from_pools([pool(0), pool(1), pool(2), pool(3), pool(4), pool(5)]);
+        allocator.on_exhausted = self.on_exhausted;
+        allocator.leak_policy = self.leak_policy;
+        allocator.missing_class = self.missing_class;
+        allocator.name = self.name;
+        for (i, route) in allocator.route.iter_mut().enumerate() {
+            *route = match self.missing_class {
+                _ if self.present[i] => Some(i),
+                MissingClass::RoundUp => (i..6).find(|&j| self.present[j]),
+                MissingClass::Fail => None,
+            };
+        }
+        allocator.class_count = 0;
+        for i in (0..6).filter(|&i| self.present[i]) {
+            allocator.classes[allocator.class_count] = CLASSES[i];
+            allocator.class_count += 1;
+        }
+        if let Some(limit) = self.budget {
+            let used = (0..6).filter(|&i| self.present[i]).map(|i| self.caps[i] * CLASSES[i]).sum();
+            let budget = Rc::new(Budget::new(limit, used));
+            let pools = [
+                &mut allocator.pool8, &mut allocator.pool16, &mut allocator.pool32,
+                &mut allocator.pool64, &mut allocator.pool128, &mut allocator.pool256,
+            ];
+            for pool in pools {
+                pool.budget = Some(budget.clone());
+            }
+        }
+        #[cfg(feature = "harden")]
+        if self.harden {
+            let rng = Rc::new(::harden::Rng::from_os());
+            let pools = [
+                &mut allocator.pool8, &mut allocator.pool16, &mut allocator.pool32,
+                &mut allocator.pool64, &mut allocator.pool128, &mut allocator.pool256,
+            ];
+            for pool in pools {
+                pool.harden(rng.clone());
+            }
+        }
+        allocator.registered()
+    }
+}
+
+impl Default for AllocatorBuilder {
+    #[inline]
+    fn default() -> AllocatorBuilder {
+        AllocatorBuilder::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capacity_for() {
+        let allocator = AllocatorBuilder::new()
+            .capacity_for(20, 3)
+            .capacity_for(256, 1)
+            .build();
+        assert_eq!(allocator.pool32.slot_count(), 3);
+        assert_eq!(allocator.pool256.slot_count(), 1);
+        assert_eq!(allocator.pool8.slot_count(), DEFAULT_POOL_SIZE / 8);
+        let ptrs: Vec<_> = (0..4).map(|i| allocator.alloc([i as u8; 20])).collect();
+        assert_eq!(allocator.pool32.slot_count(), 6);
+        assert!(ptrs.iter().enumerate().all(|(i, p)| **p == [i as u8; 20]));
+    }
+
+    #[test]
+    fn test_only_classes() {
+        let allocator = AllocatorBuilder::new()
+            .capacity_for(32, 4)
+            .capacity_for(64, 2)
+            .only_classes(&[64, 32])
+            .build();
+        // rounded up to the 32 bytes class
+        let small = allocator.alloc(7u64);
+        assert_eq!(*small, 7);
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.pool32.live.get(), 1);
+        assert_eq!(allocator.pool8.slot_count(), 0);
+        let mid = allocator.alloc([1u8; 40]);
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats(), ::Stats{ live: 2, allocs: 2, slots: 6 });
+        let classes: Vec<_> = allocator.pages().iter().map(|p| (p.class_size, p.bytes)).collect();
+        assert_eq!(classes, [(32, 128), (64, 128)]);
+        assert!(allocator.try_alloc([0u8; 100]).is_err());
+        drop((small, mid));
+        assert_eq!(allocator.trim(0), 2);
+    }
+
+    #[test]
+    #[cfg_attr(target_family = "wasm", ignore = "panics abort on wasm")]
+    fn test_missing_class_fail() {
+        let allocator = AllocatorBuilder::new()
+            .only_classes(&[32, 64])
+            .missing_class(MissingClass::Fail)
+            .budget(4096 * 2)
+            .build();
+        assert!(allocator.try_alloc(1u64).is_err());
+        assert_eq!(*allocator.try_alloc([2u8; 30]).unwrap(), [2; 30]);
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().slots, 4096 / 32 + 4096 / 64);
+        let err = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| { allocator.alloc(1u64); })).unwrap_err();
+        let msg = err.downcast_ref::<String>().unwrap();
+        assert_eq!(msg, "no size class of the allocator holds `u64` of 8 bytes");
+
+        // blocks of missing classes come from `System`
+        let mut v = Vec::with_capacity_in(2, ::PoolAlloc::new(&allocator));
+        v.push(1u32);
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 0);
+    }
+
+    #[test]
+    fn test_config() {
+        let builder = AllocatorBuilder::new()
+            .capacity_for(32, 100)
+            .only_classes(&[8, 32])
+            .missing_class(MissingClass::Fail)
+            .budget(1 << 20)
+            .leak_policy(LeakPolicy::Ignore);
+        let config = builder.to_config();
+        assert_eq!(config, AllocatorConfig{
+            classes: vec![8, 32],
+            capacities: vec![DEFAULT_POOL_SIZE / 8, 100],
+            missing_class: MissingClass::Fail,
+            budget: Some(1 << 20),
+            leak_policy: LeakPolicy::Ignore,
+        });
+        assert_eq!(AllocatorBuilder::from_config(&config).to_config(), config);
+        assert_eq!(builder.build().config(), config);
+        assert_eq!(Allocator::with_capacity(4).config().capacities, [4; 6]);
+    }
+
+    thread_local! {
+        static EXHAUSTED: ::std::cell::Cell<usize> = const { ::std::cell::Cell::new(0) };
+    }
+
+    fn count_exhausted(_: &Allocator, _: &::AllocRequest) -> ::Recovery {
+        EXHAUSTED.with(|n| n.set(n.get() + 1));
+        ::Recovery::Fail
+    }
+
+    #[test]
+    fn test_new_like() {
+        // every setting of the builder
+        let builder = AllocatorBuilder::new()
+            .capacity_for(16, 3)
+            .capacity_for(128, 2)
+            .only_classes(&[16, 128])
+            .missing_class(MissingClass::Fail)
+            .budget(16 * 3 + 128 * 2)
+            .on_exhausted(count_exhausted)
+            .leak_policy(LeakPolicy::Ignore)
+            .name("template");
+        #[cfg(feature = "harden")]
+        let builder = builder.harden(true);
+        let template = builder.build();
+        let held = template.alloc(1u128);
+
+        let like = template.new_like();
+        assert_eq!(like.config(), template.config());
+        assert_eq!(like.config(), builder.to_config());
+        assert_eq!(like.name(), Some("template"));
+        #[cfg(feature = "harden")]
+        assert!(like.pools().all(|pool| pool.rng.is_some()));
+        // fresh, with a budget of its own
+        assert_eq!(like.live_count(16), 0);
+        let ptrs: Vec<_> = (0..3u128).map(|i| like.alloc(i)).collect();
+        assert!(like.try_alloc(4u128).is_err());
+        assert_eq!(EXHAUSTED.with(|n| n.get()), 1);
+        drop((held, ptrs));
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn test_config_json() {
+        let config = AllocatorBuilder::new()
+            .capacity_for(16, 3)
+            .capacity_for(128, 7)
+            .only_classes(&[16, 128, 256])
+            .to_config();
+        let json = ::serde_json::to_string(&config).unwrap();
+        assert_eq!(json, r#"{"classes":[16,128,256],"capacities":[3,7,16],"missing_class":"RoundUp","budget":null,"leak_policy":"Panic"}"#
+                   .replace("16]", &format!("{}]", DEFAULT_POOL_SIZE / 256)));
+        let loaded: AllocatorConfig = ::serde_json::from_str(&json).unwrap();
+        let (a, b) = (AllocatorBuilder::from_config(&config).build(), AllocatorBuilder::from_config(&loaded).build());
+        assert_eq!(a.classes(), b.classes());
+        let shape = |allocator: &Allocator| allocator.pages().iter().map(|p| (p.class_size, p.slots, p.bytes)).collect::<Vec<_>>();
+        assert_eq!(shape(&a), shape(&b));
+        assert_eq!(shape(&b), [(16, 3, 48), (128, 7, 896), (256, DEFAULT_POOL_SIZE / 256, DEFAULT_POOL_SIZE)]);
+        assert_eq!(b.config(), config);
+    }
+
+    #[test]
+    #[should_panic(expected = "the config has 2 classes but 1 capacities")]
+    fn test_config_mismatch() {
+        let mut config = AllocatorBuilder::new().only_classes(&[8, 16]).to_config();
+        config.capacities.pop();
+        AllocatorBuilder::from_config(&config);
+    }
+
+    #[test]
+    #[should_panic(expected = "no size class of 48 bytes")]
+    fn test_only_classes_unknown() {
+        AllocatorBuilder::new().only_classes(&[32, 48]);
+    }
+
+    #[test]
+    #[should_panic(expected = "element size too big!")]
+    fn test_too_big() {
+        AllocatorBuilder::new().capacity_for(257, 1);
+    }
+}
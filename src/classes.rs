@@ -0,0 +1,88 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! What a type costs in the size classes of an allocator.
+
+use {Allocator, PAGE_ALIGN};
+
+/// The slot a type would be allocated in, see `Allocator::layout_for`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ClassInfo {
+    /// Size of the slots of the class.
+    pub class_size: usize,
+    /// Alignment every slot of the class is guaranteed.
+    pub align: usize,
+    /// Bytes of the slot left unused by the type.
+    pub slack: usize,
+}
+
+impl Allocator {
+    /// Describe the slot a `T` would be allocated in, or return `None`
+    /// if no class of the allocator holds it, in size or alignment.
+    ///
+    /// ```rust
+    /// use aren_alloc::{Allocator, ClassInfo};
+    /// let allocator = Allocator::new();
+    /// assert_eq!(allocator.layout_for::<[u8; 9]>(), Some(ClassInfo{ class_size: 16, align: 16, slack: 7 }));
+    /// assert_eq!(allocator.layout_for::<[u8; 257]>(), None);
+    /// ```
+    #[inline]
+    pub fn layout_for<T>(&self) -> Option<ClassInfo> {
+        let size = std::mem::size_of::<T>();
+        let class_size = self.pool_for_size(size)?.ele_size;
+        let align = std::cmp::min(class_size, PAGE_ALIGN);
+        if std::mem::align_of::<T>() > align {
+            return None;
+        }
+        Some(ClassInfo{ class_size, align, slack: class_size - size })
+    }
+
+    /// Sizes of the classes of the allocator, from the smallest.
+    #[inline]
+    pub fn classes(&self) -> &[usize] {
+        &self.classes[..self.class_count]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {AllocatorBuilder, MissingClass};
+
+    #[repr(align(32))]
+    struct Wide;
+
+    #[test]
+    fn test_layout_for() {
+        let allocator = Allocator::new();
+        let info = |class_size, align, slack| Some(ClassInfo{ class_size, align, slack });
+        assert_eq!(allocator.layout_for::<()>(), info(8, 8, 8));
+        assert_eq!(allocator.layout_for::<[u8; 8]>(), info(8, 8, 0));
+        assert_eq!(allocator.layout_for::<[u8; 9]>(), info(16, 16, 7));
+        assert_eq!(allocator.layout_for::<u128>(), info(16, 16, 0));
+        assert_eq!(allocator.layout_for::<[u8; 33]>(), info(64, 16, 31));
+        assert_eq!(allocator.layout_for::<[u8; 256]>(), info(256, 16, 0));
+        assert_eq!(allocator.layout_for::<[u8; 257]>(), None);
+        // fits in size, but not in alignment
+        assert_eq!(allocator.layout_for::<Wide>(), None);
+        assert_eq!(allocator.classes(), &[8, 16, 32, 64, 128, 256]);
+    }
+
+    #[test]
+    fn test_layout_for_only_classes() {
+        let allocator = AllocatorBuilder::new().only_classes(&[16, 128]).build();
+        assert_eq!(allocator.classes(), &[16, 128]);
+        assert_eq!(allocator.layout_for::<u32>(), Some(ClassInfo{ class_size: 16, align: 16, slack: 12 }));
+        assert_eq!(allocator.layout_for::<[u8; 17]>(), Some(ClassInfo{ class_size: 128, align: 16, slack: 111 }));
+        assert_eq!(allocator.layout_for::<[u8; 129]>(), None);
+
+        let allocator = AllocatorBuilder::new().only_classes(&[16, 128]).missing_class(MissingClass::Fail).build();
+        assert_eq!(allocator.layout_for::<u32>(), None);
+        assert_eq!(allocator.layout_for::<[u8; 128]>().map(|info| info.slack), Some(0));
+    }
+}
@@ -0,0 +1,239 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Blocks beyond the largest class, spanning adjacent slots of a page.
+
+use std::alloc::AllocError;
+
+use {Allocator, Node, Pointer, Pool};
+
+impl Pool {
+    /// Take `n` adjacent free slots of one page off the free list, adding
+    /// a page if none has as many, return the first one. Return `None`
+    /// if a page holds fewer slots, or the budget is exhausted.
+    ///
+    /// This walks the whole free list, as the free slots aren't tracked
+    /// by address.
+    pub(crate) fn pop_run(&self, n: usize) -> Option<*mut Node> {
+        self.check_owner();
+        self.drain_remote();
+        if n > self.page_slots {
+            return None;
+        }
+        let start = match self.find_run(n) {
+            Some(start) => start,
+            None => {
+                if !self.extend_ahead() {
+                    return None;
+                }
+                self.find_run(n).unwrap()
+            }
+        };
+        let run = start.addr()..start.addr() + n * self.ele_size;
+        // unlink the run, keeping the order of the other slots
+        unsafe {
+            let mut head: *mut Node = std::ptr::null_mut();
+            let mut tail: *mut Node = std::ptr::null_mut();
+            let mut node = self.head.get().map_or(std::ptr::null_mut(), |node| node.as_ptr());
+            while !node.is_null() {
+                let next = (*node).next;
+                if !run.contains(&node.addr()) {
+                    if tail.is_null() {
+                        head = node;
+                    } else {
+                        (*tail).next = node;
+                    }
+                    tail = node;
+                }
+                node = next;
+            }
+            if !tail.is_null() {
+                (*tail).next = std::ptr::null_mut();
+            }
+            self.head.set(std::ptr::NonNull::new(head));
+        }
+        self.free_slots.set(self.free_slots.get() - n);
+        for i in 0..n {
+            self.track_alloc(unsafe { start.byte_add(i * self.ele_size) });
+            self.count_alloc();
+        }
+        Some(start)
+    }
+
+    /// Find the lowest `n` adjacent free slots of one page.
+    fn find_run(&self, n: usize) -> Option<*mut Node> {
+        let mut free = Vec::new();
+        let mut node = self.head.get();
+        while let Some(cur) = node {
+            free.push(cur.as_ptr());
+            node = std::ptr::NonNull::new(unsafe { cur.as_ref().next });
+        }
+        free.sort_unstable_by_key(|node| node.addr());
+        for p in self.chain() {
+            let page = p.pool.borrow();
+            let base = page.as_ptr().addr();
+            let lo = free.partition_point(|node| node.addr() < base);
+            let hi = free.partition_point(|node| node.addr() < base + page.len());
+            let mut run = 0;
+            for i in lo..hi {
+                run = if run != 0 && free[i].addr() == free[i - 1].addr() + self.ele_size { run + 1 } else { 1 };
+                if run == n {
+                    return Some(free[i + 1 - n]);
+                }
+            }
+        }
+        None
+    }
+
+    /// Add a page whose slots are handed out before the free ones.
+    /// Return `false` if the page would exceed the budget.
+    fn extend_ahead(&self) -> bool {
+        let free = self.head.take();
+        if !self.extend() {
+            self.head.set(free);
+            return false;
+        }
+        // the slots of the new page make the list, link its end to the
+        // others, in whichever order the slots were linked
+        unsafe {
+            let mut node = self.head.get().unwrap().as_ptr();
+            while !(*node).next.is_null() {
+                node = (*node).next;
+            }
+            (*node).next = free.map_or(std::ptr::null_mut(), |node| node.as_ptr());
+        }
+        true
+    }
+
+    /// Recycle the `n` adjacent slots from `node`, taken by `pop_run`.
+    pub(crate) unsafe fn recycle_run(&self, node: *mut Node, n: usize) {
+        // the first slot ends up first on the free list
+        for i in (0..n).rev() {
+            self.recycle(node.byte_add(i * self.ele_size));
+        }
+    }
+}
+
+impl Allocator {
+    /// Allocate a zeroed block of `bytes`, spanning as many adjacent
+    /// slots of the largest class as needed, for blocks too large for
+    /// a single slot. Blocks fitting in a slot take one, as
+    /// `alloc_bytes_at_least` does.
+    ///
+    /// Finding a run of adjacent free slots walks the free list of the
+    /// class, and adds a page if no page has one. Dropping the block
+    /// recycles each of its slots.
+    ///
+    /// ```rust
+    /// use aren_alloc::{Allocator, Pointer};
+    /// let allocator = Allocator::new();
+    /// let mut samples = allocator.alloc_contiguous(80 * 4).unwrap();
+    /// assert_eq!(Pointer::len(&samples), 320);
+    /// samples[300] = 1;
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `bytes` is larger than a page of the class,
+    /// or the budget of the allocator is exhausted. The handler of an
+    /// exhausted budget isn't called.
+    pub fn alloc_contiguous(&self, bytes: usize) -> Result<Pointer<'_, [u8]>, AllocError> {
+        let pool = match self.pool_for_size(bytes) {
+            Some(pool) => pool,
+            None => self.pools().last().ok_or(AllocError)?,
+        };
+        let node = if bytes <= pool.ele_size {
+            self.pop_or_recover(pool, bytes)
+        } else {
+            pool.pop_run(bytes.div_ceil(pool.ele_size))
+        };
+        let node = node.ok_or(AllocError)? as *mut u8;
+        unsafe {
+            std::ptr::write_bytes(node, 0, bytes);
+            Ok(Pointer::from_node(pool, std::ptr::slice_from_raw_parts_mut(node, bytes)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use AllocatorBuilder;
+
+    fn addr(ptr: &Pointer<[u8]>) -> usize {
+        ptr.as_ptr() as usize
+    }
+
+    #[test]
+    fn test_alloc_contiguous() {
+        let allocator = Allocator::new();
+        let free = allocator.pool256.free_count();
+        let mut block = allocator.alloc_contiguous(320).unwrap();
+        assert_eq!((Pointer::len(&block), allocator.pool256.free_count()), (320, free - 2));
+        assert!(block.iter().all(|&b| b == 0));
+        block[319] = 1;
+        // the next block starts right after
+        let next = allocator.alloc_contiguous(1000).unwrap();
+        assert_eq!(addr(&next), addr(&block) + 2 * 256);
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 2 + 4);
+        drop(block);
+        assert_eq!(allocator.pool256.free_count(), free - 4);
+
+        // a block fitting in a slot takes one
+        let small = allocator.alloc_contiguous(100).unwrap();
+        assert!(allocator.pool128.owns(small.as_ptr()));
+        drop((next, small));
+        assert_eq!(allocator.pool256.free_count(), free);
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 0);
+    }
+
+    #[test]
+    fn test_fragmented() {
+        // two pages of 4 slots of 256 bytes, at most
+        let allocator = AllocatorBuilder::new()
+            .only_classes(&[256])
+            .capacity_for(256, 4)
+            .budget(2 * 4 * 256)
+            .build();
+        let slots: Vec<_> = (0..4).map(|_| allocator.alloc([0u8; 256])).collect();
+        let (odd, even): (Vec<_>, Vec<_>) = slots.into_iter().enumerate().partition(|&(i, _)| i % 2 == 1);
+        drop(odd);
+        // two free slots, but not adjacent, so a page is added
+        let block = allocator.alloc_contiguous(512).unwrap();
+        assert_eq!(allocator.pool256.free_count(), 2 + 2);
+        // no room for another page, nor a run of 3 slots
+        assert!(allocator.alloc_contiguous(3 * 256).is_err());
+        assert!(allocator.alloc_contiguous(5 * 256).is_err());
+        assert_eq!(allocator.pool256.free_count(), 2 + 2);
+        let again = allocator.alloc_contiguous(2 * 256).unwrap();
+        assert_eq!(addr(&again), addr(&block) + 2 * 256);
+        drop(again);
+
+        // freeing the block makes a run of 3 with the slot before it
+        drop(even);
+        drop(block);
+        let whole = allocator.alloc_contiguous(4 * 256).unwrap();
+        assert!(allocator.alloc_contiguous(257).is_ok());
+        drop(whole);
+        assert_eq!(allocator.pool256.free_count(), 8);
+    }
+
+    #[test]
+    fn test_missing_class() {
+        let allocator = AllocatorBuilder::new().only_classes(&[8, 64]).build();
+        let block = allocator.alloc_contiguous(200).unwrap();
+        assert!(allocator.pool64.owns(block.as_ptr()));
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 4);
+        drop(block);
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 0);
+    }
+}
@@ -0,0 +1,101 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Rendering the pages of an allocator as a Graphviz graph.
+
+use std::io;
+use std::ptr::NonNull;
+
+use {Allocator, Pool};
+
+impl Pool {
+    /// Addresses of the slots on the free list.
+    fn free_addrs(&self) -> Vec<usize> {
+        let mut addrs = Vec::new();
+        let mut node = self.head.get();
+        while let Some(cur) = node {
+            addrs.push(cur.as_ptr() as usize);
+            node = NonNull::new(unsafe { cur.as_ref().next });
+        }
+        addrs
+    }
+
+    fn dump_dot(&self, w: &mut dyn io::Write) -> io::Result<()> {
+        let class = self.ele_size;
+        let free = self.free_addrs();
+        let head = self.head.get().map_or(0, |head| head.as_ptr() as usize);
+        writeln!(w, "    subgraph cluster_{} {{", class)?;
+        writeln!(w, "        label=\"{} bytes\";", class)?;
+        let mut prev = None;
+        for page in self.pages() {
+            let end = page.base_addr + page.bytes;
+            let in_page = |addr: &usize| *addr >= page.base_addr && *addr < end;
+            let live = page.slots - free.iter().filter(|addr| in_page(addr)).count();
+            let id = format!("page_{}_{}", class, page.index_in_chain);
+            let highlight = if in_page(&head) { ", color=red, penwidth=2" } else { "" };
+            writeln!(w, "        {} [label=\"{{page {}|{:#x}|{} / {} live}}\"{}];",
+                     id, page.index_in_chain, page.base_addr, live, page.slots, highlight)?;
+            if let Some(prev) = prev {
+                writeln!(w, "        {} -> {};", prev, id)?;
+            }
+            if in_page(&head) {
+                writeln!(w, "        head_{} [label=\"free list\", shape=plaintext, fontcolor=red];", class)?;
+                writeln!(w, "        head_{} -> {} [color=red];", class, id)?;
+            }
+            prev = Some(id);
+        }
+        writeln!(w, "    }}")
+    }
+}
+
+impl Allocator {
+    /// Write a Graphviz graph of the allocator to `w`: a cluster per size
+    /// class, holding a node per page with its live slots, linked in the
+    /// order the pages were added. The page holding the head of the free
+    /// list of a class is highlighted.
+    ///
+    /// Slots of `SendPointer`s dropped on other threads count as live
+    /// until they are recycled by `drain_remote_frees`.
+    pub fn dump_dot(&self, w: &mut dyn io::Write) -> io::Result<()> {
+        writeln!(w, "digraph allocator {{")?;
+        writeln!(w, "    node [shape=record];")?;
+        for pool in self.pools() {
+            pool.dump_dot(w)?;
+        }
+        writeln!(w, "}}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dump_dot() {
+        let allocator = Allocator::with_capacity(2);
+        let _held: Vec<_> = (0..3u64).map(|i| allocator.alloc(i)).collect();
+        let _big = allocator.alloc([0u8; 200]);
+        let mut out = Vec::new();
+        allocator.dump_dot(&mut out).unwrap();
+        let dot = String::from_utf8(out).unwrap();
+
+        assert!(dot.starts_with("digraph allocator {"));
+        assert_eq!(dot.matches("subgraph cluster_").count(), 6);
+        for class in &[8, 16, 32, 64, 128, 256] {
+            assert!(dot.contains(&format!("label=\"{} bytes\";", class)));
+        }
+        assert_eq!(dot.matches("[label=\"{page ").count(), 7);
+        assert_eq!(dot.matches(" -> page_").count(), 1 + 6);
+        assert!(dot.contains("page_8_0 -> page_8_1;"));
+        // the first page of the 8 bytes class is full, the second half so
+        assert!(dot.contains("|2 / 2 live}\"];"));
+        assert!(dot.contains("|1 / 2 live}\", color=red, penwidth=2];"));
+        assert!(dot.contains("head_8 -> page_8_1 [color=red];"));
+        assert!(dot.contains("|1 / 2 live}\", color=red, penwidth=2];\n        head_256"));
+    }
+}
@@ -0,0 +1,186 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Epoch-based deferred reclamation for `SharedAllocator`, built on
+//! the global collector of `crossbeam-epoch`.
+
+use std::marker::PhantomData;
+
+use crossbeam_epoch::{self, Guard};
+
+use {SharedAllocator, SharedPointer};
+
+/// A guard pinning the current thread to the current epoch.
+///
+/// While any thread stays pinned, slots retired through `retire` are
+/// never recycled, so a reader could keep dereferencing an address
+/// loaded while it was pinned, even if a writer has retired the pointer
+/// in the meantime. Readers and writers should keep their guards short
+/// lived, as a pinned thread holds back reclamation for everyone.
+///
+/// ```rust
+/// use std::sync::atomic::{AtomicPtr, Ordering};
+/// use aren_alloc::{SharedAllocator, SharedPointer};
+///
+/// let allocator = SharedAllocator::new();
+/// let current = AtomicPtr::new(SharedPointer::into_raw(allocator.alloc([0u32; 4])));
+///
+/// // a reader
+/// {
+///     let guard = allocator.pin();
+///     let value = unsafe { guard.deref(current.load(Ordering::Acquire)) };
+///     assert_eq!(value[3], 0);
+/// }
+///
+/// // a writer
+/// {
+///     let guard = allocator.pin();
+///     let new = SharedPointer::into_raw(allocator.alloc([1u32; 4]));
+///     let old = current.swap(new, Ordering::AcqRel);
+///     guard.retire(unsafe { SharedPointer::from_raw(&allocator, old) });
+/// }
+/// # drop(unsafe { SharedPointer::from_raw(&allocator, current.into_inner()) });
+/// ```
+pub struct EpochGuard<'a> {
+    /// Borrows the allocator, as the pointers retired through the guard.
+    marker: PhantomData<&'a SharedAllocator>,
+    guard: Guard,
+}
+
+impl SharedAllocator {
+    /// Pin the current thread, returning a guard to read through
+    /// and to retire pointers with.
+    #[inline]
+    pub fn pin(&self) -> EpochGuard<'_> {
+        EpochGuard{ marker: PhantomData, guard: crossbeam_epoch::pin() }
+    }
+}
+
+impl<'a> EpochGuard<'a> {
+    /// Drop `ptr` once no thread pinned now could still be reading it.
+    ///
+    /// The pointee is dropped in place, and its slot recycled into the
+    /// allocator of `ptr`, on whichever thread happens to collect the
+    /// garbage afterwards.
+    #[inline]
+    pub fn retire<T: Send + ?Sized + 'static>(&self, ptr: SharedPointer<'a, T>) {
+        let depot = ptr.depot.clone();
+        let node = SharedPointer::into_raw(ptr);
+        unsafe {
+            // the closure owns the only handle to the slot, and `T`
+            // could be dropped on any thread
+            self.guard.defer_unchecked(move || {
+                drop(SharedPointer{ depot: &depot, node });
+            });
+        }
+    }
+
+    /// Dereference `raw` for as long as this guard is alive.
+    ///
+    /// # Safety
+    ///
+    /// `raw` must point to a live `T` allocated by this allocator when
+    /// loaded, the load must happen while this guard is pinned, and the
+    /// pointer owning it must only ever be dropped through `retire`.
+    #[inline]
+    pub unsafe fn deref<T: ?Sized>(&self, raw: *const T) -> &T {
+        &*raw
+    }
+
+    /// Try to recycle the retired slots no pinned thread could be reading.
+    #[inline]
+    pub fn flush(&self) {
+        self.guard.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+    use std::thread;
+
+    fn collect_until<F: Fn() -> bool>(shared: &SharedAllocator, done: F) -> bool {
+        for _ in 0..10_000 {
+            if done() {
+                return true;
+            }
+            shared.pin().flush();
+        }
+        done()
+    }
+
+    #[test]
+    fn test_retire_deferred() {
+        let shared = SharedAllocator::with_capacity(64);
+        let ptr = shared.alloc(42u64);
+        let free = shared.free_count::<u64>();
+
+        let reader = shared.pin();
+        let value = unsafe { reader.deref(SharedPointer::as_ptr(&ptr)) };
+        shared.pin().retire(ptr);
+        for _ in 0..128 {
+            shared.pin().flush();
+        }
+        // still pinned, so the slot can't be recycled
+        assert_eq!(shared.free_count::<u64>(), free);
+        assert_eq!(*value, 42);
+
+        drop(reader);
+        assert!(collect_until(&shared, || shared.free_count::<u64>() == free + 1));
+    }
+
+    #[test]
+    fn test_retire_foreign() {
+        let shared = SharedAllocator::with_capacity(64);
+        let other = SharedAllocator::with_capacity(64);
+        let ptr = other.alloc(42u64);
+        shared.pin().retire(ptr);
+        // the slot goes back to the allocator it came from
+        assert!(collect_until(&other, || other.free_count::<u64>() == 64));
+        assert_eq!(shared.free_count::<u64>(), 64);
+    }
+
+    #[test]
+    #[cfg_attr(target_family = "wasm", ignore = "no threads on wasm")]
+    fn test_concurrent_readers() {
+        let shared: &'static SharedAllocator = Box::leak(Box::new(SharedAllocator::with_capacity(16)));
+        let current: &'static AtomicPtr<[u64; 4]> = Box::leak(Box::new(
+            AtomicPtr::new(SharedPointer::into_raw(shared.alloc([0; 4])))));
+        let done: &'static AtomicBool = Box::leak(Box::new(AtomicBool::new(false)));
+
+        let readers: Vec<_> = (0..2).map(|_| thread::spawn(move || {
+            while !done.load(Ordering::Acquire) {
+                let guard = shared.pin();
+                let value = unsafe { guard.deref(current.load(Ordering::Acquire)) };
+                let first = value[0];
+                for _ in 0..16 {
+                    assert!(value.iter().all(|&v| v == first));
+                }
+            }
+        })).collect();
+
+        let writer = thread::spawn(move || {
+            for i in 1..=20_000u64 {
+                let guard = shared.pin();
+                let new = SharedPointer::into_raw(shared.alloc([i; 4]));
+                let old = current.swap(new, Ordering::AcqRel);
+                guard.retire(unsafe { SharedPointer::from_raw(shared, old) });
+            }
+            done.store(true, Ordering::Release);
+        });
+        writer.join().unwrap();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+
+        let last = current.swap(std::ptr::null_mut(), Ordering::AcqRel);
+        shared.pin().retire(unsafe { SharedPointer::from_raw(shared, last) });
+        assert!(collect_until(shared, || shared.free_count::<[u64; 4]>() == shared.slot_count::<[u64; 4]>()));
+    }
+}
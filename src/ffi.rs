@@ -0,0 +1,141 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! C bindings, declared in `include/aren_alloc.h`.
+//!
+//! An allocator is only ever used from the thread creating it. Null
+//! allocators, sizes over 256 bytes, and pointers not handed out by
+//! the allocator are ignored rather than trusted.
+
+use std::alloc::Layout;
+use std::os::raw::c_void;
+use std::ptr::NonNull;
+
+use {Allocator, LeakPolicy};
+
+/// Construct an allocator with pages of `page_cap` slots, or of the
+/// default 4096 bytes, 1024 on wasm32, if `page_cap` is 0.
+#[no_mangle]
+pub extern "C" fn aren_alloc_new(page_cap: usize) -> *mut Allocator {
+    let mut allocator = if page_cap == 0 {
+        Allocator::new()
+    } else {
+        Allocator::with_capacity(page_cap)
+    };
+    // slots left to `aren_alloc_destroy` are freed on purpose
+    allocator.leak_policy = LeakPolicy::Ignore;
+    Box::into_raw(Box::new(allocator))
+}
+
+/// Destroy `alloc`, freeing every slot at once.
+///
+/// # Safety
+///
+/// `alloc` must be null, or returned by `aren_alloc_new` and not yet
+/// destroyed. Pointers allocated from it dangle afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn aren_alloc_destroy(alloc: *mut Allocator) {
+    if !alloc.is_null() {
+        drop(Box::from_raw(alloc));
+    }
+}
+
+/// Allocate `size` bytes from `alloc`, aligned to the smallest power of
+/// two holding `size`, up to 8. Return null if `alloc` is null, or
+/// `size` is larger than 256.
+///
+/// # Safety
+///
+/// `alloc` must be null, or a live allocator of the current thread.
+#[no_mangle]
+pub unsafe extern "C" fn aren_alloc_alloc(alloc: *mut Allocator, size: usize) -> *mut c_void {
+    let (alloc, layout) = match (alloc.as_ref(), Layout::from_size_align(size, 1)) {
+        (Some(alloc), Ok(layout)) => (alloc, layout),
+        _ => return std::ptr::null_mut(),
+    };
+    match alloc.alloc_layout(layout) {
+        Ok(ptr) => ptr.as_ptr() as *mut c_void,
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// Free `ptr` of `size` bytes back into `alloc`. Do nothing if either
+/// is null, or `ptr` isn't a slot of the size class of `size`.
+///
+/// # Safety
+///
+/// `alloc` must be null, or a live allocator of the current thread.
+/// `ptr` must not be freed twice.
+#[no_mangle]
+pub unsafe extern "C" fn aren_alloc_free(alloc: *mut Allocator, ptr: *mut c_void, size: usize) {
+    let (alloc, ptr, layout) = match (alloc.as_ref(), NonNull::new(ptr as *mut u8), Layout::from_size_align(size, 1)) {
+        (Some(alloc), Some(ptr), Ok(layout)) => (alloc, ptr, layout),
+        _ => return,
+    };
+    if alloc.pool_for_size(size).is_some_and(|pool| pool.owns(ptr.as_ptr())) {
+        alloc.dealloc_layout(ptr, layout);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let new: extern "C" fn(usize) -> *mut Allocator = aren_alloc_new;
+        let destroy: unsafe extern "C" fn(*mut Allocator) = aren_alloc_destroy;
+        let alloc: unsafe extern "C" fn(*mut Allocator, usize) -> *mut c_void = aren_alloc_alloc;
+        let free: unsafe extern "C" fn(*mut Allocator, *mut c_void, usize) = aren_alloc_free;
+
+        unsafe {
+            let allocator = new(2);
+            let ptrs: Vec<*mut c_void> = (1..=5).map(|size| alloc(allocator, size * 50)).collect();
+            assert!(ptrs.iter().all(|p| !p.is_null()));
+            for (i, &p) in ptrs.iter().enumerate() {
+                std::ptr::write_bytes(p as *mut u8, i as u8, (i + 1) * 50);
+            }
+            #[cfg(feature = "stats")]
+            assert_eq!((*allocator).stats().live, 5);
+
+            // the second slot of the 256 bytes class is reused
+            free(allocator, ptrs[3], 200);
+            assert_eq!(alloc(allocator, 256), ptrs[3]);
+            free(allocator, ptrs[4], 250);
+            free(allocator, ptrs[3], 256);
+            free(allocator, ptrs[0], 50);
+            #[cfg(feature = "stats")]
+            assert_eq!((*allocator).stats().live, 2);
+            destroy(allocator);
+        }
+    }
+
+    #[test]
+    fn test_defensive() {
+        unsafe {
+            assert!(aren_alloc_alloc(std::ptr::null_mut(), 8).is_null());
+            aren_alloc_free(std::ptr::null_mut(), 8 as *mut c_void, 8);
+            aren_alloc_destroy(std::ptr::null_mut());
+
+            let allocator = aren_alloc_new(0);
+            assert!(aren_alloc_alloc(allocator, 257).is_null());
+            let p = aren_alloc_alloc(allocator, 16);
+            aren_alloc_free(allocator, std::ptr::null_mut(), 16);
+            aren_alloc_free(allocator, p, 300);
+            // wrong class, or not a slot start
+            aren_alloc_free(allocator, p, 32);
+            aren_alloc_free(allocator, (p as *mut u8).add(1) as *mut c_void, 16);
+            let mut local = 0u64;
+            aren_alloc_free(allocator, &mut local as *mut u64 as *mut c_void, 8);
+            assert!(!(*allocator).is_idle(16));
+            aren_alloc_free(allocator, p, 16);
+            assert!((*allocator).is_idle(16));
+            aren_alloc_destroy(allocator);
+        }
+    }
+}
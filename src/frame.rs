@@ -0,0 +1,254 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Double buffered allocators for game loops, values of a frame living
+//! through the next one.
+
+use std::fmt;
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use {Allocator, AllocatorBuilder};
+
+/// Two allocators taking turns by frame: values allocated during frame
+/// N may be read during frame N + 1, and their slots are reclaimed all
+/// at once when frame N + 2 begins.
+///
+/// A `FramePointer` doesn't borrow the allocator, so that it may be kept
+/// across frames, but its value is only reached through a shared borrow
+/// of the allocator, which `begin_frame` needs exclusively.
+///
+/// ```rust
+/// use aren_alloc::{FrameAllocator, FramePointer};
+///
+/// let mut frames = FrameAllocator::new();
+/// let mut last_hit: Option<FramePointer<(u32, f32)>> = None;
+/// for frame in 0..4u32 {
+///     frames.begin_frame();
+///     let hit = frames.alloc((frame, 0.5f32));
+///     if let Some(last) = last_hit {
+///         assert_eq!(*last.get(&frames), (frame - 1, 0.5));
+///     }
+///     last_hit = Some(hit);
+/// }
+/// ```
+///
+/// References to the values can't outlive the frame:
+///
+/// ```rust,compile_fail,E0502
+/// use aren_alloc::FrameAllocator;
+///
+/// let mut frames = FrameAllocator::new();
+/// let mut ptr = frames.alloc(1u64);
+/// let value = ptr.get_mut(&frames);
+/// frames.begin_frame();
+/// frames.begin_frame();
+/// let other = frames.alloc(2u64);
+/// *value = 99;
+/// ```
+pub struct FrameAllocator {
+    arenas: [Allocator; 2],
+    /// The frame begun last, whose values go to `arenas[frame % 2]`.
+    frame: u64,
+    /// Tells apart the pointers of this allocator from those of others.
+    id: u64,
+}
+
+/// The id of the next frame allocator.
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+impl FrameAllocator {
+    /// Construct a frame allocator of two default allocators, in frame 0.
+    pub fn new() -> FrameAllocator {
+        FrameAllocator::from_arenas(Allocator::new(), Allocator::new())
+    }
+
+    /// Construct a frame allocator of two allocators built by `builder`,
+    /// in frame 0.
+    pub fn from_builder(builder: &AllocatorBuilder) -> FrameAllocator {
+        FrameAllocator::from_arenas(builder.build(), builder.build())
+    }
+
+    fn from_arenas(even: Allocator, odd: Allocator) -> FrameAllocator {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        FrameAllocator{ arenas: [even, odd], frame: 0, id }
+    }
+
+    /// The frame begun last.
+    #[inline]
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    /// Begin the next frame, reclaiming every slot of the frame before
+    /// the current one.
+    pub fn begin_frame(&mut self) {
+        self.frame += 1;
+        // no reference into the arena is left, as `self` isn't borrowed,
+        // and the pointers into it are two frames old now
+        self.arenas[(self.frame % 2) as usize].reset();
+    }
+
+    /// Move `value` into a slot of the current frame.
+    ///
+    /// # Panics
+    ///
+    /// Panics as `Allocator::alloc` does.
+    #[inline]
+    #[track_caller]
+    pub fn alloc<T: Copy>(&self, value: T) -> FramePointer<T> {
+        let node = NonNull::from(self.arenas[(self.frame % 2) as usize].alloc_ref(value));
+        FramePointer{ node, frame: self.frame, owner: self.id }
+    }
+
+    /// Whether the slot of `ptr` is yet to be reclaimed. Pointers of
+    /// other frame allocators never are.
+    #[inline]
+    pub fn is_live<T: Copy>(&self, ptr: &FramePointer<T>) -> bool {
+        ptr.owner == self.id && self.frame - ptr.frame < 2
+    }
+}
+
+impl Default for FrameAllocator {
+    #[inline]
+    fn default() -> FrameAllocator {
+        FrameAllocator::new()
+    }
+}
+
+impl fmt::Debug for FrameAllocator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FrameAllocator").field("frame", &self.frame).finish()
+    }
+}
+
+/// A pointer to a value of a frame, obtained by `FrameAllocator::alloc`.
+///
+/// Dropping it does nothing, its slot is reclaimed along with the rest
+/// of its frame. Reaching its value once its slot is reclaimed, two
+/// frames later, or through another frame allocator, panics rather than
+/// reading the slot, which may hold a newer value by then. This is
+/// checked in release builds too.
+pub struct FramePointer<T: Copy> {
+    node: NonNull<T>,
+    frame: u64,
+    /// The id of the frame allocator of the slot.
+    owner: u64,
+}
+
+impl<T: Copy> FramePointer<T> {
+    /// The frame `ptr` was allocated during.
+    /// This is an associated function, for symmetry with `Pointer`.
+    #[inline]
+    pub fn frame(ptr: &Self) -> u64 {
+        ptr.frame
+    }
+
+    /// Borrow the value, for as long as `frames`, its allocator, is.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the slot was reclaimed, or `frames` isn't its allocator.
+    #[inline]
+    #[track_caller]
+    pub fn get<'f>(&'f self, frames: &'f FrameAllocator) -> &'f T {
+        self.check(frames);
+        unsafe { self.node.as_ref() }
+    }
+
+    /// Borrow the value mutably, for as long as `frames`, its allocator,
+    /// is borrowed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the slot was reclaimed, or `frames` isn't its allocator.
+    #[inline]
+    #[track_caller]
+    pub fn get_mut<'f>(&'f mut self, frames: &'f FrameAllocator) -> &'f mut T {
+        self.check(frames);
+        unsafe { self.node.as_mut() }
+    }
+
+    #[inline]
+    #[track_caller]
+    fn check(&self, frames: &FrameAllocator) {
+        if self.owner != frames.id {
+            panic!("pointer of frame {} used with another frame allocator", self.frame);
+        }
+        if !frames.is_live(self) {
+            stale(self.frame, frames.frame);
+        }
+    }
+}
+
+#[cold]
+#[track_caller]
+fn stale(frame: u64, now: u64) -> ! {
+    panic!("pointer of frame {} dereferenced during frame {}, after its slot was reclaimed", frame, now)
+}
+
+impl<T: Copy> fmt::Debug for FramePointer<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FramePointer").field("frame", &self.frame).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_previous_frame() {
+        let mut frames = FrameAllocator::from_builder(&AllocatorBuilder::new().capacity_for(16, 4));
+        let mut previous = frames.alloc([0u64; 2]);
+        for frame in 1..100u64 {
+            frames.begin_frame();
+            assert_eq!(frames.frame(), frame);
+            let mut current = frames.alloc([frame; 2]);
+            current.get_mut(&frames)[1] += previous.get(&frames)[1];
+            // the previous value is still readable, and writable
+            previous.get_mut(&frames)[0] = frame;
+            assert_eq!((*previous.get(&frames), FramePointer::frame(&previous)), ([frame, frame * (frame - 1) / 2], frame - 1));
+            for i in 0..5u64 {
+                frames.alloc([i; 2]);
+            }
+            previous = current;
+        }
+        // every frame reuses the two pages of its arena
+        assert!(frames.arenas.iter().all(|arena| arena.pages().iter().filter(|p| p.class_size == 16).count() == 2));
+    }
+
+    #[test]
+    #[cfg_attr(target_family = "wasm", ignore = "panics abort on wasm")]
+    fn test_stale_frame() {
+        let mut frames = FrameAllocator::new();
+        let old = frames.alloc(1u32);
+        frames.begin_frame();
+        assert!(frames.is_live(&old));
+        frames.begin_frame();
+        assert!(!frames.is_live(&old));
+        assert_eq!(format!("{:?}", old), "FramePointer { frame: 0 }");
+        let newer = frames.alloc(2u32);
+        assert_eq!(newer.node, old.node);
+        let err = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| *old.get(&frames))).unwrap_err();
+        let msg = err.downcast_ref::<String>().unwrap();
+        assert!(msg.contains("pointer of frame 0 dereferenced during frame 2"), "{}", msg);
+        assert_eq!(*newer.get(&frames), 2);
+    }
+
+    #[test]
+    #[cfg_attr(target_family = "wasm", ignore = "panics abort on wasm")]
+    fn test_other_allocator() {
+        let (frames, other) = (FrameAllocator::new(), FrameAllocator::new());
+        let ptr = frames.alloc(1u32);
+        assert!(!other.is_live(&ptr));
+        let err = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| *ptr.get(&other))).unwrap_err();
+        let msg = err.downcast_ref::<String>().unwrap();
+        assert_eq!(msg, "pointer of frame 0 used with another frame allocator");
+    }
+}
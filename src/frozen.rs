@@ -0,0 +1,161 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Read-only pointers that could be shared across threads.
+
+use std::marker::Unsize;
+use std::ops::CoerceUnsized;
+
+use Pointer;
+
+/// A read-only pointer to `T`, obtained by `Pointer::freeze`.
+///
+/// It is `Sync` as long as `T` is, so it could be borrowed by scoped
+/// threads, but it is never `Send`, so it is always dropped, and the
+/// underlying memory recycled, on the thread owning the allocator.
+///
+/// ```rust,compile_fail
+/// use aren_alloc::{Allocator, Pointer};
+/// let allocator = Allocator::new();
+/// let frozen = Pointer::freeze(allocator.alloc(1u32));
+/// std::thread::scope(|s| {
+///     s.spawn(move || drop(frozen));
+/// });
+/// ```
+///
+/// Erased into a trait object, it is only `Sync` if the trait object
+/// says so, as in `Frozen<dyn Trait + Sync>`:
+///
+/// ```rust,compile_fail
+/// use aren_alloc::{Allocator, Frozen, Pointer};
+/// let allocator = Allocator::new();
+/// let frozen: Frozen<dyn std::fmt::Debug> = Pointer::freeze(allocator.alloc(1u32));
+/// std::thread::scope(|s| {
+///     s.spawn(|| println!("{:?}", &*frozen));
+/// });
+/// ```
+pub struct Frozen<'a, T: ?Sized> {
+    ptr: Pointer<'a, T>,
+}
+
+impl<'a, T, U> CoerceUnsized<Frozen<'a, T>> for Frozen<'a, U>
+    where U: Unsize<T> + ?Sized,
+          T: ?Sized,
+{ }
+
+// Only `&T` is reachable through a `&Frozen`, the pool is only
+// touched on drop, which requires ownership.
+unsafe impl<'a, T: Sync + ?Sized> Sync for Frozen<'a, T> { }
+
+impl<'a, T: ?Sized> Pointer<'a, T> {
+    /// Freeze `ptr` into a read-only pointer shareable across threads.
+    #[inline]
+    pub fn freeze(ptr: Self) -> Frozen<'a, T> {
+        Frozen{ ptr }
+    }
+}
+
+impl<'a, T: ?Sized> Frozen<'a, T> {
+    /// Turn `this` back into a mutable `Pointer`.
+    #[inline]
+    pub fn unfreeze(this: Self) -> Pointer<'a, T> {
+        this.ptr
+    }
+}
+
+impl<'a, T: ?Sized> std::ops::Deref for Frozen<'a, T> {
+    type Target = T;
+    #[inline]
+    fn deref(&self) -> &T {
+        Pointer::as_ref(&self.ptr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use Allocator;
+
+    trait Renderable {
+        fn draw(&self) -> u32;
+    }
+
+    impl Renderable for [u32; 4] {
+        fn draw(&self) -> u32 {
+            self.iter().sum()
+        }
+    }
+
+    // shared across threads only if the trait object says so, and never
+    // dropped on another thread
+    assert_impl_all!(Frozen<'static, u32>: Sync);
+    assert_impl_all!(Frozen<'static, dyn Renderable + Sync>: Sync);
+    assert_impl_all!(Frozen<'static, dyn Renderable + Send + Sync>: Sync);
+    assert_not_impl_any!(Frozen<'static, u32>: Send);
+    assert_not_impl_any!(Frozen<'static, dyn Renderable>: Send, Sync);
+    assert_not_impl_any!(Frozen<'static, dyn Renderable + Send + Sync>: Send);
+
+    // sent across threads only if the trait object says so
+    assert_impl_all!(::SendPointer<'static, dyn Renderable + Send>: Send);
+    assert_impl_all!(::SendPointer<'static, dyn Renderable + Sync>: Sync);
+    assert_impl_all!(::SendPointer<'static, dyn Renderable + Send + Sync>: Send, Sync);
+    assert_not_impl_any!(::SendPointer<'static, dyn Renderable>: Send, Sync);
+    assert_not_impl_any!(::SendPointer<'static, dyn Renderable + Send>: Sync);
+    assert_not_impl_any!(::SendPointer<'static, dyn Renderable + Sync>: Send);
+
+    // neither, whatever the pointee
+    assert_not_impl_any!(::RcPointer<'static, u32>: Send, Sync);
+    assert_not_impl_any!(::RcPointer<'static, dyn Renderable + Send + Sync>: Send, Sync);
+
+    #[test]
+    #[cfg_attr(target_family = "wasm", ignore = "no threads on wasm")]
+    fn test_erased_auto_traits() {
+        let allocator = Allocator::new();
+        let frozen: Frozen<dyn Renderable + Sync> = Pointer::freeze(allocator.alloc([1u32, 2, 3, 4]));
+        let drawn = thread::scope(|s| {
+            let frozen = &frozen;
+            s.spawn(move || frozen.draw()).join().unwrap()
+        });
+        assert_eq!(drawn, 10);
+        // and the erased pointer is just as usable once unfrozen
+        let ptr: Pointer<dyn Renderable + Send + Sync> = Frozen::unfreeze(Pointer::freeze(allocator.alloc([2u32; 4])));
+        assert_eq!(ptr.draw(), 8);
+        drop((frozen, ptr));
+
+        let sent: ::SendPointer<dyn Renderable + Send> = allocator.alloc_send([5u32; 4]);
+        assert_eq!(thread::scope(|s| s.spawn(move || sent.draw()).join().unwrap()), 20);
+        allocator.drain_remote_frees();
+        assert_eq!(allocator.pool16.free_count(), allocator.pool16.slot_count());
+    }
+
+    #[test]
+    #[cfg_attr(target_family = "wasm", ignore = "no threads on wasm")]
+    fn test_scoped_readers() {
+        let allocator = Allocator::new();
+        let mut table = allocator.alloc([0u32; 32]);
+        for (i, v) in table.iter_mut().enumerate() {
+            *v = (i * i) as u32;
+        }
+
+        let frozen = Pointer::freeze(table);
+        let sums: Vec<u32> = thread::scope(|s| {
+            let handles: Vec<_> = (0..4).map(|t| {
+                let frozen = &frozen;
+                s.spawn(move || frozen[t * 8..(t + 1) * 8].iter().sum::<u32>())
+            }).collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+        assert_eq!(sums.iter().sum::<u32>(), (0..32).map(|i| i * i).sum::<u32>());
+
+        let mut table = Frozen::unfreeze(frozen);
+        table[0] = 42;
+        assert_eq!(table[0], 42);
+        assert_eq!(table[31], 31 * 31);
+    }
+}
@@ -0,0 +1,254 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Groups of slots of one allocator, reporting their usage apart.
+
+use std::alloc::AllocError;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+
+use {too_big, Allocator, Pointer};
+
+/// The usage of a group of an allocator, see `Allocator::group_report`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct GroupUsage {
+    /// Name the group was registered with.
+    pub name: &'static str,
+    /// Slots of the group handed out.
+    pub live: usize,
+    /// Bytes of the slots of the group handed out, counting whole slots.
+    pub bytes: usize,
+    /// Cap of `bytes`, if any.
+    pub budget: Option<usize>,
+}
+
+impl Allocator {
+    /// Get the group of slots named `name`, registering it on first use.
+    /// Slots allocated through the group are attributed to it until
+    /// their pointers are dropped.
+    ///
+    /// ```rust
+    /// use aren_alloc::Allocator;
+    /// let allocator = Allocator::new();
+    /// let bvh = allocator.group("bvh");
+    /// let particles = allocator.group("particles");
+    /// let node = bvh.alloc([0f32; 6]);
+    /// let sparks: Vec<_> = (0..3).map(|i| particles.alloc((i, 0.5f32))).collect();
+    /// assert_eq!(bvh.usage().bytes, 32);
+    /// assert_eq!(allocator.group_report()[1].live, 3);
+    /// ```
+    pub fn group(&self, name: &'static str) -> Group<'_> {
+        let mut groups = self.groups.borrow_mut();
+        let id = match groups.iter().position(|g| g.name == name) {
+            Some(id) => id,
+            None => {
+                groups.push(GroupUsage{ name, live: 0, bytes: 0, budget: None });
+                groups.len() - 1
+            }
+        };
+        Group{ allocator: self, id }
+    }
+
+    /// The usage of every group, in the order they were registered.
+    pub fn group_report(&self) -> Vec<GroupUsage> {
+        self.groups.borrow().clone()
+    }
+}
+
+/// A group of slots of an allocator, see `Allocator::group`.
+#[derive(Copy, Clone)]
+pub struct Group<'a> {
+    allocator: &'a Allocator,
+    id: usize,
+}
+
+impl<'a> Group<'a> {
+    /// Name the group was registered with.
+    #[inline]
+    pub fn name(&self) -> &'static str {
+        self.usage().name
+    }
+
+    /// The current usage of the group.
+    #[inline]
+    pub fn usage(&self) -> GroupUsage {
+        self.allocator.groups.borrow()[self.id]
+    }
+
+    /// Cap the bytes of the slots of the group to `bytes`, or lift the
+    /// cap with `None`. Slots handed out already are kept, even beyond
+    /// the cap. Other groups, and the allocator itself, are unaffected.
+    #[inline]
+    pub fn set_budget(&self, bytes: Option<usize>) {
+        self.allocator.groups.borrow_mut()[self.id].budget = bytes;
+    }
+
+    /// Allocate an instance of `T` with value `elem`, attributed to the
+    /// group.
+    ///
+    /// # Panics
+    ///
+    /// Panics as `Allocator::alloc` does, or if the slot would exceed the
+    /// budget of the group.
+    #[inline]
+    #[track_caller]
+    pub fn alloc<T: Copy>(&self, elem: T) -> GroupPointer<'a, T> {
+        let ptr = self.allocator.alloc(elem);
+        if !self.charge(ptr.pool.ele_size) {
+            panic!("allocation budget of group `{}` exhausted", self.name());
+        }
+        GroupPointer{ ptr, group: *self }
+    }
+
+    /// Allocate an instance of `T` with value `elem`, attributed to the
+    /// group, or return an error as `Allocator::try_alloc` does, or if
+    /// the slot would exceed the budget of the group.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size_of::<T>()` is larger than 256 bytes.
+    #[inline]
+    #[track_caller]
+    pub fn try_alloc<T: Copy>(&self, elem: T) -> Result<GroupPointer<'a, T>, AllocError> {
+        let class = match self.allocator.pool_for_size(std::mem::size_of::<T>()) {
+            Some(pool) => pool.ele_size,
+            None if std::mem::size_of::<T>() <= 256 => return Err(AllocError),
+            None => too_big::<T>(),
+        };
+        if !self.charge(class) {
+            return Err(AllocError);
+        }
+        match self.allocator.try_alloc(elem) {
+            Ok(ptr) => Ok(GroupPointer{ ptr, group: *self }),
+            Err(err) => {
+                self.uncharge(class);
+                Err(err)
+            }
+        }
+    }
+
+    /// Attribute a slot of `bytes` to the group, unless it would exceed
+    /// its budget.
+    fn charge(&self, bytes: usize) -> bool {
+        let mut groups = self.allocator.groups.borrow_mut();
+        let usage = &mut groups[self.id];
+        if usage.budget.is_some_and(|budget| usage.bytes + bytes > budget) {
+            return false;
+        }
+        usage.live += 1;
+        usage.bytes += bytes;
+        true
+    }
+
+    fn uncharge(&self, bytes: usize) {
+        let usage = &mut self.allocator.groups.borrow_mut()[self.id];
+        usage.live -= 1;
+        usage.bytes -= bytes;
+    }
+}
+
+impl<'a> fmt::Debug for Group<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&self.usage(), f)
+    }
+}
+
+/// A pointer allocated through a `Group`, attributed to it until dropped.
+pub struct GroupPointer<'a, T> {
+    ptr: Pointer<'a, T>,
+    group: Group<'a>,
+}
+
+impl<'a, T> GroupPointer<'a, T> {
+    /// The group `this` is attributed to.
+    /// This is an associated function so that
+    /// `T`'s methods won't be shadowed.
+    #[inline]
+    pub fn group(this: &Self) -> Group<'a> {
+        this.group
+    }
+}
+
+impl<'a, T> Deref for GroupPointer<'a, T> {
+    type Target = T;
+    #[inline]
+    #[track_caller]
+    fn deref(&self) -> &T {
+        &self.ptr
+    }
+}
+
+impl<'a, T> DerefMut for GroupPointer<'a, T> {
+    #[inline]
+    #[track_caller]
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.ptr
+    }
+}
+
+impl<'a, T> Drop for GroupPointer<'a, T> {
+    #[inline]
+    fn drop(&mut self) {
+        // the slot itself is recycled by `ptr`
+        self.group.uncharge(self.ptr.pool.ele_size);
+    }
+}
+
+impl<'a, T: fmt::Debug> fmt::Debug for GroupPointer<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_groups_apart() {
+        let allocator = Allocator::new();
+        let bvh = allocator.group("bvh");
+        let shading = allocator.group("shading");
+        // both in the class of 16 bytes
+        let nodes: Vec<_> = (0..3).map(|i| bvh.alloc([i as u32; 4])).collect();
+        let mut params = shading.alloc((1u64, 2u64));
+        params.1 += 1;
+        assert_eq!(allocator.group("bvh").usage(), GroupUsage{ name: "bvh", live: 3, bytes: 48, budget: None });
+        assert_eq!(shading.usage().bytes, 16);
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 4);
+
+        drop(params);
+        let report = allocator.group_report();
+        assert_eq!(report.iter().map(|g| (g.name, g.live)).collect::<Vec<_>>(), [("bvh", 3), ("shading", 0)]);
+        assert_eq!(GroupPointer::group(&nodes[2]).name(), "bvh");
+        assert_eq!(*nodes[2], [2; 4]);
+        drop(nodes);
+        assert_eq!(bvh.usage().live, 0);
+    }
+
+    #[test]
+    #[cfg_attr(target_family = "wasm", ignore = "panics abort on wasm")]
+    fn test_group_budget() {
+        let allocator = Allocator::new();
+        let particles = allocator.group("particles");
+        let other = allocator.group("other");
+        particles.set_budget(Some(64));
+        let sparks: Vec<_> = (0..2).map(|i| particles.try_alloc([i as u8; 20]).unwrap()).collect();
+        assert!(particles.try_alloc(0u8).is_err());
+        assert_eq!(*other.try_alloc([0u8; 20]).unwrap(), [0; 20]);
+        let err = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| { particles.alloc(0u8); })).unwrap_err();
+        assert_eq!(err.downcast_ref::<String>().unwrap(), "allocation budget of group `particles` exhausted");
+        // the refused slot is neither attributed nor leaked
+        assert_eq!(particles.usage().live, 2);
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 2);
+        drop(sparks);
+        assert_eq!(*particles.alloc(3u8), 3);
+    }
+}
@@ -0,0 +1,174 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Free lists in random order, so that the addresses handed out are
+//! harder to predict. Under the `harden` feature, for allocators built
+//! with `AllocatorBuilder::harden`.
+//!
+//! The slots of a new page are linked in a shuffled order, and half of
+//! the slots recycled are pushed a few slots deep rather than on top,
+//! so the slot freed last isn't always the next one handed out.
+
+#[cfg(feature = "harden")]
+use std::cell::Cell;
+use std::ptr::NonNull;
+#[cfg(feature = "harden")]
+use std::rc::Rc;
+
+use {Node, Pool};
+
+/// How deep a recycled slot is pushed into the free list, at most.
+#[cfg(feature = "harden")]
+const MAX_DEPTH: usize = 16;
+
+/// A xorshift generator seeded from the randomness of the OS, shared by
+/// the pools of an allocator.
+#[cfg(feature = "harden")]
+pub(crate) struct Rng(Cell<u64>);
+
+#[cfg(feature = "harden")]
+impl Rng {
+    /// Seed a generator from the keys `RandomState` gets from the OS.
+    pub(crate) fn from_os() -> Rng {
+        use std::hash::BuildHasher;
+        let seed = std::collections::hash_map::RandomState::new().hash_one(0usize);
+        Rng(Cell::new(seed | 1))
+    }
+
+    #[inline]
+    fn next(&self) -> u64 {
+        let mut x = self.0.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0.set(x);
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// A number in `0..n`, for a small `n`.
+    #[inline]
+    pub(crate) fn below(&self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+impl Pool {
+    /// Shuffle the free list of the pool with `rng`, and every page
+    /// added later. The pool must have no slot handed out.
+    #[cfg(feature = "harden")]
+    pub(crate) fn harden(&mut self, rng: Rc<Rng>) {
+        self.rng = Some(rng);
+        let page = self.pool.borrow();
+        if page.len() != 0 {
+            self.head.set(Some(unsafe { self.link(&page, std::ptr::null_mut()) }));
+        }
+    }
+
+    /// Link every slot of `page` in a shuffled order, the last one to
+    /// `next`, return the first one.
+    ///
+    /// # Safety
+    ///
+    /// No slot of `page` may be in use, nor on any list.
+    #[cfg(feature = "harden")]
+    pub(crate) unsafe fn link_shuffled(&self, base: *mut u8, num: usize, next: *mut Node, rng: &Rng) -> NonNull<Node> {
+        let mut order: Vec<usize> = (0..num).collect();
+        for i in (1..num).rev() {
+            order.swap(i, rng.below(i + 1));
+        }
+        let slot = |i: usize| base.add(order[i] * self.ele_size).cast::<Node>();
+        for i in 0..num - 1 {
+            (*slot(i)).next = slot(i + 1);
+        }
+        (*slot(num - 1)).next = next;
+        NonNull::new_unchecked(slot(0))
+    }
+
+    /// The free slot after which to push a recycled slot, or `None` to
+    /// push it on top, as a pool not hardened always does.
+    #[inline(always)]
+    pub(crate) fn push_point(&self) -> Option<NonNull<Node>> {
+        #[cfg(feature = "harden")]
+        if let Some(ref rng) = self.rng {
+            let draw = rng.next();
+            if draw & 1 == 0 {
+                return None;
+            }
+            // a bounded walk, stopping short at the end of the list
+            let mut prev = self.head.get()?;
+            for _ in 0..(draw >> 1) as usize % MAX_DEPTH {
+                match NonNull::new(unsafe { prev.as_ref().next }) {
+                    Some(next) => prev = next,
+                    None => break,
+                }
+            }
+            return Some(prev);
+        }
+        None
+    }
+}
+
+#[cfg(all(test, feature = "harden"))]
+mod tests {
+    use AllocatorBuilder;
+
+    fn addr<T>(ptr: &::Pointer<T>) -> usize {
+        &**ptr as *const T as usize
+    }
+
+    #[test]
+    fn test_not_adjacent() {
+        let allocator = AllocatorBuilder::new().harden(true).build();
+        let ptrs: Vec<_> = (0..256u64).map(|i| allocator.alloc(i)).collect();
+        let adjacent = ptrs.windows(2).filter(|w| addr(&w[1]) == addr(&w[0]) + 8).count();
+        // about 1 in 512 for a shuffled page, 255 of 255 in address order
+        assert!(adjacent < 16, "{} of 255 consecutive allocations adjacent", adjacent);
+        assert!(ptrs.iter().enumerate().all(|(i, p)| **p == i as u64));
+
+        // nor is the slot freed last always the next one
+        let mut reused = 0;
+        for i in 0..256u64 {
+            let ptr = allocator.alloc(i);
+            let freed = addr(&ptr);
+            drop(ptr);
+            if addr(&allocator.alloc(i)) == freed {
+                reused += 1;
+            }
+        }
+        assert!(reused < 200, "the slot freed last reused {} times of 256", reused);
+        drop(ptrs);
+        assert_eq!(allocator.free_slots(8), allocator.pool8.free_count());
+    }
+
+    #[test]
+    fn test_hardened_churn() {
+        let mut allocator = AllocatorBuilder::new().harden(true).capacity_for(64, 4).build();
+        let mut held: Vec<_> = (0..100u32).map(|i| allocator.alloc([i; 16])).collect();
+        let addrs: std::collections::HashSet<usize> = held.iter().map(addr).collect();
+        assert_eq!(addrs.len(), 100);
+        let mut i = 0;
+        held.retain(|_| {
+            i += 1;
+            i % 3 != 0
+        });
+        held.extend((100..150u32).map(|i| allocator.alloc([i; 16])));
+        assert!(held.iter().all(|p| p.iter().all(|&x| x == p[0])));
+        assert_eq!(allocator.live_count(64), held.len());
+        assert_eq!(allocator.free_slots(64), allocator.pool64.free_count());
+
+        // runs are found among shuffled slots
+        let block = allocator.alloc_contiguous(600).unwrap();
+        assert!(block.iter().all(|&b| b == 0));
+        drop((held, block));
+        assert_eq!(allocator.free_slots(64), allocator.pool64.slot_count());
+        allocator.reset();
+        assert_eq!(allocator.pool64.free_count(), allocator.pool64.slot_count());
+        let pages = allocator.pool64.pages().count();
+        assert_eq!(allocator.trim(0), 5 + pages);
+    }
+}
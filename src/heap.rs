@@ -0,0 +1,213 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Pools standing for the heap, so that a `Pointer` could own a value
+//! no class holds.
+//!
+//! A heap pool has no page. It allocates each of its slots from the
+//! global allocator, and frees each one as it's recycled, so every path
+//! recycling the slot of a `Pointer` frees a heap block with the right
+//! layout. There's one heap pool per layout, shared by all threads,
+//! which only ever read its fields.
+
+use std::alloc::Layout;
+use std::sync::Mutex;
+
+use {check_slot, Allocator, Node, Pointer, Pool, PAGE_ALIGN};
+
+/// A heap pool, shared by all threads.
+struct HeapPool(Pool);
+
+// A heap pool never touches its cells, except for reading the epoch,
+// which is never bumped.
+unsafe impl Sync for HeapPool { }
+unsafe impl Send for HeapPool { }
+
+/// The heap pools created so far, never freed.
+static HEAP_POOLS: Mutex<Vec<&'static HeapPool>> = Mutex::new(Vec::new());
+
+/// Get the heap pool of the blocks of `T`.
+///
+/// The blocks are aligned to at least the alignment of a slot of their
+/// size, so that whatever fits in a slot of the class fits in a block.
+fn heap_pool<T>() -> &'static Pool {
+    let size = std::mem::size_of::<T>();
+    let align = std::cmp::max(std::mem::align_of::<T>(), std::cmp::min(size.next_power_of_two(), PAGE_ALIGN));
+    let mut pools = HEAP_POOLS.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(pool) = pools.iter().find(|pool| pool.0.ele_size == size && pool.0.heap_align == align) {
+        return &pool.0;
+    }
+    let mut pool = Pool::lazy(1, std::mem::size_of::<Node>());
+    pool.disown();
+    pool.ele_size = size;
+    pool.heap_align = align;
+    let pool: &'static HeapPool = Box::leak(Box::new(HeapPool(*pool)));
+    pools.push(pool);
+    &pool.0
+}
+
+impl Pool {
+    #[inline]
+    fn heap_layout(&self) -> Layout {
+        Layout::from_size_align(self.ele_size, self.heap_align).unwrap()
+    }
+
+    /// Allocate a slot of a heap pool.
+    #[cold]
+    pub(crate) fn heap_alloc(&self) -> Option<*mut Node> {
+        let layout = self.heap_layout();
+        if layout.size() == 0 {
+            return Some(std::ptr::without_provenance_mut(layout.align()));
+        }
+        match unsafe { std::alloc::alloc(layout) } {
+            ptr if ptr.is_null() => std::alloc::handle_alloc_error(layout),
+            ptr => Some(ptr as *mut Node),
+        }
+    }
+
+    /// Free a slot of a heap pool.
+    #[cold]
+    pub(crate) unsafe fn heap_free(&self, node: *mut Node) {
+        let layout = self.heap_layout();
+        if layout.size() != 0 {
+            std::alloc::dealloc(node as *mut u8, layout);
+        }
+    }
+}
+
+impl Allocator {
+    /// Allocate an instance of `T` with value `elem`, from the pool of
+    /// its class, or from the heap if no class holds it: `T` is larger
+    /// than 256 bytes, aligned beyond 16 bytes, or its class is missing.
+    ///
+    /// Either way, it's a `Pointer` as any other, freeing its slot or
+    /// its heap block when dropped. Heap blocks count neither in the
+    /// stats nor against the budget of the allocator.
+    ///
+    /// ```rust
+    /// use aren_alloc::{Allocator, Pointer};
+    /// let allocator = Allocator::new();
+    /// let small: Pointer<[u8]> = allocator.alloc_or_heap([1u8; 10]);
+    /// let large: Pointer<[u8]> = allocator.alloc_or_heap([2u8; 1000]);
+    /// assert_eq!(small.len() + large.len(), 1010);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if the budget of the allocator is exhausted, and its
+    /// handler couldn't recover.
+    #[track_caller]
+    pub fn alloc_or_heap<T: Copy>(&self, elem: T) -> Pointer<'_, T> {
+        let (pool, node) = match self.pool_for_layout(Layout::new::<T>()) {
+            Some(pool) => {
+                check_slot::<T>(pool.ele_size);
+                match self.pop_or_recover(pool, std::mem::size_of::<T>()) {
+                    Some(node) => (pool, node),
+                    None => panic!("allocation budget exhausted"),
+                }
+            }
+            None => {
+                let pool = heap_pool::<T>();
+                (pool, pool.heap_alloc().unwrap())
+            }
+        };
+        unsafe {
+            std::ptr::write(node as *mut T, elem);
+            Pointer::from_node(pool, node as *mut T)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use AllocatorBuilder;
+
+    trait Weigh {
+        fn weight(&self) -> usize;
+    }
+
+    impl<const N: usize> Weigh for [u8; N] {
+        fn weight(&self) -> usize {
+            self.iter().map(|&b| b as usize).sum()
+        }
+    }
+
+    #[derive(Copy, Clone)]
+    #[repr(align(64))]
+    struct Line([u8; 64]);
+
+    impl Weigh for Line {
+        fn weight(&self) -> usize {
+            self.0.weight()
+        }
+    }
+
+    #[derive(Copy, Clone)]
+    #[repr(align(32))]
+    struct Marker;
+
+    impl Weigh for Marker {
+        fn weight(&self) -> usize {
+            1
+        }
+    }
+
+    #[test]
+    fn test_mixed() {
+        let allocator = Allocator::new();
+        let mut ptrs: Vec<Pointer<dyn Weigh>> = Vec::new();
+        for i in 0..10u8 {
+            ptrs.push(allocator.alloc_or_heap([i; 8]));
+            ptrs.push(allocator.alloc_or_heap([i; 300]));
+            ptrs.push(allocator.alloc_or_heap(Line([i; 64])));
+            ptrs.push(allocator.alloc_or_heap(Marker));
+        }
+        assert_eq!(&*ptrs[6] as *const dyn Weigh as *const u8 as usize % 64, 0);
+        assert_eq!(&*ptrs[7] as *const dyn Weigh as *const u8 as usize % 32, 0);
+        assert_eq!(ptrs[1].weight(), 0);
+        assert_eq!(ptrs.iter().map(|p| p.weight()).sum::<usize>(), (0..10).map(|i| i * (8 + 300 + 64) + 1).sum::<usize>());
+        assert!(allocator.pool8.owns(&*ptrs[4] as *const dyn Weigh as *const u8));
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 10);
+
+        // dropped interleaved, each to where it came from
+        let mut i = 0;
+        ptrs.retain(|_| {
+            i += 1;
+            i % 3 != 0
+        });
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 7);
+        drop(ptrs);
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 0);
+        assert_eq!(std::mem::size_of::<Pointer<[u8; 300]>>(), std::mem::size_of::<Pointer<u8>>());
+    }
+
+    #[test]
+    #[cfg_attr(target_family = "wasm", ignore = "no threads on wasm")]
+    fn test_heap_conversions() {
+        let allocator = AllocatorBuilder::new().only_classes(&[8]).build();
+        // the missing class of 16 bytes isn't rounded up
+        let pair = allocator.alloc_or_heap([3u64; 2]);
+        assert!(!allocator.pool8.owns(&*pair as *const [u64; 2] as *const u8));
+        let mapped = Pointer::map(pair, |[a, b]| a + b);
+        assert_eq!(*mapped, 6);
+        let small = Pointer::map_in(mapped, &allocator, |x| x as u32);
+        assert!(allocator.pool8.owns(&*small as *const u32 as *const u8));
+        let erased = Pointer::erase(allocator.alloc_or_heap([1u8; 500]));
+        drop(erased);
+        let restored = unsafe { Pointer::erase(allocator.alloc_or_heap([2u16; 200])).assume_type::<[u16; 200]>() };
+        assert_eq!(restored[199], 2);
+        std::thread::scope(|s| {
+            s.spawn(|| drop(Allocator::new().alloc_or_heap([5u16; 200])));
+        });
+        drop((small, restored));
+    }
+}
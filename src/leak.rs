@@ -0,0 +1,241 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Catching slots still handed out when their allocator is dropped.
+
+use std::fmt::Write;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use Allocator;
+
+/// What a debug build does when an `Allocator` is dropped while some of
+/// its slots are still handed out, e.g. pointers leaked with
+/// `mem::forget`, or slots lent through FFI. Their pages are freed
+/// under them either way. Release builds don't check.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum LeakPolicy {
+    /// Panic, listing the live slots of every class. This is the default.
+    /// Nothing is checked if the thread is already panicking.
+    Panic,
+    /// Print the live slots of every class, then abort the process.
+    Abort,
+    /// Drop the allocator anyway, for slots leaked on purpose.
+    Ignore,
+}
+
+/// The most ids of live slots listed per class by a leak report.
+const MAX_IDS: usize = 8;
+
+impl Allocator {
+    /// Describe the live slots of every class, with the `SlotId`s of the
+    /// first ones, or `None` if there's none.
+    fn leak_report(&self) -> Option<String> {
+        let mut report = String::new();
+        for pool in self.pools() {
+            // slots freed on other threads are no longer in use
+            pool.drain_remote();
+            // slots of `alloc_ref` are reclaimed with the allocator
+            let refs = self.ref_slots(pool).get();
+            let live = pool.tracked_live() - refs;
+            if live != 0 {
+                let sep = if report.is_empty() { "" } else { ", " };
+                let _ = write!(report, "{}{} of {} bytes", sep, live, pool.ele_size);
+                // which live slots are those of `alloc_ref` isn't known
+                if refs == 0 {
+                    let ids = pool.live_ids();
+                    let shown: Vec<String> = ids.iter().take(MAX_IDS).map(|id| id.to_string()).collect();
+                    let more = if ids.len() > MAX_IDS { " ..." } else { "" };
+                    let _ = write!(report, " [{}{}]", shown.join(" "), more);
+                }
+            }
+        }
+        if report.is_empty() {
+            None
+        } else {
+            match self.name {
+                Some(name) => Some(format!("allocator `{}` dropped with live slots: {}", name, report)),
+                None => Some(format!("allocator dropped with live slots: {}", report)),
+            }
+        }
+    }
+}
+
+impl Allocator {
+    /// The slots handed out by the pool serving `class_size` bytes, or 0
+    /// if no class does. Slots freed on other threads are drained first,
+    /// slots of `alloc_ref` count until `reset`.
+    ///
+    /// ```rust
+    /// use aren_alloc::Allocator;
+    /// let allocator = Allocator::new();
+    /// let ptr = allocator.alloc([0u8; 100]);
+    /// assert_eq!(allocator.live_count(128), 1);
+    /// drop(ptr);
+    /// assert!(allocator.is_idle(128));
+    /// ```
+    pub fn live_count(&self, class_size: usize) -> usize {
+        match self.pool_for_size(class_size) {
+            Some(pool) => {
+                pool.drain_remote();
+                pool.tracked_live()
+            }
+            None => 0,
+        }
+    }
+
+    /// Whether no slot of the pool serving `class_size` bytes is handed
+    /// out, see `live_count`.
+    #[inline]
+    pub fn is_idle(&self, class_size: usize) -> bool {
+        self.live_count(class_size) == 0
+    }
+
+    /// Panic if a slot of the pool serving `class_size` bytes is handed
+    /// out, see `live_count`, e.g. before tearing down what the values
+    /// of a class refer to.
+    ///
+    /// # Panics
+    ///
+    /// Panics with the number of live slots, if any.
+    #[track_caller]
+    pub fn assert_idle(&self, class_size: usize) {
+        let live = self.live_count(class_size);
+        if live != 0 {
+            let ele_size = self.pool_for_size(class_size).unwrap().ele_size;
+            match self.name {
+                Some(name) => panic!("allocator `{}` has {} live slots of {} bytes", name, live, ele_size),
+                None => panic!("allocator has {} live slots of {} bytes", live, ele_size),
+            }
+        }
+    }
+}
+
+impl Drop for Allocator {
+    fn drop(&mut self) {
+        #[cfg(feature = "registry")]
+        ::registry::deregister(self);
+        let report = if !cfg!(debug_assertions) || self.leak_policy == LeakPolicy::Ignore || std::thread::panicking() {
+            None
+        } else {
+            self.leak_report()
+        };
+        // whatever the policy, pinned values never dropped stay in place
+        for pool in self.all_pools() {
+            pool.leak_pinned();
+        }
+        if let Some(report) = report {
+            if self.leak_policy == LeakPolicy::Abort {
+                eprintln!("{}", report);
+                std::process::abort();
+            }
+            panic!("{}", report);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic;
+    use AllocatorBuilder;
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[cfg_attr(target_family = "wasm", ignore = "panics abort on wasm")]
+    fn test_leak_panics() {
+        let err = panic::catch_unwind(|| {
+            let allocator = Allocator::new();
+            std::mem::forget(allocator.alloc(1u64));
+            std::mem::forget(allocator.alloc([0u8; 100]));
+            std::mem::forget(allocator.alloc([0u8; 100]));
+        }).unwrap_err();
+        let msg = err.downcast_ref::<String>().unwrap();
+        assert_eq!(msg, "allocator dropped with live slots: 1 of 8 bytes [0:0:0], 2 of 128 bytes [4:0:0 4:0:1]");
+
+        let err = panic::catch_unwind(|| {
+            let allocator = AllocatorBuilder::new().name("shading").build();
+            std::mem::forget(allocator.alloc(1u32));
+        }).unwrap_err();
+        let msg = err.downcast_ref::<String>().unwrap();
+        assert_eq!(msg, "allocator `shading` dropped with live slots: 1 of 8 bytes [0:0:0]");
+
+        let err = panic::catch_unwind(|| {
+            let allocator = Allocator::new();
+            for i in 0..10u64 {
+                std::mem::forget(allocator.alloc(i));
+            }
+            // which of the two is that of `alloc_ref` is unknown
+            let _ = allocator.alloc_ref(0u128);
+            std::mem::forget(allocator.alloc([0u8; 16]));
+        }).unwrap_err();
+        let msg = err.downcast_ref::<String>().unwrap();
+        assert_eq!(msg, "allocator dropped with live slots: \
+                         10 of 8 bytes [0:0:0 0:0:1 0:0:2 0:0:3 0:0:4 0:0:5 0:0:6 0:0:7 ...], 1 of 16 bytes");
+    }
+
+    #[test]
+    #[cfg_attr(target_family = "wasm", ignore = "no threads on wasm")]
+    fn test_no_leak() {
+        let allocator = Allocator::new();
+        let held: Vec<_> = (0..100u32).map(|i| allocator.alloc(i)).collect();
+        drop(held);
+        let sent = allocator.alloc_send(1u64);
+        std::thread::scope(|s| {
+            s.spawn(move || drop(sent));
+        });
+        // the slot freed on another thread isn't drained yet
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 1);
+        drop(allocator);
+
+        let allocator = AllocatorBuilder::new().leak_policy(LeakPolicy::Ignore).build();
+        std::mem::forget(allocator.alloc(1u64));
+        drop(allocator);
+    }
+
+    #[test]
+    #[cfg_attr(target_family = "wasm", ignore = "no threads on wasm")]
+    fn test_live_count() {
+        let allocator = Allocator::with_capacity(4);
+        allocator.assert_idle(128);
+        // over three pages, then two
+        let mut held: Vec<_> = (0..10).map(|i| allocator.alloc([i as u8; 100])).collect();
+        assert_eq!((allocator.live_count(128), allocator.live_count(100)), (10, 10));
+        assert_eq!((allocator.live_count(64), allocator.live_count(257)), (0, 0));
+        held.drain(..6);
+        assert_eq!(allocator.live_count(128), 4);
+        assert!(!allocator.is_idle(128) && allocator.is_idle(256));
+
+        let sent = allocator.alloc_send([0u8; 100]);
+        std::thread::scope(|s| {
+            s.spawn(move || drop(sent));
+        });
+        drop(held);
+        // freed on another thread, but drained
+        allocator.assert_idle(128);
+        assert_eq!(allocator.pool128.free_count(), 12);
+    }
+
+    #[test]
+    #[cfg_attr(target_family = "wasm", ignore = "panics abort on wasm")]
+    fn test_assert_idle_panics() {
+        let allocator = AllocatorBuilder::new().name("staging").leak_policy(LeakPolicy::Ignore).build();
+        let kept = allocator.alloc([1u8; 100]);
+        std::mem::forget(allocator.alloc([2u8; 100]));
+        allocator.assert_idle(64);
+        let err = panic::catch_unwind(panic::AssertUnwindSafe(|| allocator.assert_idle(128))).unwrap_err();
+        let msg = err.downcast_ref::<String>().unwrap();
+        assert_eq!(msg, "allocator `staging` has 2 live slots of 128 bytes");
+        drop(kept);
+        let err = panic::catch_unwind(panic::AssertUnwindSafe(|| allocator.assert_idle(100))).unwrap_err();
+        assert_eq!(err.downcast_ref::<String>().unwrap(), "allocator `staging` has 1 live slots of 128 bytes");
+    }
+}
@@ -28,127 +28,744 @@
 //! assert_eq!(p.1, 2);
 //! ```
 
+#![feature(allocator_api)]
 #![feature(coerce_unsized)]
+#![feature(fn_traits)]
+#![feature(pin_coerce_unsized_trait)]
+#![feature(tuple_trait)]
+#![feature(unboxed_closures)]
 #![feature(unsize)]
 
+#[cfg(feature = "derive")]
+extern crate aren_alloc_derive;
+#[cfg(feature = "allocator-api2")]
+extern crate allocator_api2;
+#[cfg(feature = "epoch")]
+extern crate crossbeam_epoch;
+#[cfg(loom)]
+extern crate loom;
+#[cfg(feature = "metrics")]
+extern crate metrics;
+#[cfg(all(test, feature = "metrics"))]
+extern crate metrics_util;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
+#[cfg(test)]
+extern crate hashbrown;
+#[cfg(all(test, feature = "allocator-api2"))]
+extern crate stable_hashbrown;
+#[cfg(test)]
+extern crate rayon;
+#[cfg(test)]
+#[macro_use]
+extern crate static_assertions;
+
+use std::alloc::{AllocError, Layout};
+use std::any::Any;
 use std::cell::{Cell, RefCell};
-use std::marker::Unsize;
-use std::ops::CoerceUnsized;
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::iter::FusedIterator;
+use std::marker::{PhantomData, Tuple, Unsize};
+use std::num::NonZeroUsize;
+use std::ops::{CoerceUnsized, Index, IndexMut, Range};
+use std::pin::{Pin, PinCoerceUnsized};
+use std::ptr::NonNull;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+mod frozen;
+pub use frozen::Frozen;
+
+mod stats;
+pub use stats::PageInfo;
+#[cfg(feature = "stats")]
+pub use stats::{ClassCounters, SizeHistogram, Stats};
+
+#[cfg(feature = "stats")]
+mod waste;
+#[cfg(feature = "stats")]
+pub use waste::{ClassWaste, WasteReport};
+
+pub mod per_thread;
+
+pub mod pooled;
+pub use pooled::{PooledString, PooledVec};
+
+mod alloc_api;
+pub use alloc_api::{PoolAlloc, PoolBox};
+
+mod builder;
+pub use builder::{AllocatorBuilder, AllocatorConfig, MissingClass};
+
+mod classes;
+pub use classes::ClassInfo;
+
+mod budget;
+pub use budget::{AllocRequest, ExhaustedHandler, Recovery};
+use budget::Budget;
+
+mod trim;
+use trim::PageMeta;
+
+mod leak;
+pub use leak::LeakPolicy;
+
+#[macro_use]
+mod local;
+pub use local::OwnedPointer;
+
+mod poolable;
+pub use poolable::{assert_poolable, Poolable};
+#[cfg(feature = "derive")]
+pub use aren_alloc_derive::Poolable;
+
+mod small;
+pub use small::SmallPointer;
+
+mod static_alloc;
+pub use static_alloc::StaticAllocator;
+
+mod task;
+pub use task::TaskQueue;
+
+mod typed;
+pub use typed::{TypedPool, TypedPoolIter, TypedPoolIterMut};
+
+mod slab;
+pub use slab::{Handle, Slab, SlabIter, SlabIterMut};
+
+mod vec_pool;
+pub use vec_pool::{PooledStringGuard, PooledVecGuard, StringPool, VecPool};
+
+mod padded;
+use padded::CACHE_LINE;
+
+mod stamp;
+use stamp::Stamp;
+
+mod slot_id;
+pub use slot_id::SlotId;
+
+mod slice;
+pub use slice::SliceBuilder;
+
+mod contiguous;
+mod heap;
+
+mod arena;
+mod pinned;
+
+mod frame;
+pub use frame::{FrameAllocator, FramePointer};
+
+mod group;
+pub use group::{Group, GroupPointer, GroupUsage};
+
+#[macro_use]
+mod text;
+
+mod lockfree;
+mod sync;
+pub use sync::{SyncAllocator, SyncPointer};
+
+mod shared;
+pub use shared::{SharedAllocator, SharedPointer, ThreadCache};
+
+#[cfg(feature = "epoch")]
+mod epoch;
+#[cfg(feature = "epoch")]
+pub use epoch::EpochGuard;
+
+mod rc;
+pub use rc::{CowPointer, RcPointer, WeakPointer};
+
+mod harden;
+
+mod send;
+pub use send::SendPointer;
+use send::RemoteFrees;
+
+#[cfg(feature = "ffi")]
+pub mod ffi;
+
+#[cfg(feature = "debug-introspection")]
+mod dot;
+
+#[cfg(feature = "registry")]
+pub mod registry;
+
+#[cfg(feature = "test-util")]
+mod test_util;
+#[cfg(feature = "test-util")]
+pub use test_util::NoGrowthGuard;
+
+#[cfg(feature = "metrics")]
+mod metrics_support;
+
+#[cfg(feature = "serde")]
+mod serde_support;
+#[cfg(feature = "serde")]
+pub use serde_support::PoolSeed;
 
 #[derive(Copy, Clone)]
 struct Node {
     next: *mut Node,
 }
 
+/// Alignment guaranteed to the slots of a class of at least this many
+/// bytes, the pages being aligned to it too.
+const PAGE_ALIGN: usize = 16;
+
+/// The memory of the slots of a pool, aligned to a cache line, so that
+/// no slot straddles two lines, and the first slot shares none with
+/// other memory.
+struct Page {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+impl Page {
+    /// A page without memory, for pools allocating theirs lazily.
+    const fn empty() -> Page {
+        Page{ ptr: NonNull::dangling(), len: 0 }
+    }
+
+    fn new(len: usize) -> Page {
+        let layout = Layout::from_size_align(len, CACHE_LINE).unwrap();
+        match NonNull::new(unsafe { std::alloc::alloc(layout) }) {
+            Some(ptr) => Page{ ptr, len },
+            None => std::alloc::handle_alloc_error(layout),
+        }
+    }
+
+    #[inline]
+    fn as_ptr(&self) -> *mut u8 {
+        self.ptr.as_ptr()
+    }
+
+    #[inline]
+    fn len(&self) -> usize {
+        self.len
+    }
+}
+
+impl Drop for Page {
+    fn drop(&mut self) {
+        if self.len != 0 {
+            let layout = Layout::from_size_align(self.len, CACHE_LINE).unwrap();
+            unsafe { std::alloc::dealloc(self.ptr.as_ptr(), layout) }
+        }
+    }
+}
+
+/// Panic for a `T` larger than the largest size class.
+#[cold]
+#[track_caller]
+fn too_big<T>() -> ! {
+    panic!("element size too big! `{}` is {} bytes, beyond the largest size class of 256 bytes",
+           std::any::type_name::<T>(), std::mem::size_of::<T>())
+}
+
+/// Panic for a `T` whose class the allocator was built without.
+#[cold]
+#[track_caller]
+fn missing_class<T>() -> ! {
+    panic!("no size class of the allocator holds `{}` of {} bytes",
+           std::any::type_name::<T>(), std::mem::size_of::<T>())
+}
+
+/// Panic unless a `T` fits in a slot of `ele_size` bytes, and is aligned
+/// by it. Slots are aligned to their size, up to the page alignment.
+///
+/// Which slot a `T` goes in is chosen by its size, so only the alignment
+/// is checked in release builds.
+#[inline]
+#[track_caller]
+fn check_slot<T>(ele_size: usize) {
+    let (size, align) = (std::mem::size_of::<T>(), std::mem::align_of::<T>());
+    debug_assert!(size <= ele_size, "`{}` of {} bytes doesn't fit in a slot of {} bytes",
+                  std::any::type_name::<T>(), size, ele_size);
+    let slot_align = std::cmp::min(ele_size, PAGE_ALIGN);
+    assert!(align <= slot_align, "`{}` is aligned to {} bytes, beyond the {} bytes alignment of a slot of {} bytes",
+            std::any::type_name::<T>(), align, slot_align, ele_size);
+}
+
 struct Pool {
-    pool: RefCell<Vec<u8>>,
-    head: Cell<*mut Node>,
+    pool: RefCell<Page>,
+    head: Cell<Option<NonNull<Node>>>,
     ele_size: usize,
+    /// Number of slots of each page.
+    page_slots: usize,
+    /// Position of the page in the chain when it was added, kept when
+    /// pages before it are trimmed, see `SlotId`.
+    page_index: usize,
+    /// The alignment of the blocks of a pool standing for the heap, see
+    /// `heap.rs`, or 0 for a pool of pages.
+    heap_align: usize,
+    /// Whether the slots are in memory owned by someone else, see
+    /// `StaticAllocator`, so the pool never grows.
+    fixed: bool,
     next_pool: RefCell<Option<Box<Pool>>>,
-    tail_pool: Cell<*mut Pool>,
+    /// The last pool of the chain, or `None` for the pool itself, as a
+    /// pointer to itself would be invalidated by moving its box.
+    tail_pool: Cell<Option<NonNull<Pool>>>,
+    remote: RemoteFrees,
+    /// Slots on the free list, not counting those freed on other threads
+    /// but not yet drained.
+    free_slots: Cell<usize>,
+    /// Slots handed out, including those freed on other threads but not
+    /// yet drained.
+    #[cfg(feature = "stats")]
+    live: Cell<usize>,
+    /// Slots handed out so far.
+    #[cfg(feature = "stats")]
+    allocs: Cell<usize>,
+    /// The most slots handed out at once so far.
+    #[cfg(feature = "stats")]
+    peak: Cell<usize>,
+    /// Pages allocated on demand so far, after construction.
+    #[cfg(feature = "stats")]
+    pages_added: Cell<usize>,
+    /// The budget charged for new pages, if the allocator is capped.
+    budget: Option<Rc<Budget>>,
+    /// Operations of the allocator so far, shared by its pools, if the
+    /// occupancy of the pages is tracked for trimming.
+    clock: Option<Rc<Cell<usize>>>,
+    /// Occupancy of the pages of the chain, sorted by address.
+    page_table: RefCell<Vec<PageMeta>>,
+    /// Addresses of the slots handed out pinned, sorted.
+    pinned: RefCell<Vec<usize>>,
+    /// The randomness shuffling the free list, if the allocator is
+    /// hardened.
+    #[cfg(feature = "harden")]
+    rng: Option<Rc<harden::Rng>>,
+    /// The thread the pool is confined to, if any.
+    #[cfg(debug_assertions)]
+    owner: Option<std::thread::ThreadId>,
+    /// Resets of the pool so far, stamped on every `Pointer` to catch
+    /// those dereferenced after their slots were reclaimed.
+    #[cfg(debug_assertions)]
+    epoch: Cell<u64>,
 }
 
+/// Bytes per page of the pools of a default allocator.
+#[cfg(not(target_arch = "wasm32"))]
 const DEFAULT_POOL_SIZE: usize = 4096;
 
+/// Bytes per page of the pools of a default allocator, smaller on wasm32
+/// as every page of linear memory counts.
+#[cfg(target_arch = "wasm32")]
+const DEFAULT_POOL_SIZE: usize = 1024;
+
 impl Pool {
+    /// Construct a pool with pages of `DEFAULT_POOL_SIZE` bytes. On wasm32,
+    /// the first page is only allocated on the first allocation, so
+    /// unused size classes take no memory.
     fn new(ele_size: usize) -> Box<Pool> {
-        debug_assert!(DEFAULT_POOL_SIZE%ele_size==0);
+        debug_assert!(DEFAULT_POOL_SIZE.is_multiple_of(ele_size));
         debug_assert!(ele_size<=DEFAULT_POOL_SIZE);
-        Pool::with_capacity(DEFAULT_POOL_SIZE/ele_size, ele_size)
+        if cfg!(target_arch = "wasm32") {
+            Pool::lazy(DEFAULT_POOL_SIZE/ele_size, ele_size)
+        } else {
+            Pool::with_capacity(DEFAULT_POOL_SIZE/ele_size, ele_size)
+        }
     }
 
     fn with_capacity(num: usize, ele_size: usize) -> Box<Pool> {
+        let p = Pool::lazy(num, ele_size);
+        p.head.set(Some(p.fill()));
+        p
+    }
+
+    /// Construct a pool whose first page is allocated on demand.
+    fn lazy(num: usize, ele_size: usize) -> Box<Pool> {
         debug_assert!(num>0);
         debug_assert!(ele_size>=std::mem::size_of::<Node>());
         debug_assert!(ele_size.is_power_of_two());
 
-        let mut pool: Vec<u8> = Vec::with_capacity(num*ele_size);
-        let head: *mut Node = unsafe {
-            let head = pool.as_mut_ptr();
-            for i in 0..num-1 {
-                let cur = head.offset((i*ele_size) as isize).as_mut().unwrap();
-                let next = head.offset(((i+1)*ele_size) as isize);
-                let cur: *mut Node = std::mem::transmute(cur);
-                cur.as_mut().unwrap().next = std::mem::transmute(next);
-            }
-            let tail = head.offset(((num-1)*ele_size) as isize);
-            let tail: *mut Node = std::mem::transmute(tail);
-            tail.as_mut().unwrap().next = std::ptr::null_mut();
-            std::mem::transmute(head)
-        };
-        
-        let mut p = Box::new(Pool{
-            pool: RefCell::new(pool),
-            head: Cell::new(head),
-            ele_size: ele_size,
+        Box::new(Pool{
+            pool: RefCell::new(Page::empty()),
+            head: Cell::new(None),
+            ele_size,
+            page_slots: num,
+            page_index: 0,
+            heap_align: 0,
+            fixed: false,
             next_pool: RefCell::new(None),
-            tail_pool: Cell::new(std::ptr::null_mut()),
-        });
-        let pmut = <Box<_> as std::ops::DerefMut>::deref_mut(&mut p) as *mut Pool;
-        p.tail_pool.set(pmut);
-        p
+            tail_pool: Cell::new(None),
+            remote: RemoteFrees::new(),
+            free_slots: Cell::new(0),
+            #[cfg(feature = "stats")]
+            live: Cell::new(0),
+            #[cfg(feature = "stats")]
+            allocs: Cell::new(0),
+            #[cfg(feature = "stats")]
+            peak: Cell::new(0),
+            #[cfg(feature = "stats")]
+            pages_added: Cell::new(0),
+            budget: None,
+            clock: None,
+            page_table: RefCell::new(Vec::new()),
+            pinned: RefCell::new(Vec::new()),
+            #[cfg(feature = "harden")]
+            rng: None,
+            #[cfg(debug_assertions)]
+            owner: Some(std::thread::current().id()),
+            #[cfg(debug_assertions)]
+            epoch: Cell::new(0),
+        })
+    }
+
+    /// Allocate the page of the pool, return the head of its free list.
+    fn fill(&self) -> NonNull<Node> {
+        let (num, ele_size) = (self.page_slots, self.ele_size);
+        let mut pool = self.pool.borrow_mut();
+        debug_assert!(pool.len() == 0);
+        *pool = Page::new(num*ele_size);
+        self.free_slots.set(self.free_slots.get() + num);
+        unsafe { self.link(&pool, std::ptr::null_mut()) }
+    }
+
+    /// Link every slot of `page` in address order, the last one to
+    /// `next`, return the first one.
+    ///
+    /// # Safety
+    ///
+    /// No slot of `page` may be in use, nor on any list.
+    unsafe fn link(&self, page: &Page, next: *mut Node) -> NonNull<Node> {
+        let (num, ele_size) = (page.len() / self.ele_size, self.ele_size);
+        let base = page.as_ptr();
+        #[cfg(feature = "harden")]
+        if let Some(ref rng) = self.rng {
+            return self.link_shuffled(base, num, next, rng);
+        }
+        for i in 0..num-1 {
+            let cur = base.add(i*ele_size).cast::<Node>();
+            (*cur).next = base.add((i+1)*ele_size).cast();
+        }
+        (*base.add((num-1)*ele_size).cast::<Node>()).next = next;
+        page.ptr.cast()
+    }
+
+    /// Take a slot for a `T`, left uninitialized: it must be written
+    /// through its raw pointer, never through the `Pointer`, see `put`.
+    #[track_caller]
+    fn alloc<T>(&self) -> Pointer<'_, T> {
+        check_slot::<T>(self.ele_size);
+        unsafe { Pointer::from_node(self, self.pop() as *mut T) }
+    }
+
+    /// Move `value` into a slot, written before any `Pointer` to the
+    /// slot exists, as a `&mut T` to the uninitialized slot may not.
+    #[inline]
+    #[track_caller]
+    fn put<T>(&self, value: T) -> Pointer<'_, T> {
+        check_slot::<T>(self.ele_size);
+        let node = self.pop() as *mut T;
+        unsafe {
+            std::ptr::write(node, value);
+            Pointer::from_node(self, node)
+        }
+    }
+
+    /// Take a slot off the free list, growing the pool if needed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pool needs another page, but its budget is exhausted.
+    fn pop(&self) -> *mut Node {
+        match self.try_pop() {
+            Some(node) => node,
+            None => panic!("allocation budget exhausted"),
+        }
     }
 
-    fn alloc<T>(&self) -> Pointer<T> {
-        debug_assert!(std::mem::size_of::<T>() <= self.ele_size);
-        debug_assert!(self.ele_size%std::mem::align_of::<T>() == 0);
+    /// Take a slot off the free list, growing the pool if its budget
+    /// allows, or return `None`.
+    fn try_pop(&self) -> Option<*mut Node> {
+        self.check_owner();
         // if std::mem::size_of::<T>() <= 16 || self.head.get().is_null() {
-        if self.head.get().is_null() {
-            self.extend();
-        }
-        debug_assert!(!self.head.get().is_null());
-        let lasthead = self.head.get();
-        let nexthead = unsafe {lasthead.as_mut().unwrap().next};
-        self.head.set(nexthead);
-        unsafe {Pointer{
-            pool: self, node: std::mem::transmute(lasthead)
-        }}
+        if self.head.get().is_none() {
+            if self.heap_align != 0 {
+                return self.heap_alloc();
+            }
+            self.drain_remote();
+            if !self.extend() {
+                return None;
+            }
+        }
+        let lasthead = self.head.get().unwrap();
+        let nexthead = unsafe { lasthead.as_ref().next };
+        self.head.set(NonNull::new(nexthead));
+        self.free_slots.set(self.free_slots.get() - 1);
+        self.track_alloc(lasthead.as_ptr());
+        self.count_alloc();
+        Some(lasthead.as_ptr())
     }
 
-    fn extend(&self) {
-        if self.head.get().is_null() { unsafe {
-            let tail = self.tail_pool.get().as_mut().unwrap();
-            debug_assert!(tail.next_pool.borrow().is_none());
-            let num = self.pool.borrow().capacity() / self.ele_size;
-            let mut next_pool = Pool::with_capacity(num, self.ele_size);
-            {
-                let newtail = <Box<_> as std::ops::DerefMut>::deref_mut(&mut next_pool);
-                self.head.set(newtail.head.get());
-                self.tail_pool.set(newtail);
+    /// Make sure the free list isn't empty, allocating a page if needed.
+    /// Return `false` if the page would exceed the budget, or the pool
+    /// is fixed.
+    fn extend(&self) -> bool {
+        if self.head.get().is_none() { unsafe {
+            if self.fixed {
+                return false;
+            }
+            if let Some(ref budget) = self.budget {
+                if !budget.charge(self.page_slots * self.ele_size) {
+                    return false;
+                }
             }
-            *tail.next_pool.get_mut() = Some(next_pool);
+            if self.pool.borrow().len() == 0 {
+                self.head.set(Some(self.fill()));
+                self.track_page(&self.pool.borrow());
+                self.count_page();
+                return true;
+            }
+            // grow by one page at a time, never geometrically
+            let tail = match self.tail_pool.get() {
+                None => self,
+                Some(tail) => tail.as_ref(),
+            };
+            debug_assert!(tail.next_pool.borrow().is_none());
+            let mut newpool = Pool::lazy(self.page_slots, self.ele_size);
+            newpool.page_index = tail.page_index + 1;
+            *newpool.pool.get_mut() = Page::new(self.page_slots * self.ele_size);
+            *tail.next_pool.borrow_mut() = Some(newpool);
+            // point into the box only once it's in place, as moving it
+            // would invalidate the pointer
+            let next_pool = tail.next_pool.borrow();
+            let newtail: &Pool = next_pool.as_deref().unwrap();
+            self.track_page(&newtail.pool.borrow());
+            self.count_page();
+            // linked by the pool heading the chain, hardened or not
+            self.head.set(Some(self.link(&newtail.pool.borrow(), std::ptr::null_mut())));
+            self.free_slots.set(self.free_slots.get() + self.page_slots);
+            self.tail_pool.set(Some(NonNull::from(newtail)));
         }}
+        true
     }
 
     unsafe fn recycle(&self, node: *mut Node) {
         debug_assert!(!node.is_null());
-        let oldhead = self.head.get();
-        let noderef = node.as_mut().unwrap();
-        noderef.next = oldhead;
-        self.head.set(node);
+        if self.heap_align != 0 {
+            return self.heap_free(node);
+        }
+        self.check_owner();
+        match self.push_point() {
+            None => {
+                (*node).next = self.head.get().map_or(std::ptr::null_mut(), NonNull::as_ptr);
+                self.head.set(Some(NonNull::new_unchecked(node)));
+            }
+            Some(prev) => {
+                (*node).next = prev.as_ref().next;
+                (*prev.as_ptr()).next = node;
+            }
+        }
+        self.free_slots.set(self.free_slots.get() + 1);
+        self.count_free(1);
+        self.track_free(node);
+        self.unpin(node as usize);
+    }
+
+    /// Let the pool be used from any thread, for pools guarded by a lock.
+    #[inline]
+    fn disown(&mut self) {
+        #[cfg(debug_assertions)]
+        {
+            self.owner = None;
+        }
+    }
+
+    /// Panic if the pool is confined to a thread other than the current one.
+    #[cfg(debug_assertions)]
+    fn check_owner(&self) {
+        if let Some(owner) = self.owner {
+            let current = std::thread::current().id();
+            if owner != current {
+                panic!("pool owned by thread {:?} used on thread {:?}", owner, current);
+            }
+        }
+    }
+
+    #[cfg(not(debug_assertions))]
+    #[inline(always)]
+    fn check_owner(&self) { }
+
+    /// Count the free slots by walking the free list.
+    #[cfg(test)]
+    fn free_count(&self) -> usize {
+        let mut count = 0;
+        let mut node = self.head.get();
+        while let Some(cur) = node {
+            count += 1;
+            node = NonNull::new(unsafe { cur.as_ref().next });
+        }
+        count
+    }
+
+    /// Iterate over the pool and the pools chained after it, each of
+    /// which holds one page.
+    fn chain(&self) -> impl Iterator<Item=&Pool> {
+        // the chain is only ever appended to, never while iterated
+        std::iter::successors(Some(self), |p| unsafe { (*p.next_pool.as_ptr()).as_deref() })
+    }
+
+    /// Whether `ptr` points to the start of a slot of the pool.
+    fn owns(&self, ptr: *const u8) -> bool {
+        self.page_of(ptr as usize).is_some_and(|page| (ptr as usize - page.start).is_multiple_of(self.ele_size))
+    }
+
+    /// Count the slots of all pages in the chain.
+    #[cfg(any(feature = "stats", test))]
+    fn slot_count(&self) -> usize {
+        self.chain().map(|p| p.pool.borrow().len() / p.ele_size).sum()
     }
 }
 
-/// A pointer to `T`, when dropped, the underlying memory
-/// would be recycled by the allocator.
+/// A pointer to `T`, when dropped, `T` would be dropped in place,
+/// then the underlying memory would be recycled by the allocator.
+///
+/// Like a `Box`, it owns its `T`: it is covariant in `T`, and the drop
+/// checker requires whatever `T` borrows to outlive it.
+///
+/// ```rust
+/// use aren_alloc::{Allocator, Pointer};
+/// let allocator = Allocator::new();
+/// let ptr: Pointer<&'static str> = allocator.alloc("static");
+/// let local = String::from("local");
+/// // a `Pointer<&'static str>` is a `Pointer<&'short str>`
+/// let mut ptrs: Vec<Pointer<&str>> = vec![ptr];
+/// ptrs.push(allocator.alloc(&local));
+/// ```
+///
+/// ```rust,compile_fail
+/// use aren_alloc::{Allocator, Pointer};
+/// let allocator = Allocator::new();
+/// let ptr: Pointer<&u32>;
+/// {
+///     let short = 1u32;
+///     ptr = allocator.alloc(&short);
+/// }
+/// drop(ptr);
+/// ```
 pub struct Pointer<'a, T: ?Sized> {
     pool: &'a Pool,
-    node: *mut T,
+    node: NonNull<T>,
+    /// Owns a `T`, for the drop checker.
+    marker: PhantomData<T>,
+    /// What debug builds remember of the allocation.
+    stamp: Stamp,
 }
 
+// Moving the pointer never moves the pointee, as with `Box`.
+impl<'a, T: ?Sized> Unpin for Pointer<'a, T> { }
+
 impl<'a, T, U> CoerceUnsized<Pointer<'a, T>> for Pointer<'a, U>
     where U: Unsize<T> + ?Sized,
           T: ?Sized,
 { }
 
+// `Pointer` always derefs to the same slot, so a `Pin<Pointer<U>>`
+// can be coerced into a `Pin<Pointer<T>>` without moving the pointee.
+unsafe impl<'a, T: ?Sized> PinCoerceUnsized for Pointer<'a, T> { }
+
 impl<'a, T: ?Sized> Pointer<'a, T> {
+    /// Wrap `node`, a slot of `pool`.
+    #[inline]
+    unsafe fn from_node(pool: &'a Pool, node: *mut T) -> Pointer<'a, T> {
+        Pointer::with_stamp(pool, node, Stamp::new(pool))
+    }
+
+    /// Wrap `node`, a slot of `pool` allocated as stamped by `stamp`.
+    #[inline]
+    unsafe fn with_stamp(pool: &'a Pool, node: *mut T, stamp: Stamp) -> Pointer<'a, T> {
+        Pointer{ pool, node: NonNull::new_unchecked(node), marker: PhantomData, stamp }
+    }
+
+    /// Wrap the slot of `ptr` as `node`, keeping the stamp of `ptr`.
+    #[inline]
+    unsafe fn retype<U: ?Sized>(ptr: Self, node: *mut U) -> Pointer<'a, U> {
+        let ret = Pointer::with_stamp(ptr.pool, node, ptr.stamp);
+        std::mem::forget(ptr);
+        ret
+    }
+
+    /// Panic if the slot of `ptr` was reclaimed by a reset of its
+    /// allocator since it was handed out.
+    #[inline]
+    #[track_caller]
+    fn check_epoch(ptr: &Self) {
+        ptr.stamp.check_epoch(ptr.pool);
+    }
+
+    /// The sequence number of the allocation of `this` among those of
+    /// its thread, counting from 0, or `None` in release builds.
+    /// This is an associated function so that
+    /// `T`'s methods won't be shadowed.
+    ///
+    /// Unlike addresses, sequence numbers are never reused, and stay
+    /// the same across runs of a deterministic program, so they tell
+    /// apart the values a slot held in turn. Conversions of `this`,
+    /// e.g. unsizing or `map`, keep its number.
+    ///
+    /// ```rust
+    /// use aren_alloc::{Allocator, Pointer};
+    /// let allocator = Allocator::new();
+    /// let a = allocator.alloc(1u8);
+    /// let b = allocator.alloc([2u64; 4]);
+    /// if let (Some(a), Some(b)) = (Pointer::debug_seq(&a), Pointer::debug_seq(&b)) {
+    ///     assert!(a < b);
+    /// }
+    /// ```
+    #[inline]
+    pub fn debug_seq(this: &Self) -> Option<u64> {
+        this.stamp.seq()
+    }
+
+    /// The address of the slot of `this`, e.g. to log it.
+    /// This is an associated function so that
+    /// `T`'s methods won't be shadowed.
+    #[inline]
+    pub fn addr(this: &Self) -> NonZeroUsize {
+        this.node.addr()
+    }
+
+    /// Where the slot of `this` is in its allocator, to tell it apart in
+    /// logs, or `None` if it isn't in a page, as for a block on the heap
+    /// of `alloc_or_heap`, or a slot of a `StaticAllocator`.
+    /// This is an associated function so that
+    /// `T`'s methods won't be shadowed.
+    ///
+    /// Ids are unique among live pointers, and kept by conversions of
+    /// `this`, but a slot is allocated again with the same id once it's
+    /// recycled. `debug_seq` tells its uses apart.
+    ///
+    /// ```rust
+    /// use aren_alloc::{Allocator, Pointer, SlotId};
+    /// let allocator = Allocator::new();
+    /// let ptr = allocator.alloc([0u8; 100]);
+    /// assert_eq!(Pointer::id(&ptr), Some(SlotId{ class: 4, page: 0, slot: 0 }));
+    /// ```
+    pub fn id(this: &Self) -> Option<SlotId> {
+        this.pool.slot_id(Pointer::addr(this).get())
+    }
+
     /// Borrow `ptr` as a reference.
     /// This is an associated function so that
     /// `T`'s methods won't be shadowed.
     #[inline]
+    #[track_caller]
+    #[allow(clippy::should_implement_trait)]
     pub fn as_ref(ptr: &Self) -> &T {
+        Pointer::check_epoch(ptr);
         unsafe {
-            &*ptr.node
+            ptr.node.as_ref()
         }
     }
 
@@ -156,267 +773,2060 @@ impl<'a, T: ?Sized> Pointer<'a, T> {
     /// This is an associated function so that
     /// `T`'s methods won't be shadowed.
     #[inline]
+    #[track_caller]
+    #[allow(clippy::should_implement_trait)]
     pub fn as_mut(ptr: &mut Self) -> &mut T {
+        Pointer::check_epoch(ptr);
         unsafe {
-            &mut *ptr.node
+            ptr.node.as_mut()
         }
     }
 
-    // /// Borrow `ptr` as a mutable reference,
-    // /// return a typed erased pointer with it.
-    // ///
-    // /// This is useful when casting the `&mut T` to some trait objects
-    // #[inline]
-    // pub fn erase_borrow_mut(ptr: Self) -> (&'a mut T, Pointer<'a, ()>) {
-    //     debug_assert!(!ptr.node.is_null());
-    //     unsafe {
-    //         let tptr: *mut T = std::mem::transmute(ptr.node);
-    //         (tptr.as_mut().unwrap(), Pointer{pool: ptr.pool, node: ptr.node, _phantom: Default::default()})
-    //     }
-    // }
-}
-
-impl<'a, T:?Sized> std::ops::Deref for Pointer<'a, T> {
-    type Target = T;
+    /// Convert `ptr` into a `Pin<Pointer<T>>`.
+    ///
+    /// The pointee of a `Pointer` is never moved until it is dropped,
+    /// just like a `Box`. Should the pinned pointer be leaked instead,
+    /// its slot is never handed out again, not even after a `reset`,
+    /// and its page is leaked too when the allocator is dropped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `ptr` is of a `StaticAllocator`, whose slots move
+    /// along with it.
     #[inline]
-    fn deref(&self) -> &T {
-        Pointer::as_ref(self)
+    pub fn into_pin(ptr: Self) -> Pin<Self> {
+        let slots = std::mem::size_of_val(Pointer::as_ref(&ptr)).div_ceil(ptr.pool.ele_size).max(1);
+        ptr.pool.pin(ptr.node.as_ptr() as *mut u8 as usize, slots);
+        unsafe {
+            Pin::new_unchecked(ptr)
+        }
     }
-}
 
-impl<'a, T:?Sized> std::ops::DerefMut for Pointer<'a, T> {
+    /// Reinterpret the slot of `ptr` as holding a `U`, without copying.
+    ///
+    /// The pool is preserved, so the slot would be recycled into the
+    /// same pool as before when the returned pointer is dropped.
+    ///
+    /// # Safety
+    ///
+    /// The bytes in the slot must form a valid `U`. `U` must fit in
+    /// the slot, both in size and alignment. The size is checked in
+    /// debug builds, the alignment always. `T`'s destructor won't run;
+    /// `U`'s would run instead.
     #[inline]
-    fn deref_mut(&mut self) -> &mut T {
-        Pointer::as_mut(self)
+    #[track_caller]
+    pub unsafe fn cast<U>(ptr: Self) -> Pointer<'a, U> {
+        check_slot::<U>(ptr.pool.ele_size);
+        let node = ptr.node.as_ptr() as *mut U;
+        Pointer::retype(ptr, node)
     }
-}
 
-impl<'a, T:?Sized> Drop for Pointer<'a, T> {
-    fn drop(&mut self) {
+    /// Allocate `value` from the pool `this` was allocated from, without
+    /// a reference to its `Allocator`. The slot is of the class of `T`,
+    /// even if `U` would fit a smaller one.
+    /// This is an associated function so that
+    /// `T`'s methods won't be shadowed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `U` doesn't fit in a slot of the class of `T`, or if
+    /// the pool has no free slot and can't grow, e.g. because it's of a
+    /// `StaticAllocator` or its budget is exhausted.
+    #[inline]
+    #[track_caller]
+    pub fn sibling<U: Copy>(this: &Self, value: U) -> Pointer<'a, U> {
+        let pool = this.pool;
+        if std::mem::size_of::<U>() > pool.ele_size {
+            panic!("`{}` of {} bytes doesn't fit in a slot of {} bytes",
+                   std::any::type_name::<U>(), std::mem::size_of::<U>(), pool.ele_size);
+        }
+        check_slot::<U>(pool.ele_size);
+        let node = match pool.try_pop() {
+            Some(node) => node as *mut U,
+            None => panic!("no free slot of {} bytes left, and the pool can't grow", pool.ele_size),
+        };
         unsafe {
-            let node: *mut Node = std::mem::transmute_copy(&self.node);
-            self.pool.recycle(node);
+            std::ptr::write(node, value);
+            Pointer::from_node(pool, node)
         }
     }
-}       
 
-/// Allows allocation
-pub struct Allocator {
-    pool8: Box<Pool>,
-    pool16: Box<Pool>,
-    pool32: Box<Pool>,
-    pool64: Box<Pool>,
-    pool128: Box<Pool>,
-    pool256: Box<Pool>,
+    /// Erase the type of `ptr`, so that pointers to different types
+    /// could be kept in one homogeneous container.
+    ///
+    /// `T`'s destructor is not run unless the type is restored with
+    /// `RawPointer::assume_type`, see `RawPointer` for details.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `ptr` spans several slots, see `Allocator::alloc_contiguous`.
+    #[inline]
+    #[track_caller]
+    pub fn erase(ptr: Self) -> RawPointer<'a> {
+        assert!(std::mem::size_of_val(unsafe { ptr.node.as_ref() }) <= ptr.pool.ele_size,
+                "can't erase a block of several slots");
+        let ret = RawPointer{
+            pool: ptr.pool,
+            node: ptr.node.cast(),
+            stamp: ptr.stamp,
+        };
+        std::mem::forget(ptr);
+        ret
+    }
 }
 
-impl Allocator {
-    /// Construct a new allocator with default page capacity.
-    pub fn new() -> Allocator {
-        Allocator{
-            pool8: Pool::new(8),
-            pool16: Pool::new(16),
-            pool32: Pool::new(32),
-            pool64: Pool::new(64),
-            pool128: Pool::new(128),
-            pool256: Pool::new(256),
+impl<'a, T> Pointer<'a, T> {
+    /// Transform the value of `this` with `f`, moving the result into
+    /// the same slot, so that no slot is recycled nor allocated.
+    /// This is an associated function so that
+    /// `T`'s methods won't be shadowed.
+    ///
+    /// The slot is recycled if `f` panics.
+    ///
+    /// ```rust
+    /// use aren_alloc::{Allocator, Pointer};
+    /// let allocator = Allocator::new();
+    /// let celsius = allocator.alloc(21.5f32);
+    /// let fahrenheit = Pointer::map(celsius, |c| c as f64 * 1.8 + 32.0);
+    /// assert_eq!(*fahrenheit, 70.7);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if `U` doesn't fit in a slot of the class of `T`, see
+    /// `map_in` for mapping into another class.
+    #[inline]
+    #[track_caller]
+    pub fn map<U, F: FnOnce(T) -> U>(this: Self, f: F) -> Pointer<'a, U> {
+        let pool = this.pool;
+        if std::mem::size_of::<U>() > pool.ele_size {
+            panic!("`{}` of {} bytes doesn't fit in a slot of {} bytes",
+                   std::any::type_name::<U>(), std::mem::size_of::<U>(), pool.ele_size);
+        }
+        check_slot::<U>(pool.ele_size);
+        Pointer::check_epoch(&this);
+        let (node, stamp) = (this.node.as_ptr(), this.stamp);
+        std::mem::forget(this);
+        // recycles the slot should `f` panic
+        let slot = SlotGuard::new(pool, node as *mut Node);
+        let value = f(unsafe { std::ptr::read(node) });
+        slot.disarm();
+        unsafe {
+            std::ptr::write(node as *mut U, value);
+            Pointer::with_stamp(pool, node as *mut U, stamp)
         }
     }
 
-    /// Construct a new allocator with `cap`acity per inner page
-    pub fn with_capacity(cap: usize) -> Allocator {
-        Allocator{
-            pool8: Pool::with_capacity(cap, 8),
-            pool16: Pool::with_capacity(cap, 16),
-            pool32: Pool::with_capacity(cap, 32),
-            pool64: Pool::with_capacity(cap, 64),
-            pool128: Pool::with_capacity(cap, 128),
-            pool256: Pool::with_capacity(cap, 256),
+    /// Transform the value of `this` with `f`, moving the result into a
+    /// slot of the class of `U` from `allocator`.
+    /// This is an associated function so that
+    /// `T`'s methods won't be shadowed.
+    ///
+    /// If `U` is of the class of `T`, this is `map`, reusing the slot.
+    /// Otherwise the slot of `this` is recycled before `f` is called,
+    /// and another one allocated for the result.
+    ///
+    /// ```rust
+    /// use aren_alloc::{Allocator, Pointer};
+    /// let allocator = Allocator::new();
+    /// let id = allocator.alloc(7u32);
+    /// let entry = Pointer::map_in(id, &allocator, |id| (id, [0u64; 4]));
+    /// assert_eq!(entry.0, 7);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if `allocator` has no size class for `U`, or its budget is
+    /// exhausted, and its handler couldn't recover.
+    #[inline]
+    #[track_caller]
+    pub fn map_in<U, F: FnOnce(T) -> U>(this: Self, allocator: &'a Allocator, f: F) -> Pointer<'a, U> {
+        if std::ptr::eq(allocator.pool_for::<U>(), this.pool) {
+            return Pointer::map(this, f);
         }
+        Pointer::check_epoch(&this);
+        let value = unsafe { std::ptr::read(this.node.as_ptr()) };
+        drop(SlotGuard::new(this.pool, this.node.as_ptr() as *mut Node));
+        std::mem::forget(this);
+        allocator.put(f(value))
     }
+}
 
-    /// Allocate an instance of `T` with value `elem`,
-    /// return the allocated pointer.
-    /// `size_of::<T>()` should be le to 256 bytes.
+impl<'a> Pointer<'a, dyn Any> {
+    /// Attempt to downcast `ptr` to a concrete type.
+    ///
+    /// On failure the original pointer is handed back untouched.
     #[inline]
-    pub fn alloc<T: Copy>(&self, elem: T) -> Pointer<T> {
-        let ele_size = std::mem::size_of::<T>();
-        let mut ret = if ele_size <= 8 {
-            self.pool8.alloc()
-        } else if ele_size <= 16 {
-            self.pool16.alloc()
-        } else if ele_size <= 32 {
-            self.pool32.alloc()
-        } else if ele_size <= 64 {
-            self.pool64.alloc()
-        } else if ele_size <= 128 {
-            self.pool128.alloc()
-        } else if ele_size <= 256 {
-            self.pool256.alloc()
+    pub fn downcast<T: Any>(ptr: Self) -> Result<Pointer<'a, T>, Self> {
+        if ptr.is::<T>() {
+            let node = ptr.node.as_ptr() as *mut T;
+            Ok(unsafe { Pointer::retype(ptr, node) })
         } else {
-            panic!("element size too big!");
-        };
+            Err(ptr)
+        }
+    }
 
-        *ret = elem;
-        ret
+    /// Borrow the pointee of `ptr` as a `T` if it is of that type.
+    /// This is an associated function so that
+    /// `dyn Any`'s methods won't be shadowed.
+    #[inline]
+    pub fn downcast_ref<T: Any>(ptr: &Self) -> Option<&T> {
+        Pointer::as_ref(ptr).downcast_ref()
     }
 
-    /// Allocate an instance of `T` with default value,
-    /// return the allocated pointer.
-    /// `size_of::<T>()` should be le to 256 bytes.
+    /// Mutably borrow the pointee of `ptr` as a `T` if it is of that type.
+    /// This is an associated function so that
+    /// `dyn Any`'s methods won't be shadowed.
     #[inline]
-    pub fn alloc_default<T: Copy+Default>(&self) -> Pointer<T> {
-        self.alloc(Default::default())
+    pub fn downcast_mut<T: Any>(ptr: &mut Self) -> Option<&mut T> {
+        Pointer::as_mut(ptr).downcast_mut()
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[derive(Copy, Clone, Eq, PartialEq, Debug)]
-    struct Byte128 {
-        val: [u64; 16]
+impl<'a, F: ?Sized> Pointer<'a, F> {
+    /// Call the pointee of `this`, typically a `dyn FnOnce`, consuming it.
+    /// This is an associated function so that
+    /// `F`'s methods won't be shadowed.
+    ///
+    /// The closure is moved out and called through a `Box` borrowing
+    /// the slot, so the slot is recycled exactly once, whether the
+    /// closure returns or panics.
+    #[inline]
+    pub fn call_once<Args: Tuple>(this: Self, args: Args) -> F::Output
+        where F: FnOnce<Args>,
+    {
+        let slot = SlotGuard::new(this.pool, this.node.as_ptr() as *mut Node);
+        let boxed = unsafe { Box::from_raw_in(this.node.as_ptr(), slot) };
+        std::mem::forget(this);
+        FnOnce::call_once(boxed, args)
     }
+}
 
-    impl Byte128 {
-        fn new(v: u64) -> Byte128 {
-            Byte128{ val: [v; 16] }
-        }
-    }
+/// A slot taken off the free list, recycled when dropped unless
+/// disarmed, so that a slot held while user code runs, a closure, an
+/// iterator or a formatting trait, is recycled should that code panic.
+///
+/// Whatever the slot holds isn't dropped: the values in flight are owned
+/// elsewhere, or `Copy`.
+///
+/// It also lends a slot to a `Box`, recycling it when the box is done
+/// with it. The slot is recycled on drop rather than on `deallocate`,
+/// as the latter isn't called for zero sized pointees.
+struct SlotGuard<'a> {
+    pool: &'a Pool,
+    node: *mut Node,
+}
 
-    impl Default for Byte128 {
-        fn default() -> Byte128 {
-            Byte128::new(0)
-        }
+impl<'a> SlotGuard<'a> {
+    /// Guard `node`, a slot of `pool` handed out to no one else.
+    #[inline]
+    fn new(pool: &'a Pool, node: *mut Node) -> SlotGuard<'a> {
+        SlotGuard{ pool, node }
     }
 
-    #[derive(Copy, Clone, Eq, PartialEq, Debug)]
-    struct Byte15 {
-        val: [u8; 15]
+    /// Keep the slot once it's fully initialized, return it.
+    #[inline]
+    fn disarm(self) -> *mut Node {
+        let node = self.node;
+        std::mem::forget(self);
+        node
     }
+}
 
-    impl Byte15 {
-        fn new(v: u8) -> Byte15 {
-            Byte15{ val: [v; 15] }
-        }
+unsafe impl<'a> std::alloc::Allocator for SlotGuard<'a> {
+    fn allocate(&self, _layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        Err(AllocError)
     }
 
-    impl Default for Byte15 {
-        fn default() -> Byte15 {
-            Byte15::new(0)
-        }
-    }
+    unsafe fn deallocate(&self, _ptr: NonNull<u8>, _layout: Layout) { }
+}
+
+impl<'a> Drop for SlotGuard<'a> {
+    fn drop(&mut self) {
+        unsafe { self.pool.recycle(self.node) }
+    }
+}
+
+impl<'a, T:?Sized> std::ops::Deref for Pointer<'a, T> {
+    type Target = T;
+    #[inline]
+    #[track_caller]
+    fn deref(&self) -> &T {
+        Pointer::as_ref(self)
+    }
+}
+
+impl<'a, T:?Sized> std::ops::DerefMut for Pointer<'a, T> {
+    #[inline]
+    #[track_caller]
+    fn deref_mut(&mut self) -> &mut T {
+        Pointer::as_mut(self)
+    }
+}
+
+impl<'a, T:?Sized> AsRef<T> for Pointer<'a, T> {
+    #[inline]
+    fn as_ref(&self) -> &T {
+        Pointer::as_ref(self)
+    }
+}
+
+impl<'a, T:?Sized> AsMut<T> for Pointer<'a, T> {
+    #[inline]
+    fn as_mut(&mut self) -> &mut T {
+        Pointer::as_mut(self)
+    }
+}
+
+impl<'a, T:?Sized> std::borrow::Borrow<T> for Pointer<'a, T> {
+    #[inline]
+    fn borrow(&self) -> &T {
+        Pointer::as_ref(self)
+    }
+}
+
+impl<'a, T:?Sized> std::borrow::BorrowMut<T> for Pointer<'a, T> {
+    #[inline]
+    fn borrow_mut(&mut self) -> &mut T {
+        Pointer::as_mut(self)
+    }
+}
+
+impl<'a, T:?Sized + fmt::Debug> fmt::Debug for Pointer<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(Pointer::as_ref(self), f)
+    }
+}
+
+impl<'a, T:?Sized + fmt::Display> fmt::Display for Pointer<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(Pointer::as_ref(self), f)
+    }
+}
+
+impl<'a, T:?Sized + Error> Error for Pointer<'a, T> {
+    #[inline]
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Pointer::as_ref(self).source()
+    }
+}
+
+impl<'a, T: Error + 'a> From<Pointer<'a, T>> for Pointer<'a, dyn Error + 'a> {
+    /// Erase the concrete error type, so that `?` could be used
+    /// in functions returning `Result<_, Pointer<dyn Error>>`.
+    #[inline]
+    fn from(ptr: Pointer<'a, T>) -> Self {
+        ptr
+    }
+}
+
+impl<'a, T> Pointer<'a, [T]> {
+    /// Number of elements of the slice of `ptr`.
+    /// This is an associated function so that
+    /// `[T]`'s methods won't be shadowed.
+    #[inline]
+    pub fn len(ptr: &Self) -> usize {
+        Pointer::as_ref(ptr).len()
+    }
+
+    /// Whether the slice of `ptr` is empty.
+    /// This is an associated function so that
+    /// `[T]`'s methods won't be shadowed.
+    #[inline]
+    pub fn is_empty(ptr: &Self) -> bool {
+        Pointer::as_ref(ptr).is_empty()
+    }
+}
+
+impl<'a, T, const N: usize> Pointer<'a, [T; N]> {
+    /// Convert `this` into a pointer to a slice of its `N` elements,
+    /// without relying on the unsized coercion.
+    /// This is an associated function so that
+    /// `[T; N]`'s methods won't be shadowed.
+    ///
+    /// The slot is still recycled into the class of `[T; N]`.
+    #[inline]
+    pub fn into_slice(this: Self) -> Pointer<'a, [T]> {
+        let node = std::ptr::slice_from_raw_parts_mut(this.node.as_ptr() as *mut T, N);
+        unsafe { Pointer::retype(this, node) }
+    }
+}
+
+// Spelled out rather than left to deref, for code generic over `Index`.
+impl<'a, T: ?Sized + Index<I>, I> Index<I> for Pointer<'a, T> {
+    type Output = T::Output;
+    #[inline]
+    fn index(&self, index: I) -> &T::Output {
+        &Pointer::as_ref(self)[index]
+    }
+}
+
+impl<'a, T: ?Sized + IndexMut<I>, I> IndexMut<I> for Pointer<'a, T> {
+    #[inline]
+    fn index_mut(&mut self, index: I) -> &mut T::Output {
+        &mut Pointer::as_mut(self)[index]
+    }
+}
+
+// `&mut Pointer<[T]>` can't be `IntoIterator`, as it would be an
+// `Iterator` too should `[T]` ever be one, so use `iter_mut()`.
+impl<'a, 'b, T> IntoIterator for &'b Pointer<'a, [T]> {
+    type Item = &'b T;
+    type IntoIter = std::slice::Iter<'b, T>;
+    #[inline]
+    fn into_iter(self) -> std::slice::Iter<'b, T> {
+        Pointer::as_ref(self).iter()
+    }
+}
+
+impl<'a, T, const N: usize> From<Pointer<'a, [T; N]>> for Pointer<'a, [T]> {
+    /// Forget the length of the array in the type. The slot is still
+    /// recycled into the class of `[T; N]`.
+    #[inline]
+    fn from(ptr: Pointer<'a, [T; N]>) -> Self {
+        Pointer::into_slice(ptr)
+    }
+}
+
+impl<'a, I: Iterator + ?Sized> Iterator for Pointer<'a, I> {
+    type Item = I::Item;
+
+    #[inline]
+    fn next(&mut self) -> Option<I::Item> {
+        Pointer::as_mut(self).next()
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        Pointer::as_ref(self).size_hint()
+    }
+
+    #[inline]
+    fn nth(&mut self, n: usize) -> Option<I::Item> {
+        Pointer::as_mut(self).nth(n)
+    }
+}
+
+impl<'a, I: DoubleEndedIterator + ?Sized> DoubleEndedIterator for Pointer<'a, I> {
+    #[inline]
+    fn next_back(&mut self) -> Option<I::Item> {
+        Pointer::as_mut(self).next_back()
+    }
+
+    #[inline]
+    fn nth_back(&mut self, n: usize) -> Option<I::Item> {
+        Pointer::as_mut(self).nth_back(n)
+    }
+}
+
+impl<'a, I: ExactSizeIterator + ?Sized> ExactSizeIterator for Pointer<'a, I> {
+    #[inline]
+    fn len(&self) -> usize {
+        Pointer::as_ref(self).len()
+    }
+}
+
+impl<'a, I: FusedIterator + ?Sized> FusedIterator for Pointer<'a, I> {}
+
+/// Like `Box`, a `Pointer<F>` is a future only if `F: Unpin`. For futures
+/// that are not `Unpin`, allocate them with `Allocator::alloc_pinned`;
+/// the resulting `Pin<Pointer<F>>` is a future as well.
+impl<'a, F: Future + Unpin + ?Sized> Future for Pointer<'a, F> {
+    type Output = F::Output;
+
+    #[inline]
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<F::Output> {
+        Pin::new(Pointer::as_mut(&mut *self)).poll(cx)
+    }
+}
+
+impl<'a, R: io::Read + ?Sized> io::Read for Pointer<'a, R> {
+    #[inline]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        Pointer::as_mut(self).read(buf)
+    }
+
+    #[inline]
+    fn read_vectored(&mut self, bufs: &mut [io::IoSliceMut]) -> io::Result<usize> {
+        Pointer::as_mut(self).read_vectored(bufs)
+    }
+
+    #[inline]
+    fn read_to_end(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        Pointer::as_mut(self).read_to_end(buf)
+    }
+
+    #[inline]
+    fn read_to_string(&mut self, buf: &mut String) -> io::Result<usize> {
+        Pointer::as_mut(self).read_to_string(buf)
+    }
+
+    #[inline]
+    fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        Pointer::as_mut(self).read_exact(buf)
+    }
+}
+
+impl<'a, W: io::Write + ?Sized> io::Write for Pointer<'a, W> {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Pointer::as_mut(self).write(buf)
+    }
+
+    #[inline]
+    fn write_vectored(&mut self, bufs: &[io::IoSlice]) -> io::Result<usize> {
+        Pointer::as_mut(self).write_vectored(bufs)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> io::Result<()> {
+        Pointer::as_mut(self).flush()
+    }
+
+    #[inline]
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        Pointer::as_mut(self).write_all(buf)
+    }
+
+    #[inline]
+    fn write_fmt(&mut self, fmt: fmt::Arguments) -> io::Result<()> {
+        Pointer::as_mut(self).write_fmt(fmt)
+    }
+}
+
+impl<'a, T:?Sized> Drop for Pointer<'a, T> {
+    fn drop(&mut self) {
+        unsafe {
+            // beyond a slot only for blocks of `alloc_contiguous`
+            let size = std::mem::size_of_val(self.node.as_ref());
+            std::ptr::drop_in_place(self.node.as_ptr());
+            // the address of the slot, dropping the metadata if any
+            let node = self.node.cast::<Node>().as_ptr();
+            if size <= self.pool.ele_size {
+                self.pool.recycle(node);
+            } else {
+                self.pool.recycle_run(node, size.div_ceil(self.pool.ele_size));
+            }
+        }
+    }
+}       
+
+/// A type-erased pointer to a slot, obtained by `Pointer::erase`.
+///
+/// When dropped, the underlying memory would be recycled by the allocator
+/// *without* running any destructor, as the type of the pointee is unknown.
+/// Restore the type with `assume_type` if the pointee needs to be dropped.
+pub struct RawPointer<'a> {
+    pool: &'a Pool,
+    node: NonNull<u8>,
+    stamp: Stamp,
+}
+
+impl<'a> RawPointer<'a> {
+    /// Get the address of the underlying slot.
+    #[inline]
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.node.as_ptr()
+    }
+
+    /// Restore the type of the pointee.
+    ///
+    /// # Safety
+    ///
+    /// The slot must hold a valid `T`, typically because this pointer
+    /// was erased from a `Pointer<T>`. `T` must fit in the slot. The
+    /// size is checked in debug builds, the alignment always.
+    #[inline]
+    #[track_caller]
+    pub unsafe fn assume_type<T>(self) -> Pointer<'a, T> {
+        check_slot::<T>(self.pool.ele_size);
+        let ret = Pointer::with_stamp(self.pool, self.node.as_ptr() as *mut T, self.stamp);
+        std::mem::forget(self);
+        ret
+    }
+}
+
+impl<'a> Drop for RawPointer<'a> {
+    fn drop(&mut self) {
+        unsafe {
+            self.pool.recycle(self.node.as_ptr() as *mut Node);
+        }
+    }
+}
+
+/// Allows allocation
+pub struct Allocator {
+    pool8: Box<Pool>,
+    pool16: Box<Pool>,
+    pool32: Box<Pool>,
+    pool64: Box<Pool>,
+    pool128: Box<Pool>,
+    pool256: Box<Pool>,
+    on_exhausted: Option<ExhaustedHandler>,
+    /// Whether `on_exhausted` is running.
+    in_handler: Cell<bool>,
+    leak_policy: LeakPolicy,
+    missing_class: MissingClass,
+    /// Index of the pool serving each class, from the smallest, or
+    /// `None` if no present class serves it.
+    route: [Option<usize>; 6],
+    /// Sizes of the classes present, from the smallest, in the first
+    /// `class_count` entries.
+    classes: [usize; 6],
+    class_count: usize,
+    /// Slots handed out by `alloc_ref` since the last `reset`, by class.
+    ref_slots: [Cell<usize>; 6],
+    /// The lifetime counts of each class at the last `reset_counters`.
+    #[cfg(feature = "stats")]
+    counter_marks: [Cell<stats::CounterMark>; 6],
+    /// Requests by size, see `SizeHistogram`.
+    #[cfg(feature = "stats")]
+    size_buckets: [Cell<usize>; 32],
+    #[cfg(feature = "stats")]
+    wasteful: Cell<usize>,
+    /// Requests served by each class, and the bytes they asked.
+    #[cfg(feature = "stats")]
+    class_requests: [(Cell<usize>, Cell<usize>); 6],
+    /// Usage of the groups registered by `group`.
+    groups: RefCell<Vec<GroupUsage>>,
+    name: Option<&'static str>,
+    /// The id of the allocator in the `registry`, or 0 if unregistered.
+    #[cfg(feature = "registry")]
+    registry_id: u64,
+    /// The prefix of the metrics installed by `install_metrics`.
+    #[cfg(feature = "metrics")]
+    metrics_prefix: RefCell<Option<String>>,
+}
+
+impl Allocator {
+    /// Construct a new allocator with default page capacity.
+    pub fn new() -> Allocator {
+        Allocator::from_pools([
+            Pool::new(8), Pool::new(16), Pool::new(32),
+            Pool::new(64), Pool::new(128), Pool::new(256),
+        ]).registered()
+    }
+
+    /// Construct a new allocator with `cap`acity per inner page
+    pub fn with_capacity(cap: usize) -> Allocator {
+        Allocator::from_pools([
+            Pool::with_capacity(cap, 8), Pool::with_capacity(cap, 16), Pool::with_capacity(cap, 32),
+            Pool::with_capacity(cap, 64), Pool::with_capacity(cap, 128), Pool::with_capacity(cap, 256),
+        ]).registered()
+    }
+
+    /// Construct an allocator from the pools of every class, from the
+    /// smallest, sharing one operation clock.
+    fn from_pools(mut pools: [Box<Pool>; 6]) -> Allocator {
+        let clock = Rc::new(Cell::new(0));
+        for pool in pools.iter_mut() {
+            pool.start_clock(clock.clone());
+        }
+        let [pool8, pool16, pool32, pool64, pool128, pool256] = pools;
+        Allocator{
+            pool8, pool16, pool32, pool64, pool128, pool256,
+            on_exhausted: None,
+            in_handler: Cell::new(false),
+            leak_policy: LeakPolicy::Panic,
+            missing_class: MissingClass::RoundUp,
+            route: [Some(0), Some(1), Some(2), Some(3), Some(4), Some(5)],
+            classes: [8, 16, 32, 64, 128, 256],
+            class_count: 6,
+            ref_slots: Default::default(),
+            #[cfg(feature = "stats")]
+            counter_marks: Default::default(),
+            #[cfg(feature = "stats")]
+            size_buckets: Default::default(),
+            #[cfg(feature = "stats")]
+            wasteful: Cell::new(0),
+            #[cfg(feature = "stats")]
+            class_requests: Default::default(),
+            groups: RefCell::new(Vec::new()),
+            name: None,
+            #[cfg(feature = "registry")]
+            registry_id: 0,
+            #[cfg(feature = "metrics")]
+            metrics_prefix: RefCell::new(None),
+        }
+    }
+
+    /// Register the allocator with the `registry`, once its classes are
+    /// final. A no-op without the `registry` feature.
+    #[inline]
+    #[cfg_attr(not(feature = "registry"), allow(unused_mut))]
+    fn registered(mut self) -> Allocator {
+        #[cfg(feature = "registry")]
+        registry::register(&mut self);
+        self
+    }
+
+    /// The settings the allocator was built with, to build others alike
+    /// with `AllocatorBuilder::from_config`, see also `new_like`.
+    pub fn config(&self) -> AllocatorConfig {
+        AllocatorConfig{
+            classes: self.classes().to_vec(),
+            capacities: self.pools().map(|pool| pool.page_slots).collect(),
+            missing_class: self.missing_class,
+            budget: self.pool8.budget.as_ref().map(|budget| budget.limit()),
+            leak_policy: self.leak_policy,
+        }
+    }
+
+    /// Construct an allocator with no slot handed out, built like this
+    /// one: with its `config`, and its name, handler of `on_exhausted`,
+    /// and hardening, which the config doesn't keep.
+    ///
+    /// ```rust
+    /// use aren_alloc::AllocatorBuilder;
+    /// let template = AllocatorBuilder::new().capacity_for(64, 1000).name("worker").build();
+    /// let workers: Vec<_> = (0..4).map(|_| template.new_like()).collect();
+    /// assert!(workers.iter().all(|w| w.config() == template.config() && w.name() == Some("worker")));
+    /// ```
+    pub fn new_like(&self) -> Allocator {
+        let mut builder = AllocatorBuilder::from_config(&self.config());
+        if let Some(name) = self.name {
+            builder = builder.name(name);
+        }
+        if let Some(handler) = self.on_exhausted {
+            builder = builder.on_exhausted(handler);
+        }
+        #[cfg(feature = "harden")]
+        {
+            builder = builder.harden(self.pool8.rng.is_some());
+        }
+        builder.build()
+    }
+
+    /// The name the allocator was built with, see `AllocatorBuilder::name`.
+    #[inline]
+    pub fn name(&self) -> Option<&'static str> {
+        self.name
+    }
+
+    /// The free slots of the pool serving `class_size` bytes, or 0 if no
+    /// class does. Slots freed on other threads count once drained.
+    ///
+    /// This reads a counter, it doesn't walk the free list.
+    ///
+    /// ```rust
+    /// use aren_alloc::AllocatorBuilder;
+    /// let allocator = AllocatorBuilder::new().capacity_for(64, 500).build();
+    /// assert_eq!(allocator.free_slots(64), 500);
+    /// let ptr = allocator.alloc([0u8; 50]);
+    /// assert_eq!(allocator.free_slots(64), 499);
+    /// ```
+    #[inline]
+    pub fn free_slots(&self, class_size: usize) -> usize {
+        self.pool_for_size(class_size).map_or(0, |pool| pool.free_slots.get())
+    }
+
+    /// Whether `n` instances of `T` could be allocated right now without
+    /// adding a page to their class, so without allocating from the
+    /// system nor charging the budget. `false` if no class holds a `T`.
+    ///
+    /// ```rust
+    /// use aren_alloc::AllocatorBuilder;
+    /// let allocator = AllocatorBuilder::new().capacity_for(64, 500).build();
+    /// assert!(allocator.can_allocate_without_growth::<[u64; 8]>(500));
+    /// assert!(!allocator.can_allocate_without_growth::<[u64; 8]>(501));
+    /// ```
+    #[inline]
+    pub fn can_allocate_without_growth<T>(&self, n: usize) -> bool {
+        self.pool_for_size(std::mem::size_of::<T>()).is_some_and(|pool| pool.free_slots.get() >= n)
+    }
+
+    /// The address ranges of the pages of every class, sorted and
+    /// disjoint, one per page, e.g. to tell whether an address points
+    /// into the allocator. Blocks on the heap aren't listed.
+    ///
+    /// The ranges are those of the pages at the time of the call, pages
+    /// added or trimmed later aren't reflected.
+    ///
+    /// ```rust
+    /// use aren_alloc::Allocator;
+    /// let allocator = Allocator::new();
+    /// let ptr = allocator.alloc(1u64);
+    /// let addr = &*ptr as *const u64 as usize;
+    /// assert!(allocator.memory_ranges().iter().any(|range| range.contains(&addr)));
+    /// ```
+    pub fn memory_ranges(&self) -> Vec<Range<usize>> {
+        let mut ranges: Vec<_> = self.pools().flat_map(|pool| pool.page_ranges()).collect();
+        ranges.sort_unstable_by_key(|range| range.start);
+        ranges
+    }
+
+    /// Select the pool whose elements are large enough to hold a `T`.
+    #[inline]
+    #[track_caller]
+    fn pool_for<T>(&self) -> &Pool {
+        match self.pool_for_size(std::mem::size_of::<T>()) {
+            Some(pool) => pool,
+            None if std::mem::size_of::<T>() <= 256 => missing_class::<T>(),
+            None => too_big::<T>(),
+        }
+    }
+
+    /// The pools of every class, from the smallest, including those the
+    /// allocator was built without.
+    #[inline]
+    fn all_pools(&self) -> [&Pool; 6] {
+        [&self.pool8, &self.pool16, &self.pool32, &self.pool64, &self.pool128, &self.pool256]
+    }
+
+    /// The pools of the classes present, from the smallest.
+    #[inline]
+    fn pools(&self) -> impl Iterator<Item=&Pool> {
+        IntoIterator::into_iter(self.all_pools()).enumerate()
+            .filter(move |&(i, _)| self.route[i] == Some(i))
+            .map(|(_, pool)| pool)
+    }
+
+    /// Get the pool serving `size` bytes: that of the smallest class
+    /// holding them, or the next present class if it is missing and
+    /// rounded up. Return `None` if none does.
+    #[inline]
+    fn pool_for_size(&self, size: usize) -> Option<&Pool> {
+        let class = if size <= 8 {
+            0
+        } else if size <= 16 {
+            1
+        } else if size <= 32 {
+            2
+        } else if size <= 64 {
+            3
+        } else if size <= 128 {
+            4
+        } else if size <= 256 {
+            5
+        } else {
+            return None;
+        };
+        self.route[class].map(|i| self.all_pools()[i])
+    }
+
+    /// Allocate an instance of `T` with value `elem`,
+    /// return the allocated pointer.
+    /// `size_of::<T>()` should be le to 256 bytes, which
+    /// `assert_poolable` checks at compile time.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the budget of the allocator is exhausted, and its
+    /// handler couldn't recover. See `try_alloc`.
+    #[inline]
+    #[track_caller]
+    pub fn alloc<T: Copy>(&self, elem: T) -> Pointer<'_, T> {
+        self.put(elem)
+    }
+
+    /// Allocate an instance of `T` with value `elem`,
+    /// return the allocated pointer pinned in place.
+    /// `size_of::<T>()` should be le to 256 bytes.
+    ///
+    /// The slot backing a `Pointer` is never moved, and `T`'s
+    /// destructor runs before the slot is recycled. A slot whose
+    /// pointer is leaked with `mem::forget` is kept for good, see
+    /// `Pointer::into_pin`. Hence `T` doesn't need to be `Unpin`,
+    /// nor `Copy`.
+    #[inline]
+    #[track_caller]
+    pub fn alloc_pinned<T>(&self, elem: T) -> Pin<Pointer<'_, T>> {
+        Pointer::into_pin(self.put(elem))
+    }
+
+    /// Move `elem` into a slot of its class, whatever `T` is.
+    #[inline]
+    #[track_caller]
+    fn put<T>(&self, elem: T) -> Pointer<'_, T> {
+        let pool = self.pool_for::<T>();
+        check_slot::<T>(pool.ele_size);
+        let node = match self.pop_or_recover(pool, std::mem::size_of::<T>()) {
+            Some(node) => node as *mut T,
+            None => panic!("allocation budget exhausted"),
+        };
+        unsafe {
+            std::ptr::write(node, elem);
+            Pointer::from_node(pool, node)
+        }
+    }
+
+    /// Allocate an instance of `T` with default value,
+    /// return the allocated pointer.
+    /// `size_of::<T>()` should be le to 256 bytes.
+    #[inline]
+    #[track_caller]
+    pub fn alloc_default<T: Copy+Default>(&self) -> Pointer<'_, T> {
+        self.alloc(Default::default())
+    }
+
+    /// Copy the value of `src`, which may be allocated from another
+    /// allocator, into a slot of this one. See `duplicate_slice` for
+    /// slices.
+    ///
+    /// The copy is independent of `src`, whose slot is recycled by its
+    /// own allocator.
+    #[inline]
+    #[track_caller]
+    pub fn duplicate<T: Copy>(&self, src: &Pointer<T>) -> Pointer<'_, T> {
+        self.alloc(**src)
+    }
+
+    /// Allocate a zeroed buffer of at least `min` bytes, return it along
+    /// with its length, which is the size of the whole slot, so that the
+    /// slack of the class could be used too.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `min` is larger than 256 bytes, or the budget of the
+    /// allocator is exhausted, and its handler couldn't recover.
+    #[inline]
+    #[track_caller]
+    pub fn alloc_bytes_at_least(&self, min: usize) -> (Pointer<'_, [u8]>, usize) {
+        let pool = match self.pool_for_size(min) {
+            Some(pool) => pool,
+            None if min <= 256 => panic!("no size class of the allocator holds {} bytes", min),
+            None => panic!("element size too big! {} bytes, beyond the largest size class of 256 bytes", min),
+        };
+        let len = pool.ele_size;
+        let node = match self.pop_or_recover(pool, min) {
+            Some(node) => node as *mut u8,
+            None => panic!("allocation budget exhausted"),
+        };
+        unsafe { std::ptr::write_bytes(node, 0, len) };
+        (unsafe { Pointer::from_node(pool, std::ptr::slice_from_raw_parts_mut(node, len)) }, len)
+    }
+}
+
+impl Default for Allocator {
+    #[inline]
+    fn default() -> Allocator {
+        Allocator::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+    struct Byte128 {
+        val: [u64; 16]
+    }
+
+    impl Byte128 {
+        fn new(v: u64) -> Byte128 {
+            Byte128{ val: [v; 16] }
+        }
+    }
+
+    impl Default for Byte128 {
+        fn default() -> Byte128 {
+            Byte128::new(0)
+        }
+    }
+
+    #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+    struct Byte15 {
+        val: [u8; 15]
+    }
+
+    impl Byte15 {
+        fn new(v: u8) -> Byte15 {
+            Byte15{ val: [v; 15] }
+        }
+    }
+
+    impl Default for Byte15 {
+        fn default() -> Byte15 {
+            Byte15::new(0)
+        }
+    }
 
     trait Sum {
         fn sum(&self) -> u64;
     }
 
-    impl Sum for Byte15 {
-        fn sum(&self) -> u64 {
-            let mut ret = 0;
-            for i in self.val.iter() {
-                ret += *i as u64;
+    impl Sum for Byte15 {
+        fn sum(&self) -> u64 {
+            let mut ret = 0;
+            for i in self.val.iter() {
+                ret += *i as u64;
+            }
+            ret
+        }
+    }
+
+    #[test]
+    fn test_alloc_16() {
+        let allocator = Allocator::new();
+        let bytes1 = allocator.alloc(Byte15::new(1));
+        let bytes2 = allocator.alloc(Byte15::new(2));
+        let bytes3 = allocator.alloc(Byte15::new(3));
+        {
+            let bytes4 = allocator.alloc(Byte15::new(4));
+            assert_eq!(*bytes1, Byte15::new(1));
+            assert_eq!(*bytes2, Byte15::new(2));
+            assert_eq!(*bytes3, Byte15::new(3));
+            assert_eq!(*bytes4, Byte15::new(4));
+        }
+        assert_eq!(*bytes1, Byte15::new(1));
+        assert_eq!(*bytes2, Byte15::new(2));
+        assert_eq!(*bytes3, Byte15::new(3));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_alloc_32_panic() {
+        let allocator = Pool::new(32);
+        let _bytes128: Pointer<Byte128> = allocator.alloc();
+    }
+
+    #[test]
+    fn test_lazy_page() {
+        let pool = Pool::lazy(4, 16);
+        assert_eq!(pool.slot_count(), 0);
+        let ptrs: Vec<Pointer<[u8; 16]>> = (0..5).map(|_| pool.alloc()).collect();
+        assert_eq!(pool.slot_count(), 8);
+        let first = &*ptrs[0] as *const [u8; 16];
+        assert_eq!(pool.pool.borrow().as_ptr() as *const [u8; 16], first);
+        drop(ptrs);
+        assert_eq!(pool.slot_count(), 8);
+    }
+
+    #[test]
+    fn test_alloc_128_addtional_pages() {
+        let allocator = Allocator::with_capacity(4);
+        let bytes1 = allocator.alloc(Byte128::new(1));
+        let bytes2 = allocator.alloc(Byte128::new(2));
+        let bytes3 = allocator.alloc(Byte128::new(3));
+        {
+            let bytes4 = allocator.alloc(Byte128::new(4));
+            let bytes5 = allocator.alloc(Byte128::new(5));
+            let bytes6 = allocator.alloc(Byte128::new(6));
+            let bytes7 = allocator.alloc(Byte128::new(7));
+            let bytes8 = allocator.alloc(Byte128::new(8));
+            let bytes9 = allocator.alloc(Byte128::new(9));
+            let bytes10 = allocator.alloc(Byte128::new(10));
+            assert_eq!(*bytes1, Byte128::new(1));
+            assert_eq!(*bytes2, Byte128::new(2));
+            assert_eq!(*bytes3, Byte128::new(3));
+            assert_eq!(*bytes4, Byte128::new(4));
+            assert_eq!(*bytes5, Byte128::new(5));
+            assert_eq!(*bytes6, Byte128::new(6));
+            assert_eq!(*bytes7, Byte128::new(7));
+            assert_eq!(*bytes8, Byte128::new(8));
+            assert_eq!(*bytes9, Byte128::new(9));
+            assert_eq!(*bytes10, Byte128::new(10));
+        }
+        let bytes6 = allocator.alloc(Byte128::new(6));
+        let bytes7 = allocator.alloc(Byte128::new(7));
+        let bytes8 = allocator.alloc(Byte128::new(8));
+        let bytes9 = allocator.alloc(Byte128::new(9));
+        let bytes10 = allocator.alloc(Byte128::new(10));
+        assert_eq!(*bytes1, Byte128::new(1));
+        assert_eq!(*bytes2, Byte128::new(2));
+        assert_eq!(*bytes3, Byte128::new(3));
+        assert_eq!(*bytes6, Byte128::new(6));
+        assert_eq!(*bytes7, Byte128::new(7));
+        assert_eq!(*bytes8, Byte128::new(8));
+        assert_eq!(*bytes9, Byte128::new(9));
+        assert_eq!(*bytes10, Byte128::new(10));
+    }
+
+    #[test]
+    fn test_alloc_default() {
+        let allocator = Allocator::new();
+        let d: Pointer<Byte128> = allocator.alloc_default();
+        assert_eq!(*d, Byte128::default());
+        let di: Pointer<i32> = allocator.alloc_default();
+        assert_eq!(*di, i32::default());
+    }
+
+    #[test]
+    fn test_as_ref_mut() {
+        let allocator = Allocator::new();
+        let mut bytes1 = allocator.alloc(Byte15::new(1));
+        assert_eq!(bytes1.val[0], 1);
+        bytes1.val[1] = 2;
+        assert_eq!(bytes1.val[1], 2);
+    }
+
+    #[test]
+    fn test_unsize_coerce() {
+        let allocator = Allocator::new();
+        let bytes0 = allocator.alloc(Byte15::new(0));
+        {
+            let bytes1: Pointer<dyn Sum> = allocator.alloc(Byte15::new(1));
+            assert_eq!(bytes1.sum(), 15);
+        }
+        let bytes2 = allocator.alloc(Byte15::new(2));
+        let bytes3 = allocator.alloc(Byte15::new(3));
+        assert_eq!(bytes0.sum(), 0);
+        assert_eq!(bytes2.sum(), 30);
+        assert_eq!(bytes3.sum(), 45);
+    }
+
+    fn first_byte(bytes: impl AsRef<Byte15>) -> u8 {
+        bytes.as_ref().val[0]
+    }
+
+    fn sum_of(s: impl AsRef<dyn Sum>) -> u64 {
+        s.as_ref().sum()
+    }
+
+    fn bump<B: std::borrow::BorrowMut<Byte15>>(mut bytes: B) -> B {
+        bytes.borrow_mut().val[0] += 1;
+        bytes
+    }
+
+    #[test]
+    fn test_conversion_traits() {
+        let allocator = Allocator::new();
+        let bytes1 = allocator.alloc(Byte15::new(1));
+        assert_eq!(first_byte(bytes1), 1);
+
+        let bytes2: Pointer<dyn Sum> = allocator.alloc(Byte15::new(2));
+        assert_eq!(sum_of(bytes2), 30);
+
+        let mut bytes3 = bump(allocator.alloc(Byte15::new(3)));
+        assert_eq!(std::borrow::Borrow::<Byte15>::borrow(&bytes3).val[0], 4);
+        AsMut::<Byte15>::as_mut(&mut bytes3).val[1] = 5;
+        assert_eq!(bytes3.val[1], 5);
+    }
+
+    struct SelfRef {
+        val: u64,
+        this: *const SelfRef,
+        _pin: std::marker::PhantomPinned,
+    }
+
+    impl SelfRef {
+        fn new(val: u64) -> SelfRef {
+            SelfRef{ val, this: std::ptr::null(), _pin: std::marker::PhantomPinned }
+        }
+
+        fn init(self: Pin<&mut Self>) {
+            unsafe {
+                let this = self.get_unchecked_mut();
+                this.this = this as *const SelfRef;
+            }
+        }
+    }
+
+    #[test]
+    fn test_alloc_pinned() {
+        let allocator = Allocator::with_capacity(2);
+        let mut pinned = allocator.alloc_pinned(SelfRef::new(42));
+        pinned.as_mut().init();
+        let addr = pinned.as_ref().get_ref() as *const SelfRef;
+        assert_eq!(pinned.this, addr);
+        {
+            let _others: Vec<_> = (0..8).map(|i| allocator.alloc_pinned(SelfRef::new(i))).collect();
+            let _bytes = allocator.alloc(Byte15::new(1));
+        }
+        let _more: Vec<_> = (0..8).map(|i| allocator.alloc_pinned(SelfRef::new(i))).collect();
+        assert_eq!(pinned.as_ref().get_ref() as *const SelfRef, addr);
+        assert_eq!(pinned.this, addr);
+        assert_eq!(pinned.val, 42);
+    }
+
+    struct DropFlag<'a>(&'a Cell<u32>);
+
+    impl<'a> Drop for DropFlag<'a> {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    #[test]
+    fn test_pinned_drop_before_recycle() {
+        let allocator = Allocator::new();
+        let dropped = Cell::new(0);
+        let pinned = allocator.alloc_pinned(DropFlag(&dropped));
+        let addr = pinned.as_ref().get_ref() as *const DropFlag as usize;
+        drop(pinned);
+        assert_eq!(dropped.get(), 1);
+        let reused = allocator.alloc(0u64);
+        assert_eq!(&*reused as *const u64 as usize, addr);
+        assert_eq!(dropped.get(), 1);
+
+        let unpinned = Pointer::into_pin(allocator.alloc(Byte15::new(7)));
+        assert_eq!(*unpinned, Byte15::new(7));
+    }
+
+    #[test]
+    fn test_downcast() {
+        let allocator = Allocator::with_capacity(2);
+        let bytes: Pointer<dyn Any> = allocator.alloc(Byte15::new(1));
+        let addr = &*bytes as *const dyn Any as *const u8 as usize;
+
+        let bytes = match Pointer::downcast::<u32>(bytes) {
+            Ok(_) => panic!("downcast to the wrong type should fail"),
+            Err(bytes) => bytes,
+        };
+        assert!(bytes.is::<Byte15>());
+        assert_eq!(&*bytes as *const dyn Any as *const u8 as usize, addr);
+
+        let bytes = match Pointer::downcast::<Byte15>(bytes) {
+            Ok(bytes) => bytes,
+            Err(_) => panic!("downcast to the original type should succeed"),
+        };
+        assert_eq!(*bytes, Byte15::new(1));
+        assert_eq!(&*bytes as *const Byte15 as usize, addr);
+
+        // the slot is recycled exactly once, by the downcasted pointer
+        drop(bytes);
+        let reused1 = allocator.alloc(Byte15::new(2));
+        let reused2 = allocator.alloc(Byte15::new(3));
+        assert_eq!(&*reused1 as *const Byte15 as usize, addr);
+        assert_ne!(&*reused2 as *const Byte15 as usize, addr);
+    }
+
+    #[test]
+    fn test_downcast_borrowed() {
+        let allocator = Allocator::new();
+        let mut val: Pointer<dyn Any> = allocator.alloc(7u32);
+        assert_eq!(Pointer::downcast_ref::<u32>(&val), Some(&7));
+        assert_eq!(Pointer::downcast_ref::<u64>(&val), None);
+        *Pointer::downcast_mut::<u32>(&mut val).unwrap() = 8;
+        assert!(Pointer::downcast_mut::<i32>(&mut val).is_none());
+        assert_eq!(val.downcast_ref::<u32>(), Some(&8));
+    }
+
+    #[derive(Copy, Clone, Debug)]
+    struct ParseError {
+        line: u32,
+        cause: Option<CauseError>,
+    }
+
+    #[derive(Copy, Clone, Debug)]
+    struct CauseError;
+
+    impl fmt::Display for ParseError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "parse error at line {}", self.line)
+        }
+    }
+
+    impl fmt::Display for CauseError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "unexpected token")
+        }
+    }
+
+    impl Error for CauseError {}
+
+    impl Error for ParseError {
+        fn source(&self) -> Option<&(dyn Error + 'static)> {
+            self.cause.as_ref().map(|c| c as &(dyn Error + 'static))
+        }
+    }
+
+    fn parse(allocator: &Allocator, line: u32) -> Result<u32, Pointer<'_, ParseError>> {
+        if line == 0 {
+            Ok(line)
+        } else {
+            Err(allocator.alloc(ParseError{ line, cause: Some(CauseError) }))
+        }
+    }
+
+    fn parse_all<'a>(allocator: &'a Allocator, lines: &[u32]) -> Result<u32, Pointer<'a, dyn Error + 'a>> {
+        let mut sum = 0;
+        for &line in lines {
+            sum += parse(allocator, line)?;
+        }
+        Ok(sum)
+    }
+
+    #[test]
+    fn test_error() {
+        let allocator = Allocator::new();
+        assert_eq!(parse_all(&allocator, &[0, 0]).unwrap(), 0);
+
+        let err = parse_all(&allocator, &[0, 3, 0]).unwrap_err();
+        let mut chain = vec![err.to_string()];
+        let mut source = err.source();
+        while let Some(cause) = source {
+            chain.push(cause.to_string());
+            source = cause.source();
+        }
+        assert_eq!(chain, ["parse error at line 3", "unexpected token"]);
+        assert_eq!(format!("{:?}", err), "ParseError { line: 3, cause: Some(CauseError) }");
+
+        let coerced: Pointer<dyn Error> = allocator.alloc(CauseError);
+        assert_eq!(coerced.to_string(), "unexpected token");
+        assert!(coerced.source().is_none());
+    }
+
+    /// A 48-byte sample sequence generator.
+    #[derive(Copy, Clone)]
+    struct Samples {
+        next: u64,
+        end: u64,
+        step: u64,
+        _pad: [u64; 3],
+    }
+
+    impl Samples {
+        fn new(end: u64, step: u64) -> Samples {
+            Samples{ next: 0, end, step, _pad: [0; 3] }
+        }
+    }
+
+    impl Iterator for Samples {
+        type Item = u64;
+
+        fn next(&mut self) -> Option<u64> {
+            if self.next >= self.end {
+                return None;
+            }
+            let ret = self.next;
+            self.next += self.step;
+            Some(ret)
+        }
+
+        fn size_hint(&self) -> (usize, Option<usize>) {
+            let len = (self.end.max(self.next) - self.next).div_ceil(self.step) as usize;
+            (len, Some(len))
+        }
+    }
+
+    impl DoubleEndedIterator for Samples {
+        fn next_back(&mut self) -> Option<u64> {
+            if self.next >= self.end {
+                return None;
+            }
+            let last = self.next + (self.len() as u64 - 1) * self.step;
+            self.end = last;
+            Some(last)
+        }
+    }
+
+    impl ExactSizeIterator for Samples {}
+
+    #[derive(Copy, Clone)]
+    struct Countdown(u32);
+
+    impl Iterator for Countdown {
+        type Item = u32;
+
+        fn next(&mut self) -> Option<u32> {
+            if self.0 == 0 {
+                None
+            } else {
+                self.0 -= 1;
+                Some(self.0)
             }
-            ret
         }
     }
 
     #[test]
-    fn test_alloc_16() {
+    fn test_iterator() {
         let allocator = Allocator::new();
-        let bytes1 = allocator.alloc(Byte15::new(1));
-        let bytes2 = allocator.alloc(Byte15::new(2));
-        let bytes3 = allocator.alloc(Byte15::new(3));
-        {
-            let bytes4 = allocator.alloc(Byte15::new(4));
-            assert_eq!(*bytes1, Byte15::new(1));
-            assert_eq!(*bytes2, Byte15::new(2));
-            assert_eq!(*bytes3, Byte15::new(3));
-            assert_eq!(*bytes4, Byte15::new(4));
+        let samples = allocator.alloc(Samples::new(10, 2));
+        assert_eq!(samples.len(), 5);
+        let doubled: Vec<u64> = samples.map(|x| x * 2).take(3).collect();
+        assert_eq!(doubled, [0, 4, 8]);
+
+        let mut samples = allocator.alloc(Samples::new(10, 2));
+        assert_eq!(samples.nth(1), Some(2));
+        assert_eq!(samples.next_back(), Some(8));
+        assert_eq!(samples.len(), 2);
+        let mut sum = 0;
+        for x in samples {
+            sum += x;
+        }
+        assert_eq!(sum, 4 + 6);
+
+        let countdown: Pointer<dyn Iterator<Item = u32>> = allocator.alloc(Countdown(4));
+        assert_eq!(countdown.size_hint(), (0, None));
+        let collected: Vec<u32> = countdown.filter(|x| x % 2 == 1).collect();
+        assert_eq!(collected, [3, 1]);
+    }
+
+    /// Becomes ready after yielding `self.0` times.
+    #[derive(Copy, Clone)]
+    struct Yield(u32);
+
+    impl Future for Yield {
+        type Output = u32;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<u32> {
+            if self.0 == 0 {
+                Poll::Ready(42)
+            } else {
+                self.0 -= 1;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    /// A `!Unpin` future checking that it hasn't moved between polls.
+    struct Pinned {
+        polls: u32,
+        addr: *const Pinned,
+        _pin: std::marker::PhantomPinned,
+    }
+
+    impl Future for Pinned {
+        type Output = u32;
+
+        fn poll(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<u32> {
+            let this = unsafe { self.get_unchecked_mut() };
+            let addr = this as *const Pinned;
+            if this.addr.is_null() {
+                this.addr = addr;
+            }
+            assert_eq!(this.addr, addr);
+            this.polls += 1;
+            if this.polls == 3 {
+                Poll::Ready(this.polls)
+            } else {
+                Poll::Pending
+            }
+        }
+    }
+
+    fn block_on<F: Future + Unpin>(mut fut: F) -> (F::Output, u32) {
+        let mut cx = Context::from_waker(std::task::Waker::noop());
+        let mut polls = 1;
+        loop {
+            if let Poll::Ready(ret) = Pin::new(&mut fut).poll(&mut cx) {
+                return (ret, polls);
+            }
+            polls += 1;
+        }
+    }
+
+    #[test]
+    fn test_future() {
+        let allocator = Allocator::new();
+        assert_eq!(block_on(allocator.alloc(Yield(3))), (42, 4));
+
+        let erased: Pointer<dyn Future<Output = u32>> = allocator.alloc(Yield(1));
+        let erased: Pin<Pointer<dyn Future<Output = u32>>> = Pointer::into_pin(erased);
+        assert_eq!(block_on(erased), (42, 2));
+
+        let pinned: Pin<Pointer<dyn Future<Output = u32>>> = allocator.alloc_pinned(Pinned{
+            polls: 0, addr: std::ptr::null(), _pin: std::marker::PhantomPinned,
+        });
+        assert_eq!(block_on(pinned), (3, 3));
+    }
+
+    /// A fixed-size in-memory cursor.
+    #[derive(Copy, Clone)]
+    struct ByteCursor {
+        buf: [u8; 56],
+        len: usize,
+    }
+
+    impl ByteCursor {
+        fn new() -> ByteCursor {
+            ByteCursor{ buf: [0; 56], len: 0 }
+        }
+    }
+
+    impl io::Read for ByteCursor {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.len);
+            buf[..n].copy_from_slice(&self.buf[..n]);
+            self.buf.copy_within(n..self.len, 0);
+            self.len -= n;
+            Ok(n)
+        }
+    }
+
+    impl io::Write for ByteCursor {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.buf.len() - self.len);
+            self.buf[self.len..self.len + n].copy_from_slice(&buf[..n]);
+            self.len += n;
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
         }
-        assert_eq!(*bytes1, Byte15::new(1));
-        assert_eq!(*bytes2, Byte15::new(2));
-        assert_eq!(*bytes3, Byte15::new(3));
+    }
+
+    trait Stream: io::Read + io::Write {}
+
+    impl Stream for ByteCursor {}
+
+    #[test]
+    fn test_io() {
+        use std::io::{Read, Write};
+
+        let allocator = Allocator::new();
+        let mut cursor = allocator.alloc(ByteCursor::new());
+        cursor.write_all(b"pooled ").unwrap();
+        write!(cursor, "{}", 42).unwrap();
+        let n = cursor.write_vectored(&[io::IoSlice::new(b"!"), io::IoSlice::new(b"?")]).unwrap();
+        assert!(n >= 1);
+        cursor.flush().unwrap();
+
+        let mut head = [0u8; 7];
+        cursor.read_exact(&mut head).unwrap();
+        assert_eq!(&head, b"pooled ");
+        let mut rest = String::new();
+        cursor.read_to_string(&mut rest).unwrap();
+        assert_eq!(&rest[..2], "42");
+
+        let mut writer: Pointer<dyn Write> = allocator.alloc(ByteCursor::new());
+        assert!(writer.write_all(&[7u8; 57]).is_err());
+
+        let mut stream: Pointer<dyn Stream> = allocator.alloc(ByteCursor::new());
+        stream.write_all(&[1, 2, 3]).unwrap();
+        let mut bytes = Vec::new();
+        stream.read_to_end(&mut bytes).unwrap();
+        assert_eq!(bytes, [1, 2, 3]);
+    }
+
+    #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+    #[repr(C)]
+    struct Header {
+        magic: u32,
+        len: u32,
+        payload: [u8; 24],
+    }
+
+    #[test]
+    fn test_cast() {
+        let allocator = Allocator::with_capacity(2);
+        let mut raw = [0u8; 32];
+        raw[..4].copy_from_slice(&0xfeedu32.to_ne_bytes());
+        raw[4..8].copy_from_slice(&24u32.to_ne_bytes());
+        raw[8] = 1;
+        let raw = allocator.alloc(raw);
+        let addr = &*raw as *const [u8; 32] as usize;
+
+        let header: Pointer<Header> = unsafe { Pointer::cast(raw) };
+        assert_eq!(&*header as *const Header as usize, addr);
+        assert_eq!(header.magic, 0xfeed);
+        assert_eq!(header.len, 24);
+        assert_eq!(header.payload[0], 1);
+
+        let raw: Pointer<[u8; 32]> = unsafe { Pointer::cast(header) };
+        assert_eq!(raw[8], 1);
+        drop(raw);
+
+        // the slot went back to the 32-byte pool exactly once
+        let reused1 = allocator.alloc([0u64; 4]);
+        let reused2 = allocator.alloc([0u64; 4]);
+        assert_eq!(&*reused1 as *const [u64; 4] as usize, addr);
+        assert_ne!(&*reused2 as *const [u64; 4] as usize, addr);
     }
 
     #[test]
     #[should_panic]
-    fn test_alloc_32_panic() {
-        let allocator = Pool::new(32);
-        let _bytes128: Pointer<Byte128> = allocator.alloc();
+    #[cfg(debug_assertions)]
+    fn test_cast_too_large_panic() {
+        let allocator = Allocator::new();
+        let raw = allocator.alloc([0u8; 32]);
+        let _too_large: Pointer<[u8; 33]> = unsafe { Pointer::cast(raw) };
     }
 
     #[test]
-    fn test_alloc_128_addtional_pages() {
-        let allocator = Allocator::with_capacity(4);
-        let bytes1 = allocator.alloc(Byte128::new(1));
-        let bytes2 = allocator.alloc(Byte128::new(2));
-        let bytes3 = allocator.alloc(Byte128::new(3));
-        {
-            let bytes4 = allocator.alloc(Byte128::new(4));
-            let bytes5 = allocator.alloc(Byte128::new(5));
-            let bytes6 = allocator.alloc(Byte128::new(6));
-            let bytes7 = allocator.alloc(Byte128::new(7));
-            let bytes8 = allocator.alloc(Byte128::new(8));
-            let bytes9 = allocator.alloc(Byte128::new(9));
-            let bytes10 = allocator.alloc(Byte128::new(10));
-            assert_eq!(*bytes1, Byte128::new(1));
-            assert_eq!(*bytes2, Byte128::new(2));
-            assert_eq!(*bytes3, Byte128::new(3));
-            assert_eq!(*bytes4, Byte128::new(4));
-            assert_eq!(*bytes5, Byte128::new(5));
-            assert_eq!(*bytes6, Byte128::new(6));
-            assert_eq!(*bytes7, Byte128::new(7));
-            assert_eq!(*bytes8, Byte128::new(8));
-            assert_eq!(*bytes9, Byte128::new(9));
-            assert_eq!(*bytes10, Byte128::new(10));
-        }
-        let bytes6 = allocator.alloc(Byte128::new(6));
-        let bytes7 = allocator.alloc(Byte128::new(7));
-        let bytes8 = allocator.alloc(Byte128::new(8));
-        let bytes9 = allocator.alloc(Byte128::new(9));
-        let bytes10 = allocator.alloc(Byte128::new(10));
-        assert_eq!(*bytes1, Byte128::new(1));
+    fn test_erase() {
+        let allocator = Allocator::with_capacity(2);
+        let erased: Vec<RawPointer> = vec![
+            Pointer::erase(allocator.alloc(Byte15::new(1))),
+            Pointer::erase(allocator.alloc(Byte128::new(2))),
+            Pointer::erase(allocator.alloc(Byte15::new(3))),
+        ];
+        let addrs: Vec<usize> = erased.iter().map(|p| p.as_ptr() as usize).collect();
+
+        let mut erased = erased.into_iter();
+        let bytes1: Pointer<Byte15> = unsafe { erased.next().unwrap().assume_type() };
+        let bytes2: Pointer<Byte128> = unsafe { erased.next().unwrap().assume_type() };
+        assert_eq!(*bytes1, Byte15::new(1));
         assert_eq!(*bytes2, Byte128::new(2));
-        assert_eq!(*bytes3, Byte128::new(3));
-        assert_eq!(*bytes6, Byte128::new(6));
-        assert_eq!(*bytes7, Byte128::new(7));
-        assert_eq!(*bytes8, Byte128::new(8));
-        assert_eq!(*bytes9, Byte128::new(9));
-        assert_eq!(*bytes10, Byte128::new(10));
+        assert_eq!(&*bytes1 as *const Byte15 as usize, addrs[0]);
+        assert_eq!(&*bytes2 as *const Byte128 as usize, addrs[1]);
+
+        // dropping the still erased pointer recycles its slot
+        drop(erased);
+        let reused = allocator.alloc(Byte15::new(4));
+        assert_eq!(&*reused as *const Byte15 as usize, addrs[2]);
     }
 
     #[test]
-    fn test_alloc_default() {
+    fn test_erase_skips_drop() {
         let allocator = Allocator::new();
-        let d: Pointer<Byte128> = allocator.alloc_default();
-        assert_eq!(*d, Byte128::default());
-        let di: Pointer<i32> = allocator.alloc_default();
-        assert_eq!(*di, i32::default());
+        let dropped = Cell::new(0);
+        let erased = Pointer::erase(Pin::into_inner(allocator.alloc_pinned(DropFlag(&dropped))));
+        drop(erased);
+        assert_eq!(dropped.get(), 0);
+
+        let erased = Pointer::erase(Pin::into_inner(allocator.alloc_pinned(DropFlag(&dropped))));
+        let restored: Pointer<DropFlag> = unsafe { erased.assume_type() };
+        drop(restored);
+        assert_eq!(dropped.get(), 1);
     }
 
     #[test]
-    fn test_as_ref_mut() {
+    fn test_call_once() {
         let allocator = Allocator::new();
-        let mut bytes1 = allocator.alloc(Byte15::new(1));
-        assert_eq!(bytes1.val[0], 1);
-        bytes1.val[1] = 2;
-        assert_eq!(bytes1.val[1], 2);
+        let dropped = Cell::new(0);
+        let flag = DropFlag(&dropped);
+        let suffix = String::from("!");
+        let f = Pin::into_inner(allocator.alloc_pinned(move |n: u32| {
+            let _flag = flag;
+            format!("{}{}", n, suffix)
+        }));
+        let f: Pointer<dyn FnOnce(u32) -> String> = f;
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 1);
+        assert_eq!(Pointer::call_once(f, (7,)), "7!");
+        assert_eq!(dropped.get(), 1);
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 0);
+
+        // never called, the captures are still dropped
+        let flag = DropFlag(&dropped);
+        let f = Pin::into_inner(allocator.alloc_pinned(move || drop(flag)));
+        let f: Pointer<dyn FnOnce()> = f;
+        drop(f);
+        assert_eq!(dropped.get(), 2);
+
+        // zero sized closures too have their slots recycled
+        let f = Pin::into_inner(allocator.alloc_pinned(|| 1));
+        let f: Pointer<dyn FnOnce() -> u8> = f;
+        assert_eq!(Pointer::call_once(f, ()), 1);
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 0);
     }
 
     #[test]
-    fn test_unsize_coerce() {
+    #[cfg_attr(target_family = "wasm", ignore = "panics abort on wasm")]
+    fn test_call_once_panics() {
         let allocator = Allocator::new();
-        let bytes0 = allocator.alloc(Byte15::new(0));
-        {
-            let bytes1: Pointer<Sum> = allocator.alloc(Byte15::new(1));
-            assert_eq!(bytes1.sum(), 15);
+        let dropped = Cell::new(0);
+        let flag = DropFlag(&dropped);
+        let f = Pin::into_inner(allocator.alloc_pinned(move || {
+            let _flag = flag;
+            panic!("boom");
+        }));
+        let f: Pointer<dyn FnOnce()> = f;
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| Pointer::call_once(f, ())));
+        assert!(result.is_err());
+        assert_eq!(dropped.get(), 1);
+        #[cfg(feature = "stats")]
+        let stats = allocator.stats();
+        #[cfg(feature = "stats")]
+        assert_eq!((stats.live, stats.allocs), (0, 1));
+        // recycled exactly once, so handed out once again, not twice
+        let a = allocator.alloc(0u64);
+        let b = allocator.alloc(0u64);
+        assert_ne!(&*a as *const u64, &*b as *const u64);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[cfg_attr(target_family = "wasm", ignore = "no threads on wasm")]
+    fn test_wrong_thread_panics() {
+        struct Smuggled<T>(T);
+        unsafe impl<T> Send for Smuggled<T> { }
+
+        let allocator: &'static Allocator = Box::leak(Box::new(Allocator::new()));
+        let ptr = Smuggled(allocator.alloc(Byte15::new(1)));
+        let err = std::thread::spawn(move || drop(ptr)).join().unwrap_err();
+        let msg = err.downcast_ref::<String>().unwrap();
+        assert!(msg.starts_with("pool owned by thread"), "{}", msg);
+        assert!(msg.contains(&format!("{:?}", std::thread::current().id())), "{}", msg);
+
+        let allocator = Smuggled(allocator);
+        let err = std::thread::spawn(move || {
+            let allocator = allocator;
+            allocator.0.alloc(1u64);
+        }).join().unwrap_err();
+        assert!(err.downcast_ref::<String>().unwrap().starts_with("pool owned by thread"));
+    }
+
+    /// Run `f`, and return the message it panicked with.
+    fn panic_message<F: FnOnce()>(f: F) -> String {
+        let err = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).unwrap_err();
+        err.downcast_ref::<String>().unwrap().clone()
+    }
+
+    #[test]
+    fn test_pointer_size() {
+        // release builds carry no stamp
+        let stamp = if cfg!(debug_assertions) { 16 } else { 0 };
+        assert_eq!(std::mem::size_of::<Pointer<u64>>(), 2 * std::mem::size_of::<usize>() + stamp);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn test_debug_seq() {
+        let allocator = Allocator::new();
+        let small = allocator.alloc(1u8);
+        let big = allocator.alloc([0u64; 20]);
+        let mid = allocator.alloc((1u64, 2u64));
+        let seqs: Vec<_> = [Pointer::debug_seq(&small), Pointer::debug_seq(&big), Pointer::debug_seq(&mid)]
+            .iter().map(|seq| seq.unwrap()).collect();
+        assert!(seqs.windows(2).all(|w| w[0] < w[1]), "{:?}", seqs);
+        // a recycled slot gets a new number
+        let addr = &*small as *const u8 as usize;
+        drop(small);
+        let again = allocator.alloc(2u8);
+        assert_eq!(&*again as *const u8 as usize, addr);
+        assert!(Pointer::debug_seq(&again).unwrap() > seqs[2]);
+
+        // conversions keep it
+        let erased: Pointer<dyn std::fmt::Debug> = big;
+        assert_eq!(Pointer::debug_seq(&erased), Some(seqs[1]));
+        let mapped = Pointer::map(mid, |(a, b)| a + b);
+        assert_eq!(Pointer::debug_seq(&mapped), Some(seqs[2]));
+        let raw = unsafe { Pointer::erase(mapped).assume_type::<u64>() };
+        assert_eq!((*raw, Pointer::debug_seq(&raw)), (3, Some(seqs[2])));
+    }
+
+    #[test]
+    #[cfg(not(debug_assertions))]
+    fn test_debug_seq_release() {
+        let allocator = Allocator::new();
+        assert_eq!(Pointer::debug_seq(&allocator.alloc(1u8)), None);
+    }
+
+    #[test]
+    fn test_too_big_message() {
+        let allocator = Allocator::new();
+        let msg = panic_message(|| { allocator.alloc([0u8; 300]); });
+        assert!(msg.starts_with("element size too big!"), "{}", msg);
+        assert!(msg.contains("`[u8; 300]` is 300 bytes"), "{}", msg);
+
+        let msg = panic_message(|| { allocator.alloc_pinned(Byte128Pair::default()); });
+        assert!(msg.contains("Byte128Pair"), "{}", msg);
+        assert!(msg.contains("256 bytes"), "{}", msg);
+
+        let sync = SyncAllocator::new();
+        let msg = panic_message(|| { sync.alloc([0u64; 33]); });
+        assert!(msg.contains("`[u64; 33]` is 264 bytes"), "{}", msg);
+    }
+
+    #[derive(Default)]
+    #[allow(dead_code)]
+    struct Byte128Pair(Byte128, Byte128, u8);
+
+    #[derive(Copy, Clone)]
+    #[repr(align(32))]
+    #[allow(dead_code)]
+    struct Align32([u8; 32]);
+
+    #[test]
+    fn test_misaligned_message() {
+        let allocator = Allocator::new();
+        let msg = panic_message(|| { allocator.alloc(Align32([0; 32])); });
+        assert!(msg.contains("Align32` is aligned to 32 bytes"), "{}", msg);
+        assert!(msg.contains("slot of 32 bytes"), "{}", msg);
+
+        let raw = allocator.alloc([0u8; 32]);
+        let msg = panic_message(|| { unsafe { Pointer::cast::<Align32>(raw) }; });
+        assert!(msg.contains("Align32"), "{}", msg);
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 0);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn test_slot_size_message() {
+        let msg = panic_message(|| { Pool::new(32).alloc::<Byte128>(); });
+        assert!(msg.contains("Byte128` of 128 bytes doesn't fit in a slot of 32 bytes"), "{}", msg);
+    }
+
+    #[test]
+    fn test_capacity_messages() {
+        let msg = panic_message(|| { AllocatorBuilder::new().capacity_for(20, 0); });
+        assert!(msg.contains("got 0 for elements of 20 bytes"), "{}", msg);
+        let msg = panic_message(|| { AllocatorBuilder::new().capacity_for(257, 1); });
+        assert!(msg.contains("257 bytes"), "{}", msg);
+        let msg = panic_message(|| { TypedPool::<Byte15>::with_page_size(0); });
+        assert!(msg.contains("0 slots of `"), "{}", msg);
+        assert!(msg.contains("Byte15"), "{}", msg);
+    }
+
+    #[test]
+    fn test_sibling() {
+        let allocator = Allocator::with_capacity(2);
+        let first = allocator.alloc(Byte15::new(1));
+        // a smaller value still goes to the class of `first`
+        let sibling = Pointer::sibling(&first, 7u32);
+        assert_eq!(*sibling, 7);
+        #[cfg(feature = "stats")]
+        assert_eq!((allocator.pool16.live.get(), allocator.pool8.live.get()), (2, 0));
+
+        let addrs = [&*first as *const Byte15 as usize, &*sibling as *const u32 as usize];
+        drop((first, sibling));
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.pool16.live.get(), 0);
+        let reused = [allocator.alloc([0u8; 16]), allocator.alloc([0u8; 16])];
+        let mut reused: Vec<usize> = reused.iter().map(|p| &**p as *const [u8; 16] as usize).collect();
+        reused.sort();
+        let mut addrs = addrs.to_vec();
+        addrs.sort();
+        assert_eq!(reused, addrs);
+    }
+
+    #[test]
+    fn test_sibling_too_big() {
+        let allocator = Allocator::new();
+        let small = allocator.alloc(1u64);
+        let msg = panic_message(|| { Pointer::sibling(&small, [0u8; 9]); });
+        assert!(msg.contains("`[u8; 9]` of 9 bytes doesn't fit in a slot of 8 bytes"), "{}", msg);
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 1);
+    }
+
+    #[test]
+    fn test_map_in_place() {
+        let allocator = Allocator::new();
+        let marker = Rc::new(());
+        let ptr = allocator.alloc_pinned((marker.clone(), 3u32));
+        let ptr = unsafe { Pin::into_inner_unchecked(ptr) };
+        let addr = &*ptr as *const _ as usize;
+        let free = allocator.pool16.free_count();
+        let mapped = Pointer::map(ptr, |(rc, n)| (n as u64 * 2, rc));
+        assert_eq!(&*mapped as *const _ as usize, addr);
+        assert_eq!((mapped.0, allocator.pool16.free_count()), (6, free));
+        assert_eq!(Rc::strong_count(&marker), 2);
+        drop(mapped);
+        assert_eq!(Rc::strong_count(&marker), 1);
+        assert_eq!(allocator.pool16.free_count(), free + 1);
+
+        let msg = panic_message(|| { Pointer::map(allocator.alloc(1u64), |_| [0u8; 9]); });
+        assert!(msg.contains("`[u8; 9]` of 9 bytes doesn't fit in a slot of 8 bytes"), "{}", msg);
+    }
+
+    #[test]
+    fn test_map_across_classes() {
+        let allocator = Allocator::new();
+        let marker = Rc::new(());
+        let ptr = unsafe { Pin::into_inner_unchecked(allocator.alloc_pinned(marker.clone())) };
+        let free = allocator.pool8.free_count();
+        let mapped = Pointer::map_in(ptr, &allocator, |rc| (rc, [1u64; 3]));
+        // the slot of the input is back on the free list of its class
+        assert_eq!(allocator.pool8.free_count(), free + 1);
+        assert_eq!((mapped.1, Rc::strong_count(&marker)), ([1; 3], 2));
+        let back = Pointer::map_in(mapped, &allocator, |(rc, _)| rc);
+        assert_eq!(Rc::strong_count(&marker), 2);
+        drop(back);
+        assert_eq!(Rc::strong_count(&marker), 1);
+        assert_eq!(allocator.pool32.free_count(), allocator.pool32.slot_count());
+        // of the same class, the slot is reused
+        let ptr = allocator.alloc(1u32);
+        let addr = &*ptr as *const u32 as usize;
+        assert_eq!(&*Pointer::map_in(ptr, &allocator, |n| n as u64) as *const u64 as usize, addr);
+    }
+
+    #[test]
+    fn test_slice_pointer() {
+        let allocator = Allocator::new();
+        let free = allocator.pool32.free_count();
+        let mut ptr: Pointer<[u32]> = Pointer::from(allocator.alloc([1u32, 2, 3, 4, 5]));
+        assert_eq!((Pointer::len(&ptr), Pointer::is_empty(&ptr)), (5, false));
+        ptr[0] = 10;
+        ptr[3..].copy_from_slice(&[40, 50]);
+        for x in ptr.iter_mut() {
+            *x += 1;
         }
-        let bytes2 = allocator.alloc(Byte15::new(2));
-        let bytes3 = allocator.alloc(Byte15::new(3));
-        assert_eq!(bytes0.sum(), 0);
-        assert_eq!(bytes2.sum(), 30);
-        assert_eq!(bytes3.sum(), 45);
+        let mut sum = 0;
+        for x in &ptr {
+            sum += *x;
+        }
+        assert_eq!((sum, &ptr[..2]), (11 + 3 + 4 + 41 + 51, &[11, 3][..]));
+        fn first<C: Index<usize, Output=u32> + ?Sized>(c: &C) -> u32 {
+            c[0]
+        }
+        assert_eq!(first(&ptr), 11);
+        // the slot goes back into the class of the array
+        drop(ptr);
+        assert_eq!(allocator.pool32.free_count(), free);
+
+        let empty: Pointer<[u8]> = allocator.alloc([]).into();
+        assert!(Pointer::is_empty(&empty));
+    }
+
+    #[derive(Copy, Clone)]
+    struct Material([(&'static str, f32); 4]);
+
+    impl Index<&str> for Material {
+        type Output = f32;
+        fn index(&self, name: &str) -> &f32 {
+            &self.0.iter().find(|entry| entry.0 == name).unwrap().1
+        }
+    }
+
+    impl IndexMut<&str> for Material {
+        fn index_mut(&mut self, name: &str) -> &mut f32 {
+            &mut self.0.iter_mut().find(|entry| entry.0 == name).unwrap().1
+        }
+    }
+
+    #[test]
+    fn test_index_passthrough() {
+        let allocator = Allocator::new();
+        let mut material = allocator.alloc(Material([("metallic", 0.0), ("roughness", 0.5), ("ior", 1.5), ("alpha", 1.0)]));
+        assert_eq!(material["roughness"], 0.5);
+        material["roughness"] = 0.25;
+        fn get<C: for<'n> Index<&'n str, Output=f32>>(c: &C, name: &str) -> f32 {
+            c[name]
+        }
+        assert_eq!(get(&material, "roughness"), 0.25);
+
+        let mut array = allocator.alloc([1u32, 2, 3, 4]);
+        array[1] += 10;
+        assert_eq!((array[1], &array[2..]), (12, &[3, 4][..]));
+    }
+
+    #[test]
+    fn test_into_slice() {
+        let allocator = Allocator::new();
+        let marker = Rc::new(());
+        let array = unsafe { Pin::into_inner_unchecked(allocator.alloc_pinned([marker.clone(), marker.clone(), marker.clone()])) };
+        let addr = &*array as *const _ as usize;
+        let free = allocator.pool32.free_count();
+        let slice = Pointer::into_slice(array);
+        assert_eq!((Pointer::len(&slice), slice.as_ptr() as usize), (3, addr));
+        assert!(Rc::ptr_eq(&slice[2], &marker));
+        assert_eq!(Rc::strong_count(&marker), 4);
+        drop(slice);
+        assert_eq!(Rc::strong_count(&marker), 1);
+        assert_eq!(allocator.pool32.free_count(), free + 1);
+
+        let bytes = Pointer::into_slice(allocator.alloc([7u8; 100]));
+        assert_eq!((bytes.len(), bytes[99]), (100, 7));
+        drop(bytes);
+        assert_eq!(allocator.pool128.free_count(), allocator.pool128.slot_count());
+    }
+
+    #[test]
+    #[cfg_attr(target_family = "wasm", ignore = "panics abort on wasm")]
+    fn test_map_panic() {
+        let allocator = Allocator::new();
+        let marker = Rc::new(());
+        let free = allocator.pool8.free_count();
+        let ptr = unsafe { Pin::into_inner_unchecked(allocator.alloc_pinned(marker.clone())) };
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| Pointer::map(ptr, |_rc| -> u64 { panic!("map") })));
+        assert!(result.is_err());
+        let ptr = unsafe { Pin::into_inner_unchecked(allocator.alloc_pinned(marker.clone())) };
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| Pointer::map_in(ptr, &allocator, |_rc| -> [u64; 2] { panic!("map") })));
+        assert!(result.is_err());
+        // every input dropped once, every slot recycled
+        assert_eq!(Rc::strong_count(&marker), 1);
+        assert_eq!(allocator.pool8.free_count(), free);
+        assert_eq!(allocator.pool16.free_count(), allocator.pool16.slot_count());
+        assert!(allocator.is_idle(8) && allocator.is_idle(16));
+    }
+
+    #[test]
+    fn test_variance() {
+        fn shorten<'a, 'b>(ptr: Pointer<'a, &'static str>) -> Pointer<'a, &'b str> {
+            ptr
+        }
+        fn unpin<T: Unpin>(_: &T) { }
+        let allocator = Allocator::new();
+        let local = String::from("local");
+        let ptrs = [shorten(allocator.alloc("static")), allocator.alloc(&local[..])];
+        assert_eq!([*ptrs[0], *ptrs[1]], ["static", "local"]);
+        unpin(&allocator.pool8.alloc::<std::marker::PhantomPinned>());
+    }
+
+    #[test]
+    fn test_alloc_bytes_at_least() {
+        let allocator = Allocator::with_capacity(2);
+        let (mut bytes, len) = allocator.alloc_bytes_at_least(20);
+        assert_eq!((len, bytes.len()), (32, 32));
+        assert!(bytes.iter().all(|&b| b == 0));
+        for (i, b) in bytes.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        assert_eq!(bytes[31], 31);
+        let addr = bytes.as_ptr() as usize;
+        drop(bytes);
+
+        let reused = allocator.alloc([0u64; 4]);
+        assert_eq!(&*reused as *const [u64; 4] as usize, addr);
+        assert_eq!(allocator.alloc_bytes_at_least(0).1, 8);
+        assert_eq!(allocator.alloc_bytes_at_least(256).1, 256);
+        let msg = panic_message(|| { allocator.alloc_bytes_at_least(257); });
+        assert!(msg.contains("257 bytes"), "{}", msg);
+    }
+
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    enum Mode {
+        Idle,
+        Busy(u8),
+    }
+
+    /// Invalid for most bit patterns, so a slot must be written before
+    /// a reference to it exists.
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    struct Tagged {
+        label: &'static str,
+        on: bool,
+        mode: Mode,
+    }
+
+    #[test]
+    fn test_init_over_garbage() {
+        let allocator = Allocator::new();
+        let tagged = Tagged{ label: "lamp", on: true, mode: Mode::Busy(3) };
+        let fill = |allocator: &Allocator| drop(allocator.alloc([0xa5u8; 24]));
+        fill(&allocator);
+        assert_eq!(*allocator.alloc(tagged), tagged);
+        fill(&allocator);
+        let send = allocator.alloc_send(tagged);
+        assert_eq!(send.label, "lamp");
+        drop(send);
+        let idle = allocator.alloc(Tagged{ label: "", on: false, mode: Mode::Idle });
+        fill(&allocator);
+        assert_eq!(*Pointer::sibling(&idle, tagged), tagged);
+        assert_eq!(*Pointer::map(idle, |t| Tagged{ on: !t.on, ..t }), Tagged{ label: "", on: true, mode: Mode::Idle });
+        fill(&allocator);
+        assert_eq!(*allocator.alloc_default::<(bool, Option<&'static str>)>(), (false, None));
+
+        let fixed = ::StaticAllocator::<4096>::new();
+        drop(fixed.alloc([0xa5u8; 24]).unwrap());
+        assert_eq!(*fixed.alloc(tagged).unwrap(), tagged);
+    }
+
+    #[test]
+    #[cfg_attr(target_family = "wasm", ignore = "no threads on wasm")]
+    fn test_free_slots() {
+        let mut allocator = AllocatorBuilder::new().only_classes(&[8, 64]).capacity_for(64, 10).build();
+        assert_eq!((allocator.free_slots(64), allocator.free_slots(33)), (10, 10));
+        // a missing class counts the next one, no class counts nothing
+        assert_eq!((allocator.free_slots(16), allocator.free_slots(257)), (10, 0));
+        let mut ptrs: Vec<_> = (0..4u8).map(|i| allocator.alloc([i; 64])).collect();
+        assert_eq!(allocator.free_slots(64), 6);
+        assert!(allocator.can_allocate_without_growth::<[u8; 64]>(6));
+        assert!(!allocator.can_allocate_without_growth::<[u8; 64]>(7));
+        assert!(!allocator.can_allocate_without_growth::<[u8; 300]>(0));
+
+        // a new page adds its slots
+        ptrs.extend((0..7u8).map(|i| allocator.alloc([i; 64])));
+        assert_eq!(allocator.free_slots(64), 9);
+        ptrs.truncate(2);
+        assert_eq!(allocator.free_slots(64), 18);
+        let block = allocator.alloc_contiguous(3 * 64).unwrap();
+        assert_eq!(allocator.free_slots(64), 18 - 3);
+        drop(block);
+        assert_eq!(allocator.free_slots(64), allocator.pool64.free_count());
+        drop(ptrs);
+        assert_eq!(allocator.free_slots(64), 20);
+
+        let send = allocator.alloc_send([1u8; 64]);
+        std::thread::scope(|s| {
+            s.spawn(move || drop(send));
+        });
+        // freed on another thread, counted once drained
+        assert_eq!(allocator.free_slots(64), 19);
+        allocator.drain_remote_frees();
+        assert_eq!(allocator.free_slots(64), 20);
+        let _ = allocator.alloc_ref(1u64);
+        allocator.reset();
+        assert_eq!(allocator.free_slots(8), allocator.pool8.free_count());
     }
 }
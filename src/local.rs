@@ -0,0 +1,181 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Thread-local allocators declared by `pool!`, and pointers owning
+//! a share of their allocator.
+
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::ptr::NonNull;
+use std::rc::Rc;
+
+use {Allocator, Node};
+
+/// Declare thread-local allocators, each lazily initialized by its
+/// expression the first time it is accessed on a thread.
+///
+/// `pool!(static NAME: Allocator = expr;)` expands to a
+/// `thread_local!` of `Rc<Allocator>`, accessed with `NAME.with(|a| ..)`.
+/// Every thread gets its own allocator. It is dropped when the thread
+/// exits, once every `OwnedPointer` allocated from it is dropped too;
+/// accessing it from the destructor of another thread-local after that
+/// panics, as for any `thread_local!`.
+///
+/// ```rust
+/// #[macro_use]
+/// extern crate aren_alloc;
+/// use aren_alloc::AllocatorBuilder;
+///
+/// pool!(static FRAME_POOL: Allocator = AllocatorBuilder::new().capacity_for(32, 8192).build(););
+///
+/// fn main() {
+///     let p = alloc_in!(FRAME_POOL, [1u64; 4]);
+///     assert_eq!(*p, [1; 4]);
+///     # #[cfg(feature = "stats")]
+///     FRAME_POOL.with(|a| assert_eq!(a.stats().live, 1));
+/// }
+/// ```
+#[macro_export]
+macro_rules! pool {
+    () => {};
+    ($(#[$attr:meta])* $vis:vis static $name:ident: Allocator = $init:expr; $($rest:tt)*) => {
+        thread_local!($(#[$attr])* $vis static $name: ::std::rc::Rc<$crate::Allocator> = ::std::rc::Rc::new($init));
+        pool!($($rest)*);
+    };
+}
+
+/// Move a value into the thread-local allocator declared by `pool!`,
+/// returning an `OwnedPointer` that may escape the `with` closure.
+#[macro_export]
+macro_rules! alloc_in {
+    ($pool:expr, $value:expr) => {
+        $pool.with(|allocator| $crate::OwnedPointer::new_in($value, allocator))
+    };
+}
+
+/// A pointer into a slot of a shared `Allocator`, keeping the allocator
+/// alive for as long as the pointer is.
+pub struct OwnedPointer<T> {
+    allocator: Rc<Allocator>,
+    node: NonNull<T>,
+}
+
+impl<T> OwnedPointer<T> {
+    /// Move `value` into a slot of `allocator`.
+    /// `size_of::<T>()` should be le to 256 bytes.
+    ///
+    /// # Panics
+    ///
+    /// Panics as `Allocator::alloc` does.
+    #[inline]
+    #[track_caller]
+    pub fn new_in(value: T, allocator: &Rc<Allocator>) -> OwnedPointer<T> {
+        let ptr = allocator.put(value);
+        let node = ptr.node;
+        std::mem::forget(ptr);
+        OwnedPointer{ allocator: allocator.clone(), node }
+    }
+
+    /// Get the allocator of `ptr`.
+    /// This is an associated function so that
+    /// `T`'s methods won't be shadowed.
+    #[inline]
+    pub fn allocator(ptr: &Self) -> &Rc<Allocator> {
+        &ptr.allocator
+    }
+}
+
+impl<T> Deref for OwnedPointer<T> {
+    type Target = T;
+    #[inline]
+    fn deref(&self) -> &T {
+        unsafe { self.node.as_ref() }
+    }
+}
+
+impl<T> DerefMut for OwnedPointer<T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.node.as_mut() }
+    }
+}
+
+impl<T> Drop for OwnedPointer<T> {
+    fn drop(&mut self) {
+        unsafe {
+            std::ptr::drop_in_place(self.node.as_ptr());
+            self.allocator.pool_for::<T>().recycle(self.node.as_ptr() as *mut Node);
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for OwnedPointer<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {AllocatorBuilder, DEFAULT_POOL_SIZE};
+
+    pool! {
+        static SMALL_POOL: Allocator = AllocatorBuilder::new().capacity_for(8, 2).build();
+        static DEFAULT_POOL: Allocator = Allocator::new();
+    }
+
+    #[test]
+    #[cfg_attr(target_family = "wasm", ignore = "no threads on wasm")]
+    fn test_two_threads() {
+        let main = alloc_in!(SMALL_POOL, 1u64);
+        let addr = &*main as *const u64 as usize;
+        let other = std::thread::spawn(move || {
+            let ptrs: Vec<_> = (0..3).map(|i| alloc_in!(SMALL_POOL, i as u64)).collect();
+            #[cfg(feature = "stats")]
+            let stats = SMALL_POOL.with(|a| a.stats());
+            #[cfg(feature = "stats")]
+            assert_eq!((stats.live, stats.allocs), (3, 3));
+            assert!(ptrs.iter().all(|p| &**p as *const u64 as usize != addr));
+            ptrs.iter().map(|p| **p).sum::<u64>()
+        }).join().unwrap();
+        assert_eq!(other, 3);
+
+        #[cfg(feature = "stats")]
+        let stats = SMALL_POOL.with(|a| a.stats());
+        #[cfg(feature = "stats")]
+        assert_eq!((stats.live, stats.allocs, stats.slots), (1, 1, 2 + [16, 32, 64, 128, 256].iter().map(|c| DEFAULT_POOL_SIZE / c).sum::<usize>()));
+        assert_eq!(*main, 1);
+    }
+
+    #[test]
+    fn test_outlives_with() {
+        let escaped = DEFAULT_POOL.with(|a| {
+            let p = OwnedPointer::new_in(String::from("escaped"), a);
+            assert_eq!(Rc::strong_count(a), 2);
+            p
+        });
+        assert_eq!(*escaped, "escaped");
+        assert_eq!(Rc::strong_count(OwnedPointer::allocator(&escaped)), 2);
+        drop(escaped);
+        #[cfg(feature = "stats")]
+        DEFAULT_POOL.with(|a| assert_eq!((Rc::strong_count(a), a.stats().live), (1, 0)));
+    }
+
+    #[test]
+    #[should_panic(expected = "allocation budget exhausted")]
+    fn test_new_in_budget() {
+        let allocator = Rc::new(AllocatorBuilder::new().capacity_for(256, 4).budget(5 * DEFAULT_POOL_SIZE + 4 * 256).build());
+        let ptrs: Vec<_> = (0..4).map(|i| OwnedPointer::new_in([i as u8; 200], &allocator)).collect();
+        // counted as requests, as those of `alloc`
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.size_histogram().total(), 4);
+        assert_eq!(ptrs[3][0], 3);
+        OwnedPointer::new_in([4u8; 200], &allocator);
+    }
+}
@@ -0,0 +1,318 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A pool whose free list is a lock-free Treiber stack.
+//!
+//! Popping from a Treiber stack suffers from the ABA problem: between
+//! a thread reading `head` and `head.next`, other threads might pop
+//! `head` and `head.next`, then push `head` back, making the stale
+//! `head.next` get installed as the new head. To detect this, a tag
+//! bumped on every successful update is packed alongside the head in
+//! the same 64-bit word. ABA then goes undetected only if the tag, of
+//! 32 bits, wraps around between the two reads of a single pop.
+//!
+//! The stack is one of slot indices, not addresses, and the links are
+//! kept apart from the slots, in a table of atomics per page: reading
+//! the link of a slot just popped by another thread then never races
+//! with that thread writing to the slot. The stale link read is
+//! discarded, as the tag has changed.
+//!
+//! Each page holds twice as many slots as the one before, so that the
+//! pages of any index or address are found among at most 32, without
+//! locking. Pages are never freed until the pool is.
+
+#[cfg(loom)]
+use loom::sync::atomic::{AtomicPtr, AtomicU32, AtomicU64, Ordering};
+#[cfg(loom)]
+use loom::sync::Mutex;
+#[cfg(not(loom))]
+use std::sync::atomic::{AtomicPtr, AtomicU32, AtomicU64, Ordering};
+#[cfg(not(loom))]
+use std::sync::Mutex;
+
+use Page;
+
+/// The most pages of a pool, enough for as many slots as indices.
+const MAX_PAGES: usize = 32;
+
+/// A link or head, as the index of a slot plus one, or 0 for none.
+type Link = u32;
+
+#[inline]
+fn pack(head: Link, tag: u32) -> u64 {
+    ((tag as u64) << 32) | head as u64
+}
+
+#[inline]
+fn unpack(word: u64) -> (Link, u32) {
+    (word as u32, (word >> 32) as u32)
+}
+
+/// A page of the pool, and the links of its slots.
+struct LinkedPage {
+    page: Page,
+    links: Box<[AtomicU32]>,
+    /// The index of the first slot of the page.
+    first: u32,
+}
+
+pub(crate) struct LockFreePool {
+    head: AtomicU64,
+    ele_size: usize,
+    /// Slots of the first page, doubled by every page after it.
+    num: usize,
+    pages: [AtomicPtr<LinkedPage>; MAX_PAGES],
+    /// The number of pages, locked while adding one.
+    page_count: Mutex<usize>,
+}
+
+unsafe impl Send for LockFreePool { }
+
+unsafe impl Sync for LockFreePool { }
+
+impl LockFreePool {
+    pub(crate) fn with_capacity(num: usize, ele_size: usize) -> LockFreePool {
+        debug_assert!(num>0);
+        debug_assert!(ele_size.is_power_of_two());
+        let pool = LockFreePool{
+            head: AtomicU64::new(pack(0, 0)),
+            ele_size,
+            num,
+            pages: std::array::from_fn(|_| AtomicPtr::new(std::ptr::null_mut())),
+            page_count: Mutex::new(0),
+        };
+        pool.extend();
+        pool
+    }
+
+    #[inline]
+    pub(crate) fn ele_size(&self) -> usize {
+        self.ele_size
+    }
+
+    /// The page of the slot of `link`, and the index of the slot in it.
+    ///
+    /// The page must have been published, by the release of the head
+    /// that `link` was read from, or a link of it.
+    #[inline]
+    fn locate(&self, link: Link) -> (&LinkedPage, usize) {
+        debug_assert!(link != 0);
+        let index = (link - 1) as usize;
+        // page `k` starts at the slot `num * (2^k - 1)`
+        let k = (index / self.num + 1).ilog2() as usize;
+        let page = unsafe { &*self.pages[k].load(Ordering::Acquire) };
+        (page, index - page.first as usize)
+    }
+
+    /// Pop a slot off the free list, growing the pool if it's empty.
+    pub(crate) fn alloc(&self) -> *mut u8 {
+        let mut word = self.head.load(Ordering::Acquire);
+        loop {
+            let (head, tag) = unpack(word);
+            if head == 0 {
+                self.extend();
+                word = self.head.load(Ordering::Acquire);
+                continue;
+            }
+            let (page, slot) = self.locate(head);
+            // if `head` is popped concurrently, the link read here may
+            // be stale, but then the tag has changed and the exchange fails
+            let next = page.links[slot].load(Ordering::Relaxed);
+            match self.head.compare_exchange_weak(
+                word, pack(next, tag.wrapping_add(1)), Ordering::Acquire, Ordering::Acquire) {
+                Ok(_) => return unsafe { page.page.as_ptr().add(slot * self.ele_size) },
+                Err(current) => word = current,
+            }
+        }
+    }
+
+    /// Push `node`, a slot handed out by the pool, back onto the free list.
+    pub(crate) unsafe fn recycle(&self, node: *mut u8) {
+        let addr = node.addr();
+        for k in 0..MAX_PAGES {
+            let page = self.pages[k].load(Ordering::Acquire);
+            debug_assert!(!page.is_null(), "slot not of the pool");
+            let page = &*page;
+            let offset = addr.wrapping_sub(page.page.as_ptr().addr());
+            if offset < page.page.len() {
+                let slot = offset / self.ele_size;
+                let link = page.first + slot as u32 + 1;
+                return self.push(link, &page.links[slot]);
+            }
+        }
+    }
+
+    /// Push the chain from `first` to the slot linked by `last` onto the
+    /// free list.
+    fn push(&self, first: Link, last: &AtomicU32) {
+        let mut word = self.head.load(Ordering::Relaxed);
+        loop {
+            let (head, tag) = unpack(word);
+            last.store(head, Ordering::Relaxed);
+            match self.head.compare_exchange_weak(
+                word, pack(first, tag.wrapping_add(1)), Ordering::Release, Ordering::Relaxed) {
+                Ok(_) => return,
+                Err(current) => word = current,
+            }
+        }
+    }
+
+    /// Add a new page, unless another thread has just refilled the free list.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pool would hold more than `u32::MAX - 1` slots.
+    fn extend(&self) {
+        let mut count = self.page_count.lock().unwrap_or_else(|e| e.into_inner());
+        if *count != 0 && unpack(self.head.load(Ordering::Acquire)).0 != 0 {
+            return;
+        }
+        let k = *count;
+        let first = self.num * ((1 << k) - 1);
+        let slots = self.num << k;
+        if first + slots >= u32::MAX as usize {
+            panic!("lock-free pool of {} bytes grown beyond {} slots", self.ele_size, u32::MAX - 1);
+        }
+        let first = first as u32;
+        // each slot linked to the next, the last one to none yet
+        let links: Box<[AtomicU32]> = (0..slots)
+            .map(|i| AtomicU32::new(if i + 1 < slots { first + i as u32 + 2 } else { 0 }))
+            .collect();
+        let page = Box::new(LinkedPage{ page: Page::new(slots * self.ele_size), links, first });
+        let page = Box::into_raw(page);
+        self.pages[k].store(page, Ordering::Release);
+        *count += 1;
+        self.push(first + 1, unsafe { &(*page).links[slots - 1] });
+    }
+
+    /// Count the free slots by walking the free list.
+    #[cfg(test)]
+    pub(crate) fn free_count(&self) -> usize {
+        let mut count = 0;
+        let mut link = unpack(self.head.load(Ordering::Acquire)).0;
+        while link != 0 {
+            count += 1;
+            let (page, slot) = self.locate(link);
+            link = page.links[slot].load(Ordering::Relaxed);
+        }
+        count
+    }
+
+    /// Count the slots of all pages.
+    #[cfg(test)]
+    pub(crate) fn slot_count(&self) -> usize {
+        let count = *self.page_count.lock().unwrap();
+        self.num * ((1 << count) - 1)
+    }
+}
+
+impl Drop for LockFreePool {
+    fn drop(&mut self) {
+        for page in self.pages.iter() {
+            let page = page.load(Ordering::Relaxed);
+            if !page.is_null() {
+                drop(unsafe { Box::from_raw(page) });
+            }
+        }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::thread;
+
+    #[test]
+    fn test_pages_double() {
+        let pool = LockFreePool::with_capacity(3, 8);
+        let held: Vec<*mut u8> = (0..3 + 6 + 12).map(|_| pool.alloc()).collect();
+        let slots: HashSet<usize> = held.iter().map(|p| p.addr()).collect();
+        assert_eq!((slots.len(), pool.slot_count(), pool.free_count()), (21, 21, 0));
+        // the slots of every index are found, and recycled
+        for slot in held {
+            unsafe { pool.recycle(slot) };
+        }
+        assert_eq!(pool.free_count(), 21);
+        let again: HashSet<usize> = (0..21).map(|_| pool.alloc().addr()).collect();
+        assert_eq!(again, slots);
+    }
+
+    #[test]
+    #[cfg_attr(target_family = "wasm", ignore = "no threads on wasm")]
+    fn test_stress() {
+        let pool = LockFreePool::with_capacity(16, 16);
+        // Miri runs the threads too slowly for the full count
+        let n = if cfg!(miri) { 200 } else { 5000usize };
+        thread::scope(|s| {
+            for t in 0..8usize {
+                let pool = &pool;
+                s.spawn(move || {
+                    let mut held = Vec::new();
+                    for i in 0..n {
+                        let slot = pool.alloc() as *mut [usize; 2];
+                        unsafe { *slot = [t, i] };
+                        held.push(slot);
+                        if i % 2 == 0 {
+                            let slot = held.swap_remove(i % held.len());
+                            unsafe { pool.recycle(slot as *mut u8) };
+                        }
+                    }
+                    for slot in held.iter() {
+                        assert_eq!(unsafe { (**slot)[0] }, t);
+                        unsafe { pool.recycle(*slot as *mut u8) };
+                    }
+                });
+            }
+        });
+        assert_eq!(pool.free_count(), pool.slot_count());
+
+        // no slot is handed out twice
+        let slots: HashSet<usize> = (0..pool.slot_count()).map(|_| pool.alloc() as usize).collect();
+        assert_eq!(slots.len(), pool.slot_count());
+    }
+}
+
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::*;
+    use loom::sync::Arc;
+    use loom::thread;
+
+    #[test]
+    fn test_concurrent_alloc_recycle() {
+        loom::model(|| {
+            let pool = Arc::new(LockFreePool::with_capacity(2, 8));
+            let handles: Vec<_> = (0..2).map(|_| {
+                let pool = pool.clone();
+                thread::spawn(move || {
+                    let slot = pool.alloc();
+                    unsafe { pool.recycle(slot) };
+                    pool.alloc() as usize
+                })
+            }).collect();
+            let slots: Vec<usize> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+            assert_ne!(slots[0], slots[1]);
+        });
+    }
+
+    #[test]
+    fn test_concurrent_extend() {
+        loom::model(|| {
+            let pool = Arc::new(LockFreePool::with_capacity(1, 8));
+            let first = pool.alloc() as usize;
+            let handles: Vec<_> = (0..2).map(|_| {
+                let pool = pool.clone();
+                thread::spawn(move || pool.alloc() as usize)
+            }).collect();
+            let slots: Vec<usize> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+            assert_ne!(slots[0], slots[1]);
+            assert!(slots.iter().all(|&s| s != first));
+        });
+    }
+}
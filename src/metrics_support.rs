@@ -0,0 +1,136 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Reporting the usage of an allocator through the `metrics` facade,
+//! enabled by the `metrics` feature.
+
+use metrics::{counter, describe_counter, describe_gauge, gauge, Unit};
+
+use Allocator;
+
+impl Allocator {
+    /// Register the metrics of the allocator with the installed `metrics`
+    /// recorder, named after `prefix`, and report them a first time.
+    /// They are updated by `report_metrics`, typically once per frame.
+    /// The names are stable, for a `prefix` of `aren_alloc`:
+    ///
+    /// - `aren_alloc.class_<n>.live`, gauge: slots of the `n` bytes
+    ///   class currently handed out.
+    /// - `aren_alloc.class_<n>.slots`, gauge: slots of all pages of the
+    ///   class.
+    /// - `aren_alloc.class_<n>.allocs`, counter: slots of the class
+    ///   handed out so far. Its rate is the allocations per second.
+    /// - `aren_alloc.pages`, gauge: pages of all classes.
+    /// - `aren_alloc.bytes_reserved`, gauge: bytes of all pages.
+    ///
+    /// `n` is one of 8, 16, 32, 64, 128 and 256. Installing again
+    /// replaces the prefix.
+    pub fn install_metrics(&self, prefix: &str) {
+        for pool in self.pools() {
+            let class = format!("{}.class_{}", prefix, pool.ele_size);
+            describe_gauge!(format!("{}.live", class), Unit::Count,
+                            format!("Slots of {} bytes handed out", pool.ele_size));
+            describe_gauge!(format!("{}.slots", class), Unit::Count,
+                            format!("Slots of {} bytes of all pages", pool.ele_size));
+            describe_counter!(format!("{}.allocs", class), Unit::Count,
+                              format!("Slots of {} bytes handed out so far", pool.ele_size));
+        }
+        describe_gauge!(format!("{}.pages", prefix), Unit::Count, "Pages of all classes");
+        describe_gauge!(format!("{}.bytes_reserved", prefix), Unit::Bytes, "Bytes of all pages");
+        *self.metrics_prefix.borrow_mut() = Some(prefix.to_string());
+        self.report_metrics();
+    }
+
+    /// Record the current usage of the allocator into the metrics
+    /// registered by `install_metrics`, or do nothing if they weren't.
+    pub fn report_metrics(&self) {
+        let prefix = self.metrics_prefix.borrow();
+        let prefix = match *prefix {
+            Some(ref prefix) => prefix,
+            None => return,
+        };
+        for pool in self.pools() {
+            let stats = pool.stats();
+            let class = format!("{}.class_{}", prefix, pool.ele_size);
+            gauge!(format!("{}.live", class)).set(stats.live as f64);
+            gauge!(format!("{}.slots", class)).set(stats.slots as f64);
+            counter!(format!("{}.allocs", class)).absolute(stats.allocs as u64);
+        }
+        let pages = self.pages();
+        gauge!(format!("{}.pages", prefix)).set(pages.len() as f64);
+        let bytes: usize = pages.iter().map(|page| page.bytes).sum();
+        gauge!(format!("{}.bytes_reserved", prefix)).set(bytes as f64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metrics::with_local_recorder;
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder, Snapshotter};
+    use metrics_util::MetricKind;
+    use std::collections::HashMap;
+
+    /// The values of the gauges and counters reported since the last
+    /// snapshot, by name.
+    fn values(snapshotter: &Snapshotter) -> (HashMap<String, f64>, HashMap<String, u64>) {
+        let (mut gauges, mut counters) = (HashMap::new(), HashMap::new());
+        for (key, _, _, value) in snapshotter.snapshot().into_vec() {
+            let name = key.key().name().to_string();
+            match (key.kind(), value) {
+                (MetricKind::Gauge, DebugValue::Gauge(value)) => { gauges.insert(name, value.into_inner()); }
+                (MetricKind::Counter, DebugValue::Counter(value)) => { counters.insert(name, value); }
+                _ => unreachable!(),
+            }
+        }
+        (gauges, counters)
+    }
+
+    #[test]
+    fn test_report_metrics() {
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        let allocator = Allocator::with_capacity(4);
+        with_local_recorder(&recorder, || {
+            // nothing is reported before installing
+            allocator.report_metrics();
+            assert!(snapshotter.snapshot().into_vec().is_empty());
+
+            allocator.install_metrics("aren_alloc");
+            let (gauges, counters) = values(&snapshotter);
+            assert_eq!((gauges.len(), counters.len()), (6 * 2 + 2, 6));
+            assert_eq!(gauges["aren_alloc.pages"], 6.0);
+            assert_eq!(gauges["aren_alloc.bytes_reserved"], (4 * 504) as f64);
+
+            // a frame: 5 values of 32 bytes, 1 of 8, then 3 freed
+            let mut held: Vec<_> = (0..5).map(|i| allocator.alloc([i as u8; 20])).collect();
+            let _small = allocator.alloc(1u64);
+            held.truncate(2);
+            allocator.report_metrics();
+            let (gauges, counters) = values(&snapshotter);
+            assert_eq!(gauges["aren_alloc.class_32.live"], 2.0);
+            assert_eq!(gauges["aren_alloc.class_32.slots"], 8.0);
+            assert_eq!(counters["aren_alloc.class_32.allocs"], 5);
+            assert_eq!(gauges["aren_alloc.class_8.live"], 1.0);
+            assert_eq!(gauges["aren_alloc.class_256.live"], 0.0);
+            assert_eq!(gauges["aren_alloc.pages"], 7.0);
+            assert_eq!(gauges["aren_alloc.bytes_reserved"], (4 * 504 + 4 * 32) as f64);
+
+            drop(held);
+            allocator.install_metrics("game.frame");
+            let (gauges, counters) = values(&snapshotter);
+            assert_eq!(gauges["game.frame.class_32.live"], 0.0);
+            assert_eq!(counters["game.frame.class_32.allocs"], 5);
+        });
+        // described once installed
+        let described = snapshotter.snapshot().into_vec().into_iter()
+            .find(|(key, ..)| key.key().name() == "game.frame.bytes_reserved")
+            .map(|(_, unit, desc, _)| (unit, desc.map(|d| d.to_string())));
+        assert_eq!(described, Some((Some(Unit::Bytes), Some("Bytes of all pages".to_string()))));
+    }
+}
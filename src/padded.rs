@@ -0,0 +1,45 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Keeping state touched by different threads on different cache lines.
+
+use std::ops::Deref;
+
+/// Size of a cache line, assumed to be 64 bytes.
+pub(crate) const CACHE_LINE: usize = 64;
+
+/// A `T` alone on its cache lines, so that threads hammering a
+/// neighbour don't invalidate it.
+#[repr(align(64))]
+pub(crate) struct CachePadded<T>(pub(crate) T);
+
+impl<T> Deref for CachePadded<T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use SyncAllocator;
+
+    #[test]
+    fn test_padding() {
+        assert_eq!(std::mem::align_of::<CachePadded<u8>>(), CACHE_LINE);
+        assert_eq!(std::mem::size_of::<CachePadded<[u8; 65]>>(), 2 * CACHE_LINE);
+
+        let allocator = SyncAllocator::lock_free();
+        let (a, b) = (allocator.alloc(1u64), allocator.alloc([2u64; 2]));
+        let (a, b) = (&*a as *const u64 as usize, &*b as *const [u64; 2] as usize);
+        assert!(a.is_multiple_of(CACHE_LINE) && b.is_multiple_of(CACHE_LINE));
+    }
+}
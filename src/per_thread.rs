@@ -0,0 +1,125 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A lazily initialized `Allocator` for every thread, e.g. the workers
+//! of a thread pool.
+//!
+//! The allocator is only ever lent to a closure, so pointers allocated
+//! from it can't outlive the call, let alone escape the thread:
+//!
+//! ```rust,compile_fail
+//! use aren_alloc::per_thread;
+//! let escaped = per_thread::with(|allocator| allocator.alloc(1u32));
+//! ```
+
+use std::sync::RwLock;
+#[cfg(feature = "stats")]
+use std::sync::{Arc, Mutex, Weak};
+
+use Allocator;
+#[cfg(feature = "stats")]
+use Stats;
+
+static TEMPLATE: RwLock<Option<fn() -> Allocator>> = RwLock::new(None);
+
+#[cfg(feature = "stats")]
+static REGISTRY: Mutex<Vec<Weak<Mutex<Stats>>>> = Mutex::new(Vec::new());
+
+struct Local {
+    allocator: Allocator,
+    #[cfg(feature = "stats")]
+    published: Arc<Mutex<Stats>>,
+}
+
+impl Local {
+    fn new() -> Local {
+        let init = TEMPLATE.read().unwrap_or_else(|e| e.into_inner()).unwrap_or(Allocator::new);
+        #[cfg(feature = "stats")]
+        let published = {
+            let published = Arc::new(Mutex::new(Stats::default()));
+            let mut registry = REGISTRY.lock().unwrap_or_else(|e| e.into_inner());
+            registry.retain(|stats| stats.strong_count() > 0);
+            registry.push(Arc::downgrade(&published));
+            published
+        };
+        Local{
+            allocator: init(),
+            #[cfg(feature = "stats")]
+            published,
+        }
+    }
+}
+
+thread_local! {
+    static LOCAL: Local = Local::new();
+}
+
+/// Set the constructor of the allocators of threads calling `with`
+/// for the first time afterwards. `Allocator::new` is used by default.
+pub fn set_template(init: fn() -> Allocator) {
+    *TEMPLATE.write().unwrap_or_else(|e| e.into_inner()) = Some(init);
+}
+
+/// Call `f` with the current thread's allocator, constructing it first
+/// if needed.
+///
+/// The allocator's statistics are published for `stats_all` on return.
+pub fn with<R, F: FnOnce(&Allocator) -> R>(f: F) -> R {
+    LOCAL.with(|local| {
+        let ret = f(&local.allocator);
+        #[cfg(feature = "stats")]
+        {
+            *local.published.lock().unwrap_or_else(|e| e.into_inner()) = local.allocator.stats();
+        }
+        ret
+    })
+}
+
+/// Add up the statistics of the allocators of all live threads,
+/// as of their last return from `with`.
+#[cfg(feature = "stats")]
+pub fn stats_all() -> Stats {
+    let registry = REGISTRY.lock().unwrap_or_else(|e| e.into_inner());
+    registry.iter()
+        .filter_map(|stats| stats.upgrade())
+        .map(|stats| *stats.lock().unwrap_or_else(|e| e.into_inner()))
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rayon::prelude::*;
+
+    #[test]
+    // rayon's crossbeam-epoch casts integers to pointers, which Miri's
+    // strict provenance rejects
+    #[cfg_attr(miri, ignore)]
+    #[cfg_attr(target_family = "wasm", ignore = "no threads on wasm")]
+    fn test_par_iter() {
+        set_template(|| Allocator::with_capacity(8));
+        let sums: u64 = (0..10_000u64).into_par_iter().map(|i| {
+            with(|allocator| {
+                let a = allocator.alloc([i; 4]);
+                let b = allocator.alloc(i as u8);
+                with(|same| assert_eq!(same as *const Allocator, allocator as *const Allocator));
+                a.iter().sum::<u64>() + *b as u64
+            })
+        }).sum();
+        assert_eq!(sums, (0..10_000u64).map(|i| 4 * i + (i as u8) as u64).sum::<u64>());
+
+        #[cfg(feature = "stats")]
+        {
+            let stats = stats_all();
+            assert_eq!(stats.live, 0);
+            assert_eq!(stats.allocs, 20_000);
+            // each worker built its allocator from the template
+            assert_eq!(stats.slots % (6 * 8), 0);
+        }
+    }
+}
@@ -0,0 +1,171 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Keeping the slots of pinned values in place until they are dropped,
+//! even if that's never.
+
+use {Node, Page, Pool};
+
+impl Pool {
+    /// Remember that the `slots` slots from `addr` on are pinned, so
+    /// they must not be reused, nor freed, unless recycled.
+    pub(crate) fn pin(&self, addr: usize, slots: usize) {
+        if self.fixed {
+            panic!("can't pin a slot of a static allocator, which moves along with it");
+        }
+        if self.heap_align != 0 {
+            // a slot of the heap is only freed once recycled anyway
+            return;
+        }
+        let mut pinned = self.pinned.borrow_mut();
+        for slot in 0..slots {
+            let addr = addr + slot * self.ele_size;
+            let i = pinned.partition_point(|&a| a < addr);
+            pinned.insert(i, addr);
+        }
+    }
+
+    /// Forget that the slot at `addr` is pinned, as it's being recycled.
+    #[inline]
+    pub(crate) fn unpin(&self, addr: usize) {
+        let mut pinned = self.pinned.borrow_mut();
+        if let Ok(i) = pinned.binary_search(&addr) {
+            pinned.remove(i);
+        }
+    }
+
+    /// Link the slots of `page` in front of `next`, as `link` does, but
+    /// for the pinned ones, left untouched. Returns the first slot linked
+    /// and the count of slots linked.
+    ///
+    /// # Safety
+    ///
+    /// No slot of `page` may be in use but the pinned ones, nor on any
+    /// list.
+    pub(crate) unsafe fn link_unpinned(&self, page: &Page, next: *mut Node) -> (*mut Node, usize) {
+        let pinned = self.pinned.borrow();
+        let base = page.as_ptr();
+        #[allow(unused_mut)]
+        let mut order: Vec<usize> = (0..page.len() / self.ele_size)
+            .filter(|i| pinned.binary_search(&(base as usize + i * self.ele_size)).is_err())
+            .collect();
+        #[cfg(feature = "harden")]
+        if let Some(ref rng) = self.rng {
+            for i in (1..order.len()).rev() {
+                order.swap(i, rng.below(i + 1));
+            }
+        }
+        let mut head = next;
+        for &i in order.iter().rev() {
+            let slot = base.add(i * self.ele_size).cast::<Node>();
+            (*slot).next = head;
+            head = slot;
+        }
+        (head, order.len())
+    }
+
+    /// Count the pinned slots as live again, once `reset` reclaimed all
+    /// the others.
+    pub(crate) fn recount_pinned(&self) {
+        let pinned = self.pinned.borrow();
+        for &addr in pinned.iter() {
+            self.track_alloc(std::ptr::without_provenance_mut(addr));
+        }
+        #[cfg(feature = "stats")]
+        self.live.set(pinned.len());
+    }
+
+    /// Leak the pages of the chain holding pinned slots, so they aren't
+    /// freed when the pool is dropped.
+    pub(crate) fn leak_pinned(&self) {
+        let pinned = self.pinned.borrow();
+        if pinned.is_empty() {
+            return;
+        }
+        for p in self.chain() {
+            let (base, len) = (p.pool.borrow().as_ptr() as usize, p.pool.borrow().len());
+            let i = pinned.partition_point(|&a| a < base);
+            if pinned.get(i).is_some_and(|&a| a < base + len) {
+                std::mem::forget(p.pool.replace(Page::empty()));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::marker::PhantomPinned;
+    use std::pin::Pin;
+    use {Allocator, AllocatorBuilder, LeakPolicy, Pointer, StaticAllocator};
+
+    #[test]
+    fn test_pinned_outlives_allocator() {
+        let allocator = AllocatorBuilder::new().leak_policy(LeakPolicy::Ignore).build();
+        let pinned = allocator.alloc_pinned((PhantomPinned, 7u64));
+        let other = allocator.alloc_pinned((PhantomPinned, 8u64));
+        let addr = &pinned.1 as *const u64;
+        std::mem::forget(pinned);
+        drop(other);
+        drop(allocator);
+        // the page of the forgotten value was leaked, not freed
+        assert_eq!(unsafe { *addr }, 7);
+    }
+
+    #[test]
+    fn test_unpin_on_drop() {
+        let allocator = Allocator::new();
+        let pinned: Vec<_> = (0..10u64).map(|i| allocator.alloc_pinned((PhantomPinned, i))).collect();
+        let bytes = Pointer::into_pin(allocator.alloc_contiguous(300).unwrap());
+        assert_eq!(allocator.pool_for_size(8).unwrap().pinned.borrow().len(), 10);
+        assert_eq!(allocator.pool_for_size(16).unwrap().pinned.borrow().len(), 0);
+        assert_eq!(allocator.pool_for_size(256).unwrap().pinned.borrow().len(), 2);
+        drop(pinned);
+        drop(bytes);
+        assert!(allocator.all_pools().iter().all(|pool| pool.pinned.borrow().is_empty()));
+    }
+
+    #[test]
+    fn test_pinned_survives_reset() {
+        let mut allocator = AllocatorBuilder::new().capacity_for(8, 4).leak_policy(LeakPolicy::Ignore).build();
+        survive_reset(&mut allocator);
+    }
+
+    #[test]
+    #[cfg(feature = "harden")]
+    fn test_pinned_survives_hardened_reset() {
+        let mut allocator = AllocatorBuilder::new().capacity_for(8, 4).leak_policy(LeakPolicy::Ignore).harden(true).build();
+        survive_reset(&mut allocator);
+    }
+
+    fn survive_reset(allocator: &mut Allocator) {
+        let pinned = allocator.alloc_pinned((PhantomPinned, 7u64));
+        let addr = &pinned.1 as *const u64;
+        std::mem::forget(pinned);
+        drop(allocator.alloc_pinned((PhantomPinned, 8u64)));
+        allocator.reset();
+        assert_eq!(allocator.pool8.free_count(), 3);
+        assert_eq!(allocator.pool8.tracked_live(), 1);
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 1);
+        // the slot of the forgotten value is never handed out again
+        let ptrs: Vec<_> = (0..8u64).map(|i| allocator.alloc(i)).collect();
+        assert!(ptrs.iter().all(|ptr| !std::ptr::eq(&**ptr, addr)));
+        assert_eq!(unsafe { *addr }, 7);
+        drop(ptrs);
+        allocator.reset();
+        assert_eq!(allocator.pool8.free_count(), allocator.pool8.slot_count() - 1);
+        assert_eq!(unsafe { *addr }, 7);
+    }
+
+    #[test]
+    #[should_panic(expected = "can't pin a slot of a static allocator")]
+    fn test_pin_static() {
+        let allocator = StaticAllocator::<4096>::new();
+        let _: Pin<_> = Pointer::into_pin(allocator.alloc(1u64).unwrap());
+    }
+}
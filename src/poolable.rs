@@ -0,0 +1,102 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Types known to fit in the pools.
+
+use {Allocator, Pointer};
+
+/// A type known to fit in the largest size class, derivable with
+/// `#[derive(Poolable)]` under the `derive` feature.
+///
+/// The derive checks the size at compile time for non-generic types.
+/// Otherwise the size is checked whenever `alloc_into` is instantiated,
+/// still at compile time.
+///
+/// ```rust
+/// use aren_alloc::{Allocator, Poolable};
+///
+/// struct Command {
+///     id: u32,
+///     name: String,
+/// }
+/// impl Poolable for Command { }
+///
+/// let allocator = Allocator::new();
+/// let cmd = Command{ id: 1, name: "draw".into() }.alloc_into(&allocator);
+/// assert_eq!(Command::SIZE_CLASS, 32);
+/// assert_eq!((cmd.id, &*cmd.name), (1, "draw"));
+/// ```
+pub trait Poolable: Sized {
+    /// Size of the slots holding a `Self`.
+    const SIZE_CLASS: usize = size_class(std::mem::size_of::<Self>());
+
+    /// Move `self` into a slot of `allocator`.
+    #[inline]
+    #[track_caller]
+    fn alloc_into(self, allocator: &Allocator) -> Pointer<'_, Self> {
+        let _ = const { Self::SIZE_CLASS };
+        allocator.pool_for::<Self>().put(self)
+    }
+}
+
+/// Assert that `T` fits in the largest size class of 256 bytes, failing
+/// to compile when evaluated in a constant, where `Allocator::alloc`
+/// would only panic once called.
+///
+/// ```rust
+/// use aren_alloc::assert_poolable;
+///
+/// struct Message([u8; 200]);
+/// const _: () = assert_poolable::<Message>();
+/// ```
+#[inline]
+pub const fn assert_poolable<T>() {
+    size_class(std::mem::size_of::<T>());
+}
+
+const fn size_class(size: usize) -> usize {
+    assert!(size <= 256, "Poolable type is larger than 256 bytes, the largest size class");
+    let mut class = 8;
+    while class < size {
+        class *= 2;
+    }
+    class
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+
+    struct Big([u8; 256]);
+    impl Poolable for Big { }
+
+    #[test]
+    fn test_size_class() {
+        assert_eq!(<()>::SIZE_CLASS, 8);
+        assert_eq!(<[u8; 9]>::SIZE_CLASS, 16);
+        assert_eq!(Big::SIZE_CLASS, 256);
+
+        let allocator = Allocator::new();
+        let big = Big([1; 256]).alloc_into(&allocator);
+        assert_eq!(big.0[255], 1);
+        let marker = Rc::new(());
+        let ptr = marker.clone().alloc_into(&allocator);
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 2);
+        drop(ptr);
+        assert_eq!(Rc::strong_count(&marker), 1);
+    }
+
+    const _: () = assert_poolable::<Big>();
+    const _: () = assert_poolable::<[u64; 32]>();
+
+    impl Poolable for () { }
+    impl Poolable for [u8; 9] { }
+    impl Poolable for Rc<()> { }
+}
@@ -0,0 +1,555 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A doubly-linked list whose nodes are pool slots.
+
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem::MaybeUninit;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use {check_slot, Allocator, Node, Pool};
+
+static LIST_IDS: AtomicUsize = AtomicUsize::new(ID_STEP);
+
+#[repr(C)]
+struct ListNode<T> {
+    prev: *mut ListNode<T>,
+    next: *mut ListNode<T>,
+    /// The id of the list owning the node, and the `LINKED` and
+    /// `HANDLED` flags in the low bits.
+    state: usize,
+    value: MaybeUninit<T>,
+}
+
+/// The node is still in the list, and its value initialized.
+const LINKED: usize = 1;
+/// A `NodeHandle` to the node is still alive.
+const HANDLED: usize = 2;
+/// List ids are multiples of this, leaving room for the flags.
+const ID_STEP: usize = 4;
+
+impl<T> ListNode<T> {
+    #[inline]
+    fn is(&self, flag: usize) -> bool {
+        self.state & flag != 0
+    }
+
+    #[inline]
+    fn unset(&mut self, flag: usize) {
+        self.state &= !flag;
+    }
+}
+
+/// A doubly-linked list of `T`, each node of which takes exactly one
+/// slot of an `Allocator`, links and payload included.
+///
+/// Pushing returns a `NodeHandle`, with which the node could later be
+/// removed or moved in O(1), e.g. to maintain an LRU order.
+pub struct LinkedList<'a, T> {
+    pool: &'a Pool,
+    id: usize,
+    head: *mut ListNode<T>,
+    tail: *mut ListNode<T>,
+    len: usize,
+    _marker: PhantomData<T>,
+}
+
+/// A handle to a node of a `LinkedList`.
+///
+/// A node is only recycled once it has been removed from the list and
+/// its handle dropped, so a handle never refers to a freed slot. Using
+/// a handle whose node has already been removed, through `remove`,
+/// popping or dropping the list, is detected and has no effect.
+pub struct NodeHandle<'a, T> {
+    pool: &'a Pool,
+    node: *mut ListNode<T>,
+}
+
+impl<'a, T> LinkedList<'a, T> {
+    /// Construct an empty list allocating its nodes from `allocator`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a node, i.e. `T` and 3 words, is larger than 256 bytes.
+    #[inline]
+    pub fn new_in(allocator: &'a Allocator) -> LinkedList<'a, T> {
+        LinkedList{
+            pool: allocator.pool_for::<ListNode<T>>(),
+            id: LIST_IDS.fetch_add(ID_STEP, Ordering::Relaxed),
+            head: std::ptr::null_mut(),
+            tail: std::ptr::null_mut(),
+            len: 0,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Number of elements in the list.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the list is empty.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Get the first element, if any.
+    #[inline]
+    pub fn front(&self) -> Option<&T> {
+        unsafe { self.head.as_ref().map(|node| node.value.assume_init_ref()) }
+    }
+
+    /// Get the last element, if any.
+    #[inline]
+    pub fn back(&self) -> Option<&T> {
+        unsafe { self.tail.as_ref().map(|node| node.value.assume_init_ref()) }
+    }
+
+    /// Insert `value` at the front of the list.
+    pub fn push_front(&mut self, value: T) -> NodeHandle<'a, T> {
+        let node = self.alloc_node(value);
+        unsafe {
+            self.link_front(node);
+        }
+        NodeHandle{ pool: self.pool, node }
+    }
+
+    /// Insert `value` at the back of the list.
+    pub fn push_back(&mut self, value: T) -> NodeHandle<'a, T> {
+        let node = self.alloc_node(value);
+        unsafe {
+            (*node).prev = self.tail;
+            match self.tail.as_mut() {
+                Some(tail) => tail.next = node,
+                None => self.head = node,
+            }
+            self.tail = node;
+        }
+        self.len += 1;
+        NodeHandle{ pool: self.pool, node }
+    }
+
+    /// Remove the first element and return it, if any.
+    #[inline]
+    pub fn pop_front(&mut self) -> Option<T> {
+        let head = self.head;
+        if head.is_null() {
+            None
+        } else {
+            Some(unsafe { self.take(head) })
+        }
+    }
+
+    /// Remove the last element and return it, if any.
+    #[inline]
+    pub fn pop_back(&mut self) -> Option<T> {
+        let tail = self.tail;
+        if tail.is_null() {
+            None
+        } else {
+            Some(unsafe { self.take(tail) })
+        }
+    }
+
+    /// Whether `handle` refers to a node still in this list.
+    #[inline]
+    pub fn contains(&self, handle: &NodeHandle<'a, T>) -> bool {
+        unsafe { (*handle.node).state == self.id | LINKED | HANDLED }
+    }
+
+    /// Get the element of `handle`, if it's still in this list.
+    #[inline]
+    pub fn get(&self, handle: &NodeHandle<'a, T>) -> Option<&T> {
+        if self.contains(handle) {
+            Some(unsafe { (*handle.node).value.assume_init_ref() })
+        } else {
+            None
+        }
+    }
+
+    /// Get the element of `handle` mutably, if it's still in this list.
+    #[inline]
+    pub fn get_mut(&mut self, handle: &NodeHandle<'a, T>) -> Option<&mut T> {
+        if self.contains(handle) {
+            Some(unsafe { (*handle.node).value.assume_init_mut() })
+        } else {
+            None
+        }
+    }
+
+    /// Remove the element of `handle` and return it, if it's still
+    /// in this list.
+    #[inline]
+    pub fn remove(&mut self, handle: NodeHandle<'a, T>) -> Option<T> {
+        if self.contains(&handle) {
+            Some(unsafe { self.take(handle.node) })
+        } else {
+            None
+        }
+    }
+
+    /// Move the element of `handle` to the front of the list. Return
+    /// whether it's still in this list.
+    pub fn move_to_front(&mut self, handle: &NodeHandle<'a, T>) -> bool {
+        if !self.contains(handle) {
+            return false;
+        }
+        if self.head != handle.node {
+            unsafe {
+                self.unlink(handle.node);
+                self.link_front(handle.node);
+            }
+        }
+        true
+    }
+
+    /// Iterate over the elements front to back.
+    #[inline]
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter{ front: self.head, back: self.tail, len: self.len, _marker: PhantomData }
+    }
+
+    /// Iterate over the elements front to back mutably.
+    #[inline]
+    pub fn iter_mut(&mut self) -> IterMut<'_, T> {
+        IterMut{ front: self.head, back: self.tail, len: self.len, _marker: PhantomData }
+    }
+
+    /// Remove all elements.
+    pub fn clear(&mut self) {
+        while self.pop_front().is_some() { }
+    }
+
+    fn alloc_node(&self, value: T) -> *mut ListNode<T> {
+        check_slot::<ListNode<T>>(self.pool.ele_size);
+        let node = self.pool.pop() as *mut ListNode<T>;
+        unsafe {
+            node.write(ListNode{
+                prev: std::ptr::null_mut(),
+                next: std::ptr::null_mut(),
+                state: self.id | LINKED | HANDLED,
+                value: MaybeUninit::new(value),
+            });
+        }
+        node
+    }
+
+    unsafe fn link_front(&mut self, node: *mut ListNode<T>) {
+        (*node).prev = std::ptr::null_mut();
+        (*node).next = self.head;
+        match self.head.as_mut() {
+            Some(head) => head.prev = node,
+            None => self.tail = node,
+        }
+        self.head = node;
+        self.len += 1;
+    }
+
+    unsafe fn unlink(&mut self, node: *mut ListNode<T>) {
+        let ListNode{ prev, next, .. } = *node;
+        match prev.as_mut() {
+            Some(prev) => prev.next = next,
+            None => self.head = next,
+        }
+        match next.as_mut() {
+            Some(next) => next.prev = prev,
+            None => self.tail = prev,
+        }
+        self.len -= 1;
+    }
+
+    /// Unlink `node` and move its value out, recycling the node
+    /// unless its handle is still alive.
+    unsafe fn take(&mut self, node: *mut ListNode<T>) -> T {
+        self.unlink(node);
+        (*node).unset(LINKED);
+        let value = (*node).value.assume_init_read();
+        if !(*node).is(HANDLED) {
+            self.pool.recycle(node as *mut Node);
+        }
+        value
+    }
+}
+
+impl<'a, T> Drop for LinkedList<'a, T> {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+impl<'a, T: fmt::Debug> fmt::Debug for LinkedList<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl<'a, T> Extend<T> for LinkedList<'a, T> {
+    fn extend<I: IntoIterator<Item=T>>(&mut self, iter: I) {
+        for value in iter {
+            self.push_back(value);
+        }
+    }
+}
+
+impl<'a, 'b, T> IntoIterator for &'b LinkedList<'a, T> {
+    type Item = &'b T;
+    type IntoIter = Iter<'b, T>;
+    #[inline]
+    fn into_iter(self) -> Iter<'b, T> {
+        self.iter()
+    }
+}
+
+impl<'a, 'b, T> IntoIterator for &'b mut LinkedList<'a, T> {
+    type Item = &'b mut T;
+    type IntoIter = IterMut<'b, T>;
+    #[inline]
+    fn into_iter(self) -> IterMut<'b, T> {
+        self.iter_mut()
+    }
+}
+
+impl<'a, T> Drop for NodeHandle<'a, T> {
+    fn drop(&mut self) {
+        unsafe {
+            if (*self.node).is(LINKED) {
+                (*self.node).unset(HANDLED);
+            } else {
+                self.pool.recycle(self.node as *mut Node);
+            }
+        }
+    }
+}
+
+/// An iterator over the elements of a `LinkedList`.
+pub struct Iter<'b, T> {
+    front: *mut ListNode<T>,
+    back: *mut ListNode<T>,
+    len: usize,
+    _marker: PhantomData<&'b T>,
+}
+
+impl<'b, T> Iterator for Iter<'b, T> {
+    type Item = &'b T;
+    #[inline]
+    fn next(&mut self) -> Option<&'b T> {
+        if self.len == 0 {
+            return None;
+        }
+        self.len -= 1;
+        unsafe {
+            let node = &*self.front;
+            self.front = node.next;
+            Some(node.value.assume_init_ref())
+        }
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.len, Some(self.len))
+    }
+}
+
+impl<'b, T> DoubleEndedIterator for Iter<'b, T> {
+    #[inline]
+    fn next_back(&mut self) -> Option<&'b T> {
+        if self.len == 0 {
+            return None;
+        }
+        self.len -= 1;
+        unsafe {
+            let node = &*self.back;
+            self.back = node.prev;
+            Some(node.value.assume_init_ref())
+        }
+    }
+}
+
+impl<'b, T> ExactSizeIterator for Iter<'b, T> { }
+
+/// A mutable iterator over the elements of a `LinkedList`.
+pub struct IterMut<'b, T> {
+    front: *mut ListNode<T>,
+    back: *mut ListNode<T>,
+    len: usize,
+    _marker: PhantomData<&'b mut T>,
+}
+
+impl<'b, T> Iterator for IterMut<'b, T> {
+    type Item = &'b mut T;
+    #[inline]
+    fn next(&mut self) -> Option<&'b mut T> {
+        if self.len == 0 {
+            return None;
+        }
+        self.len -= 1;
+        unsafe {
+            let node = &mut *self.front;
+            self.front = node.next;
+            Some(node.value.assume_init_mut())
+        }
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.len, Some(self.len))
+    }
+}
+
+impl<'b, T> DoubleEndedIterator for IterMut<'b, T> {
+    #[inline]
+    fn next_back(&mut self) -> Option<&'b mut T> {
+        if self.len == 0 {
+            return None;
+        }
+        self.len -= 1;
+        unsafe {
+            let node = &mut *self.back;
+            self.back = node.prev;
+            Some(node.value.assume_init_mut())
+        }
+    }
+}
+
+impl<'b, T> ExactSizeIterator for IterMut<'b, T> { }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_build() {
+        let allocator = Allocator::new();
+        let mut list = LinkedList::new_in(&allocator);
+        list.push_back(2u64);
+        list.push_back(3);
+        list.push_front(1);
+        list.extend(4..6);
+        assert_eq!(list.len(), 5);
+        assert_eq!(list.iter().cloned().collect::<Vec<_>>(), vec![1, 2, 3, 4, 5]);
+        assert_eq!(list.iter().rev().cloned().collect::<Vec<_>>(), vec![5, 4, 3, 2, 1]);
+        for v in &mut list {
+            *v *= 10;
+        }
+        assert_eq!((list.front(), list.back()), (Some(&10), Some(&50)));
+        assert_eq!(format!("{:?}", list), "[10, 20, 30, 40, 50]");
+        // one slot per node, in the 32 bytes class
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.pool32.live.get(), 5);
+
+        assert_eq!(list.pop_front(), Some(10));
+        assert_eq!(list.pop_back(), Some(50));
+        assert_eq!(list.len(), 3);
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 3);
+    }
+
+    #[test]
+    fn test_remove_middle() {
+        let allocator = Allocator::new();
+        let mut list = LinkedList::new_in(&allocator);
+        let handles: Vec<_> = (0..5u32).map(|i| list.push_back(i)).collect();
+        let mut handles = handles.into_iter();
+        let first = handles.next().unwrap();
+        let second = handles.next().unwrap();
+        let third = handles.next().unwrap();
+        let last = handles.last().unwrap();
+
+        assert_eq!(list.remove(third), Some(2));
+        assert_eq!(list.iter().cloned().collect::<Vec<_>>(), vec![0, 1, 3, 4]);
+        assert_eq!(list.remove(first), Some(0));
+        assert_eq!(list.remove(last), Some(4));
+        assert_eq!(list.iter().cloned().collect::<Vec<_>>(), vec![1, 3]);
+        assert_eq!(list.iter().rev().cloned().collect::<Vec<_>>(), vec![3, 1]);
+        *list.get_mut(&second).unwrap() = 10;
+        assert_eq!(list.front(), Some(&10));
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 2);
+    }
+
+    #[test]
+    fn test_stale_handles() {
+        let allocator = Allocator::new();
+        let mut list = LinkedList::new_in(&allocator);
+        let mut other = LinkedList::new_in(&allocator);
+        let popped = list.push_back(1u32);
+        let foreign = other.push_back(2u32);
+        assert_eq!(list.pop_front(), Some(1));
+
+        // the node of a handle is only recycled once the handle is dropped
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 2);
+        assert!(!list.contains(&popped));
+        assert_eq!(list.get(&popped), None);
+        assert!(!list.move_to_front(&popped));
+        assert_eq!(list.remove(popped), None);
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 1);
+
+        assert_eq!(list.remove(foreign), None);
+        assert_eq!(other.len(), 1);
+
+        let outlived = other.push_back(3);
+        drop(other);
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 1);
+        drop(outlived);
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 0);
+    }
+
+    #[test]
+    fn test_lru() {
+        let allocator = Allocator::new();
+        let mut lru = LinkedList::new_in(&allocator);
+        let a = lru.push_front('a');
+        let b = lru.push_front('b');
+        let _c = lru.push_front('c');
+        assert!(lru.move_to_front(&a));
+        assert!(lru.move_to_front(&a));
+        assert_eq!(lru.iter().collect::<String>(), "acb");
+        assert!(lru.move_to_front(&b));
+        assert_eq!(lru.iter().collect::<String>(), "bac");
+        assert_eq!(lru.pop_back(), Some('c'));
+        assert_eq!(lru.iter().rev().collect::<String>(), "ab");
+    }
+
+    #[test]
+    fn test_drop_returns_slots() {
+        let allocator = Allocator::with_capacity(8);
+        let marker = Rc::new(());
+        let addrs: Vec<usize> = {
+            let mut list = LinkedList::new_in(&allocator);
+            for _ in 0..20 {
+                list.push_back(marker.clone());
+            }
+            assert_eq!(Rc::strong_count(&marker), 21);
+            list.iter().map(|v| v as *const Rc<()> as usize).collect()
+        };
+        assert_eq!(Rc::strong_count(&marker), 1);
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 0);
+
+        // the slots are reused afterwards, without growing further
+        #[cfg(feature = "stats")]
+        let slots = allocator.stats().slots;
+        let mut list = LinkedList::new_in(&allocator);
+        list.extend(0..20u64);
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().slots, slots);
+        let mut reused: Vec<usize> = list.iter().map(|v| v as *const u64 as usize).collect();
+        let mut addrs = addrs;
+        reused.sort();
+        addrs.sort();
+        assert_eq!(reused, addrs);
+    }
+}
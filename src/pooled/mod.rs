@@ -0,0 +1,34 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Collections whose storage lives in the pools of an `Allocator`.
+
+use std::error::Error;
+use std::fmt;
+
+mod vec;
+pub use self::vec::{IntoIter, PooledVec};
+
+mod string;
+pub use self::string::PooledString;
+
+mod list;
+pub use self::list::{Iter, IterMut, LinkedList, NodeHandle};
+
+/// The error returned when a collection would outgrow the largest
+/// size class.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CapacityError;
+
+impl fmt::Display for CapacityError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("capacity exceeds the largest size class")
+    }
+}
+
+impl Error for CapacityError { }
@@ -0,0 +1,279 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A small growable UTF-8 string stored in a single pool slot.
+
+use std::borrow::Borrow;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+
+use super::{CapacityError, PooledVec};
+use Allocator;
+
+/// A growable UTF-8 string, stored in a single slot of an `Allocator`.
+///
+/// It grows through the size classes the same way as `PooledVec`.
+/// Every push either appends the whole string or character, or fails,
+/// leaving the string as it was, so it never ends in the middle of a
+/// character.
+pub struct PooledString<'a> {
+    vec: PooledVec<'a, u8>,
+}
+
+impl<'a> PooledString<'a> {
+    /// Construct an empty string, without allocating until the first push.
+    #[inline]
+    pub fn new_in(allocator: &'a Allocator) -> PooledString<'a> {
+        PooledString{ vec: PooledVec::new_in(allocator) }
+    }
+
+    /// Construct an empty string that moves onto the heap when it
+    /// outgrows the largest size class.
+    #[inline]
+    pub fn with_heap_fallback(allocator: &'a Allocator) -> PooledString<'a> {
+        PooledString{ vec: PooledVec::with_heap_fallback(allocator) }
+    }
+
+    /// Construct a string holding a copy of `s`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `s` is longer than 256 bytes.
+    #[inline]
+    pub fn from_str_in(s: &str, allocator: &'a Allocator) -> PooledString<'a> {
+        let mut ret = PooledString::new_in(allocator);
+        ret.push_str(s);
+        ret
+    }
+
+    /// Length of the string in bytes.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.vec.len()
+    }
+
+    /// Whether the string is empty.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.vec.is_empty()
+    }
+
+    /// Number of bytes the string could hold without growing.
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.vec.capacity()
+    }
+
+    /// Append `s` to the end of the string.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the string would be longer than 256 bytes, and it has
+    /// no heap fallback.
+    #[inline]
+    pub fn push_str(&mut self, s: &str) {
+        if self.try_push_str(s).is_err() {
+            panic!("PooledString of {} bytes exceeds the largest size class", self.len() + s.len());
+        }
+    }
+
+    /// Append `s` to the end of the string, or fail if the string would
+    /// be longer than 256 bytes, and it has no heap fallback.
+    #[inline]
+    pub fn try_push_str(&mut self, s: &str) -> Result<(), CapacityError> {
+        self.vec.try_extend_from_slice(s.as_bytes())
+    }
+
+    /// Append `c` to the end of the string.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the string would be longer than 256 bytes, and it has
+    /// no heap fallback.
+    #[inline]
+    pub fn push(&mut self, c: char) {
+        self.push_str(c.encode_utf8(&mut [0; 4]));
+    }
+
+    /// Append `c` to the end of the string, or fail if the string would
+    /// be longer than 256 bytes, and it has no heap fallback.
+    #[inline]
+    pub fn try_push(&mut self, c: char) -> Result<(), CapacityError> {
+        self.try_push_str(c.encode_utf8(&mut [0; 4]))
+    }
+
+    /// Remove the last character and return it, if any.
+    #[inline]
+    pub fn pop(&mut self) -> Option<char> {
+        let c = self.as_str().chars().next_back()?;
+        let len = self.len() - c.len_utf8();
+        self.vec.truncate(len);
+        Some(c)
+    }
+
+    /// Remove all characters, keeping the storage.
+    #[inline]
+    pub fn clear(&mut self) {
+        self.vec.clear();
+    }
+
+    /// Get the string as a `&str`.
+    #[inline]
+    pub fn as_str(&self) -> &str {
+        // only ever appended whole strings
+        unsafe { std::str::from_utf8_unchecked(&self.vec) }
+    }
+
+    /// Get the string as a `&mut str`.
+    #[inline]
+    pub fn as_mut_str(&mut self) -> &mut str {
+        unsafe { std::str::from_utf8_unchecked_mut(&mut self.vec) }
+    }
+}
+
+impl<'a> Deref for PooledString<'a> {
+    type Target = str;
+    #[inline]
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl<'a> DerefMut for PooledString<'a> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut str {
+        self.as_mut_str()
+    }
+}
+
+impl<'a> AsRef<str> for PooledString<'a> {
+    #[inline]
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl<'a> Borrow<str> for PooledString<'a> {
+    #[inline]
+    fn borrow(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl<'a> fmt::Display for PooledString<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self.as_str(), f)
+    }
+}
+
+impl<'a> fmt::Debug for PooledString<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl<'a> PartialEq<str> for PooledString<'a> {
+    #[inline]
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl<'a, 'b> PartialEq<&'b str> for PooledString<'a> {
+    #[inline]
+    fn eq(&self, other: &&'b str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+impl<'a, 'b> From<&'b PooledString<'a>> for String {
+    #[inline]
+    fn from(s: &'b PooledString<'a>) -> String {
+        s.as_str().to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_push() {
+        let allocator = Allocator::new();
+        let mut s = PooledString::new_in(&allocator);
+        s.push_str("DEFINE");
+        s.push('_');
+        s.push_str("SHADOWS=1");
+        assert_eq!(s, "DEFINE_SHADOWS=1");
+        assert_eq!(s.capacity(), 16);
+        assert_eq!(format!("{}", s), "DEFINE_SHADOWS=1");
+        assert_eq!(format!("{:?}", s), "\"DEFINE_SHADOWS=1\"");
+        assert_eq!(String::from(&s), "DEFINE_SHADOWS=1");
+        assert!(s.ends_with("=1"));
+
+        assert_eq!(s.pop(), Some('1'));
+        s.make_ascii_lowercase();
+        assert_eq!(s.as_str(), "define_shadows=");
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 1);
+    }
+
+    #[test]
+    fn test_multi_byte_at_growth() {
+        let allocator = Allocator::new();
+        let mut s = PooledString::from_str_in("abcdefg", &allocator);
+        assert_eq!(s.capacity(), 8);
+        // 3 bytes, only 1 of which fits in the current slot
+        s.push('€');
+        assert_eq!(s.capacity(), 16);
+        assert_eq!(s, "abcdefg€");
+        s.push_str("ñandú🦀");
+        assert_eq!(s.capacity(), 32);
+        assert_eq!(s.len(), 21);
+        assert_eq!(s.pop(), Some('🦀'));
+        assert_eq!(s.pop(), Some('ú'));
+        assert_eq!(s, "abcdefg€ñand");
+        assert_eq!(s.chars().count(), 12);
+    }
+
+    #[test]
+    fn test_try_push_at_limit() {
+        let allocator = Allocator::new();
+        let mut s = PooledString::new_in(&allocator);
+        for _ in 0..255 {
+            s.push('x');
+        }
+        assert_eq!(s.try_push('ß'), Err(CapacityError));
+        assert_eq!(s.try_push_str("yz"), Err(CapacityError));
+        assert_eq!(s.len(), 255);
+        assert!(s.chars().all(|c| c == 'x'));
+        s.try_push('y').unwrap();
+        assert_eq!(s.len(), 256);
+        assert_eq!(s.try_push_str(""), Ok(()));
+    }
+
+    #[test]
+    #[should_panic(expected = "exceeds the largest size class")]
+    fn test_overflow_panics() {
+        let allocator = Allocator::new();
+        let mut s = PooledString::new_in(&allocator);
+        s.push_str(&"é".repeat(128));
+        s.push('a');
+    }
+
+    #[test]
+    fn test_heap_fallback() {
+        let allocator = Allocator::new();
+        let mut s = PooledString::with_heap_fallback(&allocator);
+        s.push_str(&"é".repeat(128));
+        s.push('😀');
+        assert_eq!(s.chars().count(), 129);
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 0);
+    }
+}
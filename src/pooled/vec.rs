@@ -0,0 +1,515 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A small growable vector stored in a single pool slot.
+
+use std::cmp;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+
+use super::CapacityError;
+use {check_slot, Allocator, Node, Pool};
+
+/// Number of elements the first slot of a vector should hold, if it fits.
+const MIN_ELEMENTS: usize = 4;
+
+enum Storage<'a, T> {
+    /// `len` elements in a slot of `cap` elements, or in no slot at all
+    /// if `pool` is `None`.
+    Pooled{ pool: Option<&'a Pool>, ptr: *mut T, cap: usize, len: usize },
+    Heap(Vec<T>),
+}
+
+/// A growable vector of `T`, stored in a single slot of an `Allocator`.
+///
+/// Whenever the slot is full, the elements are moved into a slot of
+/// the next size class up, and the old slot recycled. Growing past the
+/// largest class, i.e. 256 bytes, panics, unless the vector has been
+/// constructed by `with_heap_fallback`, in which case the elements are
+/// moved into a `Vec` instead.
+pub struct PooledVec<'a, T: Copy> {
+    allocator: &'a Allocator,
+    storage: Storage<'a, T>,
+    heap_fallback: bool,
+}
+
+impl<'a, T: Copy> PooledVec<'a, T> {
+    /// Construct an empty vector, without allocating until the first push.
+    #[inline]
+    pub fn new_in(allocator: &'a Allocator) -> PooledVec<'a, T> {
+        let cap = if std::mem::size_of::<T>() == 0 { usize::MAX } else { 0 };
+        PooledVec{
+            allocator,
+            storage: Storage::Pooled{ pool: None, ptr: std::ptr::NonNull::dangling().as_ptr(), cap, len: 0 },
+            heap_fallback: false,
+        }
+    }
+
+    /// Construct an empty vector that moves onto the heap when it
+    /// outgrows the largest size class.
+    #[inline]
+    pub fn with_heap_fallback(allocator: &'a Allocator) -> PooledVec<'a, T> {
+        let mut vec = PooledVec::new_in(allocator);
+        vec.heap_fallback = true;
+        vec
+    }
+
+    /// Number of elements in the vector.
+    #[inline]
+    pub fn len(&self) -> usize {
+        match self.storage {
+            Storage::Pooled{ len, .. } => len,
+            Storage::Heap(ref vec) => vec.len(),
+        }
+    }
+
+    /// Whether the vector is empty.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of elements the vector could hold without growing.
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        match self.storage {
+            Storage::Pooled{ cap, .. } => cap,
+            Storage::Heap(ref vec) => vec.capacity(),
+        }
+    }
+
+    /// Whether the elements have been moved onto the heap.
+    #[inline]
+    pub fn spilled(&self) -> bool {
+        match self.storage {
+            Storage::Pooled{ .. } => false,
+            Storage::Heap(_) => true,
+        }
+    }
+
+    /// Make room for at least `additional` more elements.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the elements would not fit in 256 bytes, and the vector
+    /// has no heap fallback.
+    #[inline]
+    pub fn reserve(&mut self, additional: usize) {
+        if self.try_reserve(additional).is_err() {
+            panic!("PooledVec of {} elements exceeds the largest size class", self.len().saturating_add(additional));
+        }
+    }
+
+    /// Make room for at least `additional` more elements, failing if
+    /// the elements would not fit in 256 bytes, and the vector has no
+    /// heap fallback.
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), CapacityError> {
+        match self.storage {
+            Storage::Pooled{ cap, len, .. } => {
+                let wanted = len.saturating_add(additional);
+                if wanted > cap {
+                    return self.grow(wanted);
+                }
+            }
+            Storage::Heap(ref mut vec) => vec.reserve(additional),
+        }
+        Ok(())
+    }
+
+    /// Append `value` to the back of the vector.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the elements would not fit in 256 bytes, and the vector
+    /// has no heap fallback.
+    #[inline]
+    pub fn push(&mut self, value: T) {
+        self.reserve(1);
+        match self.storage {
+            Storage::Pooled{ ptr, ref mut len, .. } => unsafe {
+                ptr.add(*len).write(value);
+                *len += 1;
+            },
+            Storage::Heap(ref mut vec) => vec.push(value),
+        }
+    }
+
+    /// Remove the last element and return it, if any.
+    #[inline]
+    pub fn pop(&mut self) -> Option<T> {
+        match self.storage {
+            Storage::Pooled{ ptr, ref mut len, .. } => {
+                if *len == 0 {
+                    None
+                } else {
+                    *len -= 1;
+                    Some(unsafe { ptr.add(*len).read() })
+                }
+            }
+            Storage::Heap(ref mut vec) => vec.pop(),
+        }
+    }
+
+    /// Append all elements of `other` to the back of the vector.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the elements would not fit in 256 bytes, and the vector
+    /// has no heap fallback.
+    #[inline]
+    pub fn extend_from_slice(&mut self, other: &[T]) {
+        self.reserve(other.len());
+        self.append(other);
+    }
+
+    /// Append all elements of `other` to the back of the vector, or none
+    /// of them if they would not fit in 256 bytes, and the vector has
+    /// no heap fallback.
+    #[inline]
+    pub fn try_extend_from_slice(&mut self, other: &[T]) -> Result<(), CapacityError> {
+        self.try_reserve(other.len())?;
+        self.append(other);
+        Ok(())
+    }
+
+    /// Append `other`, for which room has been reserved already.
+    #[inline]
+    fn append(&mut self, other: &[T]) {
+        match self.storage {
+            Storage::Pooled{ ptr, cap, ref mut len, .. } => unsafe {
+                debug_assert!(*len + other.len() <= cap);
+                std::ptr::copy_nonoverlapping(other.as_ptr(), ptr.add(*len), other.len());
+                *len += other.len();
+            },
+            Storage::Heap(ref mut vec) => vec.extend_from_slice(other),
+        }
+    }
+
+    /// Shorten the vector to `len` elements, keeping the storage.
+    #[inline]
+    pub fn truncate(&mut self, new_len: usize) {
+        match self.storage {
+            Storage::Pooled{ ref mut len, .. } => *len = cmp::min(*len, new_len),
+            Storage::Heap(ref mut vec) => vec.truncate(new_len),
+        }
+    }
+
+    /// Remove all elements, keeping the storage.
+    #[inline]
+    pub fn clear(&mut self) {
+        self.truncate(0);
+    }
+
+    /// Get the elements as a slice.
+    #[inline]
+    pub fn as_slice(&self) -> &[T] {
+        match self.storage {
+            Storage::Pooled{ ptr, len, .. } => unsafe { std::slice::from_raw_parts(ptr, len) },
+            Storage::Heap(ref vec) => vec,
+        }
+    }
+
+    /// Get the elements as a mutable slice.
+    #[inline]
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        match self.storage {
+            Storage::Pooled{ ptr, len, .. } => unsafe { std::slice::from_raw_parts_mut(ptr, len) },
+            Storage::Heap(ref mut vec) => vec,
+        }
+    }
+
+    /// Move the elements into storage for at least `wanted` of them.
+    fn grow(&mut self, wanted: usize) -> Result<(), CapacityError> {
+        let size = std::mem::size_of::<T>();
+        let (old_pool, old_ptr, len) = match self.storage {
+            Storage::Pooled{ pool, ptr, len, .. } => (pool, ptr, len),
+            Storage::Heap(_) => unreachable!(),
+        };
+        let bytes = cmp::max(wanted.saturating_mul(size), cmp::min(MIN_ELEMENTS * size, 256));
+        self.storage = match self.allocator.pool_for_size(bytes) {
+            Some(pool) => {
+                check_slot::<T>(pool.ele_size);
+                let ptr = pool.pop() as *mut T;
+                unsafe {
+                    std::ptr::copy_nonoverlapping(old_ptr, ptr, len);
+                }
+                Storage::Pooled{ pool: Some(pool), ptr, cap: pool.ele_size / size, len }
+            }
+            None if self.heap_fallback => {
+                let mut vec = Vec::with_capacity(cmp::max(wanted, 2 * len));
+                vec.extend_from_slice(self.as_slice());
+                Storage::Heap(vec)
+            }
+            None => return Err(CapacityError),
+        };
+        if let Some(old_pool) = old_pool {
+            unsafe {
+                old_pool.recycle(old_ptr as *mut Node);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a, T: Copy> Drop for PooledVec<'a, T> {
+    fn drop(&mut self) {
+        if let Storage::Pooled{ pool: Some(pool), ptr, .. } = self.storage {
+            unsafe {
+                pool.recycle(ptr as *mut Node);
+            }
+        }
+    }
+}
+
+impl<'a, T: Copy> Deref for PooledVec<'a, T> {
+    type Target = [T];
+    #[inline]
+    fn deref(&self) -> &[T] {
+        self.as_slice()
+    }
+}
+
+impl<'a, T: Copy> DerefMut for PooledVec<'a, T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut [T] {
+        self.as_mut_slice()
+    }
+}
+
+impl<'a, T: Copy + fmt::Debug> fmt::Debug for PooledVec<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl<'a, T: Copy> Extend<T> for PooledVec<'a, T> {
+    fn extend<I: IntoIterator<Item=T>>(&mut self, iter: I) {
+        let iter = iter.into_iter();
+        self.reserve(iter.size_hint().0);
+        for value in iter {
+            self.push(value);
+        }
+    }
+}
+
+impl<'a, 'b, T: Copy + 'b> Extend<&'b T> for PooledVec<'a, T> {
+    fn extend<I: IntoIterator<Item=&'b T>>(&mut self, iter: I) {
+        self.extend(iter.into_iter().cloned());
+    }
+}
+
+impl<'a, 'b, T: Copy> IntoIterator for &'b PooledVec<'a, T> {
+    type Item = &'b T;
+    type IntoIter = std::slice::Iter<'b, T>;
+    #[inline]
+    fn into_iter(self) -> std::slice::Iter<'b, T> {
+        self.iter()
+    }
+}
+
+impl<'a, 'b, T: Copy> IntoIterator for &'b mut PooledVec<'a, T> {
+    type Item = &'b mut T;
+    type IntoIter = std::slice::IterMut<'b, T>;
+    #[inline]
+    fn into_iter(self) -> std::slice::IterMut<'b, T> {
+        self.iter_mut()
+    }
+}
+
+impl<'a, T: Copy> IntoIterator for PooledVec<'a, T> {
+    type Item = T;
+    type IntoIter = IntoIter<'a, T>;
+    #[inline]
+    fn into_iter(self) -> IntoIter<'a, T> {
+        IntoIter{ vec: self, front: 0 }
+    }
+}
+
+/// An iterator moving the elements out of a `PooledVec`,
+/// whose slot is recycled when the iterator is dropped.
+pub struct IntoIter<'a, T: Copy> {
+    vec: PooledVec<'a, T>,
+    front: usize,
+}
+
+impl<'a, T: Copy> Iterator for IntoIter<'a, T> {
+    type Item = T;
+    #[inline]
+    fn next(&mut self) -> Option<T> {
+        let value = self.vec.get(self.front).cloned();
+        if value.is_some() {
+            self.front += 1;
+        }
+        value
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.vec.len() - self.front;
+        (len, Some(len))
+    }
+}
+
+impl<'a, T: Copy> DoubleEndedIterator for IntoIter<'a, T> {
+    #[inline]
+    fn next_back(&mut self) -> Option<T> {
+        if self.vec.len() > self.front {
+            self.vec.pop()
+        } else {
+            None
+        }
+    }
+}
+
+impl<'a, T: Copy> ExactSizeIterator for IntoIter<'a, T> { }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_class_boundaries() {
+        let allocator = Allocator::with_capacity(4);
+        let mut vec = PooledVec::new_in(&allocator);
+        assert_eq!(vec.capacity(), 0);
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 0);
+        for class in [8, 16, 32, 64, 128, 256].iter() {
+            let start = vec.len();
+            for i in start..*class {
+                vec.push(i as u8);
+                assert_eq!(vec.capacity(), *class);
+                // the old slot is recycled as soon as the new one is taken
+                #[cfg(feature = "stats")]
+                assert_eq!(allocator.stats().live, 1);
+            }
+            assert!(vec.iter().enumerate().all(|(i, v)| *v == i as u8));
+        }
+        assert_eq!(vec.len(), 256);
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().allocs, 6);
+    }
+
+    #[test]
+    fn test_min_elements() {
+        let allocator = Allocator::new();
+        let mut vec = PooledVec::new_in(&allocator);
+        vec.push(1u64);
+        assert_eq!(vec.capacity(), 4);
+        vec.extend(&[2, 3, 4, 5]);
+        assert_eq!(vec.capacity(), 8);
+        assert_eq!(vec.as_slice(), &[1, 2, 3, 4, 5]);
+
+        // odd element sizes waste the tail of each slot
+        let mut vec = PooledVec::new_in(&allocator);
+        vec.extend((0..6).map(|i| [i as u8; 3]));
+        assert_eq!(vec.capacity(), 10);
+        assert_eq!(vec[5], [5; 3]);
+
+        let mut vec = PooledVec::new_in(&allocator);
+        vec.push([0u8; 100]);
+        assert_eq!(vec.capacity(), 2);
+    }
+
+    #[test]
+    fn test_drop_recycles_one_slot() {
+        let allocator = Allocator::new();
+        let mut vec = PooledVec::new_in(&allocator);
+        vec.extend(0..20u32);
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 1);
+        let addr = vec.as_ptr() as usize;
+        drop(vec);
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 0);
+        assert_eq!(&*allocator.alloc([0u32; 20]) as *const _ as usize, addr);
+    }
+
+    #[test]
+    fn test_push_pop() {
+        let allocator = Allocator::new();
+        let mut vec = PooledVec::new_in(&allocator);
+        assert_eq!(vec.pop(), None);
+        vec.push(1i16);
+        vec.push(2);
+        vec[0] = 3;
+        assert_eq!(vec.pop(), Some(2));
+        assert_eq!(vec.pop(), Some(3));
+        assert_eq!(vec.pop(), None);
+        assert!(vec.is_empty());
+        assert_eq!(vec.capacity(), 4);
+    }
+
+    #[test]
+    fn test_into_iter() {
+        let allocator = Allocator::new();
+        let mut vec = PooledVec::new_in(&allocator);
+        vec.extend(0..10u16);
+        for v in &mut vec {
+            *v *= 2;
+        }
+        assert_eq!((&vec).into_iter().count(), 10);
+        let mut iter = vec.into_iter();
+        assert_eq!(iter.len(), 10);
+        assert_eq!(iter.next(), Some(0));
+        assert_eq!(iter.next_back(), Some(18));
+        assert_eq!(iter.collect::<Vec<_>>(), vec![2, 4, 6, 8, 10, 12, 14, 16]);
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "exceeds the largest size class")]
+    fn test_overflow_panics() {
+        let allocator = Allocator::new();
+        let mut vec = PooledVec::new_in(&allocator);
+        vec.extend(0..64u32);
+        vec.push(64);
+    }
+
+    #[test]
+    fn test_extend_from_slice() {
+        let allocator = Allocator::new();
+        let mut vec = PooledVec::new_in(&allocator);
+        vec.extend_from_slice(&[1u32, 2, 3]);
+        vec.extend_from_slice(&[4; 13]);
+        assert_eq!(vec.capacity(), 16);
+        assert_eq!(vec[..4], [1, 2, 3, 4]);
+
+        assert_eq!(vec.try_extend_from_slice(&[0; 49]), Err(CapacityError));
+        assert_eq!(vec.len(), 16);
+        vec.try_extend_from_slice(&[5; 48]).unwrap();
+        assert_eq!(vec.len(), 64);
+        vec.truncate(3);
+        assert_eq!(vec.as_slice(), &[1, 2, 3]);
+    }
+
+    #[test]
+    fn test_heap_fallback() {
+        let allocator = Allocator::new();
+        let mut vec = PooledVec::with_heap_fallback(&allocator);
+        vec.extend(0..64u32);
+        assert!(!vec.spilled());
+        vec.push(64);
+        assert!(vec.spilled());
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 0);
+        vec.extend(65..1000);
+        assert!(vec.iter().enumerate().all(|(i, v)| *v == i as u32));
+    }
+
+    #[test]
+    fn test_zero_sized() {
+        let allocator = Allocator::new();
+        let mut vec = PooledVec::new_in(&allocator);
+        vec.extend(std::iter::repeat_n((), 1000));
+        assert_eq!(vec.len(), 1000);
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().allocs, 0);
+    }
+}
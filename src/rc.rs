@@ -0,0 +1,411 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Reference-counted pointers to pooled values.
+
+use std::cell::Cell;
+use std::marker::Unsize;
+use std::ops::CoerceUnsized;
+
+use {Allocator, Node, Pool};
+
+/// The content of a slot owned by `RcPointer`s: a small header
+/// followed by the value.
+///
+/// As with `Rc`, all the strong pointers collectively hold one weak
+/// reference, so the slot is recycled when `weak` drops to zero.
+#[repr(C)]
+struct RcBox<T: ?Sized> {
+    strong: Cell<usize>,
+    weak: Cell<usize>,
+    value: T,
+}
+
+/// A reference-counted pointer to `T`.
+///
+/// The counts are kept in a header inside the slot, so a slot large
+/// enough for both the header and `T` is used.
+/// When the last clone is dropped, `T` would be dropped in place,
+/// then the underlying memory would be recycled by the allocator
+/// once no `WeakPointer` to it remains.
+pub struct RcPointer<'a, T: ?Sized> {
+    pool: &'a Pool,
+    node: *mut RcBox<T>,
+}
+
+impl<'a, T, U> CoerceUnsized<RcPointer<'a, T>> for RcPointer<'a, U>
+    where U: Unsize<T> + ?Sized,
+          T: ?Sized,
+{ }
+
+impl<'a, T> RcPointer<'a, T> {
+    /// Allocate a slot from `pool` for a fresh `RcBox` holding `value`.
+    #[inline]
+    #[track_caller]
+    fn new_in(pool: &'a Pool, value: T) -> RcPointer<'a, T> {
+        let ptr = pool.alloc::<RcBox<T>>();
+        let ret = RcPointer{ pool: ptr.pool, node: ptr.node.as_ptr() };
+        std::mem::forget(ptr);
+        unsafe {
+            std::ptr::write(ret.node, RcBox{ strong: Cell::new(1), weak: Cell::new(1), value });
+        }
+        ret
+    }
+}
+
+impl<'a, T: ?Sized> RcPointer<'a, T> {
+    #[inline]
+    fn inner(&self) -> &RcBox<T> {
+        unsafe {
+            &*self.node
+        }
+    }
+
+    /// Get the number of `RcPointer`s to the pointee of `this`.
+    #[inline]
+    pub fn strong_count(this: &Self) -> usize {
+        this.inner().strong.get()
+    }
+
+    /// Get the number of `WeakPointer`s to the pointee of `this`.
+    #[inline]
+    pub fn weak_count(this: &Self) -> usize {
+        this.inner().weak.get() - 1
+    }
+
+    /// Create a new `WeakPointer` to the pointee of `this`.
+    #[inline]
+    pub fn downgrade(this: &Self) -> WeakPointer<'a, T> {
+        let weak = &this.inner().weak;
+        weak.set(weak.get() + 1);
+        WeakPointer{ pool: this.pool, node: this.node }
+    }
+}
+
+impl<'a, T: ?Sized> Clone for RcPointer<'a, T> {
+    #[inline]
+    fn clone(&self) -> Self {
+        let strong = &self.inner().strong;
+        strong.set(strong.get() + 1);
+        RcPointer{ pool: self.pool, node: self.node }
+    }
+}
+
+impl<'a, T: ?Sized> std::ops::Deref for RcPointer<'a, T> {
+    type Target = T;
+    #[inline]
+    fn deref(&self) -> &T {
+        &self.inner().value
+    }
+}
+
+impl<'a, T: ?Sized> Drop for RcPointer<'a, T> {
+    fn drop(&mut self) {
+        let strong = self.inner().strong.get() - 1;
+        self.inner().strong.set(strong);
+        if strong == 0 {
+            unsafe {
+                std::ptr::drop_in_place(&mut (*self.node).value);
+            }
+            // the weak reference held by the strong pointers
+            drop(WeakPointer{ pool: self.pool, node: self.node });
+        }
+    }
+}
+
+/// A non-owning pointer to a value owned by `RcPointer`s.
+///
+/// The slot is kept out of the free list while weak pointers to it
+/// exist, though the value itself is dropped with the last `RcPointer`.
+pub struct WeakPointer<'a, T: ?Sized> {
+    pool: &'a Pool,
+    node: *mut RcBox<T>,
+}
+
+impl<'a, T, U> CoerceUnsized<WeakPointer<'a, T>> for WeakPointer<'a, U>
+    where U: Unsize<T> + ?Sized,
+          T: ?Sized,
+{ }
+
+impl<'a, T: ?Sized> WeakPointer<'a, T> {
+    #[inline]
+    fn inner(&self) -> &RcBox<T> {
+        unsafe {
+            &*self.node
+        }
+    }
+
+    /// Attempt to upgrade to an `RcPointer`, returning `None`
+    /// if the value has already been dropped.
+    #[inline]
+    pub fn upgrade(&self) -> Option<RcPointer<'a, T>> {
+        let strong = &self.inner().strong;
+        if strong.get() == 0 {
+            None
+        } else {
+            strong.set(strong.get() + 1);
+            Some(RcPointer{ pool: self.pool, node: self.node })
+        }
+    }
+}
+
+impl<'a, T: ?Sized> Clone for WeakPointer<'a, T> {
+    #[inline]
+    fn clone(&self) -> Self {
+        let weak = &self.inner().weak;
+        weak.set(weak.get() + 1);
+        WeakPointer{ pool: self.pool, node: self.node }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for WeakPointer<'a, T> {
+    fn drop(&mut self) {
+        let weak = self.inner().weak.get() - 1;
+        self.inner().weak.set(weak);
+        if weak == 0 {
+            unsafe {
+                self.pool.recycle(self.node as *mut u8 as *mut Node);
+            }
+        }
+    }
+}
+
+/// A copy-on-write pointer to `T`, built on `RcPointer`.
+///
+/// Clones share the same slot until one of them is mutated
+/// through `make_mut`.
+pub struct CowPointer<'a, T: Copy> {
+    rc: RcPointer<'a, T>,
+}
+
+impl<'a, T: Copy> CowPointer<'a, T> {
+    /// Construct a copy-on-write pointer sharing the pointee of `rc`.
+    #[inline]
+    pub fn new(rc: RcPointer<'a, T>) -> CowPointer<'a, T> {
+        CowPointer{ rc }
+    }
+
+    /// Unwrap the underlying `RcPointer`.
+    #[inline]
+    pub fn into_rc(this: Self) -> RcPointer<'a, T> {
+        this.rc
+    }
+
+    /// Mutably borrow the pointee of `this`.
+    ///
+    /// If other `RcPointer`s or `WeakPointer`s to the same value exist,
+    /// the value is first copied into a fresh slot from the same pool,
+    /// which `this` then uniquely owns. This matches `Rc::make_mut`:
+    /// other strong pointers keep observing the old value, while weak
+    /// pointers are disassociated from `this`.
+    pub fn make_mut(this: &mut Self) -> &mut T {
+        let rc = &mut this.rc;
+        if RcPointer::strong_count(rc) != 1 || RcPointer::weak_count(rc) != 0 {
+            *rc = RcPointer::new_in(rc.pool, rc.inner().value);
+        }
+        unsafe {
+            &mut (*rc.node).value
+        }
+    }
+}
+
+impl<'a, T: Copy> Clone for CowPointer<'a, T> {
+    #[inline]
+    fn clone(&self) -> Self {
+        CowPointer{ rc: self.rc.clone() }
+    }
+}
+
+impl<'a, T: Copy> std::ops::Deref for CowPointer<'a, T> {
+    type Target = T;
+    #[inline]
+    fn deref(&self) -> &T {
+        &self.rc
+    }
+}
+
+impl<'a, T: Copy> From<RcPointer<'a, T>> for CowPointer<'a, T> {
+    #[inline]
+    fn from(rc: RcPointer<'a, T>) -> Self {
+        CowPointer::new(rc)
+    }
+}
+
+impl Allocator {
+    /// Allocate an instance of `T` with value `elem`,
+    /// return a reference-counted pointer to it.
+    /// `size_of::<T>()` plus the size of the counts
+    /// should be le to 256 bytes.
+    #[inline]
+    #[track_caller]
+    pub fn alloc_rc<T: Copy>(&self, elem: T) -> RcPointer<'_, T> {
+        RcPointer::new_in(self.pool_for::<RcBox<T>>(), elem)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    trait Area {
+        fn area(&self) -> u32;
+    }
+
+    #[derive(Copy, Clone)]
+    struct Rect(u32, u32);
+
+    impl Area for Rect {
+        fn area(&self) -> u32 {
+            self.0 * self.1
+        }
+    }
+
+    #[test]
+    fn test_clone_across_scopes() {
+        let allocator = Allocator::new();
+        let free = allocator.pool32.free_count();
+        let outer;
+        {
+            let inner = allocator.alloc_rc(Rect(2, 3));
+            outer = inner.clone();
+            let another = inner.clone();
+            assert_eq!(another.0, 2);
+            assert_eq!(allocator.pool32.free_count(), free - 1);
+        }
+        assert_eq!(outer.1, 3);
+        assert_eq!(allocator.pool32.free_count(), free - 1);
+        drop(outer);
+        assert_eq!(allocator.pool32.free_count(), free);
+    }
+
+    #[test]
+    fn test_drop_order() {
+        let allocator = Allocator::new();
+        let free = allocator.pool32.free_count();
+        let first = allocator.alloc_rc(7u64);
+        let clones: Vec<_> = (0..4).map(|_| first.clone()).collect();
+        assert_eq!(allocator.pool32.free_count(), free - 1);
+        drop(first);
+        for (i, c) in clones.into_iter().enumerate().rev() {
+            assert_eq!(*c, 7);
+            assert_eq!(allocator.pool32.free_count(), free - 1);
+            drop(c);
+            if i == 0 {
+                assert_eq!(allocator.pool32.free_count(), free);
+            }
+        }
+    }
+
+    #[test]
+    fn test_header_class() {
+        let allocator = Allocator::new();
+        let free16 = allocator.pool16.free_count();
+        let free32 = allocator.pool32.free_count();
+        let _rc = allocator.alloc_rc(1u64);
+        assert_eq!(allocator.pool16.free_count(), free16);
+        assert_eq!(allocator.pool32.free_count(), free32 - 1);
+    }
+
+    #[test]
+    fn test_unsize_coerce() {
+        let allocator = Allocator::new();
+        let rect = allocator.alloc_rc(Rect(4, 5));
+        let area: RcPointer<dyn Area> = rect.clone();
+        let area2 = area.clone();
+        drop(rect);
+        assert_eq!(area.area(), 20);
+        assert_eq!(area2.area(), 20);
+    }
+
+    #[test]
+    fn test_upgrade_after_drop() {
+        let allocator = Allocator::new();
+        let rc = allocator.alloc_rc(Rect(1, 2));
+        let weak = RcPointer::downgrade(&rc);
+        {
+            let upgraded = weak.upgrade().unwrap();
+            assert_eq!(upgraded.area(), 2);
+        }
+        drop(rc);
+        assert!(weak.upgrade().is_none());
+        assert!(weak.clone().upgrade().is_none());
+    }
+
+    #[test]
+    fn test_weak_keeps_slot() {
+        let allocator = Allocator::new();
+        let free = allocator.pool32.free_count();
+        let rc = allocator.alloc_rc(Rect(3, 3));
+        let weak1 = RcPointer::downgrade(&rc);
+        let weak2: WeakPointer<dyn Area> = weak1.clone();
+
+        // the strong pointer created by upgrading outlives the original
+        let upgraded = weak2.upgrade().unwrap();
+        drop(rc);
+        assert_eq!(upgraded.area(), 9);
+        drop(upgraded);
+        assert!(weak1.upgrade().is_none());
+        assert_eq!(allocator.pool32.free_count(), free - 1);
+
+        drop(weak1);
+        assert_eq!(allocator.pool32.free_count(), free - 1);
+        drop(weak2);
+        assert_eq!(allocator.pool32.free_count(), free);
+    }
+
+    #[test]
+    fn test_counts() {
+        let allocator = Allocator::new();
+        let rc = allocator.alloc_rc(1u32);
+        let rc2 = rc.clone();
+        let weak = RcPointer::downgrade(&rc);
+        assert_eq!(RcPointer::strong_count(&rc), 2);
+        assert_eq!(RcPointer::weak_count(&rc2), 1);
+        drop(weak);
+        drop(rc2);
+        assert_eq!(RcPointer::strong_count(&rc), 1);
+        assert_eq!(RcPointer::weak_count(&rc), 0);
+    }
+
+    #[test]
+    fn test_make_mut_unique() {
+        let allocator = Allocator::new();
+        let mut cow = CowPointer::new(allocator.alloc_rc(Rect(1, 1)));
+        let addr = &*cow as *const Rect;
+        CowPointer::make_mut(&mut cow).0 = 5;
+        assert_eq!(&*cow as *const Rect, addr);
+        assert_eq!(cow.area(), 5);
+    }
+
+    #[test]
+    fn test_make_mut_shared() {
+        let allocator = Allocator::new();
+        let free = allocator.pool32.free_count();
+        let mut cow = CowPointer::from(allocator.alloc_rc(Rect(2, 2)));
+        let other = cow.clone();
+        let addr = &*cow as *const Rect;
+        CowPointer::make_mut(&mut cow).1 = 3;
+        assert_ne!(&*cow as *const Rect, addr);
+        assert_eq!(&*other as *const Rect, addr);
+        assert_eq!(cow.area(), 6);
+        assert_eq!(other.area(), 4);
+        assert_eq!(allocator.pool32.free_count(), free - 2);
+
+        // now unique, so no more copies
+        let addr = &*cow as *const Rect;
+        CowPointer::make_mut(&mut cow).1 = 4;
+        assert_eq!(&*cow as *const Rect, addr);
+        drop(other);
+        assert_eq!(allocator.pool32.free_count(), free - 1);
+
+        let weak = RcPointer::downgrade(&cow.rc);
+        CowPointer::make_mut(&mut cow).0 = 1;
+        assert!(weak.upgrade().is_none());
+        assert_eq!(CowPointer::into_rc(cow).area(), 4);
+    }
+}
@@ -0,0 +1,123 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! The allocators alive on the current thread, for diagnostics.
+//!
+//! Under the `registry` feature, every allocator registers with the
+//! thread constructing it, and deregisters when dropped. As allocators
+//! never leave their thread, the registry is thread-local. It doesn't
+//! keep them alive.
+//!
+//! ```rust
+//! use aren_alloc::{registry, AllocatorBuilder};
+//! let shading = AllocatorBuilder::new().name("shading").build();
+//! let ptr = shading.alloc(1u64);
+//! let snapshot = registry::snapshot();
+//! assert!(snapshot.iter().any(|(name, stats)| name == "shading" && stats.live == 1));
+//! ```
+
+use std::cell::{Cell, RefCell};
+use std::ptr::NonNull;
+
+use {Allocator, Pool, Stats};
+
+/// An allocator alive on the thread, by the pools of its classes.
+struct Entry {
+    id: u64,
+    name: Option<&'static str>,
+    /// The pools are boxed, so they stay put as the allocator moves.
+    pools: Vec<NonNull<Pool>>,
+}
+
+thread_local! {
+    static REGISTRY: RefCell<Vec<Entry>> = const { RefCell::new(Vec::new()) };
+    static NEXT_ID: Cell<u64> = const { Cell::new(1) };
+}
+
+/// Register `allocator` with the current thread. Its classes must be
+/// final.
+pub(crate) fn register(allocator: &mut Allocator) {
+    let id = NEXT_ID.with(|next| {
+        let id = next.get();
+        next.set(id + 1);
+        id
+    });
+    let pools = allocator.pools().map(NonNull::from).collect();
+    let entry = Entry{ id, name: allocator.name, pools };
+    // the registry is gone if the thread is exiting, the allocator is
+    // simply left out then
+    if REGISTRY.try_with(|registry| registry.borrow_mut().push(entry)).is_ok() {
+        allocator.registry_id = id;
+    }
+}
+
+/// Deregister `allocator`, if it was registered.
+pub(crate) fn deregister(allocator: &Allocator) {
+    let id = allocator.registry_id;
+    if id != 0 {
+        let _ = REGISTRY.try_with(|registry| registry.borrow_mut().retain(|entry| entry.id != id));
+    }
+}
+
+/// The name and usage of every allocator alive on the current thread,
+/// in the order they were constructed. Allocators built without a name
+/// are listed as `"unnamed"`.
+pub fn snapshot() -> Vec<(String, Stats)> {
+    REGISTRY.with(|registry| {
+        registry.borrow().iter().map(|entry| {
+            // registered pools are deregistered before they're dropped
+            let stats = entry.pools.iter().map(|pool| unsafe { pool.as_ref() }.stats()).sum();
+            (entry.name.unwrap_or("unnamed").to_string(), stats)
+        }).collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {AllocatorBuilder, DEFAULT_POOL_SIZE};
+
+    fn names() -> Vec<String> {
+        snapshot().into_iter().map(|(name, _)| name).collect()
+    }
+
+    #[test]
+    fn test_register_drop() {
+        let shading = AllocatorBuilder::new().name("shading").only_classes(&[16]).build();
+        let bvh = AllocatorBuilder::new().name("bvh").build();
+        let anonymous = Allocator::with_capacity(2);
+        let ptrs = [shading.alloc(1u8), shading.alloc(2u8)];
+        // moving an allocator doesn't lose its registration
+        let moved = Box::new(bvh);
+        let node = moved.alloc([0u64; 3]);
+        let snapshot = snapshot();
+        assert_eq!(snapshot.len(), 3);
+        assert_eq!(snapshot[0], ("shading".to_string(), Stats{ live: 2, allocs: 2, slots: DEFAULT_POOL_SIZE / 16 }));
+        assert_eq!((snapshot[1].0.as_str(), snapshot[1].1.live), ("bvh", 1));
+        assert_eq!((snapshot[2].0.as_str(), snapshot[2].1.slots), ("unnamed", 12));
+
+        drop(node);
+        drop(moved);
+        assert_eq!(names(), ["shading", "unnamed"]);
+        drop(ptrs);
+        drop((shading, anonymous));
+        assert!(names().is_empty());
+    }
+
+    #[test]
+    #[cfg_attr(target_family = "wasm", ignore = "no threads on wasm")]
+    fn test_per_thread() {
+        let _main = AllocatorBuilder::new().name("main").build();
+        let other = std::thread::spawn(|| {
+            let _worker = AllocatorBuilder::new().name("worker").build();
+            names()
+        }).join().unwrap();
+        assert_eq!(other, ["worker"]);
+        assert_eq!(names(), ["main"]);
+    }
+}
@@ -0,0 +1,242 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Pointers that could be sent to, and dropped on, other threads.
+
+use std::marker::Unsize;
+use std::ops::CoerceUnsized;
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+use {Allocator, Node, Pool};
+
+/// A lock-free stack of slots freed on other threads.
+///
+/// Any thread may push onto it, but only the thread owning the pool
+/// takes slots back, and always the whole stack at once, so there is
+/// no ABA problem to worry about.
+pub(crate) struct RemoteFrees {
+    head: AtomicPtr<Node>,
+}
+
+impl RemoteFrees {
+    pub(crate) const fn new() -> RemoteFrees {
+        RemoteFrees{ head: AtomicPtr::new(std::ptr::null_mut()) }
+    }
+
+    unsafe fn push(&self, node: *mut Node) {
+        let mut head = self.head.load(Ordering::Relaxed);
+        loop {
+            (*node).next = head;
+            match self.head.compare_exchange_weak(head, node, Ordering::Release, Ordering::Relaxed) {
+                Ok(_) => return,
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    pub(crate) fn take_all(&self) -> *mut Node {
+        if self.head.load(Ordering::Relaxed).is_null() {
+            std::ptr::null_mut()
+        } else {
+            self.head.swap(std::ptr::null_mut(), Ordering::Acquire)
+        }
+    }
+}
+
+impl Pool {
+    /// Move the slots freed on other threads back into the free list.
+    pub(crate) fn drain_remote(&self) {
+        let list = self.remote.take_all();
+        if list.is_null() {
+            return;
+        }
+        let mut count = 1;
+        unsafe {
+            let mut tail = list;
+            self.track_free(tail);
+            while !(*tail).next.is_null() {
+                tail = (*tail).next;
+                self.track_free(tail);
+                count += 1;
+            }
+            (*tail).next = self.head.get().map_or(std::ptr::null_mut(), NonNull::as_ptr);
+        }
+        self.head.set(NonNull::new(list));
+        self.free_slots.set(self.free_slots.get() + count);
+        self.count_free(count);
+    }
+}
+
+/// A pointer to `T` that could be sent to other threads.
+///
+/// When dropped on any thread, `T` would be dropped in place, then
+/// the underlying memory would be pushed onto a lock-free queue owned
+/// by the allocator, to be recycled on the allocator's own thread,
+/// either when the pool runs out of free slots, or when
+/// `Allocator::drain_remote_frees` is called.
+///
+/// Erased into a trait object, it is only `Send` if the trait object
+/// says so, as in `SendPointer<dyn Trait + Send>`:
+///
+/// ```rust,compile_fail
+/// use aren_alloc::{Allocator, SendPointer};
+/// let allocator = Allocator::new();
+/// let ptr: SendPointer<dyn std::fmt::Debug> = allocator.alloc_send(1u32);
+/// std::thread::scope(|s| {
+///     s.spawn(move || println!("{:?}", &*ptr));
+/// });
+/// ```
+pub struct SendPointer<'a, T: ?Sized> {
+    remote: &'a RemoteFrees,
+    node: *mut T,
+}
+
+impl<'a, T, U> CoerceUnsized<SendPointer<'a, T>> for SendPointer<'a, U>
+    where U: Unsize<T> + ?Sized,
+          T: ?Sized,
+{ }
+
+unsafe impl<'a, T: Send + ?Sized> Send for SendPointer<'a, T> { }
+
+unsafe impl<'a, T: Sync + ?Sized> Sync for SendPointer<'a, T> { }
+
+impl<'a, T: ?Sized> std::ops::Deref for SendPointer<'a, T> {
+    type Target = T;
+    #[inline]
+    fn deref(&self) -> &T {
+        unsafe {
+            &*self.node
+        }
+    }
+}
+
+impl<'a, T: ?Sized> std::ops::DerefMut for SendPointer<'a, T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe {
+            &mut *self.node
+        }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for SendPointer<'a, T> {
+    fn drop(&mut self) {
+        unsafe {
+            std::ptr::drop_in_place(self.node);
+            self.remote.push(self.node as *mut u8 as *mut Node);
+        }
+    }
+}
+
+impl Allocator {
+    /// Allocate an instance of `T` with value `elem`,
+    /// return a pointer that could be sent to other threads.
+    /// `size_of::<T>()` should be le to 256 bytes.
+    ///
+    /// # Panics
+    ///
+    /// Panics as `alloc` does.
+    #[inline]
+    #[track_caller]
+    pub fn alloc_send<T: Copy + Send>(&self, elem: T) -> SendPointer<'_, T> {
+        let ptr = self.put(elem);
+        let ret = SendPointer{ remote: &ptr.pool.remote, node: ptr.node.as_ptr() };
+        std::mem::forget(ptr);
+        ret
+    }
+
+    /// Recycle the slots of `SendPointer`s dropped on other threads.
+    ///
+    /// This is done automatically before a pool grows, so calling it is
+    /// only needed to keep memory usage down eagerly.
+    pub fn drain_remote_frees(&self) {
+        self.pool8.drain_remote();
+        self.pool16.drain_remote();
+        self.pool32.drain_remote();
+        self.pool64.drain_remote();
+        self.pool128.drain_remote();
+        self.pool256.drain_remote();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use {AllocatorBuilder, DEFAULT_POOL_SIZE};
+
+    #[derive(Copy, Clone)]
+    struct WorkItem {
+        input: [u32; 6],
+        output: u32,
+    }
+
+    #[test]
+    #[cfg_attr(target_family = "wasm", ignore = "no threads on wasm")]
+    fn test_round_trip() {
+        let allocator: &'static Allocator = Box::leak(Box::new(Allocator::with_capacity(4)));
+        let free = allocator.pool32.free_count();
+        let mut item = allocator.alloc_send(WorkItem{ input: [1, 2, 3, 4, 5, 6], output: 0 });
+        let addr = &*item as *const WorkItem as usize;
+        let item = thread::spawn(move || {
+            item.output = item.input.iter().sum();
+            item
+        }).join().unwrap();
+        assert_eq!(item.output, 21);
+        thread::spawn(move || drop(item)).join().unwrap();
+
+        assert_eq!(allocator.pool32.free_count(), free - 1);
+        allocator.drain_remote_frees();
+        assert_eq!(allocator.pool32.free_count(), free);
+        let reused = allocator.alloc(WorkItem{ input: [0; 6], output: 0 });
+        assert_eq!(&*reused as *const WorkItem as usize, addr);
+    }
+
+    #[test]
+    #[cfg_attr(target_family = "wasm", ignore = "no threads on wasm")]
+    fn test_drain_before_growth() {
+        let allocator = Allocator::with_capacity(4);
+        let items: Vec<_> = (0..4).map(|i| allocator.alloc_send(i as u64)).collect();
+        assert_eq!(allocator.pool8.free_count(), 0);
+        thread::scope(|s| {
+            s.spawn(move || drop(items));
+        });
+        // the freed slots are reused, rather than a new page allocated
+        let _reused: Vec<_> = (0..4).map(|i| allocator.alloc(i as u64)).collect();
+        assert!(allocator.pool8.next_pool.borrow().is_none());
+    }
+
+    #[test]
+    #[cfg_attr(target_family = "wasm", ignore = "no threads on wasm")]
+    fn test_no_leak() {
+        let allocator = Allocator::with_capacity(16);
+        assert_eq!(allocator.pool64.free_count(), 16);
+        let items: Vec<_> = (0..1000).map(|i| allocator.alloc_send([i as u64; 8])).collect();
+        thread::scope(|s| {
+            let (first, second): (Vec<_>, Vec<_>) = items.into_iter().partition(|i| i[0] % 2 == 0);
+            s.spawn(move || drop(first));
+            s.spawn(move || drop(second));
+        });
+        allocator.drain_remote_frees();
+        // 63 pages of 16 slots each were needed, all of which are free again
+        assert_eq!(allocator.pool64.free_count(), 63 * 16);
+    }
+
+    #[test]
+    #[should_panic(expected = "allocation budget exhausted")]
+    fn test_budget() {
+        let allocator = AllocatorBuilder::new().capacity_for(256, 4).budget(5 * DEFAULT_POOL_SIZE + 4 * 256).build();
+        let items: Vec<_> = (0..4).map(|i| allocator.alloc_send([i as u8; 200])).collect();
+        // counted as requests, as those of `alloc`
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.size_histogram().total(), 4);
+        assert_eq!(items[3][0], 3);
+        allocator.alloc_send([4u8; 200]);
+    }
+}
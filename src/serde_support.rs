@@ -0,0 +1,198 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! `serde` support, enabled by the `serde` feature.
+
+use std::marker::PhantomData;
+
+use serde::de::{Deserialize, DeserializeSeed, Deserializer};
+use serde::ser::{Serialize, Serializer};
+
+use {check_slot, Allocator, Handle, Pointer, SlotGuard};
+
+/// Serialized transparently as the pointee, just like a `Box`.
+impl<'a, T: Serialize + ?Sized> Serialize for Pointer<'a, T> {
+    #[inline]
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Pointer::as_ref(self).serialize(serializer)
+    }
+}
+
+/// A `DeserializeSeed` deserializing a `T` into `allocator`,
+/// producing a `Pointer<T>`.
+///
+/// The slot is taken before deserializing, and recycled should
+/// deserializing fail or panic. `T` needn't be `Copy`, its destructor
+/// runs when the pointer is dropped.
+///
+/// # Example
+///
+/// ```rust
+/// # extern crate aren_alloc;
+/// # extern crate serde;
+/// # extern crate serde_json;
+/// use aren_alloc::{Allocator, PoolSeed};
+/// use serde::de::DeserializeSeed;
+///
+/// # fn main() {
+/// let allocator = Allocator::new();
+/// let mut de = serde_json::Deserializer::from_str("[1, 2, 3]");
+/// let p = PoolSeed::<[u32; 3]>::new(&allocator).deserialize(&mut de).unwrap();
+/// assert_eq!(*p, [1, 2, 3]);
+/// # }
+/// ```
+pub struct PoolSeed<'a, T> {
+    allocator: &'a Allocator,
+    _phantom: PhantomData<fn() -> T>,
+}
+
+impl<'a, T> PoolSeed<'a, T> {
+    /// Construct a seed allocating from `allocator`.
+    #[inline]
+    pub fn new(allocator: &'a Allocator) -> PoolSeed<'a, T> {
+        PoolSeed{ allocator, _phantom: PhantomData }
+    }
+}
+
+impl<'a, T> Clone for PoolSeed<'a, T> {
+    #[inline]
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, T> Copy for PoolSeed<'a, T> { }
+
+impl<'a, 'de, T: Deserialize<'de>> DeserializeSeed<'de> for PoolSeed<'a, T> {
+    type Value = Pointer<'a, T>;
+
+    /// # Panics
+    ///
+    /// Panics if the budget of the allocator is exhausted, and its
+    /// handler couldn't recover.
+    #[inline]
+    #[track_caller]
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Pointer<'a, T>, D::Error> {
+        let allocator = self.allocator;
+        let pool = allocator.pool_for::<T>();
+        check_slot::<T>(pool.ele_size);
+        let node = match allocator.pop_or_recover(pool, std::mem::size_of::<T>()) {
+            Some(node) => node,
+            None => panic!("allocation budget exhausted"),
+        };
+        // recycles the slot should `T::deserialize` fail or panic
+        let slot = SlotGuard::new(pool, node);
+        let value = T::deserialize(deserializer)?;
+        let node = slot.disarm() as *mut T;
+        unsafe {
+            std::ptr::write(node, value);
+            Ok(Pointer::from_node(pool, node))
+        }
+    }
+}
+
+/// Serialized as its packed bits.
+impl Serialize for Handle {
+    #[inline]
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(self.to_bits())
+    }
+}
+
+impl<'de> Deserialize<'de> for Handle {
+    #[inline]
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Handle, D::Error> {
+        u64::deserialize(deserializer).map(Handle::from_bits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::{Deserialize, Serialize};
+    use serde_json;
+
+    #[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+    struct Transform {
+        translation: [f32; 3],
+        scale: f32,
+        id: u64,
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let allocator = Allocator::new();
+        let transform = allocator.alloc(Transform{ translation: [1.0, 2.0, 3.0], scale: 0.5, id: 7 });
+        let json = serde_json::to_string(&transform).unwrap();
+        assert_eq!(json, serde_json::to_string(&*transform).unwrap());
+
+        let mut de = serde_json::Deserializer::from_str(&json);
+        let restored = PoolSeed::<Transform>::new(&allocator).deserialize(&mut de).unwrap();
+        assert_eq!(*restored, *transform);
+    }
+
+    #[test]
+    fn test_seed_class() {
+        let allocator = Allocator::with_capacity(1);
+        let mut de = serde_json::Deserializer::from_str(r#"{"translation":[0,0,1],"scale":2,"id":1}"#);
+        let first = PoolSeed::<Transform>::new(&allocator).deserialize(&mut de).unwrap();
+        let mut de = serde_json::Deserializer::from_str(r#"{"translation":[0,1,0],"scale":3,"id":2}"#);
+        let second = PoolSeed::<Transform>::new(&allocator).deserialize(&mut de).unwrap();
+        assert_eq!(first.id, 1);
+        assert_eq!(second.translation, [0.0, 1.0, 0.0]);
+        assert_eq!(first.pool.ele_size, 32);
+        assert_eq!(second.pool as *const _, &*allocator.pool32 as *const _);
+    }
+
+    #[derive(Copy, Clone, Debug)]
+    struct Fuse;
+
+    impl<'de> Deserialize<'de> for Fuse {
+        fn deserialize<D: Deserializer<'de>>(_deserializer: D) -> Result<Fuse, D::Error> {
+            panic!("fuse")
+        }
+    }
+
+    #[test]
+    #[cfg_attr(target_family = "wasm", ignore = "panics abort on wasm")]
+    fn test_seed_panic() {
+        let allocator = Allocator::new();
+        let mut de = serde_json::Deserializer::from_str("null");
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            PoolSeed::<Fuse>::new(&allocator).deserialize(&mut de)
+        }));
+        assert!(result.is_err());
+        // the slot taken beforehand is recycled
+        assert!(allocator.is_idle(8));
+        assert_eq!(allocator.free_slots(8), allocator.pool8.slot_count());
+    }
+
+    #[test]
+    fn test_seed_error() {
+        let allocator = Allocator::new();
+        let mut de = serde_json::Deserializer::from_str(r#"["cached", 3]"#);
+        let named = PoolSeed::<(String, u32)>::new(&allocator).deserialize(&mut de).unwrap();
+        assert_eq!(*named, ("cached".to_string(), 3));
+        let mut de = serde_json::Deserializer::from_str(r#"["cut short"]"#);
+        assert!(PoolSeed::<(String, u32)>::new(&allocator).deserialize(&mut de).is_err());
+        drop(named);
+        assert!(allocator.is_idle(32));
+    }
+
+    #[test]
+    fn test_handle() {
+        let mut slab = ::Slab::new();
+        let removed = slab.insert(1u8);
+        slab.remove(removed);
+        let handle = slab.insert(2u8);
+        let json = serde_json::to_string(&handle).unwrap();
+        assert_eq!(json, (1u64 << 32).to_string());
+        let restored: Handle = serde_json::from_str(&json).unwrap();
+        assert_eq!(slab.get(restored), Some(&2));
+    }
+}
@@ -0,0 +1,511 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A thread-safe allocator with per-thread caches over a shared depot.
+//!
+//! The depot owns the pages of every class, guarded by one mutex per
+//! class. Each thread keeps a cache holding up to `2 * MAGAZINE_SIZE`
+//! free slots per class, so that most allocations and frees never touch
+//! shared state. An empty cache is refilled with `MAGAZINE_SIZE` slots
+//! from the depot at once, and a full one flushes `MAGAZINE_SIZE` slots
+//! back the same way.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex, Weak};
+
+use padded::CachePadded;
+use sync::{lock, SendPool};
+use {check_slot, too_big, Node, Pool, DEFAULT_POOL_SIZE};
+
+/// Number of slots moved between a thread cache and the depot at once.
+const MAGAZINE_SIZE: usize = 32;
+
+const CLASSES: [usize; 6] = [8, 16, 32, 64, 128, 256];
+
+#[inline]
+fn class_index(ele_size: usize) -> usize {
+    if ele_size <= 8 {
+        0
+    } else if ele_size <= 16 {
+        1
+    } else if ele_size <= 32 {
+        2
+    } else if ele_size <= 64 {
+        3
+    } else if ele_size <= 128 {
+        4
+    } else if ele_size <= 256 {
+        5
+    } else {
+        // sizes beyond the largest class are rejected by `too_big` first
+        unreachable!("no size class of {} bytes", ele_size);
+    }
+}
+
+pub(crate) struct Depot {
+    /// Locked by threads refilling different classes at once.
+    pools: [CachePadded<Mutex<SendPool>>; 6],
+}
+
+impl Drop for Depot {
+    fn drop(&mut self) {
+        // the slots cached on this thread go away with the pages, while
+        // other threads prune their caches lazily
+        let depot: *const Depot = self;
+        let _ = CACHES.try_with(|caches| {
+            if let Ok(mut caches) = caches.try_borrow_mut() {
+                caches.retain(|c| !std::ptr::eq(c.depot.as_ptr(), depot) && c.depot.strong_count() > 0);
+            }
+        });
+    }
+}
+
+impl Depot {
+    fn refill(&self, class: usize, magazine: &mut Vec<*mut Node>) {
+        let pool = lock(&self.pools[class]);
+        for _ in 0..MAGAZINE_SIZE {
+            magazine.push(pool.0.pop());
+        }
+    }
+
+    fn flush(&self, class: usize, nodes: &[*mut Node]) {
+        let pool = lock(&self.pools[class]);
+        for &node in nodes {
+            unsafe {
+                pool.0.recycle(node);
+            }
+        }
+    }
+}
+
+/// A thread-safe allocator, cloneable into handles sharing the same pages.
+///
+/// Allocation and recycling go through the current thread's
+/// `ThreadCache`, falling back to the shared depot in batches.
+/// A slot freed on a thread other than the one allocating it simply
+/// goes into the freeing thread's cache.
+#[derive(Clone)]
+pub struct SharedAllocator {
+    pub(crate) depot: Arc<Depot>,
+}
+
+impl SharedAllocator {
+    /// Construct a new allocator with default page capacity.
+    pub fn new() -> SharedAllocator {
+        SharedAllocator::with_pools(|ele_size| Pool::with_capacity(DEFAULT_POOL_SIZE/ele_size, ele_size))
+    }
+
+    /// Construct a new allocator with `cap`acity per inner page
+    pub fn with_capacity(cap: usize) -> SharedAllocator {
+        SharedAllocator::with_pools(|ele_size| Pool::with_capacity(cap, ele_size))
+    }
+
+    fn with_pools<F: Fn(usize) -> Box<Pool>>(pool: F) -> SharedAllocator {
+        let pools = [
+            CachePadded(Mutex::new(SendPool::new(pool(CLASSES[0])))),
+            CachePadded(Mutex::new(SendPool::new(pool(CLASSES[1])))),
+            CachePadded(Mutex::new(SendPool::new(pool(CLASSES[2])))),
+            CachePadded(Mutex::new(SendPool::new(pool(CLASSES[3])))),
+            CachePadded(Mutex::new(SendPool::new(pool(CLASSES[4])))),
+            CachePadded(Mutex::new(SendPool::new(pool(CLASSES[5])))),
+        ];
+        SharedAllocator{ depot: Arc::new(Depot{ pools }) }
+    }
+
+    /// Allocate an instance of `T` with value `elem` through the current
+    /// thread's cache, return the allocated pointer.
+    /// `size_of::<T>()` should be le to 256 bytes.
+    #[inline]
+    #[track_caller]
+    pub fn alloc<T: Copy>(&self, elem: T) -> SharedPointer<'_, T> {
+        ThreadCache::for_current(self).alloc(elem)
+    }
+
+    /// Count the free slots for `T` in the depot and this thread's cache.
+    #[cfg(test)]
+    pub(crate) fn free_count<T>(&self) -> usize {
+        let class = class_index(std::mem::size_of::<T>());
+        let cached = ThreadCache::for_current(self).cache.magazines[class].borrow().len();
+        lock(&self.depot.pools[class]).0.free_count() + cached
+    }
+
+    /// Count the slots for `T` of all pages.
+    #[cfg(test)]
+    pub(crate) fn slot_count<T>(&self) -> usize {
+        lock(&self.depot.pools[class_index(std::mem::size_of::<T>())]).0.slot_count()
+    }
+}
+
+impl Default for SharedAllocator {
+    #[inline]
+    fn default() -> SharedAllocator {
+        SharedAllocator::new()
+    }
+}
+
+struct Cache {
+    /// Weak, so that caches don't keep the depot alive after every handle
+    /// to it is dropped. The depot is passed to `pop` and `push` by the
+    /// handles instead.
+    depot: Weak<Depot>,
+    magazines: [RefCell<Vec<*mut Node>>; 6],
+}
+
+impl Cache {
+    fn new(depot: &Arc<Depot>) -> Cache {
+        let magazine = || RefCell::new(Vec::with_capacity(2 * MAGAZINE_SIZE));
+        Cache{
+            depot: Arc::downgrade(depot),
+            magazines: [magazine(), magazine(), magazine(), magazine(), magazine(), magazine()],
+        }
+    }
+
+    /// Whether this is the cache of `depot`. The address of a depot isn't
+    /// reused while a cache holds a weak reference to it.
+    #[inline]
+    fn is_of(&self, depot: &Arc<Depot>) -> bool {
+        std::ptr::eq(self.depot.as_ptr(), Arc::as_ptr(depot))
+    }
+
+    #[inline]
+    fn pop(&self, depot: &Depot, class: usize) -> *mut Node {
+        let mut magazine = self.magazines[class].borrow_mut();
+        if magazine.is_empty() {
+            depot.refill(class, &mut magazine);
+        }
+        magazine.pop().unwrap()
+    }
+
+    #[inline]
+    fn push(&self, depot: &Depot, class: usize, node: *mut Node) {
+        let mut magazine = self.magazines[class].borrow_mut();
+        if magazine.len() == 2 * MAGAZINE_SIZE {
+            depot.flush(class, &magazine[MAGAZINE_SIZE..]);
+            magazine.truncate(MAGAZINE_SIZE);
+        }
+        magazine.push(node);
+    }
+}
+
+impl Drop for Cache {
+    fn drop(&mut self) {
+        // once the depot is gone, so are the pages of the cached slots
+        if let Some(depot) = self.depot.upgrade() {
+            for (class, magazine) in self.magazines.iter().enumerate() {
+                depot.flush(class, &magazine.borrow());
+            }
+        }
+    }
+}
+
+thread_local! {
+    static CACHES: RefCell<Vec<Rc<Cache>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// The current thread's cache of free slots for a `SharedAllocator`.
+///
+/// Caches are created lazily, one per thread and allocator, and flushed
+/// back into the depot when the thread exits. Caches of allocators since
+/// dropped are discarded.
+pub struct ThreadCache<'a> {
+    shared: &'a SharedAllocator,
+    cache: Rc<Cache>,
+}
+
+impl<'a> ThreadCache<'a> {
+    /// Get the current thread's cache for `shared`.
+    pub fn for_current(shared: &'a SharedAllocator) -> ThreadCache<'a> {
+        let cache = CACHES.with(|caches| {
+            let mut caches = caches.borrow_mut();
+            if let Some(cache) = caches.iter().find(|c| c.is_of(&shared.depot)) {
+                return cache.clone();
+            }
+            caches.retain(|c| c.depot.strong_count() > 0);
+            let cache = Rc::new(Cache::new(&shared.depot));
+            caches.push(cache.clone());
+            cache
+        });
+        ThreadCache{ shared, cache }
+    }
+
+    /// Allocate an instance of `T` with value `elem`,
+    /// return the allocated pointer.
+    /// `size_of::<T>()` should be le to 256 bytes.
+    #[inline]
+    #[track_caller]
+    pub fn alloc<T: Copy>(&self, elem: T) -> SharedPointer<'a, T> {
+        if std::mem::size_of::<T>() > 256 {
+            too_big::<T>();
+        }
+        let class = class_index(std::mem::size_of::<T>());
+        check_slot::<T>(CLASSES[class]);
+        let node = self.cache.pop(&self.shared.depot, class) as *mut T;
+        unsafe {
+            std::ptr::write(node, elem);
+        }
+        SharedPointer{ depot: &self.shared.depot, node }
+    }
+
+    /// Drop `ptr`, recycling its slot into this cache, or as dropping it
+    /// would if it's of another allocator.
+    #[inline]
+    pub fn free<T: ?Sized>(&self, ptr: SharedPointer<'a, T>) {
+        if !self.cache.is_of(ptr.depot) {
+            return drop(ptr);
+        }
+        let (class, node) = ptr.take();
+        self.cache.push(&self.shared.depot, class, node);
+    }
+}
+
+/// A pointer to `T` allocated by a `SharedAllocator`.
+///
+/// It is `Send` and `Sync` whenever `T` is. When dropped, `T` would be
+/// dropped in place, then the underlying memory would be recycled into
+/// the current thread's cache.
+pub struct SharedPointer<'a, T: ?Sized> {
+    pub(crate) depot: &'a Arc<Depot>,
+    pub(crate) node: *mut T,
+}
+
+unsafe impl<'a, T: Send + ?Sized> Send for SharedPointer<'a, T> { }
+
+unsafe impl<'a, T: Sync + ?Sized> Sync for SharedPointer<'a, T> { }
+
+impl<'a, T: ?Sized> SharedPointer<'a, T> {
+    /// Get the address of the pointee.
+    #[inline]
+    pub fn as_ptr(this: &Self) -> *const T {
+        this.node
+    }
+
+    /// Consume `this` without recycling the slot, returning the address
+    /// of the pointee, which could be turned back with `from_raw`.
+    ///
+    /// The raw pointer carries the provenance of the page of the slot:
+    /// it must be kept as a pointer, as an `AtomicPtr` does, not as an
+    /// integer, for `from_raw` to get a pointer allowed to access the
+    /// slot again. Pointers derived from it with `add`, `cast` or
+    /// `with_addr` keep the provenance too.
+    #[inline]
+    pub fn into_raw(this: Self) -> *mut T {
+        let node = this.node;
+        std::mem::forget(this);
+        node
+    }
+
+    /// Reconstruct a pointer from the result of `into_raw`.
+    ///
+    /// # Safety
+    ///
+    /// `raw` must have been returned by `into_raw` on a pointer allocated
+    /// by `shared`, or a clone of it, and must not be reconstructed twice.
+    /// Its provenance must be that of the result of `into_raw`, not of a
+    /// pointer made up from its address.
+    #[inline]
+    pub unsafe fn from_raw(shared: &'a SharedAllocator, raw: *mut T) -> SharedPointer<'a, T> {
+        SharedPointer{ depot: &shared.depot, node: raw }
+    }
+
+    /// Drop the pointee, returning the class and the node of the slot.
+    #[inline]
+    fn take(self) -> (usize, *mut Node) {
+        let class = class_index(std::mem::size_of_val(&*self));
+        let node = self.node;
+        std::mem::forget(self);
+        unsafe {
+            std::ptr::drop_in_place(node);
+        }
+        (class, node as *mut u8 as *mut Node)
+    }
+}
+
+impl<'a, T: ?Sized> std::ops::Deref for SharedPointer<'a, T> {
+    type Target = T;
+    #[inline]
+    fn deref(&self) -> &T {
+        unsafe {
+            &*self.node
+        }
+    }
+}
+
+impl<'a, T: ?Sized> std::ops::DerefMut for SharedPointer<'a, T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe {
+            &mut *self.node
+        }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for SharedPointer<'a, T> {
+    fn drop(&mut self) {
+        let depot = self.depot;
+        let (class, node) = SharedPointer{ depot, node: self.node }.take();
+        let cached = CACHES.try_with(|caches| {
+            let caches = caches.borrow();
+            match caches.iter().find(|c| c.is_of(depot)) {
+                Some(cache) => cache.push(depot, class, node),
+                None => depot.flush(class, &[node]),
+            }
+        });
+        // the thread is exiting, and its caches are gone
+        if cached.is_err() {
+            depot.flush(class, &[node]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::sync::mpsc;
+    use std::thread;
+
+    fn depot_free_count(shared: &SharedAllocator, class: usize) -> usize {
+        lock(&shared.depot.pools[class]).0.free_count()
+    }
+
+    #[test]
+    fn test_cache_batches() {
+        let shared = SharedAllocator::with_capacity(64);
+        let cache = ThreadCache::for_current(&shared);
+        let first = cache.alloc(1u64);
+        assert_eq!(depot_free_count(&shared, 0), 64 - MAGAZINE_SIZE);
+        assert_eq!(cache.cache.magazines[0].borrow().len(), MAGAZINE_SIZE - 1);
+
+        let mut held: Vec<_> = (0..MAGAZINE_SIZE as u64).map(|i| cache.alloc(i)).collect();
+        assert_eq!(depot_free_count(&shared, 0), 0);
+        held.push(first);
+        let addrs: HashSet<usize> = held.iter().map(|p| &**p as *const u64 as usize).collect();
+        assert_eq!(addrs.len(), held.len());
+
+        for p in held.drain(..) {
+            cache.free(p);
+        }
+        // 31 slots were left over from the second refill, plus the 33 freed
+        assert_eq!(cache.cache.magazines[0].borrow().len(), 2 * MAGAZINE_SIZE);
+        assert_eq!(depot_free_count(&shared, 0), 0);
+        // the same cache is handed out again on this thread
+        assert!(Rc::ptr_eq(&ThreadCache::for_current(&shared).cache, &cache.cache));
+    }
+
+    #[test]
+    fn test_free_foreign() {
+        let shared = SharedAllocator::with_capacity(64);
+        let other = SharedAllocator::with_capacity(64);
+        let cache = ThreadCache::for_current(&shared);
+        let mine = cache.alloc(1u64);
+        let foreign = other.alloc(2u64);
+        let addr = &*foreign as *const u64 as usize;
+        cache.free(mine);
+        cache.free(foreign);
+        // the foreign slot went back to its own allocator
+        assert_eq!(shared.free_count::<u64>(), 64);
+        assert_eq!(other.free_count::<u64>(), 64);
+        let held: Vec<_> = (0..64u64).map(|i| cache.alloc(i)).collect();
+        assert!(held.iter().all(|p| &**p as *const u64 as usize != addr));
+    }
+
+    #[test]
+    fn test_flush_when_full() {
+        let shared = SharedAllocator::with_capacity(256);
+        let held: Vec<_> = (0..3 * MAGAZINE_SIZE).map(|i| shared.alloc(i as u32)).collect();
+        let cache = ThreadCache::for_current(&shared);
+        let before = depot_free_count(&shared, 0);
+        drop(held);
+        assert_eq!(depot_free_count(&shared, 0), before + MAGAZINE_SIZE);
+        assert_eq!(cache.cache.magazines[0].borrow().len(), 2 * MAGAZINE_SIZE);
+    }
+
+    #[test]
+    #[cfg_attr(target_family = "wasm", ignore = "no threads on wasm")]
+    fn test_cross_thread_free() {
+        // `thread::scope` might return before the thread-locals of the
+        // scoped threads are destroyed, so join plain threads instead
+        let shared: &'static SharedAllocator = Box::leak(Box::new(SharedAllocator::with_capacity(64)));
+        let (tx, rx) = mpsc::channel();
+        // Miri runs the threads too slowly for the full count
+        let n = if cfg!(miri) { 100 } else { 10_000u64 };
+        let producer = thread::spawn(move || {
+            for i in 0..n {
+                tx.send(shared.alloc([i; 4])).unwrap();
+            }
+        });
+        let consumer = thread::spawn(move || {
+            let cache = ThreadCache::for_current(shared);
+            for (i, p) in rx.iter().enumerate() {
+                assert_eq!(p[3], i as u64);
+                cache.free(p);
+            }
+        });
+        producer.join().unwrap();
+        consumer.join().unwrap();
+        // both threads exited, flushing their caches
+        assert_eq!(shared.free_count::<[u64; 4]>(), shared.slot_count::<[u64; 4]>());
+    }
+
+    #[test]
+    #[cfg_attr(target_family = "wasm", ignore = "no threads on wasm")]
+    fn test_stress() {
+        let shared: &'static SharedAllocator = Box::leak(Box::new(SharedAllocator::with_capacity(128)));
+        let n = if cfg!(miri) { 100 } else { 5000 };
+        let handles: Vec<_> = (0..8u64).map(|t| {
+            thread::spawn(move || {
+                let mut held = Vec::new();
+                for i in 0..n {
+                    held.push(shared.alloc([t, i, 0]));
+                    if i % 3 != 0 {
+                        let p = held.swap_remove((i as usize * 7) % held.len());
+                        assert_eq!(p[0], t);
+                    }
+                }
+                held
+            })
+        }).collect();
+        let held: Vec<Vec<SharedPointer<[u64; 3]>>> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        let addrs: HashSet<usize> = held.iter().flatten().map(|p| &**p as *const _ as usize).collect();
+        assert_eq!(addrs.len(), held.iter().map(|h| h.len()).sum::<usize>());
+
+        // drop the pointers allocated on the other threads here
+        drop(held);
+        assert_eq!(shared.free_count::<[u64; 3]>(), shared.slot_count::<[u64; 3]>());
+    }
+
+    #[test]
+    #[cfg_attr(target_family = "wasm", ignore = "no threads on wasm")]
+    fn test_depot_freed() {
+        let shared = SharedAllocator::with_capacity(64);
+        let depot = Arc::downgrade(&shared.depot);
+        drop(shared.alloc(1u64));
+        let clone = shared.clone();
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let (exit_tx, exit_rx) = mpsc::channel::<()>();
+        let worker = thread::spawn(move || {
+            drop(clone.alloc(2u32));
+            drop(clone);
+            ready_tx.send(()).unwrap();
+            // exits with a cache of the allocator, after it's dropped
+            exit_rx.recv().unwrap();
+        });
+        ready_rx.recv().unwrap();
+        drop(shared);
+        // neither cache keeps the depot alive
+        assert!(depot.upgrade().is_none());
+        CACHES.with(|caches| assert!(caches.borrow().is_empty()));
+        exit_tx.send(()).unwrap();
+        worker.join().unwrap();
+
+        let shared = SharedAllocator::with_capacity(64);
+        assert_eq!(*shared.alloc(3u64), 3);
+        CACHES.with(|caches| assert_eq!(caches.borrow().len(), 1));
+    }
+}
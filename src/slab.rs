@@ -0,0 +1,572 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Slab storage addressed by small copyable handles.
+
+use {TypedPool, TypedPoolIter, TypedPoolIterMut};
+
+/// A handle to an element of a `Slab`, encoding the index of its key,
+/// and the generation of the key.
+///
+/// Keys are handed out in order and reused, much like slots, but they
+/// stay put as `Slab::compact` moves the elements. The generation is 32
+/// bits wide. As a key is retired for good rather than wrapping its
+/// generation around, a stale handle never hits a later element.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Handle {
+    index: u32,
+    generation: u32,
+}
+
+impl Handle {
+    /// Get the index of the key.
+    #[inline]
+    pub fn index(self) -> u32 {
+        self.index
+    }
+
+    /// Get the generation of the key when the handle was created.
+    #[inline]
+    pub fn generation(self) -> u32 {
+        self.generation
+    }
+
+    /// Pack the handle into 64 bits, the generation in the high half.
+    #[inline]
+    pub fn to_bits(self) -> u64 {
+        (self.generation as u64) << 32 | self.index as u64
+    }
+
+    /// Unpack a handle from the result of `to_bits`.
+    #[inline]
+    pub fn from_bits(bits: u64) -> Handle {
+        Handle{ index: bits as u32, generation: (bits >> 32) as u32 }
+    }
+}
+
+/// A storage of `T`s addressed by `Handle`s rather than pointers.
+///
+/// Looking up a handle is O(1), and an out of range handle, or the
+/// handle of a removed element, simply misses, even if its slot has
+/// been reused since.
+pub struct Slab<T: Copy> {
+    pool: TypedPool<Entry<T>>,
+    keys: Vec<Key>,
+    /// The first free key, the free keys linking to one another.
+    free_key: Option<u32>,
+}
+
+/// An element, along with the key it is found by.
+#[derive(Copy, Clone)]
+struct Entry<T> {
+    key: u32,
+    value: T,
+}
+
+#[derive(Copy, Clone)]
+enum KeyState {
+    /// The key of the element at the index of the pool.
+    Occupied(u32),
+    /// A free key, linking to the next free one.
+    Vacant(Option<u32>),
+    /// A free key whose generation wrapped around, never handed out again.
+    Retired,
+}
+
+#[derive(Copy, Clone)]
+struct Key {
+    /// Bumped whenever the element of the key is removed.
+    generation: u32,
+    state: KeyState,
+}
+
+impl<T: Copy> Slab<T> {
+    /// Construct a slab with pages of about 4096 bytes, or 1024 bytes
+    /// on wasm32.
+    #[inline]
+    pub fn new() -> Slab<T> {
+        Slab::from_pool(TypedPool::new())
+    }
+
+    /// Construct a slab with pages of `slots` slots, rounded up to
+    /// a power of two.
+    #[inline]
+    pub fn with_page_size(slots: usize) -> Slab<T> {
+        Slab::from_pool(TypedPool::with_page_size(slots))
+    }
+
+    #[inline]
+    fn from_pool(pool: TypedPool<Entry<T>>) -> Slab<T> {
+        Slab{ pool, keys: Vec::new(), free_key: None }
+    }
+
+    /// Number of elements in the slab.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.pool.len()
+    }
+
+    /// Whether the slab is empty.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.pool.is_empty()
+    }
+
+    /// Insert `value`, return its handle.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the slab would hold more than `u32::MAX` keys.
+    #[inline]
+    pub fn insert(&mut self, value: T) -> Handle {
+        let key = match self.free_key {
+            Some(key) => key,
+            None => {
+                assert!(self.keys.len() < u32::MAX as usize, "Slab key space exhausted");
+                self.keys.push(Key{ generation: 0, state: KeyState::Vacant(None) });
+                (self.keys.len() - 1) as u32
+            }
+        };
+        let index = self.pool.alloc(Entry{ key, value });
+        let slot = &mut self.keys[key as usize];
+        if let KeyState::Vacant(next) = std::mem::replace(&mut slot.state, KeyState::Occupied(index)) {
+            self.free_key = next;
+        }
+        Handle{ index: key, generation: slot.generation }
+    }
+
+    /// The index in the pool of the element of `handle`, if any.
+    #[inline]
+    fn index_of(&self, handle: Handle) -> Option<u32> {
+        match self.keys.get(handle.index as usize) {
+            Some(&Key{ generation, state: KeyState::Occupied(index) }) if generation == handle.generation => Some(index),
+            _ => None,
+        }
+    }
+
+    /// Whether the element of `handle` is still in the slab.
+    #[inline]
+    pub fn contains(&self, handle: Handle) -> bool {
+        self.index_of(handle).is_some()
+    }
+
+    /// Get the element of `handle`, if any.
+    #[inline]
+    pub fn get(&self, handle: Handle) -> Option<&T> {
+        let index = self.index_of(handle)?;
+        self.pool.get(index).map(|entry| &entry.value)
+    }
+
+    /// Get the element of `handle` mutably, if any.
+    #[inline]
+    pub fn get_mut(&mut self, handle: Handle) -> Option<&mut T> {
+        let index = self.index_of(handle)?;
+        self.pool.get_mut(index).map(|entry| &mut entry.value)
+    }
+
+    /// Remove the element of `handle` and return it, if any.
+    #[inline]
+    pub fn remove(&mut self, handle: Handle) -> Option<T> {
+        let index = self.index_of(handle)?;
+        let entry = self.pool.recycle(index)?;
+        let key = &mut self.keys[handle.index as usize];
+        key.generation = key.generation.wrapping_add(1);
+        if key.generation == 0 {
+            key.state = KeyState::Retired;
+        } else {
+            key.state = KeyState::Vacant(self.free_key);
+            self.free_key = Some(handle.index);
+        }
+        Some(entry.value)
+    }
+
+    /// Iterate over the elements with their handles, in the order of
+    /// their slots, page by page. That's not the order of the handles,
+    /// as removed keys are reused last freed first, and `compact` moves
+    /// the elements.
+    #[inline]
+    pub fn iter(&self) -> SlabIter<'_, T> {
+        SlabIter{ entries: self.pool.iter(), keys: &self.keys }
+    }
+
+    /// Iterate over the elements mutably with their handles, in the
+    /// order of their slots, as `iter` does.
+    #[inline]
+    pub fn iter_mut(&mut self) -> SlabIterMut<'_, T> {
+        SlabIterMut{ entries: self.pool.iter_mut(), keys: &self.keys }
+    }
+
+    /// Visit the elements in the order of `iter`, removing those for which
+    /// `f` returns `false`, as `remove` does.
+    ///
+    /// If `f` panics, the elements removed so far stay removed, and the
+    /// elements left unvisited stay in the slab.
+    ///
+    /// ```rust
+    /// use aren_alloc::Slab;
+    /// let mut lives = Slab::new();
+    /// let handles: Vec<_> = (0..10u32).map(|i| lives.insert(i)).collect();
+    /// lives.retain(|_, life| {
+    ///     *life = life.saturating_sub(4);
+    ///     *life > 0
+    /// });
+    /// assert_eq!(lives.len(), 5);
+    /// assert_eq!(lives.get(handles[4]), None);
+    /// assert_eq!(lives.get(handles[5]), Some(&1));
+    /// ```
+    pub fn retain<F: FnMut(Handle, &mut T) -> bool>(&mut self, mut f: F) {
+        let keys = &mut self.keys;
+        let free_key = &mut self.free_key;
+        self.pool.retain(|_, entry| {
+            let key = &mut keys[entry.key as usize];
+            if f(Handle{ index: entry.key, generation: key.generation }, &mut entry.value) {
+                return true;
+            }
+            key.generation = key.generation.wrapping_add(1);
+            if key.generation == 0 {
+                key.state = KeyState::Retired;
+            } else {
+                key.state = KeyState::Vacant(*free_key);
+                *free_key = Some(entry.key);
+            }
+            false
+        });
+    }
+
+    /// Remove every element, but keep the pages. All the handles go
+    /// stale, and the keys are handed out again from the first one.
+    ///
+    /// ```rust
+    /// use aren_alloc::Slab;
+    /// let mut slab = Slab::new();
+    /// let old = slab.insert(1u8);
+    /// slab.clear();
+    /// let new = slab.insert(2);
+    /// assert_eq!((slab.get(old), slab.get(new)), (None, Some(&2)));
+    /// ```
+    pub fn clear(&mut self) {
+        self.pool.clear();
+        self.free_key = None;
+        for (index, key) in self.keys.iter_mut().enumerate().rev() {
+            if let KeyState::Occupied(_) = key.state {
+                key.generation = key.generation.wrapping_add(1);
+                if key.generation == 0 {
+                    key.state = KeyState::Retired;
+                    continue;
+                }
+            }
+            if let KeyState::Occupied(_) | KeyState::Vacant(_) = key.state {
+                key.state = KeyState::Vacant(self.free_key);
+                self.free_key = Some(index as u32);
+            }
+        }
+    }
+
+    /// Move the elements into the first pages, then release the pages
+    /// left empty, returning the bytes released.
+    ///
+    /// Handles stay valid, as they address keys rather than slots. The
+    /// elements moved get new addresses, and their previous slots new
+    /// generations, as if they were removed.
+    ///
+    /// ```rust
+    /// use aren_alloc::Slab;
+    /// let mut slab = Slab::with_page_size(16);
+    /// let handles: Vec<_> = (0..1000u64).map(|i| slab.insert(i)).collect();
+    /// for h in handles.iter().filter(|h| h.index() % 10 != 0) {
+    ///     slab.remove(*h);
+    /// }
+    /// assert!(slab.compact() > 0);
+    /// assert_eq!(slab.get(handles[990]), Some(&990));
+    /// ```
+    pub fn compact(&mut self) -> usize {
+        let keys = &mut self.keys;
+        self.pool.compact(|entry, index| keys[entry.key as usize].state = KeyState::Occupied(index))
+    }
+
+    /// Number of slots of all pages.
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.pool.capacity()
+    }
+
+    /// Number of elements to be inserted before the slab grows.
+    #[inline]
+    pub fn free_slots(&self) -> usize {
+        self.pool.free_slots()
+    }
+
+    /// Add pages until at least `additional` slots are free, and room
+    /// for as many keys.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the slab would hold more than `u32::MAX` slots.
+    pub fn reserve(&mut self, additional: usize) {
+        self.pool.reserve(additional);
+        self.keys.reserve(additional);
+    }
+}
+
+/// An iterator over the elements of a `Slab`, see `Slab::iter`.
+pub struct SlabIter<'a, T: 'a> {
+    entries: TypedPoolIter<'a, Entry<T>>,
+    keys: &'a [Key],
+}
+
+impl<'a, T> Iterator for SlabIter<'a, T> {
+    type Item = (Handle, &'a T);
+
+    #[inline]
+    fn next(&mut self) -> Option<(Handle, &'a T)> {
+        let (_, entry) = self.entries.next()?;
+        let generation = self.keys[entry.key as usize].generation;
+        Some((Handle{ index: entry.key, generation }, &entry.value))
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.entries.size_hint()
+    }
+}
+
+impl<'a, T> ExactSizeIterator for SlabIter<'a, T> { }
+
+/// A mutable iterator over the elements of a `Slab`, see `Slab::iter_mut`.
+pub struct SlabIterMut<'a, T: 'a> {
+    entries: TypedPoolIterMut<'a, Entry<T>>,
+    keys: &'a [Key],
+}
+
+impl<'a, T> Iterator for SlabIterMut<'a, T> {
+    type Item = (Handle, &'a mut T);
+
+    #[inline]
+    fn next(&mut self) -> Option<(Handle, &'a mut T)> {
+        let (_, entry) = self.entries.next()?;
+        let generation = self.keys[entry.key as usize].generation;
+        Some((Handle{ index: entry.key, generation }, &mut entry.value))
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.entries.size_hint()
+    }
+}
+
+impl<'a, T> ExactSizeIterator for SlabIterMut<'a, T> { }
+
+impl<T: Copy> Default for Slab<T> {
+    #[inline]
+    fn default() -> Slab<T> {
+        Slab::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_remove_reuse() {
+        let mut slab = Slab::with_page_size(4);
+        let a = slab.insert(1u32);
+        let b = slab.insert(2);
+        assert_eq!((slab.get(a), slab.get(b)), (Some(&1), Some(&2)));
+        *slab.get_mut(b).unwrap() = 3;
+
+        let mut b = b;
+        for round in 0..10 {
+            assert_eq!(slab.remove(b), Some(if round == 0 { 3 } else { round }));
+            assert_eq!(slab.remove(b), None);
+            assert_eq!(slab.get(b), None);
+            // the freed slot is reused first
+            let new = slab.insert(round + 1);
+            assert_eq!(new.index(), b.index());
+            b = new;
+        }
+        assert_eq!(slab.len(), 2);
+        assert_eq!(slab.get(a), Some(&1));
+    }
+
+    #[test]
+    fn test_stable_across_growth() {
+        let mut slab = Slab::with_page_size(8);
+        let first = slab.insert([7u64; 4]);
+        let addr = slab.get(first).unwrap() as *const [u64; 4];
+        let handles: Vec<Handle> = (0..1000u64).map(|i| slab.insert([i; 4])).collect();
+        assert_eq!(slab.get(first), Some(&[7; 4]));
+        assert_eq!(slab.get(first).unwrap() as *const [u64; 4], addr);
+        assert!(handles.iter().enumerate().all(|(i, h)| slab.get(*h) == Some(&[i as u64; 4])));
+        // page 10, slot 3
+        assert_eq!(handles[82].index(), 10 * 8 + 3);
+    }
+
+    #[test]
+    fn test_out_of_range() {
+        let mut slab = Slab::with_page_size(4);
+        assert_eq!(slab.get(Handle::from_bits(0)), None);
+        slab.insert(1u8);
+        assert_eq!(slab.get(Handle::from_bits(3)), None);
+        assert_eq!(slab.get(Handle::from_bits(4)), None);
+        assert_eq!(slab.get_mut(Handle::from_bits(u32::MAX as u64)), None);
+        assert_eq!(slab.remove(Handle::from_bits(1 << 20)), None);
+        assert!(!slab.contains(Handle::from_bits(1 << 32)));
+        assert_eq!(slab.len(), 1);
+    }
+
+    #[test]
+    fn test_stale_handle() {
+        let mut slab = Slab::new();
+        let old = slab.insert(1u16);
+        assert!(slab.contains(old));
+        assert_eq!(slab.remove(old), Some(1));
+        assert!(!slab.contains(old));
+
+        let new = slab.insert(2);
+        assert_eq!(new.index(), old.index());
+        assert_eq!(new.generation(), old.generation() + 1);
+        assert_eq!(slab.get(old), None);
+        assert_eq!(slab.get_mut(old), None);
+        assert_eq!(slab.remove(old), None);
+        assert!(slab.contains(new));
+        assert_eq!(slab.get(new), Some(&2));
+        assert_eq!(Handle::from_bits(new.to_bits()), new);
+    }
+
+    #[test]
+    fn test_generation_wraparound() {
+        let mut slab = Slab::with_page_size(2);
+        let first = slab.insert(1u32);
+        slab.keys[first.index() as usize].generation = u32::MAX;
+        let last = Handle{ index: first.index(), generation: u32::MAX };
+        assert!(!slab.contains(first));
+        assert_eq!(slab.remove(last), Some(1));
+
+        // rather than wrapping around to generation 0, which `first` holds,
+        // the key is retired
+        let next = slab.insert(2);
+        assert_ne!(next.index(), first.index());
+        assert!(!slab.contains(first));
+        assert!(!slab.contains(last));
+        assert_eq!(slab.remove(first), None);
+        assert_eq!(slab.len(), 1);
+    }
+
+    #[test]
+    fn test_compact() {
+        let mut slab = Slab::with_page_size(8);
+        let handles: Vec<Handle> = (0..100u64).map(|i| slab.insert([i; 2])).collect();
+        let removed: Vec<Handle> = handles.iter().filter(|h| h.index() % 9 != 4).cloned().collect();
+        for &h in removed.iter() {
+            slab.remove(h);
+        }
+        let kept: Vec<Handle> = handles.iter().filter(|h| h.index() % 9 == 4).cloned().collect();
+        assert_eq!((slab.len(), slab.capacity()), (11, 104));
+
+        let page = 8 * std::mem::size_of::<::typed::Slot<Entry<[u64; 2]>>>();
+        assert_eq!(slab.compact(), 11 * page);
+        assert_eq!(slab.capacity(), 16);
+        assert!(kept.iter().all(|&h| slab.get(h) == Some(&[h.index() as u64; 2])));
+        assert!(removed.iter().all(|&h| !slab.contains(h)));
+        // the keys of removed elements are reused, not those of the moved ones
+        let new = slab.insert([7; 2]);
+        assert_eq!(new, Handle{ index: removed.last().unwrap().index(), generation: 1 });
+        *slab.get_mut(kept[10]).unwrap() = [0; 2];
+        assert_eq!(slab.remove(kept[10]), Some([0; 2]));
+        assert_eq!(slab.get(kept[9]), Some(&[85; 2]));
+        assert_eq!(slab.compact(), 0);
+    }
+
+    #[test]
+    fn test_iter() {
+        let mut slab = Slab::with_page_size(4);
+        let handles: Vec<Handle> = (0..10u32).map(|i| slab.insert(i)).collect();
+        for h in handles.iter().filter(|h| h.index() % 3 == 0) {
+            slab.remove(*h);
+        }
+        let live: Vec<(Handle, u32)> = slab.iter().map(|(h, v)| (h, *v)).collect();
+        let expected: Vec<(Handle, u32)> = handles.iter().filter(|h| h.index() % 3 != 0).map(|&h| (h, h.index())).collect();
+        assert_eq!(live, expected);
+
+        // the new elements take the freed slots, then grow into a new page
+        let added: Vec<Handle> = (10..15).map(|i| slab.insert(i)).collect();
+        for (h, v) in slab.iter_mut() {
+            *v += 100 * h.generation();
+        }
+        let live: Vec<(u32, u32)> = slab.iter().map(|(h, v)| (h.index(), *v)).collect();
+        assert_eq!(live, [(0, 113), (1, 1), (2, 2), (3, 112), (4, 4), (5, 5), (6, 111), (7, 7), (8, 8), (9, 110), (10, 14)]);
+        assert!(slab.iter().all(|(h, v)| slab.get(h) == Some(v)));
+        assert_eq!(added[4].index(), 10);
+        assert_eq!(slab.iter_mut().len(), 11);
+    }
+
+    #[test]
+    #[cfg_attr(target_family = "wasm", ignore = "panics abort on wasm")]
+    fn test_retain() {
+        let mut slab = Slab::with_page_size(4);
+        let handles: Vec<Handle> = (0..10u32).map(|i| slab.insert(i)).collect();
+        let mut visited = Vec::new();
+        slab.retain(|h, v| {
+            visited.push(h);
+            *v += 100;
+            h.index() % 3 != 0
+        });
+        assert_eq!(visited, handles);
+        assert_eq!(slab.len(), 6);
+        assert!(handles.iter().all(|&h| slab.get(h).cloned() == if h.index() % 3 == 0 { None } else { Some(h.index() + 100) }));
+        let new = slab.insert(7);
+        assert_eq!(new, Handle{ index: 9, generation: 1 });
+
+        // the elements removed before a panic stay removed
+        let err = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            slab.retain(|h, _| {
+                assert!(h.index() != 4, "stop");
+                false
+            });
+        })).unwrap_err();
+        assert_eq!(err.downcast_ref::<&str>(), Some(&"stop"));
+        assert_eq!(slab.iter().map(|(h, _)| h.index()).collect::<Vec<u32>>(), [4, 5, 7, 8, 9]);
+        assert!(!slab.contains(handles[1]) && !slab.contains(handles[2]));
+    }
+
+    #[test]
+    fn test_clear() {
+        let mut slab = Slab::with_page_size(4);
+        let handles: Vec<Handle> = (0..10u32).map(|i| slab.insert(i)).collect();
+        slab.remove(handles[6]);
+        slab.clear();
+        assert_eq!((slab.len(), slab.capacity()), (0, 12));
+        assert!(handles.iter().all(|&h| !slab.contains(h)));
+        assert_eq!(slab.iter().next(), None);
+
+        let new: Vec<Handle> = (0..10u32).map(|i| slab.insert(i)).collect();
+        // the key removed before was bumped once already
+        assert!(new.iter().enumerate().all(|(i, n)| *n == Handle{ index: i as u32, generation: 1 }));
+        assert!(handles.iter().all(|&h| !slab.contains(h)));
+        assert_eq!(slab.capacity(), 12);
+    }
+
+    #[test]
+    fn test_counters() {
+        let mut slab = Slab::with_page_size(8);
+        let counters = |slab: &Slab<u8>| (slab.len(), slab.capacity(), slab.free_slots(), slab.is_empty());
+        assert_eq!(counters(&slab), (0, 0, 0, true));
+        slab.reserve(10);
+        assert_eq!(counters(&slab), (0, 16, 16, true));
+        let handles: Vec<Handle> = (0..20).map(|i| slab.insert(i)).collect();
+        assert_eq!(counters(&slab), (20, 24, 4, false));
+        slab.remove(handles[3]);
+        assert_eq!(counters(&slab), (19, 24, 5, false));
+        slab.clear();
+        assert_eq!(counters(&slab), (0, 24, 24, true));
+        slab.insert(0);
+        slab.compact();
+        assert_eq!(counters(&slab), (1, 8, 7, false));
+    }
+}
@@ -0,0 +1,351 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Slices allocated from the pools.
+
+use std::alloc::AllocError;
+use std::fmt;
+use std::marker::PhantomData;
+
+use {check_slot, Allocator, Pointer, SlotGuard};
+
+impl Allocator {
+    /// Collect `iter` into a slot of the class of its `len()` elements,
+    /// writing each element into the slot as it is produced.
+    ///
+    /// An iterator yielding fewer elements than its `len()` gives a
+    /// shorter slice; elements beyond `len()` aren't consumed.
+    ///
+    /// ```rust
+    /// use aren_alloc::Allocator;
+    /// let allocator = Allocator::new();
+    /// let squares = allocator.alloc_iter_exact((1..5u32).map(|i| i * i)).unwrap();
+    /// assert_eq!(&*squares, &[1, 4, 9, 16]);
+    /// assert!(allocator.alloc_iter_exact((0..33u32).map(u64::from)).is_err());
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns an error, without consuming `iter`, if the elements are
+    /// larger than 256 bytes, or their class is missing. Returns an
+    /// error too if the budget of the allocator is exhausted, and its
+    /// handler couldn't recover.
+    ///
+    /// # Panics
+    ///
+    /// If the iterator panics, the slot is recycled before unwinding.
+    #[inline]
+    #[track_caller]
+    pub fn alloc_iter_exact<T: Copy, I>(&self, iter: I) -> Result<Pointer<'_, [T]>, AllocError>
+        where I: ExactSizeIterator<Item=T>,
+    {
+        let len = iter.len();
+        let size = len.checked_mul(std::mem::size_of::<T>()).ok_or(AllocError)?;
+        let pool = self.pool_for_size(size).ok_or(AllocError)?;
+        check_slot::<T>(pool.ele_size);
+        let node = self.pop_or_recover(pool, size).ok_or(AllocError)?;
+        // recycles the slot should `iter` panic
+        let slot = SlotGuard::new(pool, node);
+        let base = node as *mut T;
+        let mut written = 0;
+        for elem in iter.take(len) {
+            unsafe { std::ptr::write(base.add(written), elem) };
+            written += 1;
+        }
+        slot.disarm();
+        Ok(unsafe { Pointer::from_node(pool, std::ptr::slice_from_raw_parts_mut(base, written)) })
+    }
+
+    /// Copy the elements of `src`, which may be allocated from another
+    /// allocator, into a slot of this one, of the class of its length.
+    ///
+    /// ```rust
+    /// use aren_alloc::Allocator;
+    /// let frame = Allocator::new();
+    /// let persistent = Allocator::new();
+    /// let ids = frame.alloc_iter_exact(0..4u32).unwrap();
+    /// let kept = persistent.duplicate_slice(&ids);
+    /// drop(ids);
+    /// drop(frame);
+    /// assert_eq!(&*kept, &[0, 1, 2, 3]);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if the class of the elements is missing, or the budget of
+    /// the allocator is exhausted, and its handler couldn't recover.
+    #[inline]
+    #[track_caller]
+    pub fn duplicate_slice<T: Copy>(&self, src: &Pointer<[T]>) -> Pointer<'_, [T]> {
+        let size = std::mem::size_of_val::<[T]>(src);
+        let pool = match self.pool_for_size(size) {
+            Some(pool) => pool,
+            None => panic!("no size class of the allocator holds {} bytes", size),
+        };
+        check_slot::<T>(pool.ele_size);
+        let node = match self.pop_or_recover(pool, size) {
+            Some(node) => node as *mut T,
+            None => panic!("allocation budget exhausted"),
+        };
+        unsafe {
+            std::ptr::copy_nonoverlapping(src.as_ptr(), node, src.len());
+            Pointer::from_node(pool, std::ptr::slice_from_raw_parts_mut(node, src.len()))
+        }
+    }
+
+    /// Start a slice of at most `max_len` elements, taking a slot of the
+    /// class of `max_len` elements up front.
+    ///
+    /// ```rust
+    /// use aren_alloc::Allocator;
+    /// let allocator = Allocator::new();
+    /// let mut clipped = allocator.slice_builder::<(f32, f32)>(12).unwrap();
+    /// for v in [(0.0, 0.0), (1.0, 0.0), (0.5, 1.0)] {
+    ///     clipped.push(v).unwrap();
+    /// }
+    /// let clipped = clipped.finish();
+    /// assert_eq!(clipped.len(), 3);
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `max_len` elements are larger than 256 bytes,
+    /// or their class is missing. Returns an error too if the budget of
+    /// the allocator is exhausted, and its handler couldn't recover.
+    #[inline]
+    #[track_caller]
+    pub fn slice_builder<T: Copy>(&self, max_len: usize) -> Result<SliceBuilder<'_, T>, AllocError> {
+        let size = max_len.checked_mul(std::mem::size_of::<T>()).ok_or(AllocError)?;
+        let pool = self.pool_for_size(size).ok_or(AllocError)?;
+        check_slot::<T>(pool.ele_size);
+        let node = self.pop_or_recover(pool, size).ok_or(AllocError)?;
+        Ok(SliceBuilder{ slot: SlotGuard::new(pool, node), len: 0, max_len, _marker: PhantomData })
+    }
+}
+
+/// A slice filled element by element into a slot sized for its longest,
+/// created by `Allocator::slice_builder`.
+///
+/// The slot is kept by the finished slice, its slack included. Dropping
+/// the builder without finishing it recycles the slot, unwinding
+/// included.
+pub struct SliceBuilder<'a, T: Copy> {
+    slot: SlotGuard<'a>,
+    len: usize,
+    max_len: usize,
+    _marker: PhantomData<T>,
+}
+
+impl<'a, T: Copy> SliceBuilder<'a, T> {
+    #[inline]
+    fn base(&self) -> *mut T {
+        self.slot.node as *mut T
+    }
+
+    /// Append `value`, or hand it back if the slice already holds
+    /// `max_len` elements.
+    #[inline]
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        if self.len == self.max_len {
+            return Err(value);
+        }
+        unsafe { std::ptr::write(self.base().add(self.len), value) };
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Number of elements pushed so far.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether no element is pushed yet.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Number of elements the slice may hold.
+    #[inline]
+    pub fn max_len(&self) -> usize {
+        self.max_len
+    }
+
+    /// The elements pushed so far.
+    #[inline]
+    pub fn as_slice(&self) -> &[T] {
+        unsafe { std::slice::from_raw_parts(self.base(), self.len) }
+    }
+
+    /// Turn the elements pushed so far into a slice, keeping the slot.
+    #[inline]
+    pub fn finish(self) -> Pointer<'a, [T]> {
+        let pool = self.slot.pool;
+        let node = self.slot.disarm() as *mut T;
+        unsafe { Pointer::from_node(pool, std::ptr::slice_from_raw_parts_mut(node, self.len)) }
+    }
+}
+
+impl<'a, T: Copy + fmt::Debug> fmt::Debug for SliceBuilder<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SliceBuilder")
+            .field("elements", &self.as_slice())
+            .field("max_len", &self.max_len)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::panic;
+
+    /// Claims one more element than it yields.
+    struct Short(std::ops::Range<u16>);
+
+    impl Iterator for Short {
+        type Item = u16;
+        fn next(&mut self) -> Option<u16> {
+            self.0.next()
+        }
+    }
+
+    impl ExactSizeIterator for Short {
+        fn len(&self) -> usize {
+            self.0.len() + 1
+        }
+    }
+
+    #[test]
+    fn test_alloc_iter_exact() {
+        let allocator = Allocator::new();
+        let nums = allocator.alloc_iter_exact(0..10u32).unwrap();
+        assert_eq!(&*nums, &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        assert_eq!(nums.len(), 10);
+        let free = allocator.pool64.free_count();
+        drop(nums);
+        assert_eq!(allocator.pool64.free_count(), free + 1);
+
+        let empty = allocator.alloc_iter_exact(std::iter::empty::<u64>()).unwrap();
+        assert!(empty.is_empty());
+
+        let full = allocator.alloc_iter_exact((0..32u32).map(|i| i as u64 * 2)).unwrap();
+        assert_eq!((full.len(), full[31]), (32, 62));
+        assert_eq!(allocator.pool256.free_count(), allocator.pool256.slot_count() - 1);
+
+        let short = allocator.alloc_iter_exact(Short(0..3)).unwrap();
+        assert_eq!(&*short, &[0, 1, 2]);
+    }
+
+    #[test]
+    fn test_alloc_iter_exact_too_big() {
+        let allocator = Allocator::new();
+        let produced = Cell::new(0);
+        let iter = (0..33u32).map(|i| { produced.set(produced.get() + 1); i as u64 });
+        assert_eq!(allocator.alloc_iter_exact(iter).err(), Some(AllocError));
+        assert_eq!(produced.get(), 0);
+        assert!(allocator.alloc_iter_exact(std::iter::repeat_n(0u64, usize::MAX / 4)).is_err());
+    }
+
+    #[test]
+    fn test_duplicate() {
+        let frame = Allocator::new();
+        let persistent = Allocator::new();
+        let pair = frame.alloc((1u32, 2.5f64));
+        let kept = persistent.duplicate(&pair);
+        assert_eq!(*kept, (1, 2.5));
+        assert_ne!(&*kept as *const _ as usize, &*pair as *const _ as usize);
+
+        let nums = frame.alloc_iter_exact(10..15u32).unwrap();
+        let kept_nums = persistent.duplicate_slice(&nums);
+        let free = (frame.pool32.free_count(), persistent.pool32.free_count());
+        // recycling either copy leaves the other untouched
+        drop((pair, nums));
+        assert_eq!(frame.pool32.free_count(), free.0 + 1);
+        assert_eq!(persistent.pool32.free_count(), free.1);
+        drop(frame);
+        assert_eq!((*kept, &*kept_nums), ((1, 2.5), &[10, 11, 12, 13, 14][..]));
+        drop(kept_nums);
+        assert_eq!(persistent.pool32.free_count(), free.1 + 1);
+
+        // a shorter slice goes into a smaller class
+        let builder = persistent.slice_builder::<u16>(30).unwrap();
+        let short = persistent.duplicate_slice(&builder.finish());
+        assert!(short.is_empty());
+        assert_eq!(persistent.pool8.free_count(), persistent.pool8.slot_count() - 1);
+    }
+
+    #[test]
+    fn test_slice_builder() {
+        let allocator = Allocator::new();
+        let mut builder = allocator.slice_builder::<u32>(12).unwrap();
+        for i in 0..5 {
+            builder.push(i).unwrap();
+        }
+        assert_eq!((builder.len(), builder.max_len()), (5, 12));
+        assert_eq!(builder.as_slice(), &[0, 1, 2, 3, 4]);
+        let free = allocator.pool64.free_count();
+        let partial = builder.finish();
+        assert_eq!(&*partial, &[0, 1, 2, 3, 4]);
+        drop(partial);
+        assert_eq!(allocator.pool64.free_count(), free + 1);
+
+        let empty = allocator.slice_builder::<u64>(4).unwrap().finish();
+        assert!(empty.is_empty());
+
+        let mut builder = allocator.slice_builder::<u8>(2).unwrap();
+        assert_eq!((builder.push(1), builder.push(2), builder.push(3)), (Ok(()), Ok(()), Err(3)));
+        assert_eq!(&*builder.finish(), &[1, 2]);
+
+        assert_eq!(allocator.slice_builder::<u64>(33).err(), Some(AllocError));
+    }
+
+    #[test]
+    fn test_slice_builder_drop() {
+        let allocator = Allocator::new();
+        let free = allocator.pool128.free_count();
+        let mut builder = allocator.slice_builder::<u64>(16).unwrap();
+        builder.push(1).unwrap();
+        assert_eq!(allocator.pool128.free_count(), free - 1);
+        drop(builder);
+        assert_eq!(allocator.pool128.free_count(), free);
+    }
+
+    #[test]
+    #[cfg_attr(target_family = "wasm", ignore = "panics abort on wasm")]
+    fn test_alloc_iter_exact_panic() {
+        let allocator = Allocator::new();
+        let free = allocator.pool32.free_count();
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            allocator.alloc_iter_exact((0..8u32).map(|i| if i == 5 { panic!("element {}", i) } else { i }))
+        }));
+        assert!(result.is_err());
+        assert_eq!(allocator.pool32.free_count(), free);
+    }
+
+    #[test]
+    #[cfg_attr(target_family = "wasm", ignore = "panics abort on wasm")]
+    fn test_slice_builder_panic() {
+        let allocator = Allocator::new();
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            let mut builder = allocator.slice_builder::<u64>(16).unwrap();
+            for i in 0..16u64 {
+                builder.push(if i == 9 { panic!("element {}", i) } else { i }).unwrap();
+            }
+            builder.finish()
+        }));
+        assert!(result.is_err());
+        // recycled once, by the builder dropped while unwinding
+        assert!(allocator.is_idle(128));
+        assert_eq!(allocator.pool128.free_count(), allocator.pool128.slot_count());
+        let (a, b) = (allocator.alloc([1u64; 16]), allocator.alloc([2u64; 16]));
+        assert_ne!(a.as_ptr(), b.as_ptr());
+    }
+}
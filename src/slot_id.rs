@@ -0,0 +1,134 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Naming slots by where they are in their allocator, for logging.
+
+use std::fmt;
+use std::ptr::NonNull;
+
+use Pool;
+
+/// Where a slot is in its allocator, see `Pointer::id`, printed as
+/// `class:page:slot`, e.g. `3:0:12`.
+///
+/// The id of a slot stays the same as long as its page is, so it's
+/// reused by whatever is allocated in the slot once it's recycled.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SlotId {
+    /// Index of the size class, 0 for 8 bytes up to 5 for 256 bytes.
+    pub class: usize,
+    /// Index of the page in the chain of its class, in the order pages
+    /// were added. It's `PageInfo::index_in_chain` until pages are
+    /// trimmed, as trimming a page doesn't renumber the later ones.
+    pub page: usize,
+    /// Index of the slot in its page.
+    pub slot: usize,
+}
+
+impl fmt::Display for SlotId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.class, self.page, self.slot)
+    }
+}
+
+impl Pool {
+    /// The id of the slot at `addr`, or `None` if it isn't in a page of
+    /// the chain, e.g. a block of a heap pool.
+    pub(crate) fn slot_id(&self, addr: usize) -> Option<SlotId> {
+        let class = self.ele_size.trailing_zeros() as usize - 3;
+        self.chain().find_map(|p| {
+            let page = p.pool.borrow();
+            let offset = addr.wrapping_sub(page.as_ptr().addr());
+            (offset < page.len()).then(|| SlotId{ class, page: p.page_index, slot: offset / self.ele_size })
+        })
+    }
+
+    /// The ids of the slots not on the free list, page by page.
+    pub(crate) fn live_ids(&self) -> Vec<SlotId> {
+        let mut free = Vec::new();
+        let mut node = self.head.get();
+        while let Some(cur) = node {
+            free.push(cur.as_ptr().addr());
+            node = NonNull::new(unsafe { cur.as_ref().next });
+        }
+        free.sort_unstable();
+        let class = self.ele_size.trailing_zeros() as usize - 3;
+        let mut ids = Vec::new();
+        for p in self.chain() {
+            let page = p.pool.borrow();
+            let base = page.as_ptr().addr();
+            for slot in 0..page.len() / self.ele_size {
+                if free.binary_search(&(base + slot * self.ele_size)).is_err() {
+                    ids.push(SlotId{ class, page: p.page_index, slot });
+                }
+            }
+        }
+        ids
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use {Allocator, Pointer};
+
+    #[test]
+    fn test_unique_among_live() {
+        let allocator = Allocator::with_capacity(4);
+        let small: Vec<_> = (0..10u64).map(|i| allocator.alloc(i)).collect();
+        let large: Vec<_> = (0..10u8).map(|i| allocator.alloc([i; 100])).collect();
+        let ids: HashSet<SlotId> = small.iter().map(|p| Pointer::id(p).unwrap())
+            .chain(large.iter().map(|p| Pointer::id(p).unwrap()))
+            .collect();
+        assert_eq!(ids.len(), 20);
+        // over three pages of four slots, in order
+        let id = Pointer::id(&large[9]).unwrap();
+        assert_eq!(id, SlotId{ class: 4, page: 2, slot: 1 });
+        assert_eq!(id.to_string(), "4:2:1");
+        assert_eq!(Pointer::id(&small[0]).unwrap().class, 0);
+    }
+
+    #[test]
+    fn test_same_after_coercion() {
+        let allocator = Allocator::new();
+        let bytes = allocator.alloc([7u8; 12]);
+        let (addr, id) = (Pointer::addr(&bytes), Pointer::id(&bytes));
+        assert_eq!(addr.get(), &*bytes as *const [u8; 12] as usize);
+        let slice: Pointer<[u8]> = bytes;
+        assert_eq!((Pointer::addr(&slice), Pointer::id(&slice)), (addr, id));
+        let any: Pointer<dyn std::any::Any> = allocator.alloc(1u32);
+        assert_eq!(Pointer::id(&any).unwrap().class, 0);
+    }
+
+    #[test]
+    fn test_reused_after_recycling() {
+        let allocator = Allocator::new();
+        let first = allocator.alloc(1u64);
+        let id = Pointer::id(&first);
+        drop(first);
+        let second = allocator.alloc(2u32);
+        assert_eq!(Pointer::id(&second), id);
+    }
+
+    #[test]
+    fn test_stable_across_trim() {
+        let allocator = Allocator::with_capacity(4);
+        let mut held: Vec<_> = (0..12u64).map(|i| allocator.alloc(i)).collect();
+        let last = held.pop().unwrap();
+        let id = Pointer::id(&last).unwrap();
+        // release the first two pages of the chain
+        drop(held);
+        allocator.trim(0);
+        assert_eq!(allocator.pool8.pages().count(), 1);
+        assert_eq!(Pointer::id(&last), Some(id));
+        assert_eq!(id.page, 2);
+        let big = allocator.alloc_or_heap([0u8; 1000]);
+        assert_eq!(Pointer::id(&big), None);
+    }
+}
@@ -0,0 +1,181 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A pointer storing small values inline, and larger ones in the pools.
+
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem::MaybeUninit;
+use std::ops::{Deref, DerefMut};
+
+use {Allocator, Pointer};
+
+/// `N` bytes, aligned for a `T`.
+#[repr(C)]
+struct Inline<T, const N: usize> {
+    _align: [T; 0],
+    bytes: MaybeUninit<[u8; N]>,
+    _marker: PhantomData<T>,
+}
+
+enum Repr<'a, T, const N: usize> {
+    Inline(Inline<T, N>),
+    Pooled(Pointer<'a, T>),
+}
+
+/// A pointer to a `T`, stored inline if `T` fits in `N` bytes, without
+/// touching the allocator at all, or in a slot of the allocator if not.
+///
+/// Whether a `T` is stored inline is a constant of `T` and `N`, so
+/// every access goes to the one representation without branching.
+/// Either way the pointee is dropped along with the pointer, and a
+/// pooled slot recycled.
+pub struct SmallPointer<'a, T, const N: usize> {
+    repr: Repr<'a, T, N>,
+}
+
+impl<'a, T, const N: usize> SmallPointer<'a, T, N> {
+    const INLINE: bool = std::mem::size_of::<T>() <= N;
+
+    /// Store `value` inline if it fits, or allocate it from `allocator`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `value` doesn't fit inline, and is larger than 256 bytes.
+    #[inline]
+    #[track_caller]
+    pub fn new_in(value: T, allocator: &'a Allocator) -> SmallPointer<'a, T, N> {
+        let repr = if Self::INLINE {
+            let mut inline = Inline{ _align: [], bytes: MaybeUninit::uninit(), _marker: PhantomData };
+            unsafe { std::ptr::write(inline.bytes.as_mut_ptr() as *mut T, value) };
+            Repr::Inline(inline)
+        } else {
+            Repr::Pooled(allocator.pool_for::<T>().put(value))
+        };
+        SmallPointer{ repr }
+    }
+
+    /// Whether the pointee of `ptr` is stored inline.
+    /// This is an associated function so that
+    /// `T`'s methods won't be shadowed.
+    #[inline]
+    pub fn is_inline(_ptr: &Self) -> bool {
+        Self::INLINE
+    }
+
+    #[inline]
+    fn as_ptr(&self) -> *const T {
+        match self.repr {
+            Repr::Inline(ref inline) if Self::INLINE => inline.bytes.as_ptr() as *const T,
+            Repr::Pooled(ref ptr) if !Self::INLINE => ptr.node.as_ptr(),
+            _ => unsafe { std::hint::unreachable_unchecked() },
+        }
+    }
+
+    #[inline]
+    fn as_mut_ptr(&mut self) -> *mut T {
+        match self.repr {
+            Repr::Inline(ref mut inline) if Self::INLINE => inline.bytes.as_mut_ptr() as *mut T,
+            Repr::Pooled(ref ptr) if !Self::INLINE => ptr.node.as_ptr(),
+            _ => unsafe { std::hint::unreachable_unchecked() },
+        }
+    }
+}
+
+impl<'a, T, const N: usize> Deref for SmallPointer<'a, T, N> {
+    type Target = T;
+    #[inline]
+    fn deref(&self) -> &T {
+        unsafe { &*self.as_ptr() }
+    }
+}
+
+impl<'a, T, const N: usize> DerefMut for SmallPointer<'a, T, N> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.as_mut_ptr() }
+    }
+}
+
+impl<'a, T, const N: usize> Drop for SmallPointer<'a, T, N> {
+    fn drop(&mut self) {
+        // a pooled pointee is dropped by its `Pointer`
+        if let Repr::Inline(ref mut inline) = self.repr {
+            unsafe { std::ptr::drop_in_place(inline.bytes.as_mut_ptr() as *mut T) };
+        }
+    }
+}
+
+impl<'a, T: fmt::Debug, const N: usize> fmt::Debug for SmallPointer<'a, T, N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: fmt::Display, const N: usize> fmt::Display for SmallPointer<'a, T, N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_inline() {
+        let allocator = Allocator::new();
+        let marker = Rc::new(());
+        let mut ptr: SmallPointer<(Rc<()>, u64), 16> = SmallPointer::new_in((marker.clone(), 1), &allocator);
+        assert!(SmallPointer::is_inline(&ptr));
+        ptr.1 += 1;
+        assert_eq!(ptr.1, 2);
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().allocs, 0);
+
+        // moving the pointer moves the value
+        let moved = ptr;
+        assert_eq!(moved.1, 2);
+        assert_eq!(Rc::strong_count(&marker), 2);
+        drop(moved);
+        assert_eq!(Rc::strong_count(&marker), 1);
+    }
+
+    #[test]
+    fn test_pooled() {
+        let allocator = Allocator::new();
+        let marker = Rc::new(());
+        let mut ptr: SmallPointer<(Rc<()>, [u64; 24]), 16> = SmallPointer::new_in((marker.clone(), [3; 24]), &allocator);
+        assert!(!SmallPointer::is_inline(&ptr));
+        ptr.1[23] = 4;
+        assert_eq!(ptr.1[..2], [3, 3]);
+        assert_eq!(ptr.1[23], 4);
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 1);
+        drop(ptr);
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 0);
+        assert_eq!(Rc::strong_count(&marker), 1);
+    }
+
+    #[test]
+    fn test_size() {
+        let word = std::mem::size_of::<usize>();
+        // debug builds stamp the pointers, which may outgrow the inline
+        // storage, the tag going into their niche then
+        let heap = std::mem::size_of::<::Pointer<u64>>();
+        assert_eq!(std::mem::size_of::<SmallPointer<u64, 16>>(), std::cmp::max(16 + word, heap));
+        assert_eq!(std::mem::size_of::<SmallPointer<[u8; 3], 32>>(), 32 + word);
+        // the alignment of the inline storage follows `T`
+        let allocator = Allocator::new();
+        let ptr: SmallPointer<u128, 16> = SmallPointer::new_in(7, &allocator);
+        assert_eq!(&*ptr as *const u128 as usize % std::mem::align_of::<u128>(), 0);
+        assert_eq!(format!("{} {:?}", ptr, ptr), "7 7");
+    }
+}
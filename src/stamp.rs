@@ -0,0 +1,80 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! What debug builds remember of an allocation, to tell it apart from
+//! the other uses of its slot.
+
+#[cfg(debug_assertions)]
+use std::cell::Cell;
+
+use Pool;
+
+#[cfg(debug_assertions)]
+thread_local! {
+    /// The sequence number of the next allocation of the thread.
+    static NEXT_SEQ: Cell<u64> = const { Cell::new(0) };
+}
+
+/// The epoch of the pool and the sequence number of an allocation, in
+/// debug builds. Release builds stamp nothing, so `Stamp` takes no room.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct Stamp {
+    /// The epoch of the pool when the slot was handed out.
+    #[cfg(debug_assertions)]
+    epoch: u64,
+    /// The allocation of the thread it was, from 0.
+    #[cfg(debug_assertions)]
+    seq: u64,
+}
+
+impl Stamp {
+    /// Stamp an allocation from `pool`, taking the next sequence number.
+    #[inline]
+    pub(crate) fn new(pool: &Pool) -> Stamp {
+        #[cfg(debug_assertions)]
+        {
+            let seq = NEXT_SEQ.with(|next| {
+                let seq = next.get();
+                next.set(seq + 1);
+                seq
+            });
+            Stamp{ epoch: pool.epoch.get(), seq }
+        }
+        #[cfg(not(debug_assertions))]
+        {
+            let _ = pool;
+            Stamp{}
+        }
+    }
+
+    /// The sequence number of the allocation, or `None` in release builds.
+    #[inline]
+    pub(crate) fn seq(&self) -> Option<u64> {
+        #[cfg(debug_assertions)]
+        return Some(self.seq);
+        #[cfg(not(debug_assertions))]
+        None
+    }
+
+    /// Panic if the slot was reclaimed by a reset of `pool`, its pool,
+    /// since it was handed out.
+    #[inline]
+    #[track_caller]
+    pub(crate) fn check_epoch(&self, pool: &Pool) {
+        #[cfg(debug_assertions)]
+        {
+            let epoch = pool.epoch.get();
+            if self.epoch != epoch {
+                panic!("pointer #{} of epoch {} dereferenced at epoch {}, after its allocator was reset",
+                       self.seq, self.epoch, epoch);
+            }
+        }
+        #[cfg(not(debug_assertions))]
+        let _ = pool;
+    }
+}
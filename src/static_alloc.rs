@@ -0,0 +1,268 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! An allocator carving its pools out of an inline array, for targets
+//! without a heap.
+
+use std::alloc::AllocError;
+use std::cell::{Cell, RefCell, UnsafeCell};
+use std::mem::MaybeUninit;
+use std::ptr::NonNull;
+
+use send::RemoteFrees;
+#[cfg(feature = "stats")]
+use Stats;
+use {too_big, Node, Page, Pointer, Pool};
+
+const CLASSES: [usize; 6] = [8, 16, 32, 64, 128, 256];
+
+/// The backing array, aligned so that every class region, each a
+/// multiple of 256 bytes, starts 256-aligned, and every slot is aligned
+/// to its class size.
+#[repr(C, align(256))]
+struct Storage<const BYTES: usize>(UnsafeCell<[MaybeUninit<u8>; BYTES]>);
+
+/// An allocator backed by an inline array of `BYTES` bytes instead of
+/// the heap, split evenly among the size classes, each share rounded
+/// down to a multiple of 256 bytes.
+///
+/// It never grows: allocating from a class whose slots are all handed
+/// out fails with `AllocError`. The pointers are the same `Pointer`s as
+/// those of an `Allocator`, recycled the same way.
+///
+/// Being `const` constructible, it could be placed in a `thread_local!`
+/// with a `const` initializer, or in a `static` wrapped by whatever
+/// serializes access on the target, without touching the heap. The
+/// slots are linked on first allocation, and again if the allocator
+/// has been moved since, which is only possible when none is handed out.
+///
+/// ```rust
+/// use aren_alloc::StaticAllocator;
+///
+/// thread_local!(static ALLOCATOR: StaticAllocator<1536> = const { StaticAllocator::new() });
+///
+/// ALLOCATOR.with(|allocator| {
+///     // 256 bytes per class, a single slot of 256 bytes
+///     let big = allocator.alloc([1u8; 256]).unwrap();
+///     assert!(allocator.alloc([2u8; 200]).is_err());
+///     drop(big);
+///     assert!(allocator.alloc([2u8; 200]).is_ok());
+/// });
+/// ```
+pub struct StaticAllocator<const BYTES: usize> {
+    storage: Storage<BYTES>,
+    /// Address of the storage when the slots were linked, null before.
+    base: Cell<*mut u8>,
+    pools: [Pool; 6],
+}
+
+impl<const BYTES: usize> StaticAllocator<BYTES> {
+    /// Bytes of storage of each size class.
+    const REGION: usize = (BYTES / CLASSES.len()) & !255;
+
+    /// Construct an allocator, without linking the slots yet.
+    pub const fn new() -> StaticAllocator<BYTES> {
+        StaticAllocator{
+            storage: Storage(UnsafeCell::new([MaybeUninit::uninit(); BYTES])),
+            base: Cell::new(std::ptr::null_mut()),
+            pools: [
+                Pool::fixed(CLASSES[0], Self::REGION / CLASSES[0]),
+                Pool::fixed(CLASSES[1], Self::REGION / CLASSES[1]),
+                Pool::fixed(CLASSES[2], Self::REGION / CLASSES[2]),
+                Pool::fixed(CLASSES[3], Self::REGION / CLASSES[3]),
+                Pool::fixed(CLASSES[4], Self::REGION / CLASSES[4]),
+                Pool::fixed(CLASSES[5], Self::REGION / CLASSES[5]),
+            ],
+        }
+    }
+
+    /// Allocate an instance of `T` with value `elem`, or fail if the
+    /// class of `T` has no free slot left.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size_of::<T>()` is larger than 256 bytes.
+    #[inline]
+    #[track_caller]
+    pub fn alloc<T: Copy>(&self, elem: T) -> Result<Pointer<'_, T>, AllocError> {
+        self.link();
+        let pool = match CLASSES.iter().position(|&class| std::mem::size_of::<T>() <= class) {
+            Some(i) => &self.pools[i],
+            None => too_big::<T>(),
+        };
+        if pool.head.get().is_none() {
+            // slots dropped as `SendPointer`s, should the pool be shared
+            pool.drain_remote();
+            if pool.head.get().is_none() {
+                return Err(AllocError);
+            }
+        }
+        Ok(pool.put(elem))
+    }
+
+    /// Sum the statistics of every size class.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> Stats {
+        self.pools.iter()
+            .map(|pool| Stats{ live: pool.live.get(), allocs: pool.allocs.get(), slots: pool.page_slots })
+            .sum()
+    }
+
+    /// Link the slots of every class, unless done at the current address.
+    fn link(&self) {
+        let base = self.storage.0.get() as *mut u8;
+        if base == self.base.get() {
+            return;
+        }
+        // moved, so none is handed out
+        #[cfg(feature = "stats")]
+        debug_assert!(self.pools.iter().all(|pool| pool.live.get() == 0));
+        for (i, pool) in self.pools.iter().enumerate() {
+            let region = unsafe { base.add(i * Self::REGION) };
+            let mut head = std::ptr::null_mut();
+            for slot in (0..pool.page_slots).rev() {
+                let node = unsafe { region.add(slot * pool.ele_size) } as *mut Node;
+                unsafe { (*node).next = head };
+                head = node;
+            }
+            pool.head.set(NonNull::new(head));
+            pool.free_slots.set(pool.page_slots);
+        }
+        self.base.set(base);
+    }
+}
+
+impl<const BYTES: usize> Default for StaticAllocator<BYTES> {
+    #[inline]
+    fn default() -> StaticAllocator<BYTES> {
+        StaticAllocator::new()
+    }
+}
+
+impl Pool {
+    /// Construct a pool of `num` slots, without a page of its own.
+    /// The slots are linked into `head` by the owner of their memory.
+    const fn fixed(ele_size: usize, num: usize) -> Pool {
+        Pool{
+            pool: RefCell::new(Page::empty()),
+            head: Cell::new(None),
+            ele_size,
+            page_slots: num,
+            page_index: 0,
+            heap_align: 0,
+            fixed: true,
+            next_pool: RefCell::new(None),
+            tail_pool: Cell::new(None),
+            remote: RemoteFrees::new(),
+            free_slots: Cell::new(0),
+            #[cfg(feature = "stats")]
+            live: Cell::new(0),
+            #[cfg(feature = "stats")]
+            allocs: Cell::new(0),
+            #[cfg(feature = "stats")]
+            peak: Cell::new(0),
+            #[cfg(feature = "stats")]
+            pages_added: Cell::new(0),
+            budget: None,
+            clock: None,
+            page_table: RefCell::new(Vec::new()),
+            pinned: RefCell::new(Vec::new()),
+            #[cfg(feature = "harden")]
+            rng: None,
+            #[cfg(debug_assertions)]
+            owner: None,
+            #[cfg(debug_assertions)]
+            epoch: Cell::new(0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exhaustion() {
+        let allocator = StaticAllocator::<4096>::new();
+        // 512 bytes per class
+        let ptrs: Vec<_> = (0..64u64).map(|i| allocator.alloc(i).unwrap()).collect();
+        assert_eq!(allocator.alloc(64u64).err(), Some(AllocError));
+        let pairs: Vec<_> = (0..2).map(|i| allocator.alloc([i as u8; 256]).unwrap()).collect();
+        assert!(allocator.alloc([0u8; 129]).is_err());
+        // other classes are unaffected
+        assert!(allocator.alloc([0u8; 16]).is_ok());
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats(), Stats{ live: 66, allocs: 67, slots: 64 + 32 + 16 + 8 + 4 + 2 });
+
+        let freed = &*ptrs[63] as *const u64;
+        drop(ptrs);
+        let again = allocator.alloc(7u64).unwrap();
+        assert_eq!(&*again as *const u64, freed);
+        drop(pairs);
+    }
+
+    #[test]
+    fn test_alignment() {
+        let allocator = StaticAllocator::<3000>::new();
+        let base = allocator.storage.0.get() as usize;
+        assert_eq!(base % 256, 0);
+        let ptrs: Vec<_> = (0..4).map(|_| allocator.alloc(0u128).unwrap()).collect();
+        assert!(ptrs.iter().all(|p| (&**p as *const u128 as usize).is_multiple_of(16)));
+        let big = allocator.alloc([0u64; 32]).unwrap();
+        assert_eq!(&*big as *const [u64; 32] as usize, base + 5 * 256);
+        assert!(allocator.alloc([0u64; 32]).is_err());
+    }
+
+    #[test]
+    fn test_interleaved() {
+        let allocator = StaticAllocator::<1536>::new();
+        let range = allocator.storage.0.get() as usize..allocator.storage.0.get() as usize + 1536;
+        let mut held = Vec::new();
+        let rounds = if cfg!(miri) { 1000 } else { 100_000u32 };
+        for round in 0..rounds {
+            match allocator.alloc(round) {
+                Ok(ptr) => held.push(ptr),
+                Err(AllocError) => {
+                    assert_eq!(held.len(), 32);
+                    held.truncate(round as usize % 32);
+                }
+            }
+            assert!(held.iter().all(|p| range.contains(&(&**p as *const u32 as usize))));
+        }
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, held.len());
+    }
+
+    #[test]
+    fn test_moved() {
+        let allocator = StaticAllocator::<1536>::new();
+        drop(allocator.alloc(1u8).unwrap());
+        let moved = Box::new(allocator);
+        let ptrs: Vec<_> = (0..32).map(|i| moved.alloc(i as u8).unwrap()).collect();
+        let range = moved.storage.0.get() as usize..moved.storage.0.get() as usize + 1536;
+        assert!(ptrs.iter().all(|p| range.contains(&(&**p as *const u8 as usize))));
+        assert!(moved.alloc(0u8).is_err());
+    }
+
+    #[test]
+    #[cfg_attr(target_family = "wasm", ignore = "panics abort on wasm")]
+    fn test_sibling_exhausted() {
+        let allocator = StaticAllocator::<1536>::new();
+        let big = allocator.alloc([1u8; 256]).unwrap();
+        let sibling = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            Pointer::sibling(&big, [3u8; 256]);
+        }));
+        let msg = sibling.unwrap_err().downcast::<String>().unwrap();
+        assert!(msg.contains("no free slot of 256 bytes left"), "{}", msg);
+        // no page was allocated for the class
+        let pool = &allocator.pools[CLASSES.len() - 1];
+        assert!(pool.next_pool.borrow().is_none());
+        assert_eq!(pool.pool.borrow().len(), 0);
+        assert_eq!(*big, [1u8; 256]);
+    }
+}
@@ -0,0 +1,565 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Usage statistics of allocators.
+//!
+//! The counters of live and past allocations only exist under the
+//! `stats` feature, on by default. Without it, pools don't hold them,
+//! and allocating doesn't touch them. The shape of the pages is always
+//! available.
+
+#[cfg(feature = "stats")]
+use std::cell::Cell;
+use std::fmt;
+#[cfg(feature = "stats")]
+use std::ops::{Add, AddAssign};
+
+use {Allocator, Pool};
+
+/// A snapshot of the usage of an allocator, or of several added up.
+#[cfg(feature = "stats")]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Number of slots currently handed out.
+    pub live: usize,
+    /// Number of slots handed out so far.
+    pub allocs: usize,
+    /// Number of slots of all pages.
+    pub slots: usize,
+}
+
+#[cfg(feature = "stats")]
+impl Add for Stats {
+    type Output = Stats;
+    #[inline]
+    fn add(self, rhs: Stats) -> Stats {
+        Stats{
+            live: self.live + rhs.live,
+            allocs: self.allocs + rhs.allocs,
+            slots: self.slots + rhs.slots,
+        }
+    }
+}
+
+#[cfg(feature = "stats")]
+impl AddAssign for Stats {
+    #[inline]
+    fn add_assign(&mut self, rhs: Stats) {
+        *self = *self + rhs;
+    }
+}
+
+#[cfg(feature = "stats")]
+impl std::iter::Sum for Stats {
+    fn sum<I: Iterator<Item=Stats>>(iter: I) -> Stats {
+        iter.fold(Stats::default(), Add::add)
+    }
+}
+
+/// Slots of a size class handed out and recycled since the counters
+/// were last reset, see `Allocator::counters`.
+#[cfg(feature = "stats")]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClassCounters {
+    /// Size of the slots of the class.
+    pub class_size: usize,
+    /// Number of slots handed out.
+    pub allocs: usize,
+    /// Number of slots recycled.
+    pub frees: usize,
+}
+
+/// The sizes requested from an allocator so far, see
+/// `Allocator::size_histogram`.
+#[cfg(feature = "stats")]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SizeHistogram {
+    /// `buckets[i]` counts the requests of `8 * i + 1` to `8 * i + 8`
+    /// bytes. Requests of 0 bytes are counted in the first bucket.
+    pub buckets: [usize; 32],
+    /// Requests wasting more than half of their slot.
+    pub wasteful: usize,
+}
+
+#[cfg(feature = "stats")]
+impl SizeHistogram {
+    /// The bucket counting requests of `size` bytes.
+    #[inline]
+    pub fn bucket_of(size: usize) -> usize {
+        size.saturating_sub(1) / 8
+    }
+
+    /// Requests in total.
+    #[inline]
+    pub fn total(&self) -> usize {
+        self.buckets.iter().sum()
+    }
+}
+
+/// The lifetime counts of a pool when the counters were last reset.
+#[cfg(feature = "stats")]
+#[derive(Copy, Clone, Debug, Default)]
+pub(crate) struct CounterMark {
+    allocs: usize,
+    frees: usize,
+}
+
+/// The shape of a page of a size class.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PageInfo {
+    /// Size of the slots of the class.
+    pub class_size: usize,
+    /// Position of the page in the chain of its class, the first
+    /// page being 0.
+    pub index_in_chain: usize,
+    /// Number of slots of the page.
+    pub slots: usize,
+    /// Size of the page.
+    pub bytes: usize,
+    /// Address of the first slot of the page.
+    pub base_addr: usize,
+}
+
+impl Pool {
+    /// Describe the allocated pages of the chain, in order.
+    pub(crate) fn pages(&self) -> impl Iterator<Item=PageInfo> + '_ {
+        self.chain()
+            .filter(|p| p.pool.borrow().len() != 0)
+            .enumerate()
+            .map(|(index_in_chain, p)| {
+                let page = p.pool.borrow();
+                PageInfo{
+                    class_size: p.ele_size,
+                    index_in_chain,
+                    slots: page.len() / p.ele_size,
+                    bytes: page.len(),
+                    base_addr: page.as_ptr() as usize,
+                }
+            })
+    }
+
+    /// Slots handed out and recycled so far, counting those freed on
+    /// other threads once they're drained.
+    #[cfg(feature = "stats")]
+    fn lifetime_counts(&self) -> CounterMark {
+        let allocs = self.allocs.get();
+        CounterMark{ allocs, frees: allocs - self.live.get() }
+    }
+
+    #[cfg(feature = "stats")]
+    pub(crate) fn stats(&self) -> Stats {
+        Stats{
+            live: self.live.get(),
+            allocs: self.allocs.get(),
+            slots: self.slot_count(),
+        }
+    }
+
+    /// Count a slot handed out.
+    #[inline(always)]
+    pub(crate) fn count_alloc(&self) {
+        #[cfg(feature = "stats")]
+        {
+            let live = self.live.get() + 1;
+            self.live.set(live);
+            if live > self.peak.get() {
+                self.peak.set(live);
+            }
+            self.allocs.set(self.allocs.get() + 1);
+        }
+    }
+
+    /// Count a page added to the chain.
+    #[inline(always)]
+    pub(crate) fn count_page(&self) {
+        #[cfg(feature = "stats")]
+        self.pages_added.set(self.pages_added.get() + 1);
+    }
+
+    /// Count `n` slots recycled.
+    #[inline(always)]
+    pub(crate) fn count_free(&self, n: usize) {
+        #[cfg(feature = "stats")]
+        self.live.set(self.live.get() - n);
+        #[cfg(not(feature = "stats"))]
+        let _ = n;
+    }
+}
+
+impl Allocator {
+    /// Count a request of `size` bytes served by `pool`.
+    #[inline(always)]
+    pub(crate) fn count_request(&self, pool: &Pool, size: usize) {
+        #[cfg(feature = "stats")]
+        {
+            let bucket = &self.size_buckets[SizeHistogram::bucket_of(size)];
+            bucket.set(bucket.get() + 1);
+            let (requests, requested) = &self.class_requests[pool.ele_size.trailing_zeros() as usize - 3];
+            requests.set(requests.get() + 1);
+            requested.set(requested.get() + size);
+            if 2 * size < pool.ele_size {
+                self.wasteful.set(self.wasteful.get() + 1);
+            }
+        }
+        #[cfg(not(feature = "stats"))]
+        let _ = (pool, size);
+    }
+
+    /// The requests served by `pool` so far, and the bytes they asked.
+    #[cfg(feature = "stats")]
+    #[inline]
+    pub(crate) fn requests(&self, pool: &Pool) -> (usize, usize) {
+        let (requests, requested) = &self.class_requests[pool.ele_size.trailing_zeros() as usize - 3];
+        (requests.get(), requested.get())
+    }
+
+    /// Get the distribution of the sizes requested so far, whichever
+    /// class served them, to tune the classes of an allocator. Requests
+    /// refused are not counted, and neither are the slots handed out
+    /// through FFI.
+    ///
+    /// ```rust
+    /// use aren_alloc::{Allocator, SizeHistogram};
+    /// let allocator = Allocator::new();
+    /// let ptrs: Vec<_> = (0..3).map(|_| allocator.alloc([0u8; 20])).collect();
+    /// let histogram = allocator.size_histogram();
+    /// assert_eq!(histogram.buckets[SizeHistogram::bucket_of(20)], 3);
+    /// assert_eq!(histogram.wasteful, 0);
+    /// ```
+    #[cfg(feature = "stats")]
+    pub fn size_histogram(&self) -> SizeHistogram {
+        let mut buckets = [0; 32];
+        for (count, bucket) in buckets.iter_mut().zip(self.size_buckets.iter()) {
+            *count = bucket.get();
+        }
+        SizeHistogram{ buckets, wasteful: self.wasteful.get() }
+    }
+
+    /// Get the usage of all pools of the allocator.
+    ///
+    /// Slots of `SendPointer`s dropped on other threads count as live
+    /// until they are recycled by `drain_remote_frees`.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> Stats {
+        self.pools().map(|pool| pool.stats()).sum()
+    }
+
+    /// Get the slots handed out and recycled by every class present,
+    /// from the smallest, since the last `reset_counters`, e.g. to plot
+    /// allocations per frame.
+    ///
+    /// The counters are kept apart from `stats`, which they don't
+    /// affect. Slots reclaimed by `reset` count as recycled, and slots
+    /// of `SendPointer`s dropped on other threads once they're drained.
+    ///
+    /// ```rust
+    /// use aren_alloc::Allocator;
+    /// let allocator = Allocator::new();
+    /// for frame in 0..3u64 {
+    ///     allocator.reset_counters();
+    ///     let particles: Vec<_> = (0..100).map(|i| allocator.alloc(i * frame)).collect();
+    ///     assert_eq!(allocator.counters()[0].allocs, 100);
+    /// }
+    /// ```
+    #[cfg(feature = "stats")]
+    pub fn counters(&self) -> Vec<ClassCounters> {
+        self.pools().map(|pool| {
+            let (now, mark) = (pool.lifetime_counts(), self.counter_mark(pool).get());
+            ClassCounters{
+                class_size: pool.ele_size,
+                allocs: now.allocs - mark.allocs,
+                frees: now.frees - mark.frees,
+            }
+        }).collect()
+    }
+
+    /// Zero the counters of `counters`.
+    #[cfg(feature = "stats")]
+    pub fn reset_counters(&self) {
+        for pool in self.pools() {
+            self.counter_mark(pool).set(pool.lifetime_counts());
+        }
+    }
+
+    #[cfg(feature = "stats")]
+    #[inline]
+    fn counter_mark(&self, pool: &Pool) -> &Cell<CounterMark> {
+        &self.counter_marks[pool.ele_size.trailing_zeros() as usize - 3]
+    }
+
+    /// Suggest a page capacity for every class present, from the
+    /// smallest, as `(class_size, capacity)` pairs for
+    /// `AllocatorBuilder::capacity_for`: the most slots of the class
+    /// ever live at once, times `headroom`, rounded up. A class never
+    /// used gets a capacity of 1.
+    ///
+    /// Run a representative workload first, then build the allocators
+    /// of later runs with the suggestions, so that the first page of
+    /// every class holds its peak.
+    ///
+    /// ```rust
+    /// use aren_alloc::{Allocator, AllocatorBuilder};
+    /// let recorder = Allocator::with_capacity(4);
+    /// let particles: Vec<_> = (0..100).map(|i| recorder.alloc(i as u64)).collect();
+    /// drop(particles);
+    /// let suggested = recorder.suggest_capacities(1.5);
+    /// assert_eq!(suggested[0], (8, 150));
+    /// let builder = suggested.iter().fold(AllocatorBuilder::new(), |b, &(class, cap)| b.capacity_for(class, cap));
+    /// let tuned = builder.build();
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if `headroom` is less than 1 or not finite.
+    #[cfg(feature = "stats")]
+    #[track_caller]
+    pub fn suggest_capacities(&self, headroom: f64) -> Vec<(usize, usize)> {
+        assert!(headroom.is_finite() && headroom >= 1.0, "headroom must be at least 1, got {}", headroom);
+        self.pools().map(|pool| {
+            let capacity = (pool.peak.get() as f64 * headroom).ceil() as usize;
+            (pool.ele_size, std::cmp::max(capacity, 1))
+        }).collect()
+    }
+
+    /// Describe every page of the allocator, by class from the smallest,
+    /// then in the order they were added. A class whose first page is
+    /// yet to be allocated, as on wasm32, has no page.
+    pub fn pages(&self) -> Vec<PageInfo> {
+        self.pools().flat_map(|pool| pool.pages()).collect()
+    }
+}
+
+/// A class as summed up by the `Debug` impl of `Allocator`.
+struct ClassSummary<'a>(&'a Pool);
+
+impl<'a> fmt::Debug for ClassSummary<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let pool = self.0;
+        write!(f, "{} bytes ({} pages, {} free, {} live)",
+               pool.ele_size, pool.pages().count(), pool.free_slots.get(), pool.tracked_live())
+    }
+}
+
+/// Sum up every class present: its pages, and its free and live slots,
+/// slots freed on other threads counting as live until drained. Nothing
+/// is drained, and neither addresses nor contents are printed.
+impl fmt::Debug for Allocator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let classes: Vec<_> = self.pools().map(ClassSummary).collect();
+        let mut s = f.debug_struct("Allocator");
+        if let Some(name) = self.name {
+            s.field("name", &name);
+        }
+        s.field("classes", &classes).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "stats")]
+    use std::thread;
+
+    #[test]
+    #[cfg(feature = "stats")]
+    fn test_stats() {
+        let allocator = Allocator::with_capacity(4);
+        assert_eq!(allocator.stats(), Stats{ live: 0, allocs: 0, slots: 24 });
+
+        let held: Vec<_> = (0..5u64).map(|i| allocator.alloc(i)).collect();
+        let other = allocator.alloc([0u8; 100]);
+        assert_eq!(allocator.stats(), Stats{ live: 6, allocs: 6, slots: 28 });
+
+        drop(held);
+        drop(other);
+        assert_eq!(allocator.stats(), Stats{ live: 0, allocs: 6, slots: 28 });
+    }
+
+    #[test]
+    #[cfg(feature = "stats")]
+    #[cfg_attr(target_family = "wasm", ignore = "no threads on wasm")]
+    fn test_stats_remote_frees() {
+        let allocator = Allocator::with_capacity(4);
+        let sent = allocator.alloc_send(1u32);
+        thread::scope(|s| {
+            s.spawn(move || drop(sent));
+        });
+        assert_eq!(allocator.stats().live, 1);
+        allocator.drain_remote_frees();
+        assert_eq!(allocator.stats().live, 0);
+    }
+
+    #[test]
+    fn test_pages() {
+        let allocator = Allocator::with_capacity(2);
+        let pages = allocator.pages();
+        assert_eq!(pages.len(), 6);
+        assert!(pages.iter().all(|p| p.index_in_chain == 0 && p.slots == 2));
+        assert_eq!(pages.iter().map(|p| p.class_size).collect::<Vec<_>>(), [8, 16, 32, 64, 128, 256]);
+        assert_eq!(pages[5].bytes, 512);
+
+        // exhaust the 8 bytes class, then force one more page
+        let held: Vec<_> = (0..3u64).map(|i| allocator.alloc(i)).collect();
+        let _more = allocator.alloc(3u64);
+        assert!(allocator.pool8.extend());
+        let eights: Vec<_> = allocator.pages().into_iter().filter(|p| p.class_size == 8).collect();
+        assert_eq!(eights.len(), 3);
+        assert_eq!(eights.iter().map(|p| p.index_in_chain).collect::<Vec<_>>(), [0, 1, 2]);
+        // the free list isn't empty, so extending again adds nothing
+        assert!(allocator.pool8.extend());
+        assert_eq!(allocator.pages().len(), 8);
+
+        // every slot lies within the page it was reported in
+        for ptr in &held {
+            let addr = &**ptr as *const u64 as usize;
+            assert!(eights.iter().any(|p| addr >= p.base_addr && addr < p.base_addr + p.bytes));
+        }
+        assert_eq!(allocator.pages().iter().map(|p| p.slots).sum::<usize>(),
+                   allocator.pools().map(|p| p.slot_count()).sum::<usize>());
+    }
+
+    #[test]
+    fn test_pages_lazy() {
+        let pool = Pool::lazy(4, 16);
+        assert_eq!(pool.pages().count(), 0);
+        pool.pop();
+        assert_eq!(pool.pages().count(), 1);
+    }
+
+    #[test]
+    fn test_debug() {
+        let allocator = Allocator::with_capacity(4);
+        let held: Vec<_> = (0..5u64).map(|i| allocator.alloc(i)).collect();
+        let other = allocator.alloc([0u8; 100]);
+        assert_eq!(format!("{:#?}", allocator), "\
+Allocator {
+    classes: [
+        8 bytes (2 pages, 3 free, 5 live),
+        16 bytes (1 pages, 4 free, 0 live),
+        32 bytes (1 pages, 4 free, 0 live),
+        64 bytes (1 pages, 4 free, 0 live),
+        128 bytes (1 pages, 3 free, 1 live),
+        256 bytes (1 pages, 4 free, 0 live),
+    ],
+}");
+        drop((held, other));
+
+        let allocator = ::AllocatorBuilder::new().name("particles").only_classes(&[16, 64]).build();
+        assert_eq!(format!("{:?}", allocator),
+                   "Allocator { name: \"particles\", classes: [16 bytes (1 pages, 256 free, 0 live), \
+                    64 bytes (1 pages, 64 free, 0 live)] }");
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn test_counters_size() {
+        // without the feature, the pools carry no counters at all
+        let counters = if cfg!(feature = "stats") { 4 * std::mem::size_of::<usize>() } else { 0 };
+        // the owner and epoch of debug builds
+        let debug = if cfg!(debug_assertions) { std::mem::size_of::<Option<std::thread::ThreadId>>() + 8 } else { 0 };
+        // the randomness of hardened pools
+        let harden = if cfg!(feature = "harden") { 8 } else { 0 };
+        assert_eq!(std::mem::size_of::<Pool>(), 192 + counters + debug + harden);
+    }
+
+    #[test]
+    #[cfg(feature = "stats")]
+    fn test_counters_by_frame() {
+        let allocator = Allocator::new();
+        let old = allocator.alloc(0u64);
+        allocator.reset_counters();
+        let mut small: Vec<_> = (0..6u64).map(|i| allocator.alloc(i)).collect();
+        let mut big: Vec<_> = (0..4).map(|i| allocator.alloc([i as u8; 100])).collect();
+        small.truncate(3);
+        big.truncate(3);
+        let counters = allocator.counters();
+        assert_eq!(counters.len(), 6);
+        assert_eq!(counters[0], ClassCounters{ class_size: 8, allocs: 6, frees: 3 });
+        assert_eq!(counters[4], ClassCounters{ class_size: 128, allocs: 4, frees: 1 });
+        assert_eq!(counters.iter().map(|c| (c.allocs, c.frees)).fold((0, 0), |a, c| (a.0 + c.0, a.1 + c.1)), (10, 4));
+        // the lifetime stats are unaffected
+        assert_eq!(allocator.stats(), Stats{ live: 7, allocs: 11, slots: allocator.stats().slots });
+
+        allocator.reset_counters();
+        assert!(allocator.counters().iter().all(|c| c.allocs == 0 && c.frees == 0));
+        drop(old);
+        small.push(allocator.alloc(9u64));
+        assert_eq!(allocator.counters()[0], ClassCounters{ class_size: 8, allocs: 1, frees: 1 });
+        drop((small, big));
+    }
+
+    #[test]
+    #[cfg(feature = "stats")]
+    fn test_size_histogram() {
+        let allocator = Allocator::new();
+        let _a = allocator.alloc(1u8);
+        let _b = allocator.alloc(());
+        let _c: Vec<_> = (0..3).map(|_| allocator.alloc([0u8; 17])).collect();
+        let _d = allocator.alloc([0u64; 3]);
+        let _e = allocator.alloc([0u8; 129]);
+        let _f = allocator.alloc([0u8; 256]);
+        // slots of some other sizes
+        let (_g, _) = allocator.alloc_bytes_at_least(64);
+        let _h = allocator.alloc_iter_exact((0..5).map(|i| i as u16)).unwrap();
+        let histogram = allocator.size_histogram();
+        let mut expected = [0; 32];
+        expected[0] = 2;
+        expected[1] = 1;
+        expected[2] = 4;
+        expected[7] = 1;
+        expected[16] = 1;
+        expected[31] = 1;
+        assert_eq!(histogram.buckets, expected);
+        assert_eq!(histogram.total(), 10);
+        // only 1 and 0 bytes in slots of 8 waste more than half, not 17
+        // in 32, nor 10 in 16
+        assert_eq!(histogram.wasteful, 2);
+        let _i = allocator.alloc(7u32);
+        assert_eq!(allocator.size_histogram().wasteful, 2);
+        let _j = allocator.alloc([0u8; 3]);
+        assert_eq!(allocator.size_histogram().wasteful, 3);
+
+        // rounded up to a missing class
+        let allocator = ::AllocatorBuilder::new().only_classes(&[64]).build();
+        let _k = allocator.alloc([0u8; 31]);
+        let _l = allocator.alloc([0u8; 32]);
+        assert_eq!(allocator.size_histogram().wasteful, 1);
+    }
+
+    #[test]
+    #[cfg(feature = "stats")]
+    #[cfg_attr(target_family = "wasm", ignore = "panics abort on wasm")]
+    fn test_suggest_capacities() {
+        let allocator = ::AllocatorBuilder::new().only_classes(&[8, 32, 64]).build();
+        // a peak of 10 slots of 8 bytes, then 6 once the first ones die
+        let mut first: Vec<_> = (0..10u64).map(|i| allocator.alloc(i)).collect();
+        first.truncate(2);
+        let second: Vec<_> = (0..4u64).map(|i| allocator.alloc(i)).collect();
+        // 3 at once of 32 bytes, three times
+        for _ in 0..3 {
+            let burst: Vec<_> = (0..3).map(|i| allocator.alloc([i as u8; 20])).collect();
+            drop(burst);
+        }
+        assert_eq!(allocator.suggest_capacities(1.0), [(8, 10), (32, 3), (64, 1)]);
+        assert_eq!(allocator.suggest_capacities(1.25), [(8, 13), (32, 4), (64, 1)]);
+        assert_eq!(allocator.suggest_capacities(2.0), [(8, 20), (32, 6), (64, 1)]);
+        drop((first, second));
+        // the peak outlives the slots
+        assert_eq!(allocator.suggest_capacities(1.0)[0], (8, 10));
+        let err = ::std::panic::catch_unwind(|| Allocator::new().suggest_capacities(0.5)).unwrap_err();
+        assert_eq!(err.downcast_ref::<String>().unwrap(), "headroom must be at least 1, got 0.5");
+    }
+
+    #[test]
+    #[cfg(feature = "stats")]
+    fn test_sum() {
+        let a = Stats{ live: 1, allocs: 2, slots: 3 };
+        let total: Stats = vec![a, a, a].into_iter().sum();
+        assert_eq!(total, Stats{ live: 3, allocs: 6, slots: 9 });
+    }
+}
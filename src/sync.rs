@@ -0,0 +1,312 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A thread-safe allocator, guarding each pool with a mutex,
+//! or managing its free list lock-free.
+
+use std::sync::{Mutex, MutexGuard};
+
+use lockfree::LockFreePool;
+use padded::CachePadded;
+use {check_slot, too_big, Node, Pool, DEFAULT_POOL_SIZE};
+
+/// A pool that could be moved across threads.
+///
+/// It is only ever accessed with its mutex held, and no `Pointer`
+/// borrowing it is kept around.
+pub(crate) struct SendPool(pub(crate) Box<Pool>);
+
+unsafe impl Send for SendPool { }
+
+impl SendPool {
+    pub(crate) fn new(mut pool: Box<Pool>) -> SendPool {
+        pool.disown();
+        SendPool(pool)
+    }
+}
+
+#[inline]
+pub(crate) fn lock(pool: &Mutex<SendPool>) -> MutexGuard<'_, SendPool> {
+    // a panic can't leave the free list in an inconsistent state
+    pool.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+enum SyncPool {
+    Locked(Mutex<SendPool>),
+    // boxed, as its page directory is much larger than a mutex
+    LockFree(Box<LockFreePool>),
+}
+
+impl SyncPool {
+    fn locked(num: usize, ele_size: usize) -> SyncPool {
+        SyncPool::Locked(Mutex::new(SendPool::new(Pool::with_capacity(num, ele_size))))
+    }
+
+    fn lock_free(num: usize, ele_size: usize) -> SyncPool {
+        SyncPool::LockFree(Box::new(LockFreePool::with_capacity(num, ele_size)))
+    }
+
+    #[inline]
+    #[track_caller]
+    fn alloc<T>(&self) -> *mut T {
+        match *self {
+            SyncPool::Locked(ref pool) => {
+                let pool = lock(pool);
+                check_slot::<T>(pool.0.ele_size);
+                pool.0.pop() as *mut T
+            }
+            SyncPool::LockFree(ref pool) => {
+                check_slot::<T>(pool.ele_size());
+                pool.alloc() as *mut T
+            }
+        }
+    }
+
+    #[inline]
+    unsafe fn recycle(&self, node: *mut u8) {
+        match *self {
+            SyncPool::Locked(ref pool) => lock(pool).0.recycle(node as *mut Node),
+            SyncPool::LockFree(ref pool) => pool.recycle(node),
+        }
+    }
+
+    #[cfg(test)]
+    fn free_count(&self) -> usize {
+        match *self {
+            SyncPool::Locked(ref pool) => lock(pool).0.free_count(),
+            SyncPool::LockFree(ref pool) => pool.free_count(),
+        }
+    }
+
+    #[cfg(test)]
+    fn slot_count(&self) -> usize {
+        match *self {
+            SyncPool::Locked(ref pool) => lock(pool).0.slot_count(),
+            SyncPool::LockFree(ref pool) => pool.slot_count(),
+        }
+    }
+}
+
+/// A thread-safe sibling of `Allocator`.
+///
+/// By default, each pool is guarded by a mutex, which is locked briefly
+/// when a slot is allocated or recycled. Allocators constructed with
+/// `lock_free` instead manage their free lists as lock-free stacks,
+/// locking only when a pool grows, by a page twice as large as the last.
+/// Either is simpler, but slower than keeping one `Allocator` per thread.
+pub struct SyncAllocator {
+    // each on its own cache lines, as threads allocating from different
+    // classes would otherwise contend on their free lists
+    pool8: CachePadded<SyncPool>,
+    pool16: CachePadded<SyncPool>,
+    pool32: CachePadded<SyncPool>,
+    pool64: CachePadded<SyncPool>,
+    pool128: CachePadded<SyncPool>,
+    pool256: CachePadded<SyncPool>,
+}
+
+impl SyncAllocator {
+    fn with_pools<F: Fn(usize) -> SyncPool>(pool: F) -> SyncAllocator {
+        SyncAllocator{
+            pool8: CachePadded(pool(8)),
+            pool16: CachePadded(pool(16)),
+            pool32: CachePadded(pool(32)),
+            pool64: CachePadded(pool(64)),
+            pool128: CachePadded(pool(128)),
+            pool256: CachePadded(pool(256)),
+        }
+    }
+
+    /// Construct a new allocator with default page capacity.
+    pub fn new() -> SyncAllocator {
+        SyncAllocator::with_pools(|ele_size| SyncPool::locked(DEFAULT_POOL_SIZE/ele_size, ele_size))
+    }
+
+    /// Construct a new allocator with `cap`acity per inner page
+    pub fn with_capacity(cap: usize) -> SyncAllocator {
+        SyncAllocator::with_pools(|ele_size| SyncPool::locked(cap, ele_size))
+    }
+
+    /// Construct a new lock-free allocator with default page capacity.
+    pub fn lock_free() -> SyncAllocator {
+        SyncAllocator::with_pools(|ele_size| SyncPool::lock_free(DEFAULT_POOL_SIZE/ele_size, ele_size))
+    }
+
+    /// Construct a new lock-free allocator with `cap`acity for the first
+    /// inner page, doubled by every page after it
+    pub fn lock_free_with_capacity(cap: usize) -> SyncAllocator {
+        SyncAllocator::with_pools(|ele_size| SyncPool::lock_free(cap, ele_size))
+    }
+
+    #[inline]
+    #[track_caller]
+    fn pool_for<T>(&self) -> &SyncPool {
+        let ele_size = std::mem::size_of::<T>();
+        if ele_size <= 8 {
+            &self.pool8.0
+        } else if ele_size <= 16 {
+            &self.pool16.0
+        } else if ele_size <= 32 {
+            &self.pool32.0
+        } else if ele_size <= 64 {
+            &self.pool64.0
+        } else if ele_size <= 128 {
+            &self.pool128.0
+        } else if ele_size <= 256 {
+            &self.pool256.0
+        } else {
+            too_big::<T>()
+        }
+    }
+
+    /// Allocate an instance of `T` with value `elem`,
+    /// return the allocated pointer.
+    /// `size_of::<T>()` should be le to 256 bytes.
+    #[inline]
+    #[track_caller]
+    pub fn alloc<T: Copy>(&self, elem: T) -> SyncPointer<'_, T> {
+        let pool = self.pool_for::<T>();
+        let node = pool.alloc::<T>();
+        unsafe {
+            std::ptr::write(node, elem);
+        }
+        SyncPointer{ pool, node }
+    }
+
+    /// Allocate an instance of `T` with default value,
+    /// return the allocated pointer.
+    /// `size_of::<T>()` should be le to 256 bytes.
+    #[inline]
+    #[track_caller]
+    pub fn alloc_default<T: Copy+Default>(&self) -> SyncPointer<'_, T> {
+        self.alloc(Default::default())
+    }
+}
+
+impl Default for SyncAllocator {
+    #[inline]
+    fn default() -> SyncAllocator {
+        SyncAllocator::new()
+    }
+}
+
+/// A pointer to `T` allocated by a `SyncAllocator`.
+///
+/// It is `Send` and `Sync` whenever `T` is. The pool is only locked
+/// while the slot is allocated and recycled, never while the pointee
+/// is accessed.
+pub struct SyncPointer<'a, T: ?Sized> {
+    pool: &'a SyncPool,
+    node: *mut T,
+}
+
+unsafe impl<'a, T: Send + ?Sized> Send for SyncPointer<'a, T> { }
+
+unsafe impl<'a, T: Sync + ?Sized> Sync for SyncPointer<'a, T> { }
+
+impl<'a, T: ?Sized> std::ops::Deref for SyncPointer<'a, T> {
+    type Target = T;
+    #[inline]
+    fn deref(&self) -> &T {
+        unsafe {
+            &*self.node
+        }
+    }
+}
+
+impl<'a, T: ?Sized> std::ops::DerefMut for SyncPointer<'a, T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe {
+            &mut *self.node
+        }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for SyncPointer<'a, T> {
+    fn drop(&mut self) {
+        unsafe {
+            std::ptr::drop_in_place(self.node);
+            self.pool.recycle(self.node as *mut u8);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_alloc() {
+        let allocator = SyncAllocator::with_capacity(2);
+        let a = allocator.alloc(1u64);
+        let b = allocator.alloc([2u8; 100]);
+        let c: SyncPointer<u32> = allocator.alloc_default();
+        assert_eq!(*a, 1);
+        assert_eq!(b[99], 2);
+        assert_eq!(*c, 0);
+        let addr = &*a as *const u64;
+        drop(a);
+        assert_eq!(&*allocator.alloc(3u64) as *const u64, addr);
+    }
+
+    #[test]
+    fn test_alloc_lock_free() {
+        let allocator = SyncAllocator::lock_free_with_capacity(2);
+        let a = allocator.alloc(1u64);
+        let b = allocator.alloc([2u8; 100]);
+        assert_eq!(*a, 1);
+        assert_eq!(b[99], 2);
+        let addr = &*a as *const u64;
+        drop(a);
+        assert_eq!(&*allocator.alloc(3u64) as *const u64, addr);
+    }
+
+    #[test]
+    #[cfg_attr(target_family = "wasm", ignore = "no threads on wasm")]
+    fn test_stress() {
+        stress(SyncAllocator::with_capacity(8));
+    }
+
+    #[test]
+    #[cfg_attr(target_family = "wasm", ignore = "no threads on wasm")]
+    fn test_stress_lock_free() {
+        stress(SyncAllocator::lock_free_with_capacity(8));
+    }
+
+    fn stress(allocator: SyncAllocator) {
+        let shared = allocator.alloc([7u32; 16]);
+        let held: Vec<Vec<SyncPointer<[u64; 2]>>> = thread::scope(|s| {
+            let handles: Vec<_> = (0..8u64).map(|t| {
+                let allocator = &allocator;
+                let shared = &shared;
+                s.spawn(move || {
+                    let mut held = Vec::new();
+                    for i in 0..2000u64 {
+                        held.push(allocator.alloc([t, i]));
+                        if i % 3 == 0 {
+                            let freed = held.swap_remove(0);
+                            assert_eq!(freed[0], t);
+                        }
+                        assert_eq!(shared[(i % 16) as usize], 7);
+                    }
+                    held
+                })
+            }).collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+        for (t, ptrs) in held.iter().enumerate() {
+            assert!(ptrs.iter().all(|p| p[0] == t as u64));
+        }
+
+        // drop the pointers allocated on the other threads here
+        drop(held);
+        assert_eq!(allocator.pool16.free_count(), allocator.pool16.slot_count());
+    }
+}
@@ -0,0 +1,236 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A queue of closures whose states are stored in the pools.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::mem::ManuallyDrop;
+
+use {Allocator, Pointer, RawPointer};
+
+/// The erased state of a closure `F`, along with how to call or drop it.
+struct PooledTask<'a> {
+    raw: RawPointer<'a>,
+    call: unsafe fn(RawPointer<'a>),
+    drop: unsafe fn(*mut u8),
+}
+
+impl<'a> PooledTask<'a> {
+    #[inline]
+    fn new<F: FnOnce() + 'a>(f: F, allocator: &'a Allocator) -> PooledTask<'a> {
+        let ptr = allocator.pool_for::<F>().put(f);
+        PooledTask{ raw: Pointer::erase(ptr), call: call_state::<F>, drop: drop_state::<F> }
+    }
+
+    #[inline]
+    fn run(self) {
+        let this = ManuallyDrop::new(self);
+        unsafe { (this.call)(std::ptr::read(&this.raw)) }
+    }
+}
+
+impl<'a> Drop for PooledTask<'a> {
+    #[inline]
+    fn drop(&mut self) {
+        // the slot itself is recycled by `raw`
+        unsafe { (self.drop)(self.raw.as_ptr()) }
+    }
+}
+
+/// Move the closure out, recycle its slot, then call it, so the slot is
+/// recycled even if the closure panics.
+unsafe fn call_state<F: FnOnce()>(raw: RawPointer) {
+    let f = std::ptr::read(raw.as_ptr() as *mut F);
+    drop(raw);
+    f()
+}
+
+unsafe fn drop_state<F>(state: *mut u8) {
+    std::ptr::drop_in_place(state as *mut F)
+}
+
+enum Task<'a> {
+    Pooled(PooledTask<'a>),
+    Heap(Box<dyn FnOnce() + 'a>),
+}
+
+/// A FIFO queue of `FnOnce()` closures, whose states are allocated
+/// from an `Allocator` rather than boxed one by one.
+///
+/// A closure whose state is larger than 256 bytes is boxed instead.
+/// Closures still queued when the queue is dropped are dropped without
+/// being called.
+pub struct TaskQueue<'a> {
+    allocator: &'a Allocator,
+    tasks: VecDeque<Task<'a>>,
+}
+
+impl<'a> TaskQueue<'a> {
+    /// Construct an empty queue allocating from `allocator`.
+    #[inline]
+    pub fn new_in(allocator: &'a Allocator) -> TaskQueue<'a> {
+        TaskQueue{ allocator, tasks: VecDeque::new() }
+    }
+
+    /// Number of queued closures.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.tasks.len()
+    }
+
+    /// Whether no closure is queued.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+
+    /// Queue `f` to be called by the next `drain`.
+    #[inline]
+    pub fn push<F: FnOnce() + 'a>(&mut self, f: F) {
+        let task = if self.allocator.pool_for_size(std::mem::size_of::<F>()).is_some() {
+            Task::Pooled(PooledTask::new(f, self.allocator))
+        } else {
+            Task::Heap(Box::new(f))
+        };
+        self.tasks.push_back(task);
+    }
+
+    /// Call every queued closure exactly once, in the order they were
+    /// pushed, recycling their states.
+    ///
+    /// If a closure panics, the panic is propagated after recycling the
+    /// state of that closure. The closures after it stay queued.
+    pub fn drain(&mut self) {
+        while let Some(task) = self.tasks.pop_front() {
+            match task {
+                Task::Pooled(task) => task.run(),
+                Task::Heap(f) => f(),
+            }
+        }
+    }
+
+    /// Drop every queued closure without calling it.
+    #[inline]
+    pub fn clear(&mut self) {
+        self.tasks.clear();
+    }
+}
+
+impl<'a> fmt::Debug for TaskQueue<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TaskQueue").field("len", &self.len()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::panic;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_drain_in_order() {
+        let allocator = Allocator::new();
+        let cell = RefCell::new(Vec::new());
+        let log = &cell;
+        let mut queue = TaskQueue::new_in(&allocator);
+        let marker = Rc::new(String::from("moved"));
+        for i in 0..10u64 {
+            let marker = marker.clone();
+            queue.push(move || log.borrow_mut().push((i, marker.len())));
+        }
+        // a zero sized closure, and one too big for the pools
+        queue.push(|| ());
+        let big = [11u64; 40];
+        queue.push(move || log.borrow_mut().push((big[39], big.len())));
+        assert_eq!(queue.len(), 12);
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 11);
+        assert_eq!(Rc::strong_count(&marker), 11);
+
+        queue.drain();
+        assert!(queue.is_empty());
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 0);
+        assert_eq!(Rc::strong_count(&marker), 1);
+        let log = log.borrow();
+        assert_eq!(log.len(), 11);
+        assert!(log.iter().take(10).enumerate().all(|(i, e)| *e == (i as u64, 5)));
+        assert_eq!(log[10], (11, 40));
+    }
+
+    #[test]
+    fn test_drop_without_calling() {
+        let allocator = Allocator::new();
+        let marker = Rc::new(());
+        {
+            let mut queue = TaskQueue::new_in(&allocator);
+            for _ in 0..3 {
+                let marker = marker.clone();
+                queue.push(move || panic!("called {:?}", marker));
+            }
+            queue.clear();
+            assert_eq!(Rc::strong_count(&marker), 1);
+            let marker = marker.clone();
+            queue.push(move || panic!("called {:?}", marker));
+        }
+        assert_eq!(Rc::strong_count(&marker), 1);
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats().live, 0);
+    }
+
+    #[test]
+    #[cfg_attr(target_family = "wasm", ignore = "panics abort on wasm")]
+    fn test_panic_keeps_rest() {
+        let allocator = Allocator::new();
+        let marker = Rc::new(());
+        let mut queue = TaskQueue::new_in(&allocator);
+        {
+            let marker = marker.clone();
+            queue.push(move || panic!("boom {:?}", marker));
+        }
+        for _ in 0..3 {
+            let marker = marker.clone();
+            queue.push(move || drop(marker));
+        }
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| queue.drain()));
+        assert!(result.is_err());
+        // the panicking closure is gone, its captures dropped while unwinding
+        #[cfg(feature = "stats")]
+        assert_eq!((queue.len(), allocator.stats().live), (3, 3));
+        assert_eq!(Rc::strong_count(&marker), 4);
+        queue.drain();
+        #[cfg(feature = "stats")]
+        assert_eq!((queue.len(), allocator.stats().live), (0, 0));
+        assert_eq!(Rc::strong_count(&marker), 1);
+    }
+
+    #[test]
+    #[cfg(feature = "stats")]
+    fn test_reuse_across_frames() {
+        let allocator = Allocator::new();
+        let counter = RefCell::new(0);
+        let mut queue = TaskQueue::new_in(&allocator);
+        let mut slots = Vec::new();
+        for frame in 0..5 {
+            for i in 0..1000 {
+                let counter = &counter;
+                queue.push(move || *counter.borrow_mut() += i + frame);
+            }
+            queue.drain();
+            slots.push(allocator.stats().slots);
+        }
+        assert_eq!(*counter.borrow(), 5 * 499500 + 1000 * 10);
+        let stats = allocator.stats();
+        assert_eq!((stats.live, stats.allocs), (0, 5000));
+        // the slots of one frame are reused by the next
+        assert!(slots.iter().all(|&n| n == slots[0]));
+    }
+}
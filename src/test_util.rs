@@ -0,0 +1,142 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Assertions on the pages of allocators, for tests guarding hot paths
+//! against growth. Under the `test-util` feature.
+
+use std::fmt::{self, Write};
+use std::panic::Location;
+
+use Allocator;
+
+impl Allocator {
+    /// Return a guard panicking on drop if any class of the allocator
+    /// allocated a page while it was alive. Reusing freed slots is fine.
+    ///
+    /// ```rust
+    /// use aren_alloc::Allocator;
+    /// let allocator = Allocator::with_capacity(16);
+    /// let warmup: Vec<_> = (0..16u64).map(|i| allocator.alloc(i)).collect();
+    /// drop(warmup);
+    /// {
+    ///     let _guard = allocator.assert_no_growth();
+    ///     let hot: Vec<_> = (0..16u64).map(|i| allocator.alloc(i)).collect();
+    /// }
+    /// ```
+    ///
+    /// Nothing is checked if the thread is already panicking.
+    #[track_caller]
+    pub fn assert_no_growth(&self) -> NoGrowthGuard<'_> {
+        NoGrowthGuard{
+            allocator: self,
+            pages_added: self.all_pools().map(|pool| pool.pages_added.get()),
+            location: Location::caller(),
+        }
+    }
+
+    /// Panic if the class of `class_size` bytes has more than `max` pages.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the allocator has no class of `class_size` bytes.
+    #[track_caller]
+    pub fn assert_max_pages(&self, class_size: usize, max: usize) {
+        let pool = match self.pools().find(|pool| pool.ele_size == class_size) {
+            Some(pool) => pool,
+            None => panic!("no size class of {} bytes, expected one of {:?}", class_size, self.classes()),
+        };
+        let pages = pool.pages().count();
+        if pages > max {
+            panic!("class of {} bytes has {} pages, more than {}", class_size, pages, max);
+        }
+    }
+}
+
+/// Guard of `Allocator::assert_no_growth`.
+pub struct NoGrowthGuard<'a> {
+    allocator: &'a Allocator,
+    /// Pages added to each class before the guard.
+    pages_added: [usize; 6],
+    location: &'static Location<'static>,
+}
+
+impl<'a> fmt::Debug for NoGrowthGuard<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("NoGrowthGuard").field("location", &self.location).finish()
+    }
+}
+
+impl<'a> Drop for NoGrowthGuard<'a> {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            return;
+        }
+        let mut report = String::new();
+        for (pool, &before) in self.allocator.all_pools().iter().zip(self.pages_added.iter()) {
+            let grown = pool.pages_added.get() - before;
+            if grown != 0 {
+                let sep = if report.is_empty() { "" } else { ", " };
+                let _ = write!(report, "{}{} of {} bytes", sep, grown, pool.ele_size);
+            }
+        }
+        if !report.is_empty() {
+            panic!("allocator grew while guarded by `assert_no_growth` at {}: {} pages", self.location, report);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic;
+    use AllocatorBuilder;
+
+    #[test]
+    fn test_quiet_on_reuse() {
+        let allocator = Allocator::with_capacity(4);
+        let warm: Vec<_> = (0..6u32).map(|i| allocator.alloc(i)).collect();
+        drop(warm);
+        let guard = allocator.assert_no_growth();
+        for _ in 0..3 {
+            let hot: Vec<_> = (0..8u32).map(|i| allocator.alloc(i)).collect();
+            assert_eq!(hot.len(), 8);
+        }
+        drop(guard);
+        allocator.assert_max_pages(8, 2);
+    }
+
+    #[test]
+    #[cfg_attr(target_family = "wasm", ignore = "panics abort on wasm")]
+    fn test_fires_on_growth() {
+        let allocator = Allocator::with_capacity(4);
+        let line = line!() + 2;
+        let err = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            let _guard = allocator.assert_no_growth();
+            let held: Vec<_> = (0..5u8).map(|i| allocator.alloc(i)).collect();
+            let big: Vec<_> = (0..9).map(|i| allocator.alloc([i as u8; 100])).collect();
+            drop((held, big));
+        })).unwrap_err();
+        let msg = err.downcast_ref::<String>().unwrap();
+        let at = format!("{}:{}:", file!(), line);
+        assert!(msg.contains(&at), "{}", msg);
+        assert!(msg.ends_with(": 1 of 8 bytes, 2 of 128 bytes pages"), "{}", msg);
+    }
+
+    #[test]
+    #[cfg_attr(target_family = "wasm", ignore = "panics abort on wasm")]
+    fn test_max_pages() {
+        let allocator = AllocatorBuilder::new().capacity_for(16, 2).only_classes(&[16]).build();
+        let held: Vec<_> = (0..5u16).map(|i| allocator.alloc([i; 8])).collect();
+        allocator.assert_max_pages(16, 3);
+        let err = panic::catch_unwind(panic::AssertUnwindSafe(|| allocator.assert_max_pages(16, 2))).unwrap_err();
+        assert_eq!(err.downcast_ref::<String>().unwrap(), "class of 16 bytes has 3 pages, more than 2");
+        let err = panic::catch_unwind(panic::AssertUnwindSafe(|| allocator.assert_max_pages(8, 2))).unwrap_err();
+        assert_eq!(err.downcast_ref::<String>().unwrap(), "no size class of 8 bytes, expected one of [16]");
+        drop(held);
+    }
+}
@@ -0,0 +1,242 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Formatted text written into the pools.
+
+use std::alloc::AllocError;
+use std::fmt::{self, Write};
+
+use {Allocator, Pointer, SlotGuard};
+
+/// Format text into a slot of an `Allocator`, returning a
+/// `Result<Pointer<str>, AllocError>`.
+///
+/// `format_in!(allocator, "...", args...)` formats like `format!`, once
+/// to measure the text, then again into a slot of its class. Text longer
+/// than 256 bytes is an error, never truncated.
+///
+/// ```rust
+/// #[macro_use]
+/// extern crate aren_alloc;
+/// use aren_alloc::Allocator;
+///
+/// fn main() {
+///     let allocator = Allocator::new();
+///     let label = format_in!(allocator, "{} rays", 128).unwrap();
+///     assert_eq!(&*label, "128 rays");
+///     assert!(format_in!(allocator, "{:300}", "").is_err());
+/// }
+/// ```
+#[macro_export]
+macro_rules! format_in {
+    ($allocator:expr, $($arg:tt)*) => {
+        $allocator.alloc_fmt(format_args!($($arg)*))
+    };
+}
+
+/// Counts the bytes written, failing past the largest class.
+struct Measure(usize);
+
+impl Write for Measure {
+    #[inline]
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0 += s.len();
+        if self.0 > 256 {
+            return Err(fmt::Error);
+        }
+        Ok(())
+    }
+}
+
+/// Writes into a slot, failing rather than splitting a `str` that
+/// doesn't fit.
+struct SlotWriter {
+    base: *mut u8,
+    len: usize,
+    cap: usize,
+}
+
+impl Write for SlotWriter {
+    #[inline]
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if s.len() > self.cap - self.len {
+            return Err(fmt::Error);
+        }
+        unsafe { std::ptr::copy_nonoverlapping(s.as_ptr(), self.base.add(self.len), s.len()) };
+        self.len += s.len();
+        Ok(())
+    }
+}
+
+impl Allocator {
+    /// Format `args` into a slot of the class of the text, see
+    /// `format_in!`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the text is longer than 256 bytes, or its
+    /// class is missing, or a formatting trait returns an error. Returns
+    /// an error too if the budget of the allocator is exhausted, and its
+    /// handler couldn't recover.
+    #[track_caller]
+    pub fn alloc_fmt(&self, args: fmt::Arguments) -> Result<Pointer<'_, str>, AllocError> {
+        let mut measure = Measure(0);
+        measure.write_fmt(args).map_err(|_| AllocError)?;
+        let pool = self.pool_for_size(measure.0).ok_or(AllocError)?;
+        let node = self.pop_or_recover(pool, measure.0).ok_or(AllocError)?;
+        // recycles the slot should the second pass fail or panic
+        let slot = SlotGuard::new(pool, node);
+        // a formatting trait may well write differently the second time,
+        // so the text is only bounded by the slot
+        let mut writer = SlotWriter{ base: node as *mut u8, len: 0, cap: pool.ele_size };
+        writer.write_fmt(args).map_err(|_| AllocError)?;
+        slot.disarm();
+        let text = std::ptr::slice_from_raw_parts_mut(writer.base, writer.len) as *mut str;
+        Ok(unsafe { Pointer::from_node(pool, text) })
+    }
+
+    /// Render `value` with its `Display` into a slot of the class of the
+    /// text, as `format_in!(allocator, "{}", value)` would.
+    ///
+    /// ```rust
+    /// use aren_alloc::Allocator;
+    /// let allocator = Allocator::new();
+    /// let label = allocator.alloc_display(&42u32).unwrap();
+    /// assert_eq!(&*label, "42");
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the text is longer than 256 bytes, see
+    /// `alloc_fmt`.
+    #[inline]
+    #[track_caller]
+    pub fn alloc_display<T: fmt::Display + ?Sized>(&self, value: &T) -> Result<Pointer<'_, str>, AllocError> {
+        self.alloc_fmt(format_args!("{}", value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    /// Writes one more `x` every time it is displayed.
+    struct Growing(Cell<usize>);
+
+    impl fmt::Display for Growing {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            self.0.set(self.0.get() + 1);
+            for _ in 0..self.0.get() {
+                f.write_str("x")?;
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_format_in() {
+        let allocator = Allocator::new();
+        let free = allocator.pool16.free_count();
+        let exact = format_in!(allocator, "{}-{}", "abcdefg", 12345678).unwrap();
+        assert_eq!(&*exact, "abcdefg-12345678");
+        assert_eq!(allocator.pool16.free_count(), free - 1);
+        drop(exact);
+        assert_eq!(allocator.pool16.free_count(), free);
+
+        let empty = format_in!(&allocator, "").unwrap();
+        assert_eq!(&*empty, "");
+        let full = format_in!(allocator, "{:>256}", "end").unwrap();
+        assert_eq!((full.len(), &full[253..]), (256, "end"));
+    }
+
+    #[test]
+    fn test_format_in_overflow() {
+        let allocator = Allocator::new();
+        let free = allocator.pool256.free_count();
+        assert_eq!(format_in!(allocator, "{:>257}", "end").err(), Some(AllocError));
+        assert_eq!(format_in!(allocator, "{:>200}{:>57}", 'a', 'b').err(), Some(AllocError));
+        // the text outgrows its class between the passes
+        let growing = Growing(Cell::new(7));
+        assert_eq!(format_in!(allocator, "{}", growing).err(), Some(AllocError));
+        assert_eq!(allocator.pool8.free_count(), allocator.pool8.slot_count());
+        assert_eq!(allocator.pool256.free_count(), free);
+    }
+
+    /// Panics the second time it is displayed.
+    struct Fuse(Cell<bool>);
+
+    impl fmt::Display for Fuse {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            if self.0.replace(true) {
+                panic!("displayed twice");
+            }
+            f.write_str("lit")
+        }
+    }
+
+    #[test]
+    #[cfg_attr(target_family = "wasm", ignore = "panics abort on wasm")]
+    fn test_format_in_panic() {
+        let allocator = Allocator::new();
+        let fuse = Fuse(Cell::new(false));
+        // panics writing into the slot, after measuring the text
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| format_in!(allocator, "{}", fuse)));
+        assert!(result.is_err());
+        assert!(allocator.is_idle(8));
+        assert_eq!(allocator.free_slots(8), allocator.pool8.slot_count());
+        assert_eq!(allocator.pool8.free_count(), allocator.pool8.slot_count());
+    }
+
+    #[derive(Copy, Clone)]
+    enum Tool {
+        Brush,
+        Eraser{ size: u8 },
+    }
+
+    impl fmt::Display for Tool {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match *self {
+                Tool::Brush => f.write_str("Brush"),
+                Tool::Eraser{ size } => write!(f, "Eraser ({}px)", size),
+            }
+        }
+    }
+
+    #[test]
+    fn test_alloc_display() {
+        let allocator = Allocator::new();
+        assert_eq!(&*allocator.alloc_display(&-17i64).unwrap(), "-17");
+        assert_eq!(&*allocator.alloc_display(&0.5f32).unwrap(), "0.5");
+        assert_eq!(&*allocator.alloc_display(&u128::MAX).unwrap(), "340282366920938463463374607431768211455");
+        let labels: Vec<_> = [Tool::Brush, Tool::Eraser{ size: 12 }].iter()
+            .map(|tool| allocator.alloc_display(tool).unwrap())
+            .collect();
+        assert_eq!((&*labels[0], &*labels[1]), ("Brush", "Eraser (12px)"));
+        assert_eq!(&*allocator.alloc_display("borrowed").unwrap(), "borrowed");
+
+        let free = allocator.pool256.free_count();
+        assert_eq!(allocator.alloc_display(&"x".repeat(257)).err(), Some(AllocError));
+        assert_eq!(allocator.pool256.free_count(), free);
+    }
+
+    #[test]
+    fn test_format_in_unicode() {
+        let allocator = Allocator::new();
+        // a two bytes `é` ending exactly at the slot of 16 bytes
+        let fits = format_in!(allocator, "{}é", "a".repeat(14)).unwrap();
+        assert_eq!((fits.len(), fits.chars().last()), (16, Some('é')));
+        assert_eq!(allocator.pool16.free_count(), allocator.pool16.slot_count() - 1);
+        // and straddling its end, pushing the text into the next class
+        let straddles = format_in!(allocator, "{}é", "a".repeat(15)).unwrap();
+        assert_eq!((straddles.len(), straddles.chars().count()), (17, 16));
+        assert_eq!(allocator.pool32.free_count(), allocator.pool32.slot_count() - 1);
+        let wide = format_in!(allocator, "{}", "日本語".repeat(28)).unwrap();
+        assert_eq!(wide.chars().count(), 84);
+    }
+}
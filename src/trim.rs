@@ -0,0 +1,322 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Tracking the occupancy of the pages, and releasing the idle ones.
+
+use std::cell::Cell;
+use std::ops::Range;
+use std::ptr::NonNull;
+use std::rc::Rc;
+
+use {Allocator, Node, Page, Pool};
+
+/// The occupancy of a page of a chain.
+pub(crate) struct PageMeta {
+    base: usize,
+    len: usize,
+    live: usize,
+    /// The clock when the page last had no live slot, meaningful only
+    /// while `live` is 0.
+    idle_since: usize,
+}
+
+impl Pool {
+    /// Start tracking the occupancy of the pages of the chain, counting
+    /// the operations on `clock`.
+    pub(crate) fn start_clock(&mut self, clock: Rc<Cell<usize>>) {
+        #[cfg(feature = "stats")]
+        debug_assert!(self.live.get() == 0);
+        self.clock = Some(clock);
+        let pages: Vec<_> = self.chain()
+            .filter(|p| p.pool.borrow().len() != 0)
+            .map(|p| (p.pool.borrow().as_ptr() as usize, p.pool.borrow().len()))
+            .collect();
+        for (base, len) in pages {
+            self.insert_page(base, len);
+        }
+    }
+
+    /// Track a page just added to the chain.
+    #[inline]
+    pub(crate) fn track_page(&self, page: &Page) {
+        if self.clock.is_some() {
+            self.insert_page(page.as_ptr() as usize, page.len());
+        }
+    }
+
+    fn insert_page(&self, base: usize, len: usize) {
+        let now = self.clock.as_ref().map_or(0, |clock| clock.get());
+        let mut table = self.page_table.borrow_mut();
+        let i = table.partition_point(|meta| meta.base < base);
+        table.insert(i, PageMeta{ base, len, live: 0, idle_since: now });
+    }
+
+    /// Account for `node` being handed out.
+    #[inline]
+    pub(crate) fn track_alloc(&self, node: *mut Node) {
+        if let Some(ref clock) = self.clock {
+            clock.set(clock.get() + 1);
+            self.page_meta(node, |meta| meta.live += 1);
+        }
+    }
+
+    /// Account for `node` being recycled.
+    #[inline]
+    pub(crate) fn track_free(&self, node: *mut Node) {
+        if let Some(ref clock) = self.clock {
+            let now = clock.get() + 1;
+            clock.set(now);
+            self.page_meta(node, |meta| {
+                meta.live -= 1;
+                if meta.live == 0 {
+                    meta.idle_since = now;
+                }
+            });
+        }
+    }
+
+    /// Account for every slot being recycled at once.
+    pub(crate) fn track_reset(&self) {
+        if let Some(ref clock) = self.clock {
+            let now = clock.get() + 1;
+            clock.set(now);
+            for meta in self.page_table.borrow_mut().iter_mut().filter(|meta| meta.live != 0) {
+                meta.live = 0;
+                meta.idle_since = now;
+            }
+        }
+    }
+
+    /// Count the live slots of the tracked pages, whether or not the
+    /// `stats` feature counts them too.
+    pub(crate) fn tracked_live(&self) -> usize {
+        self.page_table.borrow().iter().map(|meta| meta.live).sum()
+    }
+
+    /// The address ranges of the pages of the chain, sorted, read off the
+    /// page table if the pages are tracked, off the chain otherwise.
+    pub(crate) fn page_ranges(&self) -> Vec<Range<usize>> {
+        if self.clock.is_some() {
+            return self.page_table.borrow().iter().map(|meta| meta.base..meta.base + meta.len).collect();
+        }
+        let mut ranges: Vec<_> = self.chain()
+            .map(|p| p.pool.borrow().as_ptr() as usize..p.pool.borrow().as_ptr() as usize + p.pool.borrow().len())
+            .filter(|range| !range.is_empty())
+            .collect();
+        ranges.sort_unstable_by_key(|range| range.start);
+        ranges
+    }
+
+    /// The address range of the page of the chain holding `addr`, if any.
+    pub(crate) fn page_of(&self, addr: usize) -> Option<Range<usize>> {
+        if self.clock.is_some() {
+            let table = self.page_table.borrow();
+            let i = table.partition_point(|meta| meta.base <= addr);
+            return table[..i].last()
+                .filter(|meta| addr - meta.base < meta.len)
+                .map(|meta| meta.base..meta.base + meta.len);
+        }
+        self.chain()
+            .map(|p| p.pool.borrow().as_ptr() as usize..p.pool.borrow().as_ptr() as usize + p.pool.borrow().len())
+            .find(|range| range.contains(&addr))
+    }
+
+    #[inline]
+    fn page_meta<F: FnOnce(&mut PageMeta)>(&self, node: *mut Node, f: F) {
+        let addr = node as usize;
+        let mut table = self.page_table.borrow_mut();
+        let i = table.partition_point(|meta| meta.base <= addr);
+        debug_assert!(i > 0 && addr - table[i - 1].base < table[i - 1].len);
+        f(&mut table[i - 1])
+    }
+
+    /// Release the pages with no live slot for the last `max_idle_ops`
+    /// operations, return how many were released.
+    fn trim(&self, max_idle_ops: usize) -> usize {
+        let now = match self.clock {
+            Some(ref clock) => clock.get(),
+            None => return 0,
+        };
+        // slots freed on other threads count as live until drained
+        self.drain_remote();
+        let idle: Vec<(usize, usize)> = self.page_table.borrow().iter()
+            .filter(|meta| meta.live == 0 && now - meta.idle_since >= max_idle_ops)
+            .map(|meta| (meta.base, meta.len))
+            .collect();
+        if idle.is_empty() {
+            return 0;
+        }
+        let is_idle = |addr: usize| idle.iter().any(|&(base, len)| addr.wrapping_sub(base) < len);
+
+        // unlink the slots of the idle pages from the free list
+        let mut prev: Option<NonNull<Node>> = None;
+        let mut node = self.head.get();
+        while let Some(cur) = node {
+            let next = unsafe { cur.as_ref().next };
+            if is_idle(cur.as_ptr() as usize) {
+                match prev {
+                    Some(prev) => unsafe { (*prev.as_ptr()).next = next },
+                    None => self.head.set(NonNull::new(next)),
+                }
+                self.free_slots.set(self.free_slots.get() - 1);
+            } else {
+                prev = Some(cur);
+            }
+            node = NonNull::new(next);
+        }
+
+        // then drop the pages, the first page of the chain staying empty
+        let mut prev = self;
+        let mut next = unsafe { (*self.next_pool.as_ptr()).as_deref() };
+        while let Some(p) = next {
+            if is_idle(p.pool.borrow().as_ptr() as usize) {
+                let mut removed = prev.next_pool.borrow_mut().take().unwrap();
+                *prev.next_pool.borrow_mut() = removed.next_pool.get_mut().take();
+            } else {
+                prev = p;
+            }
+            next = unsafe { (*prev.next_pool.as_ptr()).as_deref() };
+        }
+        // moving the boxes invalidated the pointer to the tail
+        let tail = if std::ptr::eq(prev, self) { None } else { Some(NonNull::from(prev)) };
+        self.tail_pool.set(tail);
+        if is_idle(self.pool.borrow().as_ptr() as usize) {
+            *self.pool.borrow_mut() = Page::empty();
+        }
+
+        if let Some(ref budget) = self.budget {
+            for &(_, len) in &idle {
+                budget.refund(len);
+            }
+        }
+        self.page_table.borrow_mut().retain(|meta| !is_idle(meta.base));
+        idle.len()
+    }
+}
+
+impl Allocator {
+    /// Release the pages that have had no live slot for the last
+    /// `max_idle_ops` operations, an operation being an allocation or a
+    /// recycling from any class of the allocator. Pages with live slots,
+    /// and pages emptied more recently, stay. Return how many pages were
+    /// released.
+    ///
+    /// A page added but never used counts as idle since it was added.
+    /// `trim(0)` releases every empty page.
+    pub fn trim(&self, max_idle_ops: usize) -> usize {
+        self.pools().map(|pool| pool.trim(max_idle_ops)).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trim_idle_page() {
+        let allocator = Allocator::with_capacity(4);
+        // three pages of the 8 bytes class, the second one emptied early
+        let mut ptrs: Vec<_> = (0..12u64).map(|i| allocator.alloc(i)).collect();
+        let second: Vec<_> = ptrs.drain(4..8).collect();
+        let addrs: Vec<usize> = second.iter().map(|p| &**p as *const u64 as usize).collect();
+        drop(second);
+        assert_eq!(allocator.pool8.pages().count(), 3);
+
+        // the other classes have been idle since the allocator was built
+        for i in 0..20u64 {
+            let _ = allocator.alloc([i; 2]);
+        }
+        assert_eq!(allocator.trim(1000), 0);
+        assert_eq!(allocator.trim(20), 1 + 4);
+        let eights: Vec<_> = allocator.pool8.pages().collect();
+        assert_eq!(eights.len(), 2);
+        assert!(addrs.iter().all(|&a| eights.iter().all(|p| a < p.base_addr || a >= p.base_addr + p.bytes)));
+        assert_eq!((allocator.pool8.free_count(), allocator.free_slots(8)), (0, 0));
+        assert_eq!(allocator.free_slots(16), allocator.pool16.free_count());
+        #[cfg(feature = "stats")]
+        assert_eq!(allocator.stats(), ::Stats{ live: 8, allocs: 12 + 20, slots: 8 + 4 });
+
+        // live pointers are untouched, and new slots come from a new page
+        assert!(ptrs.iter().enumerate().all(|(i, p)| **p == if i < 4 { i as u64 } else { i as u64 + 4 }));
+        let fresh = allocator.alloc(99u64);
+        assert_eq!(*fresh, 99);
+        assert_eq!(allocator.pool8.pages().count(), 3);
+    }
+
+    #[test]
+    fn test_memory_ranges() {
+        let allocator = Allocator::with_capacity(4);
+        let mut small: Vec<_> = (0..30u64).map(|i| allocator.alloc(i)).collect();
+        let big: Vec<_> = (0..9).map(|i| allocator.alloc([i as u8; 200])).collect();
+        let ranges = allocator.memory_ranges();
+        // 8 pages of 8 bytes, 3 of 256 bytes, one for each other class
+        assert_eq!(ranges.len(), 8 + 3 + 4);
+        assert!(ranges.windows(2).all(|w| w[0].end <= w[1].start));
+        let addrs = small.iter().map(|p| &**p as *const u64 as usize)
+            .chain(big.iter().map(|p| &**p as *const [u8; 200] as usize));
+        for addr in addrs {
+            assert_eq!(ranges.iter().filter(|range| range.contains(&addr)).count(), 1);
+        }
+        assert!(!ranges.iter().any(|range| range.contains(&(&small as *const _ as usize))));
+
+        // the trimmed pages are gone
+        small.truncate(4);
+        assert_eq!(allocator.trim(0), 7 + 4);
+        let trimmed = allocator.memory_ranges();
+        assert_eq!(trimmed.len(), 1 + 3);
+        assert!(trimmed.iter().all(|range| ranges.contains(range)));
+        assert!(trimmed.iter().any(|range| range.contains(&(&*small[0] as *const u64 as usize))));
+    }
+
+    #[test]
+    fn test_trim_keeps_refilled_page() {
+        let allocator = Allocator::with_capacity(2);
+        let a = allocator.alloc([1u8; 100]);
+        let b = allocator.alloc([2u8; 100]);
+        drop((a, b));
+        for _ in 0..10 {
+            let _ = allocator.alloc(0u64);
+        }
+        // refilled, then emptied again just now
+        let c = allocator.alloc([3u8; 100]);
+        drop(c);
+        assert_eq!(allocator.trim(1), 5);
+        assert_eq!(allocator.pool128.pages().count(), 1);
+        assert_eq!(allocator.pool8.pages().count(), 0);
+        // a class without pages gets its first one back on demand
+        assert_eq!(*allocator.alloc(8u64), 8);
+        assert_eq!(allocator.pool8.pages().count(), 1);
+    }
+
+    #[test]
+    fn test_trim_chain_tail() {
+        let allocator = Allocator::with_capacity(2);
+        let first: Vec<_> = (0..2u64).map(|i| allocator.alloc(i)).collect();
+        let tail: Vec<_> = (2..6u64).map(|i| allocator.alloc(i)).collect();
+        drop(tail);
+        assert_eq!(allocator.pool8.trim(0), 2);
+        // the chain grows again from its new tail
+        let more: Vec<_> = (0..5u64).map(|i| allocator.alloc(i)).collect();
+        assert_eq!(allocator.pool8.pages().count(), 4);
+        assert!(first.iter().chain(more.iter()).enumerate().all(|(i, p)| **p == if i < 2 { i as u64 } else { i as u64 - 2 }));
+    }
+
+    #[test]
+    fn test_trim_refunds_budget() {
+        let allocator = ::AllocatorBuilder::new()
+            .capacity_for(8, 2)
+            .budget(4096 * 8)
+            .build();
+        let mut ptrs: Vec<_> = (0..4u64).map(|i| allocator.alloc(i)).collect();
+        ptrs.truncate(1);
+        assert_eq!(allocator.trim(0), 1 + 5);
+        let used = allocator.pool8.budget.as_ref().unwrap().used();
+        let pages: usize = allocator.pages().iter().map(|p| p.bytes).sum();
+        assert_eq!(used, pages);
+    }
+}
@@ -0,0 +1,811 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A pool dedicated to a single type, whose slots are addressed by index.
+
+use std::mem::MaybeUninit;
+use std::ptr;
+
+use DEFAULT_POOL_SIZE;
+
+#[derive(Copy, Clone)]
+enum State {
+    Occupied,
+    /// A free slot, linking to the next free one.
+    Vacant(Option<u32>),
+    /// A free slot whose generation wrapped around, never handed out again.
+    Retired,
+}
+
+/// The free list link is kept beside the value rather than in it, so
+/// a free slot keeps the bytes of its previous occupant.
+pub(crate) struct Slot<T> {
+    /// Bumped whenever the slot is recycled.
+    generation: u32,
+    state: State,
+    value: MaybeUninit<T>,
+}
+
+/// A pool of `T`s, whose slots are addressed by `u32` indices.
+///
+/// Pages hold a power of two number of slots, so an index is split into
+/// a page index in the high bits and a slot index in the low bits, and
+/// looked up in O(1) through the page directory. Pages are never moved
+/// or freed until the pool is, so the address of an element stays the
+/// same until it is recycled.
+///
+/// Each slot has a generation, bumped whenever it is recycled, to tell
+/// its successive occupants apart. A slot whose generation would wrap
+/// around is retired instead, and never handed out again.
+pub struct TypedPool<T> {
+    pages: Vec<Box<[Slot<T>]>>,
+    page_bits: u32,
+    free: Option<u32>,
+    len: usize,
+    /// Number of retired slots.
+    retired: usize,
+    hooks: Option<Hooks<T>>,
+}
+
+type Hook<T> = Box<dyn FnMut(&mut T)>;
+
+struct Hooks<T> {
+    on_alloc: Hook<T>,
+    on_free: Hook<T>,
+}
+
+impl<T> TypedPool<T> {
+    /// Construct a pool with pages of about 4096 bytes, or 1024 bytes
+    /// on wasm32.
+    pub fn new() -> TypedPool<T> {
+        let per_page = DEFAULT_POOL_SIZE / std::cmp::max(std::mem::size_of::<Slot<T>>(), 1);
+        // round down to a power of two
+        let page_bits = usize::BITS - 1 - std::cmp::max(per_page, 1).leading_zeros();
+        TypedPool{ pages: Vec::new(), page_bits, free: None, len: 0, retired: 0, hooks: None }
+    }
+
+    /// Construct a pool with pages of `slots` slots, rounded up to
+    /// a power of two.
+    #[track_caller]
+    pub fn with_page_size(slots: usize) -> TypedPool<T> {
+        assert!(slots > 0 && slots <= 1 << 31, "page size out of range: {} slots of `{}`, expected 1 to 2^31",
+                slots, std::any::type_name::<T>());
+        let page_bits = slots.next_power_of_two().trailing_zeros();
+        TypedPool{ pages: Vec::new(), page_bits, free: None, len: 0, retired: 0, hooks: None }
+    }
+
+    /// Set hooks to run on every element handed out by `alloc`, and on
+    /// every element about to be moved out by `recycle`, e.g. to acquire
+    /// and reset the external resources they stand for. Elements still
+    /// in the pool when it is dropped are dropped without `on_free`.
+    ///
+    /// A hook can't reach the pool running it, unless the pool is shared
+    /// through a `RefCell` or the like, whose borrow then fails.
+    pub fn set_hooks<A, F>(&mut self, on_alloc: A, on_free: F)
+        where A: FnMut(&mut T) + 'static,
+              F: FnMut(&mut T) + 'static,
+    {
+        self.hooks = Some(Hooks{ on_alloc: Box::new(on_alloc), on_free: Box::new(on_free) });
+    }
+
+    /// Remove the hooks, if any.
+    #[inline]
+    pub fn clear_hooks(&mut self) {
+        self.hooks = None;
+    }
+
+    /// Number of slots per page.
+    #[inline]
+    pub fn page_size(&self) -> usize {
+        1 << self.page_bits
+    }
+
+    /// Number of occupied slots.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether no slot is occupied.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Number of slots of all pages.
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.pages.len() << self.page_bits
+    }
+
+    /// Number of slots to be handed out before the pool grows, that is
+    /// the slots neither occupied nor retired.
+    #[inline]
+    pub fn free_slots(&self) -> usize {
+        self.capacity() - self.len - self.retired
+    }
+
+    /// Add pages until at least `additional` slots are free.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pool would hold more than `u32::MAX` slots.
+    pub fn reserve(&mut self, additional: usize) {
+        while self.free_slots() < additional {
+            self.extend();
+        }
+    }
+
+    /// Move `value` into a free slot, growing the pool if needed,
+    /// return the index of the slot.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pool would hold more than `u32::MAX` slots.
+    pub fn alloc(&mut self, value: T) -> u32 {
+        let index = match self.free {
+            Some(index) => index,
+            None => self.extend(),
+        };
+        self.occupy(index);
+        let mask = self.slot_mask();
+        let slot = &mut self.pages[(index >> self.page_bits) as usize][(index & mask) as usize];
+        let value = slot.value.write(value);
+        if let Some(hooks) = self.hooks.as_mut() {
+            (hooks.on_alloc)(value);
+        }
+        index
+    }
+
+    /// Take a free slot without writing anything into it, growing the
+    /// pool if needed, return its index and its contents.
+    ///
+    /// The contents are whatever the previous occupant of the slot left,
+    /// as the free list link is stored out of the slot, or uninitialized
+    /// if the slot was never occupied. It saves writing a whole fresh
+    /// value when every field is overwritten anyway. `on_alloc` isn't
+    /// run for the slot.
+    ///
+    /// # Safety
+    ///
+    /// The contents must be fully initialized before the pool is used
+    /// again, as from then on the slot is treated as occupied.
+    pub unsafe fn alloc_recycled(&mut self) -> (u32, &mut MaybeUninit<T>) {
+        let index = match self.free {
+            Some(index) => index,
+            None => self.extend(),
+        };
+        self.occupy(index);
+        (index, &mut self.slot_mut(index).unwrap().value)
+    }
+
+    /// Get the element at `index`, if the slot is occupied.
+    #[inline]
+    pub fn get(&self, index: u32) -> Option<&T> {
+        let slot = self.slot(index)?;
+        match slot.state {
+            State::Occupied => Some(unsafe { slot.value.assume_init_ref() }),
+            State::Vacant(_) | State::Retired => None,
+        }
+    }
+
+    /// Iterate over the occupied slots by ascending index, yielding their
+    /// indices and elements.
+    #[inline]
+    pub fn iter(&self) -> TypedPoolIter<'_, T> {
+        TypedPoolIter{ pages: self.pages.iter(), slots: [].iter(), index: 0, len: self.len }
+    }
+
+    /// Iterate over the occupied slots by ascending index, yielding their
+    /// indices and elements mutably.
+    #[inline]
+    pub fn iter_mut(&mut self) -> TypedPoolIterMut<'_, T> {
+        TypedPoolIterMut{ pages: self.pages.iter_mut(), slots: [].iter_mut(), index: 0, len: self.len }
+    }
+
+    /// Get the generation of the slot at `index`, occupied or not.
+    #[inline]
+    pub fn generation(&self, index: u32) -> Option<u32> {
+        self.slot(index).map(|slot| slot.generation)
+    }
+
+    /// Get the element at `index` mutably, if the slot is occupied.
+    #[inline]
+    pub fn get_mut(&mut self, index: u32) -> Option<&mut T> {
+        let slot = self.slot_mut(index)?;
+        match slot.state {
+            State::Occupied => Some(unsafe { slot.value.assume_init_mut() }),
+            State::Vacant(_) | State::Retired => None,
+        }
+    }
+
+    /// Move the element at `index` out and free the slot, if the slot
+    /// is occupied.
+    pub fn recycle(&mut self, index: u32) -> Option<T> {
+        let free = self.free;
+        let mask = self.slot_mask();
+        let slot = self.pages.get_mut((index >> self.page_bits) as usize)?
+            .get_mut((index & mask) as usize)
+            .unwrap();
+        if !matches!(slot.state, State::Occupied) {
+            return None;
+        }
+        if let Some(hooks) = self.hooks.as_mut() {
+            (hooks.on_free)(unsafe { slot.value.assume_init_mut() });
+        }
+        slot.generation = slot.generation.wrapping_add(1);
+        let retired = slot.generation == 0;
+        slot.state = if retired { State::Retired } else { State::Vacant(free) };
+        // a bitwise copy, the bytes stay for `alloc_recycled`
+        let value = unsafe { slot.value.assume_init_read() };
+        if retired {
+            self.retired += 1;
+        } else {
+            self.free = Some(index);
+        }
+        self.len -= 1;
+        Some(value)
+    }
+
+    /// Visit the occupied slots by ascending index, freeing those for which
+    /// `f` returns `false` as `recycle` does, hooks included, and dropping
+    /// their elements. The slots freed are handed out again last first.
+    ///
+    /// If `f` or a hook panics, the slots freed so far stay free, and
+    /// the slots left unvisited stay occupied.
+    pub fn retain<F: FnMut(u32, &mut T) -> bool>(&mut self, mut f: F) {
+        let page_bits = self.page_bits;
+        for (p, page) in self.pages.iter_mut().enumerate() {
+            for (i, slot) in page.iter_mut().enumerate() {
+                if !matches!(slot.state, State::Occupied) {
+                    continue;
+                }
+                let index = ((p << page_bits) + i) as u32;
+                let value = unsafe { slot.value.assume_init_mut() };
+                if f(index, value) {
+                    continue;
+                }
+                if let Some(hooks) = self.hooks.as_mut() {
+                    (hooks.on_free)(value);
+                }
+                slot.generation = slot.generation.wrapping_add(1);
+                if slot.generation == 0 {
+                    slot.state = State::Retired;
+                    self.retired += 1;
+                } else {
+                    slot.state = State::Vacant(self.free);
+                    self.free = Some(index);
+                }
+                self.len -= 1;
+                // the slot is free by now, should the drop panic
+                unsafe { ptr::drop_in_place(slot.value.as_mut_ptr()) };
+            }
+        }
+    }
+
+    /// Free every slot as `recycle` does, hooks included, dropping the
+    /// elements, but keep the pages. The slots are visited from the last
+    /// one, so they're handed out again from the first one.
+    ///
+    /// If a hook or a drop panics, the slots left unvisited keep their
+    /// elements, and the free slots among them go unused until the pool
+    /// is cleared again.
+    pub fn clear(&mut self) {
+        self.free = None;
+        let page_bits = self.page_bits;
+        for (p, page) in self.pages.iter_mut().enumerate().rev() {
+            for (i, slot) in page.iter_mut().enumerate().rev() {
+                let index = ((p << page_bits) + i) as u32;
+                match slot.state {
+                    State::Retired => continue,
+                    State::Vacant(_) => {
+                        slot.state = State::Vacant(self.free);
+                        self.free = Some(index);
+                        continue;
+                    }
+                    State::Occupied => { }
+                }
+                if let Some(hooks) = self.hooks.as_mut() {
+                    (hooks.on_free)(unsafe { slot.value.assume_init_mut() });
+                }
+                slot.generation = slot.generation.wrapping_add(1);
+                if slot.generation == 0 {
+                    slot.state = State::Retired;
+                    self.retired += 1;
+                } else {
+                    slot.state = State::Vacant(self.free);
+                    self.free = Some(index);
+                }
+                self.len -= 1;
+                unsafe { ptr::drop_in_place(slot.value.as_mut_ptr()) };
+            }
+        }
+    }
+
+    /// Unlink the free slot at `index` from the free list, mark it occupied.
+    #[inline]
+    fn occupy(&mut self, index: u32) {
+        let slot = self.slot_mut(index).unwrap();
+        match std::mem::replace(&mut slot.state, State::Occupied) {
+            State::Vacant(next) => self.free = next,
+            State::Occupied | State::Retired => unreachable!(),
+        }
+        self.len += 1;
+    }
+
+    #[inline]
+    fn slot_mask(&self) -> u32 {
+        ((1u64 << self.page_bits) - 1) as u32
+    }
+
+    #[inline]
+    fn slot(&self, index: u32) -> Option<&Slot<T>> {
+        let page = self.pages.get((index >> self.page_bits) as usize)?;
+        Some(&page[(index & self.slot_mask()) as usize])
+    }
+
+    #[inline]
+    fn slot_mut(&mut self, index: u32) -> Option<&mut Slot<T>> {
+        let mask = self.slot_mask();
+        let page = self.pages.get_mut((index >> self.page_bits) as usize)?;
+        Some(&mut page[(index & mask) as usize])
+    }
+
+    /// Move the elements of the last slots into the first free ones, then
+    /// release the pages left without elements at the end, returning the
+    /// bytes released. `moved` is called with every element moved, and
+    /// its new index. The slots moved out are recycled, their generation
+    /// bumped, but hooks aren't run.
+    ///
+    /// Indices of moved elements are invalidated, hence this is left to
+    /// owners mapping their own keys to indices, such as `Slab`.
+    pub(crate) fn compact<F: FnMut(&mut T, u32)>(&mut self, mut moved: F) -> usize {
+        let mut vacant = (0..self.capacity() as u32).filter(|&i| matches!(self.slot(i).unwrap().state, State::Vacant(_)));
+        let mut occupied = (0..self.capacity() as u32).rev().filter(|&i| matches!(self.slot(i).unwrap().state, State::Occupied));
+        let mut moves = Vec::new();
+        while let (Some(to), Some(from)) = (vacant.next(), occupied.next()) {
+            // moving within a page releases nothing
+            if to >> self.page_bits >= from >> self.page_bits {
+                break;
+            }
+            moves.push((from, to));
+        }
+        for (from, to) in moves {
+            let slot = self.slot_mut(from).unwrap();
+            let value = unsafe { slot.value.assume_init_read() };
+            slot.generation = slot.generation.wrapping_add(1);
+            if slot.generation == 0 {
+                slot.state = State::Retired;
+                self.retired += 1;
+            } else {
+                slot.state = State::Vacant(None);
+            }
+            let slot = self.slot_mut(to).unwrap();
+            slot.state = State::Occupied;
+            moved(slot.value.write(value), to);
+        }
+
+        let pages = self.pages.len();
+        while self.pages.last().is_some_and(|page| page.iter().all(|slot| !matches!(slot.state, State::Occupied))) {
+            let page = self.pages.pop().unwrap();
+            self.retired -= page.iter().filter(|slot| matches!(slot.state, State::Retired)).count();
+        }
+        // relink the free slots left, the first ones first
+        self.free = None;
+        for index in (0..self.capacity() as u32).rev() {
+            let free = self.free;
+            let slot = self.slot_mut(index).unwrap();
+            if let State::Vacant(_) = slot.state {
+                slot.state = State::Vacant(free);
+                self.free = Some(index);
+            }
+        }
+        (pages - self.pages.len()) * self.page_size() * std::mem::size_of::<Slot<T>>()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn set_generation(&mut self, index: u32, generation: u32) {
+        self.slot_mut(index).unwrap().generation = generation;
+    }
+
+    /// Add a new page, its slots handed out before the free ones, returning
+    /// the index of its first slot.
+    fn extend(&mut self) -> u32 {
+        let first = self.capacity();
+        let size = self.page_size();
+        assert!(first + size - 1 <= u32::MAX as usize, "TypedPool index space exhausted");
+        let first = first as u32;
+        let page: Vec<Slot<T>> = (1..size as u32)
+            .map(|i| State::Vacant(Some(first + i)))
+            .chain(Some(State::Vacant(self.free)))
+            .map(|state| Slot{ generation: 0, state, value: MaybeUninit::uninit() })
+            .collect();
+        self.pages.push(page.into_boxed_slice());
+        self.free = Some(first);
+        first
+    }
+}
+
+/// An iterator over the occupied slots of a `TypedPool`, see `TypedPool::iter`.
+pub struct TypedPoolIter<'a, T> {
+    pages: std::slice::Iter<'a, Box<[Slot<T>]>>,
+    /// The slots left of the current page.
+    slots: std::slice::Iter<'a, Slot<T>>,
+    /// Index of the next slot.
+    index: u32,
+    /// Number of occupied slots left.
+    len: usize,
+}
+
+impl<'a, T> Iterator for TypedPoolIter<'a, T> {
+    type Item = (u32, &'a T);
+
+    fn next(&mut self) -> Option<(u32, &'a T)> {
+        while self.len != 0 {
+            let slot = match self.slots.next() {
+                Some(slot) => slot,
+                None => {
+                    self.slots = self.pages.next()?.iter();
+                    continue;
+                }
+            };
+            let index = self.index;
+            self.index = self.index.wrapping_add(1);
+            if let State::Occupied = slot.state {
+                self.len -= 1;
+                return Some((index, unsafe { slot.value.assume_init_ref() }));
+            }
+        }
+        None
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.len, Some(self.len))
+    }
+}
+
+impl<'a, T> ExactSizeIterator for TypedPoolIter<'a, T> { }
+
+/// A mutable iterator over the occupied slots of a `TypedPool`, see
+/// `TypedPool::iter_mut`.
+pub struct TypedPoolIterMut<'a, T> {
+    pages: std::slice::IterMut<'a, Box<[Slot<T>]>>,
+    /// The slots left of the current page.
+    slots: std::slice::IterMut<'a, Slot<T>>,
+    /// Index of the next slot.
+    index: u32,
+    /// Number of occupied slots left.
+    len: usize,
+}
+
+impl<'a, T> Iterator for TypedPoolIterMut<'a, T> {
+    type Item = (u32, &'a mut T);
+
+    fn next(&mut self) -> Option<(u32, &'a mut T)> {
+        while self.len != 0 {
+            let slot = match self.slots.next() {
+                Some(slot) => slot,
+                None => {
+                    self.slots = self.pages.next()?.iter_mut();
+                    continue;
+                }
+            };
+            let index = self.index;
+            self.index = self.index.wrapping_add(1);
+            if let State::Occupied = slot.state {
+                self.len -= 1;
+                return Some((index, unsafe { slot.value.assume_init_mut() }));
+            }
+        }
+        None
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.len, Some(self.len))
+    }
+}
+
+impl<'a, T> ExactSizeIterator for TypedPoolIterMut<'a, T> { }
+
+impl<T> Drop for TypedPool<T> {
+    fn drop(&mut self) {
+        if !std::mem::needs_drop::<T>() {
+            return;
+        }
+        for slot in self.pages.iter_mut().flat_map(|page| page.iter_mut()) {
+            if let State::Occupied = slot.state {
+                unsafe { ptr::drop_in_place(slot.value.as_mut_ptr()) };
+            }
+        }
+    }
+}
+
+impl<T> Default for TypedPool<T> {
+    #[inline]
+    fn default() -> TypedPool<T> {
+        TypedPool::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::{Cell, RefCell};
+    use std::panic;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_page_size() {
+        let (small, large) = if cfg!(target_arch = "wasm32") { (32, 1) } else { (128, 4) };
+        assert_eq!(TypedPool::<u64>::new().page_size(), small);
+        assert_eq!(TypedPool::<[u8; 1000]>::new().page_size(), large);
+        assert_eq!(TypedPool::<[u8; 5000]>::new().page_size(), 1);
+        assert_eq!(TypedPool::<u8>::with_page_size(5).page_size(), 8);
+    }
+
+    #[test]
+    fn test_alloc_recycle() {
+        let mut pool = TypedPool::with_page_size(2);
+        let marker = Rc::new(());
+        let indices: Vec<u32> = (0..5).map(|_| pool.alloc(marker.clone())).collect();
+        assert_eq!(indices, vec![0, 1, 2, 3, 4]);
+        assert_eq!((pool.len(), pool.capacity()), (5, 6));
+
+        assert!(pool.recycle(3).is_some());
+        assert!(pool.recycle(3).is_none());
+        assert!(pool.get(3).is_none());
+        assert_eq!(pool.alloc(marker.clone()), 3);
+        assert_eq!(pool.generation(3), Some(1));
+        assert_eq!(pool.generation(4), Some(0));
+        assert_eq!(pool.generation(6), None);
+        drop(pool);
+        assert_eq!(Rc::strong_count(&marker), 1);
+    }
+
+    #[test]
+    fn test_retire_on_wraparound() {
+        let mut pool = TypedPool::with_page_size(2);
+        let index = pool.alloc(1u8);
+        pool.set_generation(index, u32::MAX);
+        assert_eq!(pool.recycle(index), Some(1));
+        assert_eq!(pool.generation(index), Some(0));
+        // the retired slot is skipped
+        assert_eq!(pool.alloc(2), 1);
+        assert_eq!(pool.alloc(3), 2);
+        assert_eq!(pool.get(index), None);
+        assert_eq!(pool.recycle(index), None);
+    }
+
+    #[test]
+    fn test_hooks() {
+        let allocs = Rc::new(Cell::new(0));
+        let frees = Rc::new(Cell::new(0));
+        let mut pool = TypedPool::with_page_size(4);
+        {
+            let allocs = allocs.clone();
+            let frees = frees.clone();
+            pool.set_hooks(move |v: &mut u32| {
+                allocs.set(allocs.get() + 1);
+                *v += 100;
+            }, move |v: &mut u32| {
+                frees.set(frees.get() + 1);
+                *v = 0;
+            });
+        }
+
+        let indices: Vec<u32> = (0..10).map(|i| pool.alloc(i)).collect();
+        assert_eq!(pool.capacity(), 12);
+        assert_eq!(pool.get(3), Some(&103));
+        for &i in indices.iter().step_by(2) {
+            assert_eq!(pool.recycle(i), Some(0));
+        }
+        assert_eq!(pool.recycle(0), None);
+        let _more: Vec<u32> = (0..9).map(|i| pool.alloc(i)).collect();
+        assert_eq!(pool.capacity(), 16);
+        assert_eq!((allocs.get(), frees.get()), (19, 5));
+
+        pool.clear_hooks();
+        pool.alloc(1);
+        pool.recycle(1);
+        assert_eq!((allocs.get(), frees.get()), (19, 5));
+    }
+
+    #[test]
+    #[cfg_attr(target_family = "wasm", ignore = "panics abort on wasm")]
+    fn test_reentrant_hook_panics() {
+        let pool = Rc::new(RefCell::new(TypedPool::with_page_size(4)));
+        {
+            let weak = Rc::downgrade(&pool);
+            pool.borrow_mut().set_hooks(move |_: &mut u32| {
+                weak.upgrade().unwrap().borrow_mut().alloc(0);
+            }, |_| ());
+        }
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            pool.borrow_mut().alloc(1);
+        }));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_compact() {
+        let mut pool = TypedPool::with_page_size(4);
+        let marker = Rc::new(());
+        let indices: Vec<u32> = (0..16).map(|_| pool.alloc(marker.clone())).collect();
+        pool.set_generation(1, u32::MAX);
+        pool.recycle(1);
+        for &i in indices.iter().filter(|&&i| i % 5 != 0 && i != 1) {
+            pool.recycle(i);
+        }
+
+        let mut moves = Vec::new();
+        let released = pool.compact(|_, to| moves.push(to));
+        // 15 and 10 move into 2 and 3, 1 being retired, 5 stays
+        assert_eq!(moves, [2, 3]);
+        assert_eq!(released, 2 * 4 * std::mem::size_of::<Slot<Rc<()>>>());
+        assert_eq!((pool.len(), pool.capacity()), (4, 8));
+        assert!([0, 2, 3, 5].iter().all(|&i| pool.get(i).is_some()));
+        assert_eq!((pool.generation(15), pool.generation(2)), (None, Some(1)));
+        assert_eq!(Rc::strong_count(&marker), 5);
+        // the first free slots are handed out first
+        let next: Vec<u32> = (0..4).map(|_| pool.alloc(marker.clone())).collect();
+        assert_eq!(next, [4, 6, 7, 8]);
+        assert_eq!(pool.compact(|_, _| unreachable!()), 0);
+        drop(pool);
+        assert_eq!(Rc::strong_count(&marker), 1);
+    }
+
+    #[test]
+    fn test_alloc_recycled() {
+        let mut pool = TypedPool::with_page_size(2);
+        let index = pool.alloc([7u64; 32]);
+        assert_eq!(pool.recycle(index), Some([7; 32]));
+        let (again, contents) = unsafe { pool.alloc_recycled() };
+        assert_eq!(again, index);
+        // all of the previous contents survive, the link included
+        let contents = unsafe { contents.assume_init_mut() };
+        assert_eq!(*contents, [7; 32]);
+        contents[0] = 1;
+        assert_eq!(pool.get(index).unwrap()[..2], [1, 7]);
+        assert_eq!(pool.len(), 1);
+
+        let (fresh, contents) = unsafe { pool.alloc_recycled() };
+        contents.write([2; 32]);
+        assert_eq!(fresh, 1);
+        assert_eq!(pool.get(fresh), Some(&[2; 32]));
+    }
+
+    #[test]
+    fn test_iter() {
+        let mut pool = TypedPool::with_page_size(4);
+        assert_eq!(pool.iter().next(), None);
+        let indices: Vec<u32> = (0..10u32).map(|i| pool.alloc(i * 10)).collect();
+        for &i in indices.iter().filter(|&&i| i % 3 == 0) {
+            pool.recycle(i);
+        }
+        // by ascending index, across pages
+        let live: Vec<(u32, u32)> = pool.iter().map(|(i, v)| (i, *v)).collect();
+        assert_eq!(live, [(1, 10), (2, 20), (4, 40), (5, 50), (7, 70), (8, 80)]);
+        assert_eq!(pool.iter().len(), 6);
+
+        // the freed slots are refilled last freed first, then the next page
+        for i in 0..5 {
+            pool.alloc(100 + i);
+        }
+        for (i, v) in pool.iter_mut() {
+            *v += i;
+        }
+        let live: Vec<u32> = pool.iter().map(|(_, v)| *v).collect();
+        assert_eq!(live, [103, 11, 22, 105, 44, 55, 107, 77, 88, 109, 114]);
+        assert_eq!(pool.iter_mut().len(), 11);
+    }
+
+    #[test]
+    #[cfg_attr(target_family = "wasm", ignore = "panics abort on wasm")]
+    fn test_retain() {
+        let marker = Rc::new(());
+        let frees = Rc::new(Cell::new(0));
+        let mut pool = TypedPool::with_page_size(4);
+        {
+            let frees = frees.clone();
+            pool.set_hooks(|_| { }, move |_: &mut (u32, Rc<()>)| frees.set(frees.get() + 1));
+        }
+        for i in 0..10 {
+            pool.alloc((i, marker.clone()));
+        }
+        let mut visited = Vec::new();
+        pool.retain(|index, value| {
+            visited.push(index);
+            value.0 % 3 != 0
+        });
+        assert_eq!(visited, (0..10).collect::<Vec<u32>>());
+        assert_eq!((pool.len(), frees.get(), Rc::strong_count(&marker)), (6, 4, 7));
+        assert_eq!(pool.generation(3), Some(1));
+        assert!(pool.get(9).is_none());
+        // last freed first
+        assert_eq!(pool.alloc((9, marker.clone())), 9);
+        assert_eq!(pool.alloc((6, marker.clone())), 6);
+
+        // a panic leaves the slots before it freed, and the rest alone
+        let err = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            pool.retain(|index, _| {
+                assert!(index < 5, "stop");
+                false
+            });
+        })).unwrap_err();
+        assert_eq!(err.downcast_ref::<&str>(), Some(&"stop"));
+        assert_eq!(pool.len(), 5);
+        assert!((0..5).all(|i| pool.get(i).is_none()));
+        assert_eq!(pool.iter().map(|(i, _)| i).collect::<Vec<u32>>(), [5, 6, 7, 8, 9]);
+        drop(pool);
+        assert_eq!(Rc::strong_count(&marker), 1);
+    }
+
+    #[test]
+    fn test_clear() {
+        let marker = Rc::new(());
+        let frees = Rc::new(Cell::new(0));
+        let mut pool = TypedPool::with_page_size(4);
+        {
+            let frees = frees.clone();
+            pool.set_hooks(|_| { }, move |_: &mut Rc<()>| frees.set(frees.get() + 1));
+        }
+        for _ in 0..10 {
+            pool.alloc(marker.clone());
+        }
+        pool.recycle(3);
+        pool.recycle(7);
+        pool.clear();
+        assert_eq!((pool.len(), pool.capacity()), (0, 12));
+        // dropped once each
+        assert_eq!((frees.get(), Rc::strong_count(&marker)), (10, 1));
+        assert!((0..12).all(|i| pool.get(i).is_none()));
+        let generations: Vec<u32> = (0..12).map(|i| pool.generation(i).unwrap()).collect();
+        assert_eq!(generations, [1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0]);
+        let next: Vec<u32> = (0..12).map(|_| pool.alloc(marker.clone())).collect();
+        assert_eq!(next, (0..12).collect::<Vec<u32>>());
+        assert_eq!(pool.capacity(), 12);
+        pool.clear_hooks();
+        pool.clear();
+        pool.clear();
+        assert_eq!((pool.len(), pool.generation(0), Rc::strong_count(&marker)), (0, Some(2), 1));
+    }
+
+    #[test]
+    fn test_counters() {
+        let mut pool = TypedPool::with_page_size(4);
+        let counters = |pool: &TypedPool<u16>| (pool.len(), pool.capacity(), pool.free_slots(), pool.is_empty());
+        assert_eq!(counters(&pool), (0, 0, 0, true));
+        for i in 0..5 {
+            pool.alloc(i);
+        }
+        assert_eq!(counters(&pool), (5, 8, 3, false));
+        pool.recycle(1);
+        assert_eq!(counters(&pool), (4, 8, 4, false));
+
+        // reserving links the new pages before the free slots
+        pool.reserve(4);
+        assert_eq!(counters(&pool), (4, 8, 4, false));
+        pool.reserve(6);
+        assert_eq!(counters(&pool), (4, 12, 8, false));
+        let next: Vec<u32> = (0..8).map(|i| pool.alloc(i)).collect();
+        assert_eq!(next, [8, 9, 10, 11, 1, 5, 6, 7]);
+        assert_eq!(counters(&pool), (12, 12, 0, false));
+
+        // retired slots are neither occupied nor free
+        pool.set_generation(0, u32::MAX);
+        pool.recycle(0);
+        assert_eq!(counters(&pool), (11, 12, 0, false));
+        pool.clear();
+        assert_eq!(counters(&pool), (0, 12, 11, true));
+        pool.alloc(0);
+        assert_eq!(pool.compact(|_, _| { }), 2 * 4 * std::mem::size_of::<Slot<u16>>());
+        assert_eq!(counters(&pool), (1, 4, 2, false));
+    }
+}
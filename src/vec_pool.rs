@@ -0,0 +1,411 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Recycling the heap buffers of `Vec`s and `String`s, bucketed by
+//! capacity.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+
+/// Number of buffers a bucket retains by default.
+const DEFAULT_RETENTION: usize = 16;
+
+/// A store of empty `Vec<T>`s, handed out instead of allocating fresh
+/// ones, and taken back instead of freed.
+///
+/// Buffers are bucketed by capacity class, the class of a buffer being
+/// its capacity rounded down to a power of two. Each bucket retains a
+/// bounded number of buffers, the extra ones being freed as they come
+/// back. Unlike the slots of an `Allocator`, the buffers live on the
+/// global heap.
+pub struct VecPool<T> {
+    buckets: RefCell<Vec<Vec<Vec<T>>>>,
+    retention: usize,
+}
+
+impl<T> VecPool<T> {
+    /// Construct a pool retaining up to 16 buffers per capacity class.
+    #[inline]
+    pub fn new() -> VecPool<T> {
+        VecPool::with_retention(DEFAULT_RETENTION)
+    }
+
+    /// Construct a pool retaining up to `retention` buffers per
+    /// capacity class.
+    pub fn with_retention(retention: usize) -> VecPool<T> {
+        let buckets = (0..usize::BITS).map(|_| Vec::new()).collect();
+        VecPool{ buckets: RefCell::new(buckets), retention }
+    }
+
+    /// Hand out an empty `Vec` of at least `min_capacity`, reusing a
+    /// retained buffer of its class if any, or allocating a buffer of
+    /// `min_capacity` rounded up to a power of two.
+    ///
+    /// The buffer comes back to the pool as the guard is dropped.
+    ///
+    /// ```rust
+    /// use aren_alloc::VecPool;
+    /// let pool = VecPool::new();
+    /// let mut scratch = pool.take(100);
+    /// scratch.extend(0..100u32);
+    /// let addr = scratch.as_ptr();
+    /// drop(scratch);
+    ///
+    /// let scratch = pool.take(80);
+    /// assert!(scratch.is_empty() && scratch.capacity() >= 100);
+    /// assert_eq!(scratch.as_ptr(), addr);
+    /// ```
+    pub fn take(&self, min_capacity: usize) -> PooledVecGuard<'_, T> {
+        PooledVecGuard{ vec: self.pop(min_capacity), pool: self }
+    }
+
+    /// Take a retained buffer of the class of `min_capacity`, or a new one.
+    fn pop(&self, min_capacity: usize) -> Vec<T> {
+        match min_capacity.checked_next_power_of_two() {
+            Some(capacity) => {
+                let class = capacity.trailing_zeros() as usize;
+                match self.buckets.borrow_mut()[class].pop() {
+                    Some(vec) => vec,
+                    None => Vec::with_capacity(capacity),
+                }
+            }
+            None => Vec::with_capacity(min_capacity),
+        }
+    }
+
+    /// Take a retained buffer of the largest class, if any.
+    fn pop_largest(&self) -> Option<Vec<T>> {
+        self.buckets.borrow_mut().iter_mut().rev().find_map(Vec::pop)
+    }
+
+    /// Clear `vec` and retain it, unless its bucket is full.
+    fn give_back(&self, mut vec: Vec<T>) {
+        if vec.capacity() == 0 {
+            return;
+        }
+        vec.clear();
+        let class = (usize::BITS - 1 - vec.capacity().leading_zeros()) as usize;
+        let mut buckets = self.buckets.borrow_mut();
+        if buckets[class].len() < self.retention {
+            buckets[class].push(vec);
+        }
+    }
+
+    /// Number of buffers retained, of all classes.
+    pub fn retained(&self) -> usize {
+        self.buckets.borrow().iter().map(Vec::len).sum()
+    }
+
+    /// Free the retained buffers beyond the first `keep` of each class,
+    /// return how many were freed. `trim(0)` frees every one.
+    pub fn trim(&self, keep: usize) -> usize {
+        let mut buckets = self.buckets.borrow_mut();
+        buckets.iter_mut()
+            .map(|bucket| {
+                let excess = bucket.len().saturating_sub(keep);
+                bucket.truncate(keep);
+                excess
+            })
+            .sum()
+    }
+}
+
+impl<T> Default for VecPool<T> {
+    #[inline]
+    fn default() -> VecPool<T> {
+        VecPool::new()
+    }
+}
+
+impl<T> fmt::Debug for VecPool<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("VecPool")
+            .field("retained", &self.retained())
+            .field("retention", &self.retention)
+            .finish()
+    }
+}
+
+/// A `Vec` handed out by a `VecPool`, given back to it on drop.
+pub struct PooledVecGuard<'a, T: 'a> {
+    vec: Vec<T>,
+    pool: &'a VecPool<T>,
+}
+
+impl<'a, T> PooledVecGuard<'a, T> {
+    /// Keep the `Vec` rather than giving it back to its pool.
+    ///
+    /// This is an associated function so that `Vec`'s methods won't be
+    /// shadowed.
+    #[inline]
+    pub fn into_inner(mut this: Self) -> Vec<T> {
+        // the empty `Vec` left isn't retained
+        std::mem::take(&mut this.vec)
+    }
+}
+
+impl<'a, T> Deref for PooledVecGuard<'a, T> {
+    type Target = Vec<T>;
+    #[inline]
+    fn deref(&self) -> &Vec<T> {
+        &self.vec
+    }
+}
+
+impl<'a, T> DerefMut for PooledVecGuard<'a, T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut Vec<T> {
+        &mut self.vec
+    }
+}
+
+impl<'a, T: fmt::Debug> fmt::Debug for PooledVecGuard<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.vec.fmt(f)
+    }
+}
+
+impl<'a, T> Drop for PooledVecGuard<'a, T> {
+    fn drop(&mut self) {
+        self.pool.give_back(std::mem::take(&mut self.vec));
+    }
+}
+
+/// A store of empty `String`s, handed out instead of allocating fresh
+/// ones, and taken back instead of freed, see `VecPool`.
+///
+/// ```rust
+/// use std::fmt::Write;
+/// use aren_alloc::StringPool;
+/// let pool = StringPool::new();
+/// for i in 0..1000 {
+///     let mut line = pool.take_with_capacity(1024);
+///     write!(line, "{{\"frame\": {}}}", i).unwrap();
+///     assert!(line.capacity() >= 1024);
+/// }
+/// assert_eq!(pool.retained(), 1);
+/// ```
+pub struct StringPool {
+    bytes: VecPool<u8>,
+}
+
+impl StringPool {
+    /// Construct a pool retaining up to 16 strings per capacity class.
+    #[inline]
+    pub fn new() -> StringPool {
+        StringPool{ bytes: VecPool::new() }
+    }
+
+    /// Construct a pool retaining up to `retention` strings per
+    /// capacity class.
+    #[inline]
+    pub fn with_retention(retention: usize) -> StringPool {
+        StringPool{ bytes: VecPool::with_retention(retention) }
+    }
+
+    /// Hand out an empty `String`, reusing the retained string of the
+    /// largest capacity if any, or a new `String` of no capacity.
+    ///
+    /// The string comes back to the pool as the guard is dropped.
+    #[inline]
+    pub fn take(&self) -> PooledStringGuard<'_> {
+        let bytes = self.bytes.pop_largest().unwrap_or_default();
+        PooledStringGuard{ string: StringPool::string(bytes), pool: self }
+    }
+
+    /// Hand out an empty `String` of at least `min_capacity`, as
+    /// `VecPool::take` hands out a `Vec`.
+    #[inline]
+    pub fn take_with_capacity(&self, min_capacity: usize) -> PooledStringGuard<'_> {
+        let bytes = self.bytes.pop(min_capacity);
+        PooledStringGuard{ string: StringPool::string(bytes), pool: self }
+    }
+
+    #[inline]
+    fn string(bytes: Vec<u8>) -> String {
+        debug_assert!(bytes.is_empty());
+        // an empty buffer is valid UTF-8
+        unsafe { String::from_utf8_unchecked(bytes) }
+    }
+
+    /// Number of strings retained, of all classes.
+    #[inline]
+    pub fn retained(&self) -> usize {
+        self.bytes.retained()
+    }
+
+    /// Free the retained strings beyond the first `keep` of each class,
+    /// return how many were freed. `trim(0)` frees every one.
+    #[inline]
+    pub fn trim(&self, keep: usize) -> usize {
+        self.bytes.trim(keep)
+    }
+}
+
+impl Default for StringPool {
+    #[inline]
+    fn default() -> StringPool {
+        StringPool::new()
+    }
+}
+
+impl fmt::Debug for StringPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("StringPool")
+            .field("retained", &self.retained())
+            .field("retention", &self.bytes.retention)
+            .finish()
+    }
+}
+
+/// A `String` handed out by a `StringPool`, given back to it on drop.
+pub struct PooledStringGuard<'a> {
+    string: String,
+    pool: &'a StringPool,
+}
+
+impl<'a> PooledStringGuard<'a> {
+    /// Keep the `String` rather than giving it back to its pool.
+    ///
+    /// This is an associated function so that `String`'s methods won't
+    /// be shadowed.
+    #[inline]
+    pub fn into_inner(mut this: Self) -> String {
+        std::mem::take(&mut this.string)
+    }
+}
+
+impl<'a> Deref for PooledStringGuard<'a> {
+    type Target = String;
+    #[inline]
+    fn deref(&self) -> &String {
+        &self.string
+    }
+}
+
+impl<'a> DerefMut for PooledStringGuard<'a> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut String {
+        &mut self.string
+    }
+}
+
+impl<'a> fmt::Write for PooledStringGuard<'a> {
+    #[inline]
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.string.push_str(s);
+        Ok(())
+    }
+
+    #[inline]
+    fn write_char(&mut self, c: char) -> fmt::Result {
+        self.string.push(c);
+        Ok(())
+    }
+}
+
+impl<'a> fmt::Debug for PooledStringGuard<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.string.fmt(f)
+    }
+}
+
+impl<'a> fmt::Display for PooledStringGuard<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.string.fmt(f)
+    }
+}
+
+impl<'a> Drop for PooledStringGuard<'a> {
+    fn drop(&mut self) {
+        let string = std::mem::take(&mut self.string);
+        self.pool.bytes.give_back(string.into_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_capacity_retained() {
+        let pool = VecPool::new();
+        let marker = Rc::new(());
+        let mut addr = None;
+        for round in 0..10 {
+            let mut vec = pool.take(50 + round);
+            assert!(vec.is_empty());
+            assert_eq!(vec.capacity(), 64);
+            assert_eq!(*addr.get_or_insert(vec.as_ptr()), vec.as_ptr());
+            vec.extend((0..40).map(|_| marker.clone()));
+        }
+        // cleared as given back
+        assert_eq!((pool.retained(), Rc::strong_count(&marker)), (1, 1));
+
+        // outgrown buffers go to the class of their new capacity
+        let mut vec = pool.take(64);
+        vec.extend((0..100).map(|_| marker.clone()));
+        drop(vec);
+        assert_eq!(pool.retained(), 1);
+        let kept = PooledVecGuard::into_inner(pool.take(128));
+        assert_eq!((kept.capacity(), pool.retained()), (128, 0));
+        assert_eq!(pool.take(0).capacity(), 1);
+        assert_eq!(pool.retained(), 1);
+    }
+
+    #[test]
+    fn test_retention_and_trim() {
+        let pool = VecPool::<u8>::with_retention(3);
+        let taken: Vec<_> = (0..5).map(|_| pool.take(8)).chain((0..2).map(|_| pool.take(1000))).collect();
+        drop(taken);
+        assert_eq!(pool.retained(), 3 + 2);
+        let again: Vec<_> = (0..4).map(|_| pool.take(5)).collect();
+        assert_eq!(pool.retained(), 2);
+        drop(again);
+
+        assert_eq!(pool.trim(1), 2 + 1);
+        assert_eq!(pool.retained(), 2);
+        assert_eq!(pool.trim(1), 0);
+        assert_eq!(pool.trim(0), 2);
+        assert_eq!(pool.retained(), 0);
+    }
+
+    #[test]
+    fn test_string_pool() {
+        use std::fmt::Write;
+        let pool = StringPool::with_retention(2);
+        let mut first = pool.take();
+        assert_eq!(first.capacity(), 0);
+        write!(first, "{:>2000}", "secret").unwrap();
+        let capacity = first.capacity();
+        drop(first);
+
+        // the largest string comes back, cleared
+        let mut second = pool.take();
+        assert!(second.is_empty());
+        assert_eq!(second.capacity(), capacity);
+        write!(second, "{}", 42).unwrap();
+        assert_eq!(second.to_string(), "42");
+        let third = pool.take_with_capacity(100);
+        assert_eq!(third.capacity(), 128);
+        drop((second, third));
+        assert_eq!(pool.retained(), 2);
+
+        // the first string grew to 2048 bytes, a class of its own
+        assert_eq!(capacity, 2048);
+        let taken: Vec<_> = (0..4).map(|_| pool.take_with_capacity(1000)).collect();
+        drop(taken);
+        assert_eq!(pool.retained(), 2 + 2);
+        let kept = PooledStringGuard::into_inner(pool.take_with_capacity(1000));
+        assert_eq!((kept.capacity(), pool.retained()), (1024, 3));
+        assert_eq!(pool.trim(0), 3);
+        assert_eq!(pool.take().capacity(), 0);
+    }
+}
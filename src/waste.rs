@@ -0,0 +1,169 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Bytes wasted by the size classes, as slack and as free slots.
+
+use std::fmt;
+
+use Allocator;
+
+/// The waste of a size class, see `Allocator::waste_report`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ClassWaste {
+    /// Size of the slots of the class.
+    pub class_size: usize,
+    /// Number of slots currently handed out.
+    pub live: usize,
+    /// Mean size requested from the class so far, rounded up, or the
+    /// class size if nothing was requested.
+    pub mean_requested: usize,
+    /// Bytes of the live slots beyond their mean requested size.
+    pub slack_bytes: usize,
+    /// Bytes of the free slots of the pages of the class.
+    pub free_bytes: usize,
+}
+
+impl ClassWaste {
+    /// Percentage of the bytes of the live slots wasted as slack.
+    #[inline]
+    pub fn slack_percent(&self) -> usize {
+        match self.live * self.class_size {
+            0 => 0,
+            bytes => self.slack_bytes * 100 / bytes,
+        }
+    }
+}
+
+/// The waste of every class present of an allocator, from the smallest.
+///
+/// Its `Display` prints a table of the classes, then a suggestion.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WasteReport {
+    /// The waste of every class present, from the smallest.
+    pub classes: Vec<ClassWaste>,
+}
+
+/// Slack percentage from which a class is worth replacing.
+const SLACK_THRESHOLD: usize = 25;
+
+impl WasteReport {
+    /// The class wasting the most bytes as slack, along with a smaller
+    /// class fitting its mean request, that rounded up to 8 bytes, the
+    /// smallest slot. Only classes wasting at least a quarter of their
+    /// live bytes are considered, and ties go to the smaller class.
+    pub fn suggestion(&self) -> Option<(usize, usize)> {
+        self.classes.iter()
+            .map(|class| (class, class.mean_requested.next_multiple_of(8)))
+            .filter(|&(class, fit)| class.slack_percent() >= SLACK_THRESHOLD && fit < class.class_size)
+            .max_by_key(|&(class, _)| (class.slack_bytes, std::cmp::Reverse(class.class_size)))
+            .map(|(class, fit)| (class.class_size, fit))
+    }
+}
+
+impl fmt::Display for WasteReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{:>5} {:>8} {:>5} {:>12} {:>12}", "class", "live", "mean", "slack bytes", "free bytes")?;
+        for class in self.classes.iter() {
+            writeln!(f, "{:>5} {:>8} {:>5} {:>12} {:>12}",
+                     class.class_size, class.live, class.mean_requested, class.slack_bytes, class.free_bytes)?;
+        }
+        match self.suggestion() {
+            Some((class_size, suggested)) => {
+                let slack = self.classes.iter().find(|class| class.class_size == class_size).unwrap().slack_percent();
+                write!(f, "{}-byte class: {}% slack \u{2014} consider a {}-byte custom class.", class_size, slack, suggested)
+            }
+            None => write!(f, "no class wastes {}% of its live bytes as slack.", SLACK_THRESHOLD),
+        }
+    }
+}
+
+impl Allocator {
+    /// Describe the bytes each class wastes: as slack, the live slots
+    /// being larger than the mean size requested from the class, and as
+    /// the free slots of its pages.
+    ///
+    /// ```rust
+    /// use aren_alloc::Allocator;
+    /// let allocator = Allocator::new();
+    /// let particles: Vec<_> = (0..10).map(|_| allocator.alloc([0f32; 10])).collect();
+    /// let report = allocator.waste_report();
+    /// assert_eq!(report.suggestion(), Some((64, 40)));
+    /// println!("{}", report);
+    /// ```
+    pub fn waste_report(&self) -> WasteReport {
+        let classes = self.pools().map(|pool| {
+            let stats = pool.stats();
+            let (requests, requested) = self.requests(pool);
+            let mean_requested = match requests {
+                0 => pool.ele_size,
+                n => requested.div_ceil(n),
+            };
+            ClassWaste{
+                class_size: pool.ele_size,
+                live: stats.live,
+                mean_requested,
+                slack_bytes: stats.live * (pool.ele_size - mean_requested),
+                free_bytes: (stats.slots - stats.live) * pool.ele_size,
+            }
+        }).collect();
+        WasteReport{ classes }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use AllocatorBuilder;
+
+    #[test]
+    fn test_waste_report() {
+        let allocator = AllocatorBuilder::new()
+            .capacity_for(16, 16)
+            .capacity_for(64, 8)
+            .only_classes(&[16, 64])
+            .build();
+        let small: Vec<_> = (0..10).map(|_| allocator.alloc([0u8; 12])).collect();
+        let big: Vec<_> = (0..5).map(|_| allocator.alloc([0u8; 40])).collect();
+        let report = allocator.waste_report();
+        assert_eq!(report.classes, [
+            ClassWaste{ class_size: 16, live: 10, mean_requested: 12, slack_bytes: 40, free_bytes: 6 * 16 },
+            ClassWaste{ class_size: 64, live: 5, mean_requested: 40, slack_bytes: 120, free_bytes: 3 * 64 },
+        ]);
+        assert_eq!(report.classes[0].slack_percent(), 25);
+        assert_eq!(report.suggestion(), Some((64, 40)));
+        assert_eq!(report.to_string(), "\
+class     live  mean  slack bytes   free bytes
+   16       10    12           40           96
+   64        5    40          120          192
+64-byte class: 37% slack \u{2014} consider a 40-byte custom class.");
+        drop((small, big));
+    }
+
+    #[test]
+    fn test_suggestion() {
+        let allocator = Allocator::new();
+        // a mean of 9 bytes wastes 43% of the 16 bytes class, but no
+        // smaller class fits it
+        let loose: Vec<_> = (0..20).map(|_| allocator.alloc_bytes_at_least(9).0).collect();
+        // 24 bytes of 32 waste 25%, fewer bytes
+        let tight: Vec<_> = (0..4).map(|_| allocator.alloc([0u64; 3])).collect();
+        let report = allocator.waste_report();
+        assert_eq!((report.classes[1].mean_requested, report.classes[1].slack_percent()), (9, 43));
+        assert_eq!(report.suggestion(), Some((32, 24)));
+        assert!(report.to_string().ends_with("32-byte class: 25% slack \u{2014} consider a 24-byte custom class."));
+        drop((loose, tight));
+
+        // nothing worth replacing
+        let allocator = Allocator::new();
+        let snug: Vec<_> = (0..4).map(|_| allocator.alloc([0u64; 4])).collect();
+        let report = allocator.waste_report();
+        assert_eq!(report.suggestion(), None);
+        assert!(report.to_string().ends_with("no class wastes 25% of its live bytes as slack."));
+        drop(snug);
+    }
+}
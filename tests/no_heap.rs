@@ -0,0 +1,53 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! `StaticAllocator` never touches the heap, checked by a global
+//! allocator counting every allocation made by this binary.
+
+extern crate aren_alloc;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use aren_alloc::StaticAllocator;
+
+struct Counting;
+
+static ALLOCS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+#[test]
+fn test_no_heap() {
+    let allocator = StaticAllocator::<2048>::new();
+    let before = ALLOCS.load(Ordering::SeqCst);
+    {
+        let a = allocator.alloc(1u8).unwrap();
+        let b = allocator.alloc([2u64; 16]).unwrap();
+        let exhausted = allocator.alloc([0u8; 256]).unwrap();
+        assert!(allocator.alloc([0u8; 256]).is_err());
+        drop(exhausted);
+        let c = allocator.alloc([3u8; 200]).unwrap();
+        assert_eq!((*a, b[15], c[199]), (1, 2, 3));
+    }
+    #[cfg(feature = "stats")]
+    assert_eq!(allocator.stats().live, 0);
+    assert_eq!(ALLOCS.load(Ordering::SeqCst), before);
+}
@@ -0,0 +1,52 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Misuse panics point at the caller rather than inside the crate,
+//! checked by a panic hook, hence a binary of its own.
+
+extern crate aren_alloc;
+
+use std::panic;
+use std::sync::Mutex;
+
+use aren_alloc::{Allocator, AllocatorBuilder, SyncAllocator};
+
+static LOCATION: Mutex<Option<(String, u32)>> = Mutex::new(None);
+
+/// Run `f`, which should panic, and return the line it panicked at.
+fn panic_line<F: FnOnce()>(f: F) -> u32 {
+    *LOCATION.lock().unwrap() = None;
+    assert!(panic::catch_unwind(panic::AssertUnwindSafe(f)).is_err());
+    let (file, line) = LOCATION.lock().unwrap().take().unwrap();
+    assert!(file.ends_with("panic_location.rs"), "panicked in {}", file);
+    line
+}
+
+#[test]
+fn test_panic_location() {
+    panic::set_hook(Box::new(|info| {
+        let location = info.location().unwrap();
+        *LOCATION.lock().unwrap() = Some((location.file().to_string(), location.line()));
+    }));
+
+    let allocator = Allocator::new();
+    let line = line!() + 1;
+    assert_eq!(panic_line(|| { allocator.alloc([0u8; 257]); }), line);
+    let line = line!() + 1;
+    assert_eq!(panic_line(|| { allocator.alloc_pinned([[0u8; 64]; 5]); }), line);
+    let line = line!() + 1;
+    assert_eq!(panic_line(|| { allocator.try_alloc([0u64; 40]).ok(); }), line);
+    let line = line!() + 1;
+    assert_eq!(panic_line(|| { allocator.alloc_rc([0u8; 256]); }), line);
+    let line = line!() + 1;
+    assert_eq!(panic_line(|| { SyncAllocator::new().alloc([0u8; 300]); }), line);
+    let line = line!() + 1;
+    assert_eq!(panic_line(|| { AllocatorBuilder::new().capacity_for(8, 0); }), line);
+
+    let _ = panic::take_hook();
+}
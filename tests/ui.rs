@@ -0,0 +1,21 @@
+// Copyright 2017 Dasein Phaos aka. Luxko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! What must and mustn't compile, with the expected compiler errors
+//! under `tests/ui`. Refresh them with `TRYBUILD=overwrite` after a
+//! compiler update changes their wording.
+
+extern crate trybuild;
+
+#[test]
+fn ui() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/ui/pointer_outlives_borrow.rs");
+    t.pass("tests/ui/pointer_covariant.rs");
+    t.compile_fail("tests/ui/assert_poolable_too_big.rs");
+}
@@ -0,0 +1,12 @@
+// A type larger than 256 bytes fails `assert_poolable` with a readable
+// message, at compile time.
+
+extern crate aren_alloc;
+
+use aren_alloc::assert_poolable;
+
+struct Frame([u8; 512]);
+
+const _: () = assert_poolable::<Frame>();
+
+fn main() { }
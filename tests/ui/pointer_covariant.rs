@@ -0,0 +1,18 @@
+// A `Pointer<&'static T>` is a `Pointer<&'short T>`.
+
+extern crate aren_alloc;
+
+use aren_alloc::{Allocator, Pointer};
+
+fn shorten<'a, 'short>(ptr: Pointer<'a, &'static str>) -> Pointer<'a, &'short str> {
+    ptr
+}
+
+fn main() {
+    let allocator = Allocator::new();
+    let local = String::from("local");
+    let mut ptrs: Vec<Pointer<&str>> = vec![shorten(allocator.alloc("static"))];
+    ptrs.push(allocator.alloc(&local));
+    assert_eq!(*ptrs[0], "static");
+    assert_eq!(*ptrs[1], "local");
+}
@@ -0,0 +1,15 @@
+// A `Pointer<&'short u32>` can't outlive `'short`.
+
+extern crate aren_alloc;
+
+use aren_alloc::{Allocator, Pointer};
+
+fn main() {
+    let allocator = Allocator::new();
+    let ptr: Pointer<&u32>;
+    {
+        let short = 1u32;
+        ptr = allocator.alloc(&short);
+    }
+    drop(ptr);
+}